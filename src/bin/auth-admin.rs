@@ -0,0 +1,391 @@
+use std::{io, path::PathBuf, process::ExitCode, sync::Arc};
+
+use auth::{
+    config::AuthConfig,
+    email::Mailer,
+    hashing::Argon2Hasher,
+    jwt::AppState,
+    keys::KeyPair,
+    outbox::Outbox,
+    password::send_reset_password_email,
+    AuthError, AuthResult, Credentials, User, UserDb,
+};
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::Serialize;
+use sqlx::sqlite::SqlitePoolOptions;
+
+#[derive(Clone, Debug, ValueEnum)]
+enum Output {
+    Table,
+    Json,
+}
+
+///
+/// Manage user accounts in the auth database.
+///
+#[derive(Parser, Debug)]
+#[command(name = "auth-admin", version, about)]
+struct Args {
+    /// SQLite database url, e.g. sqlite://data/users.db
+    #[arg(long, env = "DATABASE_URL")]
+    database: String,
+
+    /// TOML config file, otherwise config is read from the environment.
+    /// Supplies the password policy and hashing settings, and the email
+    /// settings reset-password sends with.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    #[arg(short, long, value_enum, default_value = "table")]
+    output: Output,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Create a user. The password is read from stdin if not given.
+    Create {
+        username: String,
+        email: String,
+        #[arg(long)]
+        password: Option<String>,
+        #[arg(long, default_value = "")]
+        first_name: String,
+        #[arg(long, default_value = "")]
+        last_name: String,
+    },
+    /// List users ordered by username
+    List {
+        #[arg(long, default_value_t = 0)]
+        offset: i64,
+        #[arg(long, default_value_t = 100)]
+        limit: i64,
+    },
+    /// Find a user by uuid, username or email
+    Find { id: String },
+    /// Stop a user from signing in
+    Disable { id: String },
+    /// Allow a disabled user to sign in again
+    Enable { id: String },
+    /// Permanently delete a user
+    Delete {
+        id: String,
+        /// Confirm the deletion
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Mark a user's email address as verified
+    Verify { id: String },
+    /// Email the user a password reset link, or a code if no callback is given
+    ResetPassword {
+        id: String,
+        #[arg(long)]
+        callback_url: Option<String>,
+        #[arg(long)]
+        url: Option<String>,
+    },
+    /// Set a user's password. The password is read from stdin if not given.
+    SetPassword {
+        id: String,
+        #[arg(long)]
+        password: Option<String>,
+    },
+    /// Register an OAuth client. Confidential clients are given a secret,
+    /// which is only shown once.
+    CreateClient {
+        name: String,
+        /// Required unless the client only uses grants without a redirect
+        #[arg(long = "redirect-uri")]
+        redirect_uris: Vec<String>,
+        /// Space separated scopes the client may ask for
+        #[arg(long, default_value = "")]
+        scope: String,
+        /// Grant types the client may use, such as client_credentials
+        #[arg(long = "grant-type", default_values = ["authorization_code", "refresh_token"])]
+        grant_types: Vec<String>,
+        #[arg(long)]
+        confidential: bool,
+    },
+    /// Replace the grant types an OAuth client may use
+    SetClientGrants {
+        client_id: String,
+        #[arg(long = "grant-type", required = true)]
+        grant_types: Vec<String>,
+    },
+    /// Give a confidential OAuth client a new secret, which is only shown
+    /// once
+    RotateClientSecret { client_id: String },
+    /// Remove an OAuth client along with its codes and consents
+    DeleteClient { client_id: String },
+}
+
+///
+/// Admin view of a user, including the account flags the public
+/// serialization of `User` hides.
+///
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct AdminUser {
+    uuid: String,
+    username: String,
+    email: String,
+    first_name: String,
+    last_name: String,
+    can_signin: bool,
+    email_verified: bool,
+    passwordless_only: bool,
+    locale: String,
+}
+
+impl From<&User> for AdminUser {
+    fn from(user: &User) -> Self {
+        AdminUser {
+            uuid: user.uuid.clone(),
+            username: user.username.clone(),
+            email: user.email.clone(),
+            first_name: user.first_name.clone(),
+            last_name: user.last_name.clone(),
+            can_signin: user.can_signin,
+            email_verified: user.email_verified,
+            passwordless_only: user.passwordless_only,
+            locale: user.locale.clone(),
+        }
+    }
+}
+
+fn print_users(users: &[User], output: &Output) {
+    let users: Vec<AdminUser> = users.iter().map(AdminUser::from).collect();
+
+    match output {
+        Output::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&users).unwrap_or_default()
+        ),
+        Output::Table => {
+            println!(
+                "{:<36}  {:<20}  {:<30}  {:<24}  {:<6}  {:<8}  {:<12}",
+                "UUID", "USERNAME", "EMAIL", "NAME", "SIGNIN", "VERIFIED", "PASSWORDLESS"
+            );
+
+            for user in &users {
+                println!(
+                    "{:<36}  {:<20}  {:<30}  {:<24}  {:<6}  {:<8}  {:<12}",
+                    user.uuid,
+                    user.username,
+                    user.email,
+                    format!("{} {}", user.first_name, user.last_name).trim(),
+                    user.can_signin,
+                    user.email_verified,
+                    user.passwordless_only
+                );
+            }
+        }
+    }
+}
+
+fn read_password(password: Option<String>) -> AuthResult<String> {
+    if let Some(password) = password {
+        return Ok(password);
+    }
+
+    eprintln!("password:");
+
+    let mut line = String::new();
+
+    match io::stdin().read_line(&mut line) {
+        Ok(_) => Ok(line.trim_end_matches(['\r', '\n']).to_string()),
+        Err(err) => Err(AuthError::PasswordError(err.to_string())),
+    }
+}
+
+fn load_config(path: &Option<PathBuf>) -> AuthResult<AuthConfig> {
+    match path {
+        Some(path) => AuthConfig::from_toml_file_unchecked(path),
+        None => AuthConfig::from_env_unchecked(),
+    }
+}
+
+///
+/// Build enough app state to send email through the outbox, which the
+/// server's worker then delivers.
+///
+fn app_state(config: AuthConfig, user_db: UserDb, outbox: Outbox) -> AuthResult<AppState> {
+    let key_pair = KeyPair::from_env("JWT_PRIVATE_KEY")?;
+
+    Ok(AppState {
+        mailer: Mailer::new(&config.smtp)?,
+        outbox: outbox.with_config(&config.outbox),
+        config: Arc::new(config),
+        user_db,
+        jwt_public_key: key_pair.jwt_decoding_key(),
+        jwt_private_key: key_pair.jwt_encoding_key()?,
+        jwt_public_jwk: key_pair.public_key().jwk(),
+    })
+}
+
+async fn run(args: Args) -> AuthResult<()> {
+    let pool = SqlitePoolOptions::new().connect(&args.database).await?;
+
+    // the same config the server runs with, so passwords set here follow
+    // its policy and pepper. Only sending email needs the rest of it.
+    let config = load_config(&args.config)?;
+    config.validate_passwords()?;
+
+    let user_db = UserDb::new(pool.clone())
+        .with_password_policy(&config.passwords)
+        .with_hasher(Argon2Hasher::new(&config.hashing)?);
+
+    user_db.migrate().await?;
+
+    match &args.command {
+        Command::Create {
+            username,
+            email,
+            password,
+            first_name,
+            last_name,
+        } => {
+            let credentials = Credentials {
+                username: username.clone(),
+                password: read_password(password.clone())?,
+                email: Some(email.clone()),
+                first_name: Some(first_name.clone()),
+                last_name: Some(last_name.clone()),
+                callback_url: None,
+                url: None,
+                locale: None,
+            };
+
+            let user = user_db.create_user(&credentials).await?;
+
+            print_users(&[user], &args.output);
+        }
+        Command::List { offset, limit } => {
+            print_users(&user_db.list_users(*offset, *limit).await?, &args.output);
+        }
+        Command::Find { id } => {
+            print_users(&[user_db.find_user_by_id(id).await?], &args.output);
+        }
+        Command::Disable { id } | Command::Enable { id } => {
+            let can_signin = matches!(args.command, Command::Enable { .. });
+            let user = user_db.find_user_by_id(id).await?;
+
+            user_db.set_can_signin(&user.uuid, can_signin).await?;
+
+            print_users(&[user_db.find_user_by_uuid(&user.uuid).await?], &args.output);
+        }
+        Command::Delete { id, yes } => {
+            if !yes {
+                eprintln!("{} was not deleted, pass --yes to confirm", id);
+                return Ok(());
+            }
+
+            let user = user_db.find_user_by_id(id).await?;
+
+            user_db.delete_user(&user.uuid).await?;
+
+            eprintln!("deleted {}", user.username);
+        }
+        Command::Verify { id } => {
+            let user = user_db.find_user_by_id(id).await?;
+
+            user_db.user_verified(&user.uuid).await?;
+
+            print_users(&[user_db.find_user_by_uuid(&user.uuid).await?], &args.output);
+        }
+        Command::ResetPassword {
+            id,
+            callback_url,
+            url,
+        } => {
+            config.validate_email()?;
+
+            let user = user_db.find_user_by_id(id).await?;
+            let state = app_state(config, user_db, Outbox::new(pool))?;
+
+            send_reset_password_email(&state, &user, callback_url.as_deref(), url.as_deref())
+                .await?;
+
+            eprintln!("queued password reset email for {}", user.email);
+        }
+        Command::SetPassword { id, password } => {
+            let user = user_db.find_user_by_id(id).await?;
+
+            user_db
+                .update_password(&user.uuid, &read_password(password.clone())?)
+                .await?;
+
+            eprintln!("updated password for {}", user.username);
+        }
+        Command::CreateClient {
+            name,
+            redirect_uris,
+            scope,
+            grant_types,
+            confidential,
+        } => {
+            let (client, secret) = user_db
+                .create_oauth_client(name, redirect_uris, scope, grant_types, *confidential)
+                .await?;
+
+            match args.output {
+                Output::Json => println!(
+                    "{}",
+                    serde_json::to_string_pretty(
+                        &serde_json::json!({ "client": client, "clientSecret": secret })
+                    )
+                    .unwrap_or_default()
+                ),
+                Output::Table => {
+                    println!("client id:     {}", client.client_id);
+
+                    if let Some(secret) = secret {
+                        println!("client secret: {}", secret);
+                    }
+                }
+            }
+        }
+        Command::SetClientGrants {
+            client_id,
+            grant_types,
+        } => {
+            let client = user_db
+                .set_oauth_client_grant_types(client_id, grant_types)
+                .await?;
+
+            eprintln!("client {} may use {}", client.client_id, client.grant_types);
+        }
+        Command::RotateClientSecret { client_id } => {
+            let secret = user_db.rotate_oauth_client_secret(client_id).await?;
+
+            match args.output {
+                Output::Json => println!(
+                    "{}",
+                    serde_json::json!({ "clientId": client_id, "clientSecret": secret })
+                ),
+                Output::Table => println!("client secret: {}", secret),
+            }
+        }
+        Command::DeleteClient { client_id } => {
+            user_db.find_oauth_client(client_id).await?;
+            user_db.delete_oauth_client(client_id).await?;
+
+            eprintln!("deleted client {}", client_id);
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    match run(Args::parse()).await {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("auth-admin: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
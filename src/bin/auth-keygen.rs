@@ -0,0 +1,66 @@
+use std::{path::PathBuf, process::ExitCode};
+
+use auth::keys::KeyPair;
+use clap::{Parser, ValueEnum};
+
+#[derive(Clone, Debug, ValueEnum)]
+enum Format {
+    Pem,
+    Hex,
+}
+
+///
+/// Generate an Ed25519 key pair for signing JWT and PASETO tokens.
+///
+#[derive(Parser, Debug)]
+#[command(name = "auth-keygen", version, about)]
+struct Args {
+    /// Directory to write NAME.pem, NAME.pub.pem, NAME.hex and NAME.pub.hex
+    /// to. If omitted the keys are printed to stdout.
+    #[arg(short, long)]
+    out: Option<PathBuf>,
+
+    /// Base file name for the written keys
+    #[arg(short, long, default_value = "auth")]
+    name: String,
+
+    /// Format to print when writing to stdout
+    #[arg(short, long, value_enum, default_value = "pem")]
+    format: Format,
+
+    /// Replace key files that already exist in the output directory
+    #[arg(long)]
+    force: bool,
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+
+    let key_pair = KeyPair::generate();
+
+    let result = match &args.out {
+        Some(dir) => key_pair.write_files(dir, &args.name, args.force).map(|_| {
+            eprintln!("wrote {} keys to {}", args.name, dir.display());
+        }),
+        None => match args.format {
+            Format::Pem => key_pair.private_pem().and_then(|private_pem| {
+                print!("{}", private_pem);
+                print!("{}", key_pair.public_key().pem()?);
+                Ok(())
+            }),
+            Format::Hex => {
+                println!("private {}", key_pair.private_hex());
+                println!("public {}", key_pair.public_key().hex());
+                Ok(())
+            }
+        },
+    };
+
+    match result {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("auth-keygen: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use std::{io, path::PathBuf, process::ExitCode};
+
+use auth::{
+    config::AuthConfig,
+    inspect::inspect_token,
+    jwt::{basic_jwt, TokenType},
+    keys::{KeyPair, PublicKey},
+    paseto::base_pasesto,
+    AuthError, AuthResult,
+};
+use clap::{Parser, Subcommand, ValueEnum};
+use rusty_paseto::core::{PasetoAsymmetricPrivateKey, Public, V4};
+use time::{Duration, OffsetDateTime};
+
+#[derive(Clone, Debug, ValueEnum)]
+enum Format {
+    Jwt,
+    Paseto,
+}
+
+///
+/// Inspect and mint JWT and PASETO tokens for debugging.
+///
+#[derive(Parser, Debug)]
+#[command(name = "auth-token", version, about)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Decode a token and check its signature. The token is read from
+    /// stdin if not given.
+    Inspect {
+        token: Option<String>,
+
+        /// Public key file, otherwise JWT_PUBLIC_KEY or JWT_PUBLIC_KEY_FILE
+        /// is used
+        #[arg(short, long)]
+        key: Option<PathBuf>,
+    },
+    /// Mint a signed token for a user
+    Mint {
+        #[arg(long)]
+        uuid: String,
+
+        /// refresh, access, passwordless, reset_password, verify_email,
+        /// change_email, revert_email_change or service
+        #[arg(long = "type", default_value = "access")]
+        token_type: TokenType,
+
+        #[arg(long, default_value = "")]
+        otp: String,
+
+        /// Lifetime of the token, defaulting to the configured TTL for its
+        /// type
+        #[arg(long)]
+        ttl_mins: Option<i64>,
+
+        #[arg(short, long, value_enum, default_value = "jwt")]
+        format: Format,
+
+        /// Private key file, otherwise JWT_PRIVATE_KEY or
+        /// JWT_PRIVATE_KEY_FILE is used
+        #[arg(short, long)]
+        key: Option<PathBuf>,
+
+        /// TOML config file, otherwise config is read from the
+        /// environment. Supplies the issuer and default TTLs.
+        #[arg(long)]
+        config: Option<PathBuf>,
+    },
+}
+
+fn read_token(token: &Option<String>) -> AuthResult<String> {
+    if let Some(token) = token {
+        return Ok(token.clone());
+    }
+
+    let mut line = String::new();
+
+    match io::stdin().read_line(&mut line) {
+        Ok(_) => Ok(line.trim().to_string()),
+        Err(err) => Err(AuthError::TokenError(err.to_string())),
+    }
+}
+
+fn load_config(path: &Option<PathBuf>) -> AuthResult<AuthConfig> {
+    match path {
+        Some(path) => AuthConfig::from_toml_file_unchecked(path),
+        None => AuthConfig::from_env_unchecked(),
+    }
+}
+
+fn run(args: Args) -> AuthResult<()> {
+    match args.command {
+        Command::Inspect { token, key } => {
+            let public_key = match key {
+                Some(path) => PublicKey::from_file(path)?,
+                None => PublicKey::from_env("JWT_PUBLIC_KEY")?,
+            };
+
+            let info = inspect_token(&read_token(&token)?, &public_key)?;
+
+            println!("{}", info);
+
+            if !info.signature_valid() || info.expired() {
+                return Err(AuthError::TokenError("token would be rejected".to_string()));
+            }
+        }
+        Command::Mint {
+            uuid,
+            token_type,
+            otp,
+            ttl_mins,
+            format,
+            key,
+            config,
+        } => {
+            // minted tokens must pass the same checks as the server's own,
+            // but the rest of the config plays no part in them
+            let config = load_config(&config)?;
+            config.validate_tokens()?;
+            let tokens = config.tokens;
+
+            let key_pair = match key {
+                Some(path) => KeyPair::from_file(path)?,
+                None => KeyPair::from_env("JWT_PRIVATE_KEY")?,
+            };
+
+            let ttl_mins = ttl_mins.unwrap_or(tokens.ttl_mins(&token_type));
+            let expires = OffsetDateTime::now_utc() + Duration::minutes(ttl_mins);
+
+            let token = match format {
+                Format::Jwt => basic_jwt(
+                    &uuid,
+                    &token_type,
+                    &otp,
+                    &tokens,
+                    &key_pair.jwt_encoding_key()?,
+                    expires.unix_timestamp(),
+                )?,
+                Format::Paseto => {
+                    let private_key = key_pair.paseto_private_key();
+
+                    let private_key =
+                        match PasetoAsymmetricPrivateKey::<V4, Public>::try_from(
+                            private_key.as_slice(),
+                        ) {
+                            Ok(private_key) => private_key,
+                            Err(err) => return Err(AuthError::CryptographyError(err.to_string())),
+                        };
+
+                    base_pasesto(&uuid, &token_type, &otp, &expires, &private_key)?
+                }
+            };
+
+            println!("{}", token);
+        }
+    }
+
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    match run(Args::parse()) {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("auth-token: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use std::{
+    cmp::Ordering,
+    fs::File,
+    io::{BufRead, BufReader, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use sha1::{Digest, Sha1};
+
+use crate::{AuthError, AuthResult};
+
+const SHA1_HEX_LEN: usize = 40;
+
+///
+/// Looks up passwords in a local copy of the Pwned Passwords SHA-1 list,
+/// one `HASH:COUNT` line per password sorted by hash, so no password or
+/// hash prefix ever leaves the server. The file is binary searched on
+/// disk so multi-gigabyte lists need no memory or index.
+///
+#[derive(Clone, Debug)]
+pub struct BreachedPasswords {
+    path: PathBuf,
+}
+
+fn breach_error(path: &Path, error: impl ToString) -> AuthError {
+    AuthError::PasswordError(format!(
+        "could not check breached passwords in {}: {}",
+        path.display(),
+        error.to_string()
+    ))
+}
+
+impl BreachedPasswords {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    ///
+    /// How many times a password appears in the breach corpus, 0 if it
+    /// has never been seen.
+    ///
+    pub fn count(&self, password: &str) -> AuthResult<u64> {
+        self.count_hash(&hex::encode_upper(Sha1::digest(password.as_bytes())))
+    }
+
+    ///
+    /// Look up a hex SHA-1 hash.
+    ///
+    pub fn count_hash(&self, hash: &str) -> AuthResult<u64> {
+        let hash = hash.to_ascii_uppercase();
+        let hash = hash.as_str();
+
+        let file = File::open(&self.path).map_err(|err| breach_error(&self.path, err))?;
+        let len = file
+            .metadata()
+            .map_err(|err| breach_error(&self.path, err))?
+            .len();
+
+        let mut reader = BufReader::new(file);
+
+        // find the first line at or after each offset whose hash is not
+        // less than the target
+        let mut lo: u64 = 0;
+        let mut hi: u64 = len;
+
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+
+            match self.line_after(&mut reader, mid)? {
+                Some(line) if line_hash(&line).cmp(hash) == Ordering::Less => lo = mid + 1,
+                _ => hi = mid,
+            }
+        }
+
+        match self.line_after(&mut reader, lo)? {
+            Some(line) if line_hash(&line) == hash => Ok(line_count(&line)),
+            _ => Ok(0),
+        }
+    }
+
+    ///
+    /// The first complete line starting at or after `pos`.
+    ///
+    fn line_after(&self, reader: &mut BufReader<File>, pos: u64) -> AuthResult<Option<String>> {
+        let mut line = String::new();
+
+        // step back a byte so a line starting exactly at pos is kept
+        reader
+            .seek(SeekFrom::Start(pos.saturating_sub(1)))
+            .map_err(|err| breach_error(&self.path, err))?;
+
+        if pos > 0 {
+            reader
+                .read_line(&mut line)
+                .map_err(|err| breach_error(&self.path, err))?;
+            line.clear();
+        }
+
+        match reader.read_line(&mut line) {
+            Ok(0) => Ok(None),
+            Ok(_) => Ok(Some(line.trim_end().to_string())),
+            Err(err) => Err(breach_error(&self.path, err)),
+        }
+    }
+}
+
+fn line_hash(line: &str) -> &str {
+    line.get(..SHA1_HEX_LEN).unwrap_or(line)
+}
+
+fn line_count(line: &str) -> u64 {
+    match line.split_once(':') {
+        Some((_, count)) => count.trim().parse().unwrap_or(1),
+        // a plain list of hashes still means the password was breached
+        None => 1,
+    }
+}
@@ -0,0 +1,1053 @@
+use std::{collections::HashMap, env, fmt, fs, path::Path, str::FromStr};
+
+use argon2::Params;
+use chrono::{Duration, Utc};
+use lettre::message::Mailbox;
+use serde::Deserialize;
+use url::Url;
+
+use crate::{
+    email::{dkim_algorithm, dkim_config},
+    hashing::Argon2Hasher,
+    jwt::{
+        TokenType, TOKEN_TYPE_ACCESS_TTL_HOURS, TOKEN_TYPE_REFRESH_TTL_HOURS,
+        TOKEN_TYPE_REVERT_EMAIL_CHANGE_TTL_HOURS, TOKEN_TYPE_SHORT_TIME_TTL_MINS,
+    },
+    outbox::{
+        OUTBOX_DEFAULT_BASE_DELAY_SECS, OUTBOX_DEFAULT_MAX_ATTEMPTS, OUTBOX_DEFAULT_MAX_DELAY_SECS,
+    },
+    password_policy::{PasswordPolicy, PASSWORD_MAX_STRENGTH},
+    saml::{idp_public_key, SAML_DEFAULT_CLOCK_SKEW_SECS},
+    social::{social_preset, DEFAULT_SOCIAL_SCOPE, SOCIAL_PRESETS},
+    AuthError, AuthResult,
+};
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct TokenConfig {
+    /// Written to the `iss` claim and checked when decoding if set
+    pub issuer: String,
+    pub access_ttl_mins: i64,
+    pub refresh_ttl_mins: i64,
+    pub passwordless_ttl_mins: i64,
+    pub reset_password_ttl_mins: i64,
+    pub verify_email_ttl_mins: i64,
+    pub change_email_ttl_mins: i64,
+    pub revert_email_change_ttl_mins: i64,
+    pub service_ttl_mins: i64,
+}
+
+impl Default for TokenConfig {
+    fn default() -> Self {
+        Self {
+            issuer: String::new(),
+            access_ttl_mins: TOKEN_TYPE_ACCESS_TTL_HOURS * 60,
+            refresh_ttl_mins: TOKEN_TYPE_REFRESH_TTL_HOURS * 60,
+            passwordless_ttl_mins: TOKEN_TYPE_SHORT_TIME_TTL_MINS,
+            reset_password_ttl_mins: TOKEN_TYPE_SHORT_TIME_TTL_MINS,
+            verify_email_ttl_mins: TOKEN_TYPE_SHORT_TIME_TTL_MINS,
+            change_email_ttl_mins: TOKEN_TYPE_SHORT_TIME_TTL_MINS,
+            revert_email_change_ttl_mins: TOKEN_TYPE_REVERT_EMAIL_CHANGE_TTL_HOURS * 60,
+            service_ttl_mins: TOKEN_TYPE_ACCESS_TTL_HOURS * 60,
+        }
+    }
+}
+
+impl TokenConfig {
+    pub fn ttl_mins(&self, token_type: &TokenType) -> i64 {
+        match token_type {
+            TokenType::Access => self.access_ttl_mins,
+            TokenType::Refresh => self.refresh_ttl_mins,
+            TokenType::Passwordless => self.passwordless_ttl_mins,
+            TokenType::ResetPassword => self.reset_password_ttl_mins,
+            TokenType::VerifyEmail => self.verify_email_ttl_mins,
+            TokenType::ChangeEmail => self.change_email_ttl_mins,
+            TokenType::RevertEmailChange => self.revert_email_change_ttl_mins,
+            TokenType::Service => self.service_ttl_mins,
+        }
+    }
+
+    ///
+    /// Unix timestamp at which a token of this type issued now expires.
+    ///
+    pub fn expiration(&self, token_type: &TokenType) -> i64 {
+        (Utc::now() + Duration::minutes(self.ttl_mins(token_type))).timestamp()
+    }
+}
+
+#[derive(Deserialize, Clone, PartialEq, Eq)]
+pub struct DkimSettings {
+    pub selector: String,
+    pub domain: String,
+    pub private_key: String,
+    #[serde(default = "default_dkim_algorithm")]
+    pub algorithm: String,
+}
+
+fn default_dkim_algorithm() -> String {
+    "rsa".to_string()
+}
+
+// secrets only show whether they are set, so configs are safe to log
+fn redacted(secret: &str) -> &'static str {
+    if secret.is_empty() {
+        ""
+    } else {
+        "<redacted>"
+    }
+}
+
+impl fmt::Debug for DkimSettings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DkimSettings")
+            .field("selector", &self.selector)
+            .field("domain", &self.domain)
+            .field("private_key", &redacted(&self.private_key))
+            .field("algorithm", &self.algorithm)
+            .finish()
+    }
+}
+
+#[derive(Deserialize, Clone, PartialEq, Eq, Default)]
+#[serde(default)]
+pub struct SmtpConfig {
+    pub name: String,
+    pub from: String,
+    pub user: String,
+    pub password: String,
+    pub host: String,
+    pub port: Option<u16>,
+    pub dkim: Option<DkimSettings>,
+}
+
+impl fmt::Debug for SmtpConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SmtpConfig")
+            .field("name", &self.name)
+            .field("from", &self.from)
+            .field("user", &self.user)
+            .field("password", &redacted(&self.password))
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("dkim", &self.dkim)
+            .finish()
+    }
+}
+
+impl SmtpConfig {
+    ///
+    /// The `Name <address>` mailbox emails are sent from.
+    ///
+    pub fn reply_to(&self) -> String {
+        format!("{} <{}>", self.name, self.from)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(default)]
+pub struct UrlConfig {
+    /// Public base url of the auth server
+    pub public_url: Option<String>,
+    /// Callback used for emailed links when a request does not supply one
+    pub callback_url: Option<String>,
+    /// Page where users enter device flow codes, defaulting to
+    /// `{public_url}/device`
+    pub device_url: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct FeatureConfig {
+    pub passwordless: bool,
+    pub password_reset: bool,
+    /// Look up the account on every request with a bearer token so
+    /// disabled or unverified users are rejected before their tokens expire
+    pub check_account_on_request: bool,
+    /// Act as an OAuth 2.0 authorization server for registered clients
+    pub oauth: bool,
+}
+
+impl Default for FeatureConfig {
+    fn default() -> Self {
+        Self {
+            passwordless: true,
+            password_reset: true,
+            check_account_on_request: false,
+            oauth: false,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct OutboxConfig {
+    pub max_attempts: i64,
+    pub base_delay_secs: i64,
+    pub max_delay_secs: i64,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: OUTBOX_DEFAULT_MAX_ATTEMPTS,
+            base_delay_secs: OUTBOX_DEFAULT_BASE_DELAY_SECS,
+            max_delay_secs: OUTBOX_DEFAULT_MAX_DELAY_SECS,
+        }
+    }
+}
+
+///
+/// Directory attributes holding each `User` field. The defaults suit
+/// OpenLDAP, for Active Directory use `sAMAccountName` as the username.
+///
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct LdapAttributes {
+    pub username: String,
+    pub email: String,
+    pub first_name: String,
+    pub last_name: String,
+}
+
+impl Default for LdapAttributes {
+    fn default() -> Self {
+        Self {
+            username: "uid".to_string(),
+            email: "mail".to_string(),
+            first_name: "givenName".to_string(),
+            last_name: "sn".to_string(),
+        }
+    }
+}
+
+///
+/// Corporate directory to check staff passwords against. Users are found
+/// with the service account and then bound as to check their password.
+///
+#[derive(Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct LdapConfig {
+    /// `ldap://` or `ldaps://` url of the directory server
+    pub url: String,
+    pub starttls: bool,
+    /// Service account used to search for users
+    pub bind_dn: String,
+    pub bind_password: String,
+    pub base_dn: String,
+    /// Search filter where `{username}` is replaced by the escaped username
+    pub user_filter: String,
+    pub attributes: LdapAttributes,
+}
+
+impl Default for LdapConfig {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            starttls: false,
+            bind_dn: String::new(),
+            bind_password: String::new(),
+            base_dn: String::new(),
+            user_filter: "(uid={username})".to_string(),
+            attributes: LdapAttributes::default(),
+        }
+    }
+}
+
+impl fmt::Debug for LdapConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LdapConfig")
+            .field("url", &self.url)
+            .field("starttls", &self.starttls)
+            .field("bind_dn", &self.bind_dn)
+            .field("bind_password", &redacted(&self.bind_password))
+            .field("base_dn", &self.base_dn)
+            .field("user_filter", &self.user_filter)
+            .field("attributes", &self.attributes)
+            .finish()
+    }
+}
+
+///
+/// Assertion attributes holding each `User` field. The defaults are the
+/// standard X.500 names, Azure AD sends its claim URIs instead.
+///
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct SamlAttributes {
+    /// Attribute holding the username, the `NameID` is used if not set
+    pub username: Option<String>,
+    pub email: String,
+    pub first_name: String,
+    pub last_name: String,
+}
+
+impl Default for SamlAttributes {
+    fn default() -> Self {
+        Self {
+            username: None,
+            email: "urn:oid:0.9.2342.19200300.100.1.3".to_string(),
+            first_name: "urn:oid:2.5.4.42".to_string(),
+            last_name: "urn:oid:2.5.4.4".to_string(),
+        }
+    }
+}
+
+///
+/// Single sign on through a SAML 2.0 identity provider, with this service
+/// as the service provider.
+///
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct SamlConfig {
+    /// Our entity id, usually the metadata url
+    pub entity_id: String,
+    /// Where the identity provider posts responses, defaulting to
+    /// `{public_url}/saml/acs`
+    pub acs_url: Option<String>,
+    pub idp_entity_id: String,
+    /// Identity provider url that AuthnRequests are redirected to
+    pub idp_sso_url: String,
+    /// Certificate the identity provider signs with, PEM or bare base64
+    /// as found in its metadata
+    pub idp_certificate: String,
+    /// Accept responses to sign ins started at the identity provider
+    pub allow_idp_initiated: bool,
+    /// Leeway for clocks being out of step when checking validity times
+    pub clock_skew_secs: i64,
+    pub attributes: SamlAttributes,
+}
+
+impl Default for SamlConfig {
+    fn default() -> Self {
+        Self {
+            entity_id: String::new(),
+            acs_url: None,
+            idp_entity_id: String::new(),
+            idp_sso_url: String::new(),
+            idp_certificate: String::new(),
+            allow_idp_initiated: false,
+            clock_skew_secs: SAML_DEFAULT_CLOCK_SKEW_SECS,
+            attributes: SamlAttributes::default(),
+        }
+    }
+}
+
+///
+/// An OAuth 2.0 or OpenID Connect provider users can sign in with. Use
+/// `SocialProviderConfig::google` and friends for well known providers.
+///
+#[derive(Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct SocialProviderConfig {
+    pub client_id: String,
+    pub client_secret: String,
+    pub authorization_url: String,
+    pub token_url: String,
+    pub userinfo_url: String,
+    /// Lists the user's addresses for providers whose profile does not
+    /// say if its email is verified, such as GitHub
+    pub emails_url: Option<String>,
+    pub scope: String,
+    /// Profile field holding the provider's id for the user
+    pub subject_claim: String,
+    /// Treat profile emails as verified without an `email_verified` claim
+    pub trust_email: bool,
+    /// Where the provider sends users back to, defaulting to
+    /// `{public_url}/social/{name}/callback`
+    pub redirect_uri: Option<String>,
+}
+
+impl Default for SocialProviderConfig {
+    fn default() -> Self {
+        Self {
+            client_id: String::new(),
+            client_secret: String::new(),
+            authorization_url: String::new(),
+            token_url: String::new(),
+            userinfo_url: String::new(),
+            emails_url: None,
+            scope: DEFAULT_SOCIAL_SCOPE.to_string(),
+            subject_claim: "sub".to_string(),
+            trust_email: false,
+            redirect_uri: None,
+        }
+    }
+}
+
+impl fmt::Debug for SocialProviderConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SocialProviderConfig")
+            .field("client_id", &self.client_id)
+            .field("client_secret", &redacted(&self.client_secret))
+            .field("authorization_url", &self.authorization_url)
+            .field("token_url", &self.token_url)
+            .field("userinfo_url", &self.userinfo_url)
+            .field("emails_url", &self.emails_url)
+            .field("scope", &self.scope)
+            .field("subject_claim", &self.subject_claim)
+            .field("trust_email", &self.trust_email)
+            .field("redirect_uri", &self.redirect_uri)
+            .finish()
+    }
+}
+
+///
+/// Argon2id costs and pepper for new password hashes. Existing hashes
+/// made with other costs or an older pepper are upgraded when their
+/// users next sign in.
+///
+#[derive(Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct HashingConfig {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+    /// Version of the pepper new hashes use, 0 to not pepper
+    pub pepper_version: u32,
+    /// Pepper secrets by version. Keep retired versions until every user
+    /// has signed in since rotating.
+    pub peppers: HashMap<String, String>,
+}
+
+impl Default for HashingConfig {
+    fn default() -> Self {
+        Self {
+            memory_kib: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+            pepper_version: 0,
+            peppers: HashMap::new(),
+        }
+    }
+}
+
+impl fmt::Debug for HashingConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // never print the peppers themselves
+        f.debug_struct("HashingConfig")
+            .field("memory_kib", &self.memory_kib)
+            .field("iterations", &self.iterations)
+            .field("parallelism", &self.parallelism)
+            .field("pepper_version", &self.pepper_version)
+            .field("pepper_versions", &self.peppers.keys().collect::<Vec<&String>>())
+            .finish()
+    }
+}
+
+///
+/// Deployment settings for the auth service. Build with
+/// `AuthConfig::builder()`, or load with `from_env` / `from_toml_file`.
+/// Those constructors validate, so a config they return is usable. The
+/// `_unchecked` loaders leave validation to the caller, for tools that only
+/// use part of the config.
+///
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(default)]
+pub struct AuthConfig {
+    pub tokens: TokenConfig,
+    pub smtp: SmtpConfig,
+    pub urls: UrlConfig,
+    pub features: FeatureConfig,
+    pub outbox: OutboxConfig,
+    pub passwords: PasswordPolicy,
+    pub hashing: HashingConfig,
+    /// Social sign in providers by name, as used in their urls
+    pub social: HashMap<String, SocialProviderConfig>,
+    pub ldap: Option<LdapConfig>,
+    pub saml: Option<SamlConfig>,
+}
+
+impl AuthConfig {
+    pub fn builder() -> AuthConfigBuilder {
+        AuthConfigBuilder::default()
+    }
+
+    pub fn from_toml_str(toml: &str) -> AuthResult<Self> {
+        let config = Self::from_toml_str_unchecked(toml)?;
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn from_toml_str_unchecked(toml: &str) -> AuthResult<Self> {
+        match toml::from_str(toml) {
+            Ok(config) => Ok(config),
+            Err(err) => Err(AuthError::ConfigError(err.to_string())),
+        }
+    }
+
+    pub fn from_toml_file(path: impl AsRef<Path>) -> AuthResult<Self> {
+        let config = Self::from_toml_file_unchecked(path)?;
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn from_toml_file_unchecked(path: impl AsRef<Path>) -> AuthResult<Self> {
+        let path = path.as_ref();
+
+        match fs::read_to_string(path) {
+            Ok(toml) => Self::from_toml_str_unchecked(&toml),
+            Err(err) => Err(AuthError::ConfigError(format!(
+                "could not read {}: {}",
+                path.display(),
+                err
+            ))),
+        }
+    }
+
+    ///
+    /// Load from `AUTH_*` and `SMTP_*` environment variables. Unset
+    /// variables keep their defaults.
+    ///
+    pub fn from_env() -> AuthResult<Self> {
+        let config = Self::from_env_unchecked()?;
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn from_env_unchecked() -> AuthResult<Self> {
+        let mut config = AuthConfig::default();
+
+        let tokens = &mut config.tokens;
+        env_string("AUTH_ISSUER", &mut tokens.issuer);
+        env_parse("AUTH_ACCESS_TTL_MINS", &mut tokens.access_ttl_mins)?;
+        env_parse("AUTH_REFRESH_TTL_MINS", &mut tokens.refresh_ttl_mins)?;
+        env_parse("AUTH_PASSWORDLESS_TTL_MINS", &mut tokens.passwordless_ttl_mins)?;
+        env_parse("AUTH_RESET_PASSWORD_TTL_MINS", &mut tokens.reset_password_ttl_mins)?;
+        env_parse("AUTH_VERIFY_EMAIL_TTL_MINS", &mut tokens.verify_email_ttl_mins)?;
+        env_parse("AUTH_CHANGE_EMAIL_TTL_MINS", &mut tokens.change_email_ttl_mins)?;
+        env_parse(
+            "AUTH_REVERT_EMAIL_CHANGE_TTL_MINS",
+            &mut tokens.revert_email_change_ttl_mins,
+        )?;
+        env_parse("AUTH_SERVICE_TTL_MINS", &mut tokens.service_ttl_mins)?;
+
+        let smtp = &mut config.smtp;
+        env_string("SMTP_NAME", &mut smtp.name);
+        env_string("SMTP_FROM", &mut smtp.from);
+        env_string("SMTP_USER", &mut smtp.user);
+        env_string("SMTP_PASSWORD", &mut smtp.password);
+        env_string("SMTP_HOST", &mut smtp.host);
+
+        if let Ok(port) = env::var("SMTP_PORT") {
+            smtp.port = Some(parse_var("SMTP_PORT", &port)?);
+        }
+
+        if let (Ok(selector), Ok(domain), Ok(private_key)) = (
+            env::var("SMTP_DKIM_SELECTOR"),
+            env::var("SMTP_DKIM_DOMAIN"),
+            env::var("SMTP_DKIM_PRIVATE_KEY"),
+        ) {
+            smtp.dkim = Some(DkimSettings {
+                selector,
+                domain,
+                private_key,
+                algorithm: env::var("SMTP_DKIM_ALGORITHM").unwrap_or(default_dkim_algorithm()),
+            });
+        }
+
+        config.urls.public_url = env::var("AUTH_PUBLIC_URL").ok();
+        config.urls.callback_url = env::var("AUTH_CALLBACK_URL").ok();
+        config.urls.device_url = env::var("AUTH_DEVICE_URL").ok();
+
+        env_parse("AUTH_PASSWORDLESS_ENABLED", &mut config.features.passwordless)?;
+        env_parse("AUTH_PASSWORD_RESET_ENABLED", &mut config.features.password_reset)?;
+        env_parse(
+            "AUTH_CHECK_ACCOUNT_ON_REQUEST",
+            &mut config.features.check_account_on_request,
+        )?;
+        env_parse("AUTH_OAUTH_ENABLED", &mut config.features.oauth)?;
+
+        env_parse("AUTH_OUTBOX_MAX_ATTEMPTS", &mut config.outbox.max_attempts)?;
+        env_parse("AUTH_OUTBOX_BASE_DELAY_SECS", &mut config.outbox.base_delay_secs)?;
+        env_parse("AUTH_OUTBOX_MAX_DELAY_SECS", &mut config.outbox.max_delay_secs)?;
+
+        let passwords = &mut config.passwords;
+        env_parse("AUTH_PASSWORD_MIN_LENGTH", &mut passwords.min_length)?;
+        env_parse("AUTH_PASSWORD_MAX_LENGTH", &mut passwords.max_length)?;
+        env_parse("AUTH_PASSWORD_REQUIRE_LOWERCASE", &mut passwords.require_lowercase)?;
+        env_parse("AUTH_PASSWORD_REQUIRE_UPPERCASE", &mut passwords.require_uppercase)?;
+        env_parse("AUTH_PASSWORD_REQUIRE_DIGIT", &mut passwords.require_digit)?;
+        env_parse("AUTH_PASSWORD_REQUIRE_SYMBOL", &mut passwords.require_symbol)?;
+        env_parse("AUTH_PASSWORD_DISALLOW_USER_INFO", &mut passwords.disallow_user_info)?;
+        env_parse("AUTH_PASSWORD_MIN_STRENGTH", &mut passwords.min_strength)?;
+        env_parse("AUTH_PASSWORD_BREACH_THRESHOLD", &mut passwords.breach_threshold)?;
+        env_parse("AUTH_PASSWORD_HISTORY_SIZE", &mut passwords.history_size)?;
+
+        if let Ok(path) = env::var("AUTH_PASSWORD_BREACHED_FILE") {
+            passwords.breached_passwords_file = Some(path);
+        }
+
+        env_parse("AUTH_ARGON2_MEMORY_KIB", &mut config.hashing.memory_kib)?;
+        env_parse("AUTH_ARGON2_ITERATIONS", &mut config.hashing.iterations)?;
+        env_parse("AUTH_ARGON2_PARALLELISM", &mut config.hashing.parallelism)?;
+        env_parse("AUTH_PEPPER_VERSION", &mut config.hashing.pepper_version)?;
+
+        // AUTH_PEPPER_1, AUTH_PEPPER_2, ... hold each pepper version
+        for (name, pepper) in env::vars() {
+            if let Some(version) = name.strip_prefix("AUTH_PEPPER_") {
+                if version != "VERSION" {
+                    config.hashing.peppers.insert(version.to_string(), pepper);
+                }
+            }
+        }
+
+        // AUTH_SOCIAL_GOOGLE_CLIENT_ID and AUTH_SOCIAL_GOOGLE_CLIENT_SECRET,
+        // and likewise for the other well known providers
+        for name in SOCIAL_PRESETS {
+            let prefix = format!("AUTH_SOCIAL_{}", name.to_uppercase());
+
+            if let (Ok(client_id), Ok(client_secret)) = (
+                env::var(format!("{}_CLIENT_ID", prefix)),
+                env::var(format!("{}_CLIENT_SECRET", prefix)),
+            ) {
+                if let Some(provider) = social_preset(name, &client_id, &client_secret) {
+                    config.social.insert(name.to_string(), provider);
+                }
+            }
+        }
+
+        if let Ok(url) = env::var("AUTH_LDAP_URL") {
+            let mut ldap = LdapConfig {
+                url,
+                ..Default::default()
+            };
+
+            env_parse("AUTH_LDAP_STARTTLS", &mut ldap.starttls)?;
+            env_string("AUTH_LDAP_BIND_DN", &mut ldap.bind_dn);
+            env_string("AUTH_LDAP_BIND_PASSWORD", &mut ldap.bind_password);
+            env_string("AUTH_LDAP_BASE_DN", &mut ldap.base_dn);
+            env_string("AUTH_LDAP_USER_FILTER", &mut ldap.user_filter);
+
+            let attributes = &mut ldap.attributes;
+            env_string("AUTH_LDAP_USERNAME_ATTRIBUTE", &mut attributes.username);
+            env_string("AUTH_LDAP_EMAIL_ATTRIBUTE", &mut attributes.email);
+            env_string("AUTH_LDAP_FIRST_NAME_ATTRIBUTE", &mut attributes.first_name);
+            env_string("AUTH_LDAP_LAST_NAME_ATTRIBUTE", &mut attributes.last_name);
+
+            config.ldap = Some(ldap);
+        }
+
+        if let Ok(entity_id) = env::var("AUTH_SAML_ENTITY_ID") {
+            let mut saml = SamlConfig {
+                entity_id,
+                acs_url: env::var("AUTH_SAML_ACS_URL").ok(),
+                ..Default::default()
+            };
+
+            env_string("AUTH_SAML_IDP_ENTITY_ID", &mut saml.idp_entity_id);
+            env_string("AUTH_SAML_IDP_SSO_URL", &mut saml.idp_sso_url);
+            env_string("AUTH_SAML_IDP_CERTIFICATE", &mut saml.idp_certificate);
+            env_parse("AUTH_SAML_ALLOW_IDP_INITIATED", &mut saml.allow_idp_initiated)?;
+            env_parse("AUTH_SAML_CLOCK_SKEW_SECS", &mut saml.clock_skew_secs)?;
+
+            let attributes = &mut saml.attributes;
+            attributes.username = env::var("AUTH_SAML_USERNAME_ATTRIBUTE").ok();
+            env_string("AUTH_SAML_EMAIL_ATTRIBUTE", &mut attributes.email);
+            env_string("AUTH_SAML_FIRST_NAME_ATTRIBUTE", &mut attributes.first_name);
+            env_string("AUTH_SAML_LAST_NAME_ATTRIBUTE", &mut attributes.last_name);
+
+            config.saml = Some(saml);
+        }
+
+        Ok(config)
+    }
+
+    ///
+    /// Check the config is usable, reporting every problem found rather
+    /// than just the first.
+    ///
+    pub fn validate(&self) -> AuthResult<()> {
+        let mut errors: Vec<String> = Vec::new();
+
+        self.token_errors(&mut errors);
+        self.email_errors(&mut errors);
+        self.url_errors(&mut errors);
+        self.password_errors(&mut errors);
+        self.sign_in_errors(&mut errors);
+
+        config_result(errors)
+    }
+
+    ///
+    /// Check only the token settings, for tools that mint tokens.
+    ///
+    pub fn validate_tokens(&self) -> AuthResult<()> {
+        let mut errors: Vec<String> = Vec::new();
+
+        self.token_errors(&mut errors);
+
+        config_result(errors)
+    }
+
+    ///
+    /// Check only the password policy and hashing, for tools that manage
+    /// accounts without sending email.
+    ///
+    pub fn validate_passwords(&self) -> AuthResult<()> {
+        let mut errors: Vec<String> = Vec::new();
+
+        self.password_errors(&mut errors);
+
+        config_result(errors)
+    }
+
+    ///
+    /// Check what sending email needs: the SMTP server, the outbox and the
+    /// urls links are built from.
+    ///
+    pub fn validate_email(&self) -> AuthResult<()> {
+        let mut errors: Vec<String> = Vec::new();
+
+        self.token_errors(&mut errors);
+        self.email_errors(&mut errors);
+        self.url_errors(&mut errors);
+
+        config_result(errors)
+    }
+
+    fn token_errors(&self, errors: &mut Vec<String>) {
+        for (name, ttl) in [
+            ("tokens.access_ttl_mins", self.tokens.access_ttl_mins),
+            ("tokens.refresh_ttl_mins", self.tokens.refresh_ttl_mins),
+            ("tokens.passwordless_ttl_mins", self.tokens.passwordless_ttl_mins),
+            ("tokens.reset_password_ttl_mins", self.tokens.reset_password_ttl_mins),
+            ("tokens.verify_email_ttl_mins", self.tokens.verify_email_ttl_mins),
+            ("tokens.change_email_ttl_mins", self.tokens.change_email_ttl_mins),
+            (
+                "tokens.revert_email_change_ttl_mins",
+                self.tokens.revert_email_change_ttl_mins,
+            ),
+            ("tokens.service_ttl_mins", self.tokens.service_ttl_mins),
+        ] {
+            if ttl <= 0 {
+                errors.push(format!("{} must be greater than 0", name));
+            }
+        }
+
+        if self.tokens.access_ttl_mins > self.tokens.refresh_ttl_mins {
+            errors.push(
+                "tokens.access_ttl_mins must not exceed tokens.refresh_ttl_mins".to_string(),
+            );
+        }
+    }
+
+    fn email_errors(&self, errors: &mut Vec<String>) {
+        if self.smtp.host.is_empty() {
+            errors.push("smtp.host must be set".to_string());
+        }
+
+        if self.smtp.reply_to().parse::<Mailbox>().is_err() {
+            errors.push(format!(
+                "smtp.name and smtp.from must form a valid mailbox, got {}",
+                self.smtp.reply_to()
+            ));
+        }
+
+        if let Some(dkim) = &self.smtp.dkim {
+            let result = dkim_algorithm(&dkim.algorithm).and_then(|algorithm| {
+                dkim_config(&dkim.selector, &dkim.domain, &dkim.private_key, algorithm)
+            });
+
+            if let Err(err) = result {
+                errors.push(format!("smtp.dkim: {}", err));
+            }
+        }
+
+        if self.outbox.max_attempts < 1 {
+            errors.push("outbox.max_attempts must be at least 1".to_string());
+        }
+
+        if self.outbox.base_delay_secs < 1
+            || self.outbox.max_delay_secs < self.outbox.base_delay_secs
+        {
+            errors.push(
+                "outbox delays must be positive with max_delay_secs >= base_delay_secs".to_string(),
+            );
+        }
+    }
+
+    fn url_errors(&self, errors: &mut Vec<String>) {
+        for (name, url) in [
+            ("urls.public_url", &self.urls.public_url),
+            ("urls.callback_url", &self.urls.callback_url),
+            ("urls.device_url", &self.urls.device_url),
+        ] {
+            if let Some(url) = url {
+                if let Err(err) = Url::parse(url) {
+                    errors.push(format!("{} is not a valid url: {}", name, err));
+                }
+            }
+        }
+    }
+
+    fn password_errors(&self, errors: &mut Vec<String>) {
+        if self.passwords.min_length < 1 || self.passwords.max_length < self.passwords.min_length {
+            errors.push(
+                "passwords.min_length must be at least 1 and at most max_length".to_string(),
+            );
+        }
+
+        if self.passwords.min_strength > PASSWORD_MAX_STRENGTH {
+            errors.push(format!(
+                "passwords.min_strength must be between 0 and {}",
+                PASSWORD_MAX_STRENGTH
+            ));
+        }
+
+        if let Some(path) = &self.passwords.breached_passwords_file {
+            if !Path::new(path).is_file() {
+                errors.push(format!("passwords.breached_passwords_file {} does not exist", path));
+            }
+        }
+
+        if self.passwords.breach_threshold < 1 {
+            errors.push("passwords.breach_threshold must be at least 1".to_string());
+        }
+
+        if let Err(AuthError::ConfigError(err)) = Argon2Hasher::new(&self.hashing) {
+            errors.push(err);
+        }
+    }
+
+    // OAuth, directory, SAML and social sign in
+    fn sign_in_errors(&self, errors: &mut Vec<String>) {
+        // OpenID Connect clients check the issuer and find the endpoints
+        // through discovery
+        if self.features.oauth {
+            if self.tokens.issuer.is_empty() {
+                errors.push("tokens.issuer must be set when features.oauth is enabled".to_string());
+            }
+
+            if self.urls.public_url.is_none() {
+                errors.push(
+                    "urls.public_url must be set when features.oauth is enabled".to_string(),
+                );
+            }
+        }
+
+        if let Some(ldap) = &self.ldap {
+            match Url::parse(&ldap.url) {
+                Ok(url) if url.scheme() == "ldap" || url.scheme() == "ldaps" => (),
+                _ => errors.push(format!(
+                    "ldap.url must be an ldap or ldaps url, got {}",
+                    ldap.url
+                )),
+            }
+
+            if ldap.base_dn.is_empty() {
+                errors.push("ldap.base_dn must be set".to_string());
+            }
+
+            if !ldap.user_filter.contains("{username}") {
+                errors.push("ldap.user_filter must contain {username}".to_string());
+            }
+        }
+
+        if let Some(saml) = &self.saml {
+            for (field, value) in [
+                ("entity_id", &saml.entity_id),
+                ("idp_entity_id", &saml.idp_entity_id),
+            ] {
+                if value.is_empty() {
+                    errors.push(format!("saml.{} must be set", field));
+                }
+            }
+
+            for (field, url) in [
+                ("idp_sso_url", Some(&saml.idp_sso_url)),
+                ("acs_url", saml.acs_url.as_ref()),
+            ] {
+                if let Some(url) = url {
+                    if let Err(err) = Url::parse(url) {
+                        errors.push(format!("saml.{} is not a valid url: {}", field, err));
+                    }
+                }
+            }
+
+            if saml.acs_url.is_none() && self.urls.public_url.is_none() {
+                errors.push("saml.acs_url or urls.public_url must be set".to_string());
+            }
+
+            if let Err(err) = idp_public_key(&saml.idp_certificate) {
+                errors.push(format!("saml.idp_certificate is invalid: {}", err));
+            }
+
+            if saml.clock_skew_secs < 0 {
+                errors.push("saml.clock_skew_secs must not be negative".to_string());
+            }
+        }
+
+        for (name, provider) in &self.social {
+            if provider.client_id.is_empty() {
+                errors.push(format!("social.{}.client_id must be set", name));
+            }
+
+            for (field, url) in [
+                ("authorization_url", Some(&provider.authorization_url)),
+                ("token_url", Some(&provider.token_url)),
+                ("userinfo_url", Some(&provider.userinfo_url)),
+                ("emails_url", provider.emails_url.as_ref()),
+                ("redirect_uri", provider.redirect_uri.as_ref()),
+            ] {
+                if let Some(url) = url {
+                    if let Err(err) = Url::parse(url) {
+                        errors.push(format!(
+                            "social.{}.{} is not a valid url: {}",
+                            name, field, err
+                        ));
+                    }
+                }
+            }
+
+            if provider.redirect_uri.is_none() && self.urls.public_url.is_none() {
+                errors.push(format!(
+                    "social.{}.redirect_uri or urls.public_url must be set",
+                    name
+                ));
+            }
+        }
+    }
+}
+
+fn config_result(errors: Vec<String>) -> AuthResult<()> {
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(AuthError::ConfigError(errors.join("; ")))
+    }
+}
+
+fn env_string(name: &str, value: &mut String) {
+    if let Ok(var) = env::var(name) {
+        *value = var;
+    }
+}
+
+fn env_parse<T: FromStr>(name: &str, value: &mut T) -> AuthResult<()> {
+    if let Ok(var) = env::var(name) {
+        *value = parse_var(name, &var)?;
+    }
+
+    Ok(())
+}
+
+fn parse_var<T: FromStr>(name: &str, var: &str) -> AuthResult<T> {
+    match var.trim().parse::<T>() {
+        Ok(value) => Ok(value),
+        Err(_) => Err(AuthError::ConfigError(format!(
+            "{} has invalid value {}",
+            name, var
+        ))),
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AuthConfigBuilder {
+    config: AuthConfig,
+}
+
+impl AuthConfigBuilder {
+    pub fn issuer(mut self, issuer: &str) -> Self {
+        self.config.tokens.issuer = issuer.to_string();
+        self
+    }
+
+    pub fn ttl_mins(mut self, token_type: &TokenType, mins: i64) -> Self {
+        let tokens = &mut self.config.tokens;
+
+        match token_type {
+            TokenType::Access => tokens.access_ttl_mins = mins,
+            TokenType::Refresh => tokens.refresh_ttl_mins = mins,
+            TokenType::Passwordless => tokens.passwordless_ttl_mins = mins,
+            TokenType::ResetPassword => tokens.reset_password_ttl_mins = mins,
+            TokenType::VerifyEmail => tokens.verify_email_ttl_mins = mins,
+            TokenType::ChangeEmail => tokens.change_email_ttl_mins = mins,
+            TokenType::RevertEmailChange => tokens.revert_email_change_ttl_mins = mins,
+            TokenType::Service => tokens.service_ttl_mins = mins,
+        }
+
+        self
+    }
+
+    pub fn smtp(mut self, smtp: SmtpConfig) -> Self {
+        self.config.smtp = smtp;
+        self
+    }
+
+    pub fn public_url(mut self, url: &str) -> Self {
+        self.config.urls.public_url = Some(url.to_string());
+        self
+    }
+
+    pub fn callback_url(mut self, url: &str) -> Self {
+        self.config.urls.callback_url = Some(url.to_string());
+        self
+    }
+
+    pub fn device_url(mut self, url: &str) -> Self {
+        self.config.urls.device_url = Some(url.to_string());
+        self
+    }
+
+    pub fn passwordless(mut self, enabled: bool) -> Self {
+        self.config.features.passwordless = enabled;
+        self
+    }
+
+    pub fn password_reset(mut self, enabled: bool) -> Self {
+        self.config.features.password_reset = enabled;
+        self
+    }
+
+    pub fn check_account_on_request(mut self, enabled: bool) -> Self {
+        self.config.features.check_account_on_request = enabled;
+        self
+    }
+
+    pub fn oauth(mut self, enabled: bool) -> Self {
+        self.config.features.oauth = enabled;
+        self
+    }
+
+    pub fn outbox(mut self, outbox: OutboxConfig) -> Self {
+        self.config.outbox = outbox;
+        self
+    }
+
+    pub fn passwords(mut self, policy: PasswordPolicy) -> Self {
+        self.config.passwords = policy;
+        self
+    }
+
+    pub fn hashing(mut self, hashing: HashingConfig) -> Self {
+        self.config.hashing = hashing;
+        self
+    }
+
+    pub fn social_provider(mut self, name: &str, provider: SocialProviderConfig) -> Self {
+        self.config.social.insert(name.to_string(), provider);
+        self
+    }
+
+    pub fn ldap(mut self, ldap: LdapConfig) -> Self {
+        self.config.ldap = Some(ldap);
+        self
+    }
+
+    pub fn saml(mut self, saml: SamlConfig) -> Self {
+        self.config.saml = Some(saml);
+        self
+    }
+
+    pub fn build(self) -> AuthResult<AuthConfig> {
+        self.config.validate()?;
+
+        Ok(self.config)
+    }
+}
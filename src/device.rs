@@ -0,0 +1,453 @@
+use axum::{
+    extract::{Query, State},
+    http::{header::CACHE_CONTROL, HeaderMap, HeaderName, StatusCode},
+    routing::{get, post},
+    Form, Json, Router,
+};
+use chrono::{Duration, Utc};
+use rand::{rngs::OsRng, Rng};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use url::Url;
+
+use crate::{
+    config::AuthConfig,
+    jwt::{AppState, FirstPartyToken},
+    oauth::{
+        authenticate_client, basic_credentials, check_oauth_enabled, client_tokens, code_hash,
+        grant_user, narrow_scope, oauth_error, random_token, required, signed_in_user,
+        OAuthClient, OAuthTokenResp, TokenReq, ACCESS_DENIED, INVALID_GRANT, UNAUTHORIZED_CLIENT,
+    },
+    AuthError, AuthResult, UserDb,
+};
+
+pub const GRANT_DEVICE_CODE: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+// polling errors from RFC 8628
+pub const AUTHORIZATION_PENDING: &str = "authorization_pending";
+pub const SLOW_DOWN: &str = "slow_down";
+pub const EXPIRED_TOKEN: &str = "expired_token";
+
+pub const DEVICE_CODE_TTL_MINS: i64 = 10;
+pub const DEVICE_POLL_INTERVAL_SECS: i64 = 5;
+
+pub const DEVICE_STATUS_PENDING: &str = "pending";
+pub const DEVICE_STATUS_APPROVED: &str = "approved";
+pub const DEVICE_STATUS_DENIED: &str = "denied";
+pub const DEVICE_STATUS_USED: &str = "used";
+
+// consonants only, so codes cannot spell words or be misread as digits
+const USER_CODE_CHARS: &[u8] = b"BCDFGHJKLMNPQRSTVWXZ";
+const USER_CODE_LEN: usize = 8;
+
+pub const CREATE_DEVICE_CODES_TABLE_SQL: &'static str =
+    r#"CREATE TABLE IF NOT EXISTS oauth_device_codes (
+id INTEGER PRIMARY KEY AUTOINCREMENT,
+device_code TEXT NOT NULL UNIQUE,
+user_code TEXT NOT NULL UNIQUE,
+client_id TEXT NOT NULL,
+user_uuid TEXT NOT NULL DEFAULT '',
+status TEXT NOT NULL DEFAULT 'pending',
+poll_interval INTEGER NOT NULL,
+last_polled INTEGER NOT NULL DEFAULT 0,
+expires INTEGER NOT NULL,
+created_on TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP)"#;
+
+const DELETE_EXPIRED_DEVICE_CODES_SQL: &'static str =
+    r#"DELETE FROM oauth_device_codes WHERE expires < $1"#;
+
+const CREATE_DEVICE_CODE_SQL: &'static str = r#"INSERT INTO oauth_device_codes
+(device_code, user_code, client_id, poll_interval, expires)
+VALUES($1, $2, $3, $4, $5)"#;
+
+const FIND_DEVICE_CODE_SQL: &'static str = r#"SELECT
+user_code, client_id, user_uuid, status, poll_interval, last_polled, expires
+FROM oauth_device_codes
+WHERE oauth_device_codes.device_code = $1 LIMIT 1"#;
+
+const FIND_DEVICE_USER_CODE_SQL: &'static str = r#"SELECT
+user_code, client_id, user_uuid, status, poll_interval, last_polled, expires
+FROM oauth_device_codes
+WHERE oauth_device_codes.user_code = $1 LIMIT 1"#;
+
+const POLL_DEVICE_CODE_SQL: &'static str =
+    r#"UPDATE oauth_device_codes SET last_polled = $2 WHERE device_code = $1"#;
+
+// clients that poll too fast must wait 5 seconds longer from then on
+const SLOW_DOWN_DEVICE_CODE_SQL: &'static str = r#"UPDATE oauth_device_codes
+SET poll_interval = poll_interval + 5, last_polled = $2
+WHERE device_code = $1"#;
+
+const DECIDE_DEVICE_CODE_SQL: &'static str = r#"UPDATE oauth_device_codes
+SET status = $2, user_uuid = $3
+WHERE user_code = $1 AND status = 'pending' AND expires > $4"#;
+
+const USE_DEVICE_CODE_SQL: &'static str = r#"UPDATE oauth_device_codes
+SET status = 'used'
+WHERE device_code = $1 AND status = 'approved'"#;
+
+#[derive(Debug, Clone, FromRow)]
+pub struct DeviceCode {
+    pub user_code: String,
+    pub client_id: String,
+    /// Who approved or denied the request, empty while pending
+    pub user_uuid: String,
+    pub status: String,
+    pub poll_interval: i64,
+    pub last_polled: i64,
+    pub expires: i64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct DeviceAuthorizationReq {
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct DeviceAuthorizationResp {
+    pub device_code: String,
+    pub user_code: String,
+    pub verification_uri: String,
+    pub verification_uri_complete: String,
+    pub expires_in: i64,
+    pub interval: i64,
+}
+
+///
+/// What the verification page shows before the user approves a device.
+///
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceInfoResp {
+    pub client_id: String,
+    pub client_name: String,
+    pub user_code: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceCodeQuery {
+    pub user_code: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceApprovalReq {
+    pub user_code: String,
+    pub approve: bool,
+}
+
+fn generate_user_code() -> String {
+    let mut rng = OsRng;
+
+    (0..USER_CODE_LEN)
+        .map(|_| USER_CODE_CHARS[rng.gen_range(0..USER_CODE_CHARS.len())] as char)
+        .collect()
+}
+
+///
+/// User codes are shown as `BCDF-GHJK` but typed however the user likes,
+/// so dashes, spaces and case are ignored.
+///
+pub fn normalize_user_code(user_code: &str) -> String {
+    user_code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+pub fn format_user_code(user_code: &str) -> String {
+    let (first, second) = user_code.split_at(user_code.len() / 2);
+
+    format!("{}-{}", first, second)
+}
+
+///
+/// The page users visit to enter their code.
+///
+pub fn device_url(config: &AuthConfig) -> AuthResult<String> {
+    if let Some(url) = &config.urls.device_url {
+        return Ok(url.clone());
+    }
+
+    match &config.urls.public_url {
+        Some(url) => Ok(format!("{}/device", url.trim_end_matches('/'))),
+        None => Err(AuthError::ConfigError(
+            "urls.device_url or urls.public_url must be set".to_string(),
+        )),
+    }
+}
+
+impl UserDb {
+    ///
+    /// Start a device authorization for the client, returning the device
+    /// code it polls with and the user code the user types in.
+    ///
+    pub async fn create_device_code(&self, client_id: &str) -> AuthResult<(String, String)> {
+        let now = Utc::now();
+
+        // expired codes are of no use to anyone and would otherwise keep
+        // their user codes taken
+        sqlx::query(DELETE_EXPIRED_DEVICE_CODES_SQL)
+            .bind(now.timestamp())
+            .execute(&self.pool)
+            .await?;
+
+        let device_code = random_token();
+        let user_code = generate_user_code();
+
+        sqlx::query(CREATE_DEVICE_CODE_SQL)
+            .bind(code_hash(&device_code))
+            .bind(&user_code)
+            .bind(client_id)
+            .bind(DEVICE_POLL_INTERVAL_SECS)
+            .bind((now + Duration::minutes(DEVICE_CODE_TTL_MINS)).timestamp())
+            .execute(&self.pool)
+            .await?;
+
+        Ok((device_code, user_code))
+    }
+
+    ///
+    /// A device authorization still waiting for the user to answer.
+    ///
+    pub async fn pending_device_code(&self, user_code: &str) -> AuthResult<DeviceCode> {
+        let invalid = || AuthError::TokenError("code is invalid or has expired".to_string());
+
+        let device = match sqlx::query_as::<_, DeviceCode>(FIND_DEVICE_USER_CODE_SQL)
+            .bind(normalize_user_code(user_code))
+            .fetch_one(&self.pool)
+            .await
+        {
+            Ok(device) => device,
+            Err(sqlx::Error::RowNotFound) => return Err(invalid()),
+            Err(err) => return Err(AuthError::DatabaseError(err.to_string())),
+        };
+
+        if device.status != DEVICE_STATUS_PENDING || device.expires <= Utc::now().timestamp() {
+            return Err(invalid());
+        }
+
+        Ok(device)
+    }
+
+    pub async fn decide_device_code(
+        &self,
+        user_code: &str,
+        user_uuid: &str,
+        approve: bool,
+    ) -> AuthResult<()> {
+        let status = if approve {
+            DEVICE_STATUS_APPROVED
+        } else {
+            DEVICE_STATUS_DENIED
+        };
+
+        let result = sqlx::query(DECIDE_DEVICE_CODE_SQL)
+            .bind(normalize_user_code(user_code))
+            .bind(status)
+            .bind(user_uuid)
+            .bind(Utc::now().timestamp())
+            .execute(&self.pool)
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(AuthError::TokenError("code is invalid or has expired".to_string()));
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Handle a client polling with its device code. Until the user answers
+    /// this is `authorization_pending`, or `slow_down` if the client polls
+    /// faster than its interval. Once approved the code is spent and the
+    /// approved authorization returned.
+    ///
+    pub async fn poll_device_code(
+        &self,
+        device_code: &str,
+        client_id: &str,
+    ) -> AuthResult<DeviceCode> {
+        let hash = code_hash(device_code);
+        let now = Utc::now().timestamp();
+
+        let device = match sqlx::query_as::<_, DeviceCode>(FIND_DEVICE_CODE_SQL)
+            .bind(&hash)
+            .fetch_one(&self.pool)
+            .await
+        {
+            Ok(device) => device,
+            Err(sqlx::Error::RowNotFound) => {
+                return Err(oauth_error(INVALID_GRANT, "device code is invalid"))
+            }
+            Err(err) => return Err(AuthError::DatabaseError(err.to_string())),
+        };
+
+        if device.client_id != client_id {
+            return Err(oauth_error(INVALID_GRANT, "device code was issued to another client"));
+        }
+
+        if device.expires <= now {
+            return Err(oauth_error(EXPIRED_TOKEN, "device code has expired"));
+        }
+
+        match device.status.as_str() {
+            DEVICE_STATUS_APPROVED => {
+                let result = sqlx::query(USE_DEVICE_CODE_SQL)
+                    .bind(&hash)
+                    .execute(&self.pool)
+                    .await?;
+
+                if result.rows_affected() == 0 {
+                    return Err(oauth_error(INVALID_GRANT, "device code has already been used"));
+                }
+
+                Ok(device)
+            }
+            DEVICE_STATUS_DENIED => Err(oauth_error(ACCESS_DENIED, "the user denied the request")),
+            DEVICE_STATUS_PENDING => {
+                if now - device.last_polled < device.poll_interval {
+                    sqlx::query(SLOW_DOWN_DEVICE_CODE_SQL)
+                        .bind(&hash)
+                        .bind(now)
+                        .execute(&self.pool)
+                        .await?;
+
+                    return Err(oauth_error(SLOW_DOWN, "polling too quickly"));
+                }
+
+                sqlx::query(POLL_DEVICE_CODE_SQL)
+                    .bind(&hash)
+                    .bind(now)
+                    .execute(&self.pool)
+                    .await?;
+
+                Err(oauth_error(AUTHORIZATION_PENDING, "the user has not answered yet"))
+            }
+            _ => Err(oauth_error(INVALID_GRANT, "device code has already been used")),
+        }
+    }
+}
+
+///
+/// Start the device flow for a client, such as a CLI, that cannot open a
+/// browser itself.
+///
+pub async fn device_authorization(
+    state: &AppState,
+    basic: Option<(String, String)>,
+    req: &DeviceAuthorizationReq,
+) -> AuthResult<DeviceAuthorizationResp> {
+    check_oauth_enabled(state)?;
+
+    let client =
+        authenticate_client(&state.user_db, basic, &req.client_id, &req.client_secret).await?;
+
+    if !client.allows_grant(GRANT_DEVICE_CODE) {
+        return Err(oauth_error(
+            UNAUTHORIZED_CLIENT,
+            "client is not registered for the device_code grant",
+        ));
+    }
+
+    let verification_uri = device_url(&state.config)?;
+
+    let (device_code, user_code) = state.user_db.create_device_code(&client.client_id).await?;
+    let user_code = format_user_code(&user_code);
+
+    let verification_uri_complete =
+        match Url::parse_with_params(&verification_uri, [("user_code", &user_code)]) {
+            Ok(url) => url.to_string(),
+            Err(err) => return Err(AuthError::ConfigError(err.to_string())),
+        };
+
+    Ok(DeviceAuthorizationResp {
+        device_code,
+        user_code,
+        verification_uri,
+        verification_uri_complete,
+        expires_in: DEVICE_CODE_TTL_MINS * 60,
+        interval: DEVICE_POLL_INTERVAL_SECS,
+    })
+}
+
+///
+/// Token endpoint handling for a polling device. An approved device gets
+/// tokens for the client, limited to the client's scope, the same as
+/// one going through `/authorize`.
+///
+pub async fn exchange_device_code(
+    state: &AppState,
+    client: &OAuthClient,
+    req: &TokenReq,
+) -> AuthResult<OAuthTokenResp> {
+    let scope = narrow_scope(&client.scope, req.scope.as_deref())?;
+
+    let device = state
+        .user_db
+        .poll_device_code(required(&req.device_code, "device_code")?, &client.client_id)
+        .await?;
+
+    let user = grant_user(&state.user_db, &device.user_uuid).await?;
+
+    client_tokens(state, client, &user, &scope, "")
+}
+
+async fn device_authorization_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(req): Form<DeviceAuthorizationReq>,
+) -> AuthResult<([(HeaderName, &'static str); 1], Json<DeviceAuthorizationResp>)> {
+    let resp = device_authorization(&state, basic_credentials(&headers)?, &req).await?;
+
+    Ok(([(CACHE_CONTROL, "no-store")], Json(resp)))
+}
+
+async fn device_info_handler(
+    State(state): State<AppState>,
+    FirstPartyToken(claims): FirstPartyToken,
+    Query(query): Query<DeviceCodeQuery>,
+) -> AuthResult<Json<DeviceInfoResp>> {
+    check_oauth_enabled(&state)?;
+    signed_in_user(&state, &claims).await?;
+
+    let device = state.user_db.pending_device_code(&query.user_code).await?;
+    let client = state.user_db.find_oauth_client(&device.client_id).await?;
+
+    Ok(Json(DeviceInfoResp {
+        client_id: client.client_id,
+        client_name: client.name,
+        user_code: format_user_code(&device.user_code),
+    }))
+}
+
+async fn device_approval_handler(
+    State(state): State<AppState>,
+    FirstPartyToken(claims): FirstPartyToken,
+    Json(req): Json<DeviceApprovalReq>,
+) -> AuthResult<StatusCode> {
+    check_oauth_enabled(&state)?;
+
+    let user = signed_in_user(&state, &claims).await?;
+
+    state
+        .user_db
+        .decide_device_code(&req.user_code, &user.uuid, req.approve)
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+///
+/// RFC 8628 device flow. Devices start at `/oauth/device_authorization`
+/// and poll `/oauth/token`, while the verification page looks up and
+/// answers codes at `/oauth/device` for the signed in user.
+///
+pub fn device_router() -> Router<AppState> {
+    Router::new()
+        .route("/oauth/device_authorization", post(device_authorization_handler))
+        .route("/oauth/device", get(device_info_handler).post(device_approval_handler))
+}
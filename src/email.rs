@@ -2,85 +2,216 @@ use std::fmt::Display;
 
 use askama::Template;
 use lettre::{
-    message::header::ContentType, transport::smtp::authentication::Credentials, Message,
-    SmtpTransport, Transport,
+    message::{
+        dkim::{DkimConfig, DkimSigningAlgorithm, DkimSigningKey},
+        header::ContentType,
+        Mailbox, MessageBuilder, MultiPart,
+    },
+    transport::smtp::authentication::Credentials,
+    Message, SmtpTransport, Transport,
 };
 
+use url::Url;
+
+use crate::{config::SmtpConfig, i18n::Messages};
+
+#[deprecated(note = "use Messages::minutes to localise durations")]
 pub const VALID_TEN_MINS: &str = "10 minutes";
+#[deprecated(note = "use Messages::do_not_reply for the localised text")]
 pub const DO_NOT_REPLY: &str = "Please do not reply to this message. It was sent from a notification-only email address that we don't monitor.";
 pub const TOKEN_PARAM: &str = "token";
 pub const URL_PARAM: &str = "url";
 
+///
+/// Build the link emailed to web clients: the callback url with the
+/// token and the url to return to appended as query parameters.
+///
+pub fn callback_link(
+    callback_url: &str,
+    token: &str,
+    url: Option<&str>,
+) -> Result<String, MailerError> {
+    let mut params = vec![(TOKEN_PARAM, token)];
+
+    if let Some(url) = url {
+        params.push((URL_PARAM, url));
+    }
+
+    match Url::parse_with_params(callback_url, &params) {
+        Ok(link) => Ok(link.to_string()),
+        Err(err) => Err(MailerError::HtmlEmailError(format!(
+            "invalid callback url {}: {}",
+            callback_url, err
+        ))),
+    }
+}
+
+///
+/// An HTML email template that can also produce the plain-text
+/// alternative part. By default the text is derived from the rendered
+/// HTML; override `render_text` to render a sibling `.txt` template
+/// instead.
+///
+pub trait EmailTemplate: Template {
+    fn render_text(&self, html: &str) -> askama::Result<String> {
+        Ok(html_to_text(html))
+    }
+}
+
 #[derive(Template)]
 #[template(path = "email/passwordless/api.html")]
 pub struct PasswordlessEmailTemplate {
     pub name: String,
     pub link: String,
     pub time: String,
-    pub do_not_reply: String,
+    pub t: Messages,
 }
 
+impl EmailTemplate for PasswordlessEmailTemplate {}
+
 #[derive(Template)]
 #[template(path = "email/passwordless/web.html")]
 pub struct PasswordlessEmailWebTemplate {
     pub name: String,
     pub link: String,
     pub time: String,
-    pub do_not_reply: String,
+    pub t: Messages,
 }
 
+impl EmailTemplate for PasswordlessEmailWebTemplate {}
+
 #[derive(Template)]
 #[template(path = "email/verify/api.html")]
 pub struct EmailVerificationTemplate {
     pub name: String,
     pub link: String,
     pub time: String,
-    pub do_not_reply: String,
+    pub t: Messages,
 }
 
+impl EmailTemplate for EmailVerificationTemplate {}
+
 #[derive(Template)]
 #[template(path = "email/verify/web.html")]
 pub struct EmailVerificationWebTemplate {
     pub name: String,
     pub link: String,
     pub time: String,
-    pub do_not_reply: String,
+    pub t: Messages,
 }
 
+impl EmailTemplate for EmailVerificationWebTemplate {}
+
 #[derive(Template)]
 #[template(path = "email/verified.html")]
 pub struct EmailVerifiedTemplate {
     pub name: String,
-    pub do_not_reply: String,
+    pub t: Messages,
 }
 
+impl EmailTemplate for EmailVerifiedTemplate {}
+
 #[derive(Template)]
 #[template(path = "email/password/reset/web.html")]
 pub struct EmailResetPasswordWebTemplate {
     pub name: String,
     pub link: String,
     pub time: String,
-    pub do_not_reply: String,
+    pub t: Messages,
+}
+
+impl EmailTemplate for EmailResetPasswordWebTemplate {}
+
+#[derive(Template)]
+#[template(path = "email/password/reset/api.html")]
+pub struct EmailResetPasswordTemplate {
+    pub name: String,
+    pub link: String,
+    pub time: String,
+    pub t: Messages,
+}
+
+impl EmailTemplate for EmailResetPasswordTemplate {}
+
+#[derive(Template)]
+#[template(path = "email/password/switch-to-passwordless.html")]
+pub struct EmailSwitchToPasswordlessTemplate {
+    pub name: String,
+    pub t: Messages,
 }
 
+impl EmailTemplate for EmailSwitchToPasswordlessTemplate {}
+
 #[derive(Template)]
 #[template(path = "email/password/updated.html")]
 pub struct EmailPasswordUpdatedTemplate {
     pub name: String,
-    pub do_not_reply: String,
+    pub t: Messages,
 }
 
+impl EmailTemplate for EmailPasswordUpdatedTemplate {}
+
 #[derive(Template)]
 #[template(path = "email/account/updated.html")]
 pub struct EmailAccountUpdatedTemplate {
     pub name: String,
-    pub do_not_reply: String,
+    pub t: Messages,
+}
+
+impl EmailTemplate for EmailAccountUpdatedTemplate {}
+
+#[derive(Template)]
+#[template(path = "email/change/api.html")]
+pub struct EmailChangeTemplate {
+    pub name: String,
+    pub link: String,
+    pub time: String,
+    pub t: Messages,
 }
 
+impl EmailTemplate for EmailChangeTemplate {}
+
+#[derive(Template)]
+#[template(path = "email/change/web.html")]
+pub struct EmailChangeWebTemplate {
+    pub name: String,
+    pub link: String,
+    pub time: String,
+    pub t: Messages,
+}
+
+impl EmailTemplate for EmailChangeWebTemplate {}
+
+#[derive(Template)]
+#[template(path = "email/change/notice-api.html")]
+pub struct EmailChangeNoticeTemplate {
+    pub name: String,
+    pub new_email: String,
+    pub link: String,
+    pub time: String,
+    pub t: Messages,
+}
+
+impl EmailTemplate for EmailChangeNoticeTemplate {}
+
+#[derive(Template)]
+#[template(path = "email/change/notice-web.html")]
+pub struct EmailChangeNoticeWebTemplate {
+    pub name: String,
+    pub new_email: String,
+    pub link: String,
+    pub time: String,
+    pub t: Messages,
+}
+
+impl EmailTemplate for EmailChangeNoticeWebTemplate {}
+
 #[derive(Debug, Clone)]
 pub enum MailerError {
     SendError(String),
     HtmlEmailError(String),
+    DkimError(String),
+    AddressError(String),
 }
 
 //impl std::error::Error for MailerError {}
@@ -96,6 +227,12 @@ impl Display for MailerError {
             Self::HtmlEmailError(message) => {
                 write!(f, "could not send HTML email: {}", message)
             }
+            Self::DkimError(message) => {
+                write!(f, "could not DKIM sign email: {}", message)
+            }
+            Self::AddressError(message) => {
+                write!(f, "invalid email address {}", message)
+            }
         }
     }
 }
@@ -126,6 +263,39 @@ impl From<askama::Error> for MailerError {
     }
 }
 
+///
+/// Load a DKIM signing key. RSA keys are PKCS#1 PEM and Ed25519 keys
+/// are the base64 encoded 32 byte secret.
+///
+pub fn dkim_config(
+    selector: &str,
+    domain: &str,
+    private_key: &str,
+    algorithm: DkimSigningAlgorithm,
+) -> Result<DkimConfig, MailerError> {
+    let key = match DkimSigningKey::new(private_key, algorithm) {
+        Ok(key) => key,
+        Err(err) => return Err(MailerError::DkimError(err.to_string())),
+    };
+
+    Ok(DkimConfig::default_config(
+        selector.to_string(),
+        domain.to_string(),
+        key,
+    ))
+}
+
+pub fn dkim_algorithm(name: &str) -> Result<DkimSigningAlgorithm, MailerError> {
+    match name.to_lowercase().as_str() {
+        "rsa" | "rsa-sha256" => Ok(DkimSigningAlgorithm::Rsa),
+        "ed25519" | "ed25519-sha256" => Ok(DkimSigningAlgorithm::Ed25519),
+        _ => Err(MailerError::DkimError(format!(
+            "unknown DKIM algorithm {}",
+            name
+        ))),
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Mailer {
     //name: String,
@@ -137,29 +307,60 @@ pub struct Mailer {
     //addr: String,
     reply_to: String,
     mailer: SmtpTransport,
+    dkim: Option<DkimConfig>,
 }
 
 impl Mailer {
-    pub fn new() -> Self {
-        let name = sys::env::str("SMTP_NAME");
-        let from = sys::env::str("SMTP_FROM");
-        let user = sys::env::str("SMTP_USER");
-        let password = sys::env::str("SMTP_PASSWORD");
-        let host = sys::env::str("SMTP_HOST");
-        //let port = env::u32("SMTP_PORT");
-        //let addr = format!("{}:{}", host, port);
+    ///
+    /// Create a mailer relaying through the configured SMTP server,
+    /// DKIM signing messages if a key is configured.
+    ///
+    pub fn new(config: &SmtpConfig) -> Result<Self, MailerError> {
+        let reply_to = config.reply_to();
+
+        if let Err(err) = reply_to.parse::<Mailbox>() {
+            return Err(MailerError::AddressError(format!("{}: {}", reply_to, err)));
+        }
 
-        let reply_to = format!("{} <{}>", name, from);
+        let creds = Credentials::new(config.user.clone(), config.password.clone());
 
-        let creds = Credentials::new(user, password);
+        let mut builder = SmtpTransport::relay(&config.host)?.credentials(creds);
 
-        // Open a remote connection to gmail
-        let mailer = SmtpTransport::relay(&host)
-            .unwrap()
-            .credentials(creds)
-            .build();
+        if let Some(port) = config.port {
+            builder = builder.port(port);
+        }
 
-        Mailer { reply_to, mailer }
+        let dkim = match &config.dkim {
+            Some(dkim) => Some(dkim_config(
+                &dkim.selector,
+                &dkim.domain,
+                &dkim.private_key,
+                dkim_algorithm(&dkim.algorithm)?,
+            )?),
+            None => None,
+        };
+
+        Ok(Mailer {
+            reply_to,
+            mailer: builder.build(),
+            dkim,
+        })
+    }
+
+    pub fn with_transport(reply_to: &str, mailer: SmtpTransport) -> Self {
+        Mailer {
+            reply_to: reply_to.to_string(),
+            mailer,
+            dkim: None,
+        }
+    }
+
+    ///
+    /// Sign all outgoing messages with this DKIM configuration.
+    ///
+    pub fn with_dkim(mut self, dkim: DkimConfig) -> Self {
+        self.dkim = Some(dkim);
+        self
     }
 
     // pub fn set_host(&mut self, host: &str) -> &mut Self {
@@ -173,29 +374,39 @@ impl Mailer {
     //     self
     // }
 
-    pub fn send_html_email<T: Template>(
+    pub fn send_html_email<T: EmailTemplate>(
         &self,
         to: &str,
         subject: &str,
         body: &T,
     ) -> Result<(), MailerError> {
         let html = body.render()?;
+        let text = body.render_text(&html)?;
 
-        let email = Message::builder()
-            .from(self.reply_to.parse().unwrap())
-            .reply_to(self.reply_to.parse().unwrap())
-            .to(to.parse().unwrap())
-            .subject(subject)
-            .header(ContentType::TEXT_HTML)
-            .body(html)?;
-
-        self.mailer.send(&email)?;
+        self.send_multipart_email(to, subject, &text, &html)?;
 
         eprintln!("HTML email sent successfully!");
 
         Ok(())
     }
 
+    ///
+    /// Send a multipart/alternative email with plain text and HTML parts.
+    ///
+    pub fn send_multipart_email(
+        &self,
+        to: &str,
+        subject: &str,
+        text: &str,
+        html: &str,
+    ) -> Result<(), MailerError> {
+        let email = self.multipart_email(to, subject, text, html)?;
+
+        self.mailer.send(&email)?;
+
+        Ok(())
+    }
+
     pub fn send_email(&self, to: &str, subject: &str, body: &str) -> Result<(), MailerError> {
         return self.send_base_email(to, subject, body, ContentType::TEXT_PLAIN);
     }
@@ -207,13 +418,7 @@ impl Mailer {
         body: &str,
         content_type: ContentType,
     ) -> Result<(), MailerError> {
-        let email = Message::builder()
-            .from(self.reply_to.parse().unwrap())
-            .reply_to(self.reply_to.parse().unwrap())
-            .to(to.parse().unwrap())
-            .subject(subject)
-            .header(content_type)
-            .body(body.to_string())?;
+        let email = self.base_email(to, subject, body, content_type)?;
 
         self.mailer.send(&email)?;
 
@@ -221,6 +426,240 @@ impl Mailer {
 
         Ok(())
     }
+
+    ///
+    /// Build a multipart/alternative message, DKIM signed if configured.
+    ///
+    pub fn multipart_email(
+        &self,
+        to: &str,
+        subject: &str,
+        text: &str,
+        html: &str,
+    ) -> Result<Message, MailerError> {
+        let email = self
+            .message_builder(to)?
+            .subject(subject)
+            .multipart(MultiPart::alternative_plain_html(
+                text.to_string(),
+                html.to_string(),
+            ))?;
+
+        Ok(self.sign(email))
+    }
+
+    ///
+    /// Build a single part message, DKIM signed if configured.
+    ///
+    pub fn base_email(
+        &self,
+        to: &str,
+        subject: &str,
+        body: &str,
+        content_type: ContentType,
+    ) -> Result<Message, MailerError> {
+        let email = self
+            .message_builder(to)?
+            .subject(subject)
+            .header(content_type)
+            .body(body.to_string())?;
+
+        Ok(self.sign(email))
+    }
+
+    fn message_builder(&self, to: &str) -> Result<MessageBuilder, MailerError> {
+        let reply_to: Mailbox = match self.reply_to.parse() {
+            Ok(mailbox) => mailbox,
+            Err(err) => {
+                return Err(MailerError::AddressError(format!(
+                    "{}: {}",
+                    self.reply_to, err
+                )))
+            }
+        };
+
+        let to: Mailbox = match to.parse() {
+            Ok(mailbox) => mailbox,
+            Err(err) => return Err(MailerError::AddressError(format!("{}: {}", to, err))),
+        };
+
+        Ok(Message::builder()
+            .from(reply_to.clone())
+            .reply_to(reply_to)
+            .to(to))
+    }
+
+    fn sign(&self, mut email: Message) -> Message {
+        if let Some(dkim) = &self.dkim {
+            email.sign(dkim);
+        }
+
+        email
+    }
+}
+
+///
+/// Convert a rendered HTML email into readable plain text. Block
+/// elements become line breaks, links are written as `text (url)` and
+/// entities are decoded.
+///
+pub fn html_to_text(html: &str) -> String {
+    let mut out = String::new();
+    let mut rest = html;
+    let mut skip = false;
+    // href of the currently open anchor and where its text starts in out
+    let mut anchor: Option<(String, usize)> = None;
+
+    while let Some(start) = rest.find('<') {
+        if !skip {
+            push_text(&mut out, &rest[..start]);
+        }
+
+        rest = &rest[start..];
+
+        if rest.starts_with("<!--") {
+            rest = match rest.find("-->") {
+                Some(end) => &rest[end + 3..],
+                None => "",
+            };
+            continue;
+        }
+
+        let end = match rest.find('>') {
+            Some(end) => end,
+            None => {
+                rest = "";
+                break;
+            }
+        };
+
+        let tag = &rest[1..end];
+        rest = &rest[end + 1..];
+
+        let closing = tag.starts_with('/');
+
+        let name = tag
+            .trim_start_matches('/')
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or("")
+            .to_lowercase();
+
+        match name.as_str() {
+            "head" | "style" | "script" | "title" => skip = !closing,
+            "p" | "div" | "tr" | "table" | "ul" | "ol" | "h1" | "h2" | "h3" | "h4" | "h5"
+            | "h6" => out.push('\n'),
+            "br" => out.push('\n'),
+            "li" if !closing => out.push_str("\n- "),
+            "a" if closing => {
+                if let Some((href, pos)) = anchor.take() {
+                    if out[pos..].trim() != href {
+                        out.push_str(&format!(" ({})", href));
+                    }
+                }
+            }
+            "a" => anchor = html_attr(tag, "href").map(|href| (href, out.len())),
+            _ => (),
+        }
+    }
+
+    if !skip {
+        push_text(&mut out, rest);
+    }
+
+    let mut lines: Vec<&str> = Vec::new();
+
+    for line in out.lines().map(|line| line.trim()) {
+        if line.is_empty() && lines.last().map_or(true, |last| last.is_empty()) {
+            continue;
+        }
+
+        lines.push(line);
+    }
+
+    while lines.last().map_or(false, |last| last.is_empty()) {
+        lines.pop();
+    }
+
+    lines.join("\n")
+}
+
+fn push_text(out: &mut String, text: &str) {
+    let text = decode_entities(text);
+
+    for c in text.chars() {
+        if c.is_whitespace() {
+            if !out.ends_with(|c: char| c.is_whitespace()) {
+                out.push(' ');
+            }
+        } else {
+            out.push(c);
+        }
+    }
+}
+
+fn html_attr(tag: &str, attr: &str) -> Option<String> {
+    for quote in ['"', '\''] {
+        let prefix = format!("{}={}", attr, quote);
+
+        if let Some(start) = tag.find(&prefix) {
+            let value = &tag[start + prefix.len()..];
+
+            if let Some(end) = value.find(quote) {
+                return Some(decode_entities(&value[..end]));
+            }
+        }
+    }
+
+    None
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let decoded = rest.find(';').and_then(|end| {
+            let entity = &rest[1..end];
+
+            let c = match entity {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some(' '),
+                _ => match entity.strip_prefix('#') {
+                    Some(num) => match num.strip_prefix(['x', 'X']) {
+                        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                        None => num.parse::<u32>().ok(),
+                    }
+                    .and_then(char::from_u32),
+                    None => None,
+                },
+            };
+
+            c.map(|c| (c, end))
+        });
+
+        match decoded {
+            Some((c, end)) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    out.push_str(rest);
+
+    out
 }
 
 //pub static EMAILER: Lazy<SMTPEmailer> = Lazy::new(|| SMTPEmailer::new());
@@ -0,0 +1,197 @@
+use axum::{extract::State, http::StatusCode, routing::post, Json, Router};
+use serde::Deserialize;
+
+use crate::{
+    email::{
+        callback_link, EmailChangeNoticeTemplate, EmailChangeNoticeWebTemplate,
+        EmailChangeTemplate, EmailChangeWebTemplate,
+    },
+    i18n::Messages,
+    jwt::{config_jwt, AppState, FirstPartyToken, JwtClaims, JwtToken, TokenType},
+    signin::{check_token_type, email_callback_url},
+    AuthError, AuthResult, EmailChange, User,
+};
+
+///
+/// Request to move the signed in account to a new email address.
+///
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct EmailChangeReq {
+    pub email: String,
+    pub callback_url: Option<String>,
+    pub url: Option<String>,
+}
+
+///
+/// Start changing a user's email address. The new address is sent a
+/// confirmation token and the old address a notice with a token to
+/// revert the change. Each token carries the change's uuid as its otp.
+///
+pub async fn request_email_change(
+    state: &AppState,
+    user: &User,
+    new_email: &str,
+    callback_url: Option<&str>,
+    url: Option<&str>,
+) -> AuthResult<EmailChange> {
+    let change = state.user_db.create_email_change(user, new_email).await?;
+
+    let tokens = &state.config.tokens;
+    let callback_url = email_callback_url(&state.config, callback_url);
+    let t = Messages::for_user(user);
+
+    let token = config_jwt(
+        &user.uuid,
+        &TokenType::ChangeEmail,
+        &change.uuid,
+        tokens,
+        &state.jwt_private_key,
+    )?;
+
+    let time = t.duration(tokens.change_email_ttl_mins);
+    let subject = t.get("email.change.subject");
+
+    match callback_url {
+        Some(callback_url) => {
+            let body = EmailChangeWebTemplate {
+                name: user.display_name(),
+                link: callback_link(callback_url, &token, url)?,
+                time,
+                t: t.clone(),
+            };
+
+            state.outbox.enqueue_html_email(new_email, subject, &body).await?;
+        }
+        None => {
+            let body = EmailChangeTemplate {
+                name: user.display_name(),
+                link: token,
+                time,
+                t: t.clone(),
+            };
+
+            state.outbox.enqueue_html_email(new_email, subject, &body).await?;
+        }
+    }
+
+    let token = config_jwt(
+        &user.uuid,
+        &TokenType::RevertEmailChange,
+        &change.uuid,
+        tokens,
+        &state.jwt_private_key,
+    )?;
+
+    let time = t.duration(tokens.revert_email_change_ttl_mins);
+    let subject = t.get("email.change.notice.subject");
+
+    match callback_url {
+        Some(callback_url) => {
+            let body = EmailChangeNoticeWebTemplate {
+                name: user.display_name(),
+                new_email: new_email.to_string(),
+                link: callback_link(callback_url, &token, url)?,
+                time,
+                t,
+            };
+
+            state.outbox.enqueue_html_email(&user.email, subject, &body).await?;
+        }
+        None => {
+            let body = EmailChangeNoticeTemplate {
+                name: user.display_name(),
+                new_email: new_email.to_string(),
+                link: token,
+                time,
+                t,
+            };
+
+            state.outbox.enqueue_html_email(&user.email, subject, &body).await?;
+        }
+    }
+
+    Ok(change)
+}
+
+async fn claimed_email_change(
+    state: &AppState,
+    claims: &JwtClaims,
+    token_type: &TokenType,
+) -> AuthResult<EmailChange> {
+    check_token_type(claims, token_type)?;
+
+    let change = state.user_db.find_email_change(&claims.otp).await?;
+
+    // the change must belong to the user the token was issued to
+    if change.user_uuid != claims.uuid {
+        return Err(AuthError::TokenError(
+            "email change does not belong to this account".to_string(),
+        ));
+    }
+
+    Ok(change)
+}
+
+///
+/// Commit an email change using the token sent to the new address.
+///
+pub async fn confirm_email_change(state: &AppState, claims: &JwtClaims) -> AuthResult<User> {
+    let change = claimed_email_change(state, claims, &TokenType::ChangeEmail).await?;
+
+    state.user_db.confirm_email_change(&change).await?;
+
+    state.user_db.find_user_by_uuid(&change.user_uuid).await
+}
+
+///
+/// Cancel or undo an email change using the token sent to the old
+/// address.
+///
+pub async fn revert_email_change(state: &AppState, claims: &JwtClaims) -> AuthResult<User> {
+    let change = claimed_email_change(state, claims, &TokenType::RevertEmailChange).await?;
+
+    state.user_db.revert_email_change(&change).await?;
+
+    state.user_db.find_user_by_uuid(&change.user_uuid).await
+}
+
+async fn change_handler(
+    State(state): State<AppState>,
+    FirstPartyToken(claims): FirstPartyToken,
+    Json(req): Json<EmailChangeReq>,
+) -> AuthResult<StatusCode> {
+    let user = state.user_db.find_user_by_uuid(&claims.uuid).await?;
+
+    request_email_change(
+        &state,
+        &user,
+        &req.email,
+        req.callback_url.as_deref(),
+        req.url.as_deref(),
+    )
+    .await?;
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+async fn confirm_handler(
+    State(state): State<AppState>,
+    JwtToken(claims): JwtToken,
+) -> AuthResult<Json<User>> {
+    Ok(Json(confirm_email_change(&state, &claims).await?))
+}
+
+async fn revert_handler(
+    State(state): State<AppState>,
+    JwtToken(claims): JwtToken,
+) -> AuthResult<Json<User>> {
+    Ok(Json(revert_email_change(&state, &claims).await?))
+}
+
+pub fn email_change_router() -> Router<AppState> {
+    Router::new()
+        .route("/email/change", post(change_handler))
+        .route("/email/change/confirm", post(confirm_handler))
+        .route("/email/change/revert", post(revert_handler))
+}
@@ -0,0 +1,210 @@
+use std::{collections::HashMap, fmt};
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, SaltString},
+    Algorithm, Argon2, Params, PasswordHasher, Version, ARGON2ID_IDENT,
+};
+use hmac::{Hmac, Mac};
+use password_auth::verify_password;
+use sha2::Sha256;
+
+use crate::{config::HashingConfig, AuthError, AuthResult};
+
+// bcrypt hashes from imported accounts are not PHC strings so are detected
+// by their prefix
+const BCRYPT_PREFIXES: [&str; 4] = ["$2a$", "$2b$", "$2x$", "$2y$"];
+
+// peppered hashes are stored as p{version}$argon2id$... so the pepper used
+// is known when verifying
+const PEPPER_PREFIX: &str = "p";
+
+pub const PEPPER_MIN_LEN: usize = 32;
+
+///
+/// Hashes new passwords with Argon2id using the configured costs.
+/// Verification also accepts older Argon2 parameters, PBKDF2 PHC strings
+/// and bcrypt so imported users can still sign in.
+///
+/// If a pepper is configured, passwords are HMAC'd with it before hashing
+/// so leaked hashes cannot be cracked without the server's secret. Old
+/// pepper versions are kept for verification and replaced on sign in.
+///
+#[derive(Clone, Default)]
+pub struct Argon2Hasher {
+    params: Params,
+    // 0 means new hashes are not peppered
+    pepper_version: u32,
+    peppers: HashMap<u32, Vec<u8>>,
+}
+
+impl fmt::Debug for Argon2Hasher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // never print the peppers themselves
+        f.debug_struct("Argon2Hasher")
+            .field("params", &self.params)
+            .field("pepper_version", &self.pepper_version)
+            .field("pepper_versions", &self.peppers.keys().collect::<Vec<&u32>>())
+            .finish()
+    }
+}
+
+impl Argon2Hasher {
+    pub fn new(config: &HashingConfig) -> AuthResult<Self> {
+        let params =
+            match Params::new(config.memory_kib, config.iterations, config.parallelism, None) {
+                Ok(params) => params,
+                Err(err) => return Err(AuthError::ConfigError(format!("hashing: {}", err))),
+            };
+
+        let mut peppers: HashMap<u32, Vec<u8>> = HashMap::new();
+
+        for (version, pepper) in &config.peppers {
+            let version: u32 = match version.parse() {
+                Ok(version) if version > 0 => version,
+                _ => {
+                    return Err(AuthError::ConfigError(format!(
+                        "hashing.peppers: version {} must be a positive integer",
+                        version
+                    )))
+                }
+            };
+
+            if pepper.len() < PEPPER_MIN_LEN {
+                return Err(AuthError::ConfigError(format!(
+                    "hashing.peppers: pepper {} must be at least {} bytes",
+                    version, PEPPER_MIN_LEN
+                )));
+            }
+
+            peppers.insert(version, pepper.as_bytes().to_vec());
+        }
+
+        if config.pepper_version > 0 && !peppers.contains_key(&config.pepper_version) {
+            return Err(AuthError::ConfigError(format!(
+                "hashing.pepper_version {} has no pepper",
+                config.pepper_version
+            )));
+        }
+
+        Ok(Self {
+            params,
+            pepper_version: config.pepper_version,
+            peppers,
+        })
+    }
+
+    fn pepper(&self, version: u32, pwd: &str) -> AuthResult<Vec<u8>> {
+        let key = match self.peppers.get(&version) {
+            Some(key) => key,
+            None => {
+                return Err(AuthError::PasswordError(format!(
+                    "pepper version {} is not configured",
+                    version
+                )))
+            }
+        };
+
+        let mut mac = match Hmac::<Sha256>::new_from_slice(key) {
+            Ok(mac) => mac,
+            Err(err) => return Err(AuthError::CryptographyError(err.to_string())),
+        };
+
+        mac.update(pwd.as_bytes());
+
+        Ok(mac.finalize().into_bytes().to_vec())
+    }
+
+    pub fn hash(&self, pwd: &str) -> AuthResult<String> {
+        if self.pepper_version == 0 {
+            return self.hash_bytes(pwd.as_bytes());
+        }
+
+        Ok(format!(
+            "{}{}{}",
+            PEPPER_PREFIX,
+            self.pepper_version,
+            self.hash_bytes(&self.pepper(self.pepper_version, pwd)?)?
+        ))
+    }
+
+    fn hash_bytes(&self, pwd: &[u8]) -> AuthResult<String> {
+        let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone());
+        let salt = SaltString::generate(&mut OsRng);
+
+        match argon2.hash_password(pwd, &salt) {
+            Ok(hash) => Ok(hash.to_string()),
+            Err(err) => Err(AuthError::CryptographyError(err.to_string())),
+        }
+    }
+
+    ///
+    /// Verify a password against a hash made by this or an earlier
+    /// configuration, peppered or not.
+    ///
+    pub fn verify(&self, pwd: &str, hash: &str) -> AuthResult<()> {
+        match split_pepper(hash) {
+            Some((version, hash)) => verify_hash(self.pepper(version, pwd)?, hash),
+            None => verify_hash(pwd, hash),
+        }
+    }
+
+    ///
+    /// Whether a hash that verified should be replaced because it is not
+    /// Argon2id with the current costs and pepper.
+    ///
+    pub fn needs_rehash(&self, hash: &str) -> bool {
+        let hash = match split_pepper(hash) {
+            Some((version, hash)) if version == self.pepper_version => hash,
+            None if self.pepper_version == 0 => hash,
+            _ => return true,
+        };
+
+        let parsed = match PasswordHash::new(hash) {
+            Ok(parsed) => parsed,
+            // bcrypt or something else foreign
+            Err(_) => return true,
+        };
+
+        if parsed.algorithm != ARGON2ID_IDENT || parsed.version != Some(Version::V0x13.into()) {
+            return true;
+        }
+
+        match Params::try_from(&parsed) {
+            Ok(params) => {
+                params.m_cost() != self.params.m_cost()
+                    || params.t_cost() != self.params.t_cost()
+                    || params.p_cost() != self.params.p_cost()
+            }
+            Err(_) => true,
+        }
+    }
+}
+
+///
+/// The pepper version and inner hash of a peppered hash.
+///
+fn split_pepper(hash: &str) -> Option<(u32, &str)> {
+    let rest = hash.strip_prefix(PEPPER_PREFIX)?;
+    let index = rest.find('$')?;
+
+    match rest[..index].parse() {
+        Ok(version) => Some((version, &rest[index..])),
+        Err(_) => None,
+    }
+}
+
+///
+/// Verify a password against an unpeppered Argon2, PBKDF2 or bcrypt hash.
+/// Use `Argon2Hasher::verify` for hashes that may be peppered.
+///
+pub fn verify_hash(pwd: impl AsRef<[u8]>, hash: &str) -> AuthResult<()> {
+    if BCRYPT_PREFIXES.iter().any(|prefix| hash.starts_with(prefix)) {
+        return match bcrypt::verify(pwd, hash) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(AuthError::PasswordError("password is invalid".to_string())),
+            Err(err) => Err(AuthError::PasswordError(err.to_string())),
+        };
+    }
+
+    Ok(verify_password(pwd, hash)?)
+}
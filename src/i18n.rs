@@ -0,0 +1,197 @@
+use std::{collections::HashMap, convert::Infallible, sync::OnceLock};
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header::ACCEPT_LANGUAGE, request::Parts},
+};
+
+use crate::User;
+
+pub const DEFAULT_LOCALE: &str = "en";
+
+// message catalogues are compiled into the crate, keyed by primary
+// language subtag
+const CATALOGUES: [(&str, &str); 3] = [
+    ("en", include_str!("../locales/en.json")),
+    ("es", include_str!("../locales/es.json")),
+    ("fr", include_str!("../locales/fr.json")),
+];
+
+type Catalogue = HashMap<String, String>;
+
+fn catalogues() -> &'static HashMap<&'static str, Catalogue> {
+    static LOADED: OnceLock<HashMap<&'static str, Catalogue>> = OnceLock::new();
+
+    LOADED.get_or_init(|| {
+        CATALOGUES
+            .iter()
+            .map(|(locale, json)| {
+                (
+                    *locale,
+                    serde_json::from_str::<Catalogue>(json).expect("valid message catalogue"),
+                )
+            })
+            .collect()
+    })
+}
+
+pub fn supported_locales() -> Vec<&'static str> {
+    CATALOGUES.iter().map(|(locale, _)| *locale).collect()
+}
+
+///
+/// Map a language tag such as `en-GB` or `fr_CA` to a supported
+/// locale, if we have a catalogue for it.
+///
+pub fn normalize_locale(tag: &str) -> Option<&'static str> {
+    let primary = tag
+        .trim()
+        .split(|c| c == '-' || c == '_')
+        .next()
+        .unwrap_or("")
+        .to_lowercase();
+
+    CATALOGUES
+        .iter()
+        .map(|(locale, _)| *locale)
+        .find(|locale| *locale == primary)
+}
+
+///
+/// Pick the best supported locale from an `Accept-Language` header,
+/// honouring q-values, and fall back to the default locale.
+///
+pub fn negotiate_locale(accept_language: &str) -> &'static str {
+    let mut ranges: Vec<(&str, f32)> = accept_language
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';');
+            let tag = parts.next()?.trim();
+
+            let q = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .next()
+                .and_then(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            if tag.is_empty() || q <= 0.0 {
+                None
+            } else {
+                Some((tag, q))
+            }
+        })
+        .collect();
+
+    // stable sort so equal weights keep header order
+    ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    ranges
+        .iter()
+        .find_map(|(tag, _)| normalize_locale(tag))
+        .unwrap_or(DEFAULT_LOCALE)
+}
+
+///
+/// Localised strings for one locale. Keys missing from the locale's
+/// catalogue fall back to the default locale, then to the key itself.
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Messages {
+    locale: &'static str,
+}
+
+impl Messages {
+    pub fn new(locale: &str) -> Self {
+        Self {
+            locale: normalize_locale(locale).unwrap_or(DEFAULT_LOCALE),
+        }
+    }
+
+    pub fn for_user(user: &User) -> Self {
+        Self::new(&user.locale)
+    }
+
+    pub fn locale(&self) -> &'static str {
+        self.locale
+    }
+
+    pub fn get<'a>(&self, key: &'a str) -> &'a str {
+        let catalogues = catalogues();
+
+        [self.locale, DEFAULT_LOCALE]
+            .iter()
+            .find_map(|locale| catalogues.get(locale)?.get(key))
+            .map(|value| value.as_str())
+            .unwrap_or(key)
+    }
+
+    ///
+    /// Look up a message and substitute `value` for its `{}` placeholder.
+    ///
+    pub fn get_with(&self, key: &str, value: &str) -> String {
+        self.get(key).replace("{}", value)
+    }
+
+    pub fn greeting(&self, name: &str) -> String {
+        self.get_with("greeting", name)
+    }
+
+    pub fn do_not_reply(&self) -> &str {
+        self.get("do_not_reply")
+    }
+
+    pub fn minutes(&self, n: i64) -> String {
+        self.plural("duration.minutes", n)
+    }
+
+    pub fn hours(&self, n: i64) -> String {
+        self.plural("duration.hours", n)
+    }
+
+    ///
+    /// A token lifetime in hours if it is a whole number of them,
+    /// otherwise in minutes.
+    ///
+    pub fn duration(&self, mins: i64) -> String {
+        if mins >= 60 && mins % 60 == 0 {
+            self.hours(mins / 60)
+        } else {
+            self.minutes(mins)
+        }
+    }
+
+    fn plural(&self, key: &str, n: i64) -> String {
+        let form = if n == 1 { "one" } else { "other" };
+
+        self.get_with(&format!("{}.{}", key, form), &n.to_string())
+    }
+}
+
+impl Default for Messages {
+    fn default() -> Self {
+        Self::new(DEFAULT_LOCALE)
+    }
+}
+
+///
+/// Negotiates the request locale from `Accept-Language`. Prefer
+/// `Messages::for_user` once the user is known so their stored
+/// preference wins.
+///
+#[async_trait]
+impl<S> FromRequestParts<S> for Messages
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let locale = match parts.headers.get(ACCEPT_LANGUAGE) {
+            Some(value) => negotiate_locale(value.to_str().unwrap_or("")),
+            None => DEFAULT_LOCALE,
+        };
+
+        Ok(Messages::new(locale))
+    }
+}
@@ -0,0 +1,240 @@
+use std::fmt;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{decode, Algorithm, Validation};
+use rusty_paseto::core::{
+    Footer, ImplicitAssertion, Paseto, PasetoAsymmetricPublicKey, Public, V4,
+};
+use serde_json::Value;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::{keys::PublicKey, AuthError, AuthResult};
+
+pub const PASETO_V4_PUBLIC_PREFIX: &str = "v4.public.";
+
+// ed25519 signatures are appended to the PASETO v4.public message
+const ED25519_SIGNATURE_LEN: usize = 64;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TokenFormat {
+    Jwt,
+    Paseto,
+}
+
+impl fmt::Display for TokenFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TokenFormat::Jwt => write!(f, "JWT"),
+            TokenFormat::Paseto => write!(f, "PASETO v4.public"),
+        }
+    }
+}
+
+///
+/// What a token claims and whether its signature checks out. Claims are
+/// decoded even if the signature is invalid so broken tokens can still
+/// be debugged.
+///
+#[derive(Clone, Debug)]
+pub struct TokenInfo {
+    pub format: TokenFormat,
+    pub header: Option<Value>,
+    pub claims: Value,
+    /// `None` if the signature is valid, otherwise why it is not
+    pub signature_error: Option<String>,
+    pub uuid: Option<String>,
+    pub token_type: Option<String>,
+    pub expires: Option<OffsetDateTime>,
+}
+
+impl TokenInfo {
+    pub fn signature_valid(&self) -> bool {
+        self.signature_error.is_none()
+    }
+
+    pub fn expired(&self) -> bool {
+        match self.expires {
+            Some(expires) => expires <= OffsetDateTime::now_utc(),
+            None => false,
+        }
+    }
+}
+
+///
+/// Decode a JWT or PASETO token and check its signature against `key`.
+/// Expiry is reported rather than enforced.
+///
+pub fn inspect_token(token: &str, key: &PublicKey) -> AuthResult<TokenInfo> {
+    let token = token.trim().trim_start_matches("Bearer").trim();
+
+    if token.starts_with(PASETO_V4_PUBLIC_PREFIX) {
+        inspect_paseto(token, key)
+    } else {
+        inspect_jwt(token, key)
+    }
+}
+
+fn decode_json(part: &str) -> AuthResult<Value> {
+    let bytes = match URL_SAFE_NO_PAD.decode(part) {
+        Ok(bytes) => bytes,
+        Err(err) => return Err(AuthError::TokenError(format!("invalid base64: {}", err))),
+    };
+
+    match serde_json::from_slice(&bytes) {
+        Ok(json) => Ok(json),
+        Err(err) => Err(AuthError::TokenError(format!("invalid json: {}", err))),
+    }
+}
+
+fn claim_string(claims: &Value, name: &str) -> Option<String> {
+    claims.get(name).and_then(|v| v.as_str()).map(|v| v.to_string())
+}
+
+pub fn inspect_jwt(token: &str, key: &PublicKey) -> AuthResult<TokenInfo> {
+    let parts: Vec<&str> = token.split('.').collect();
+
+    if parts.len() != 3 {
+        return Err(AuthError::TokenError(
+            "jwt should have 3 dot separated parts".to_string(),
+        ));
+    }
+
+    let header = decode_json(parts[0])?;
+    let claims = decode_json(parts[1])?;
+
+    // check only the signature here, expiry is reported separately
+    let mut validation = Validation::new(Algorithm::EdDSA);
+    validation.validate_exp = false;
+    validation.required_spec_claims.clear();
+
+    let signature_error = match decode::<Value>(token, &key.jwt_decoding_key(), &validation) {
+        Ok(_) => None,
+        Err(err) => Some(err.to_string()),
+    };
+
+    let expires = claims
+        .get("exp")
+        .and_then(|exp| exp.as_i64())
+        .and_then(|exp| OffsetDateTime::from_unix_timestamp(exp).ok());
+
+    Ok(TokenInfo {
+        format: TokenFormat::Jwt,
+        header: Some(header),
+        uuid: claim_string(&claims, "uuid"),
+        token_type: claim_string(&claims, "token_type"),
+        claims,
+        signature_error,
+        expires,
+    })
+}
+
+pub fn inspect_paseto(token: &str, key: &PublicKey) -> AuthResult<TokenInfo> {
+    let body = match token.strip_prefix(PASETO_V4_PUBLIC_PREFIX) {
+        Some(body) => body.split('.').next().unwrap_or(""),
+        None => {
+            return Err(AuthError::TokenError(
+                "only v4.public paseto tokens are supported".to_string(),
+            ))
+        }
+    };
+
+    let bytes = match URL_SAFE_NO_PAD.decode(body) {
+        Ok(bytes) => bytes,
+        Err(err) => return Err(AuthError::TokenError(format!("invalid base64: {}", err))),
+    };
+
+    if bytes.len() < ED25519_SIGNATURE_LEN {
+        return Err(AuthError::TokenError("paseto token is too short".to_string()));
+    }
+
+    let claims: Value =
+        match serde_json::from_slice(&bytes[..bytes.len() - ED25519_SIGNATURE_LEN]) {
+            Ok(claims) => claims,
+            Err(err) => return Err(AuthError::TokenError(format!("invalid json: {}", err))),
+        };
+
+    let public_key = key.paseto_public_key();
+
+    let signature_error = match Paseto::<V4, Public>::try_verify(
+        token,
+        &PasetoAsymmetricPublicKey::<V4, Public>::from(&public_key),
+        None::<Footer>,
+        None::<ImplicitAssertion>,
+    ) {
+        Ok(_) => None,
+        Err(err) => Some(err.to_string()),
+    };
+
+    let expires = claims
+        .get("exp")
+        .and_then(|exp| exp.as_str())
+        .and_then(|exp| OffsetDateTime::parse(exp, &Rfc3339).ok());
+
+    Ok(TokenInfo {
+        format: TokenFormat::Paseto,
+        header: None,
+        uuid: claim_string(&claims, "jti"),
+        token_type: claim_string(&claims, "type"),
+        claims,
+        signature_error,
+        expires,
+    })
+}
+
+///
+/// Format a number of seconds as a short human readable duration.
+///
+fn human_duration(secs: i64) -> String {
+    let secs = secs.abs();
+
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m {}s", secs / 60, secs % 60),
+        3600..=86399 => format!("{}h {}m", secs / 3600, (secs % 3600) / 60),
+        _ => format!("{}d {}h", secs / 86400, (secs % 86400) / 3600),
+    }
+}
+
+impl fmt::Display for TokenInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "format:     {}", self.format)?;
+
+        match &self.signature_error {
+            None => writeln!(f, "signature:  valid")?,
+            Some(err) => writeln!(f, "signature:  INVALID ({})", err)?,
+        }
+
+        writeln!(f, "type:       {}", self.token_type.as_deref().unwrap_or("-"))?;
+        writeln!(f, "uuid:       {}", self.uuid.as_deref().unwrap_or("-"))?;
+
+        match self.expires {
+            Some(expires) => {
+                let remaining = (expires - OffsetDateTime::now_utc()).whole_seconds();
+                let when = expires.format(&Rfc3339).unwrap_or_default();
+
+                if remaining > 0 {
+                    writeln!(f, "expires:    {} (in {})", when, human_duration(remaining))?
+                } else {
+                    writeln!(
+                        f,
+                        "expires:    {} (EXPIRED {} ago)",
+                        when,
+                        human_duration(remaining)
+                    )?
+                }
+            }
+            None => writeln!(f, "expires:    never")?,
+        }
+
+        if let Some(header) = &self.header {
+            writeln!(f, "header:     {}", header)?;
+        }
+
+        writeln!(f, "claims:")?;
+        write!(
+            f,
+            "{}",
+            serde_json::to_string_pretty(&self.claims).unwrap_or_default()
+        )
+    }
+}
@@ -0,0 +1,247 @@
+use axum::{
+    extract::State,
+    http::{header::CACHE_CONTROL, HeaderMap, HeaderName, StatusCode},
+    routing::post,
+    Form, Json, Router,
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::TokenConfig,
+    inspect::{inspect_token, TokenFormat, TokenInfo},
+    jwt::AppState,
+    keys::PublicKey,
+    oauth::{
+        authenticate_client, basic_credentials, check_oauth_enabled, code_hash, oauth_error,
+        OAuthClient, UNAUTHORIZED_CLIENT,
+    },
+    AuthResult, UserDb,
+};
+
+pub const CREATE_REVOKED_TOKENS_TABLE_SQL: &'static str =
+    r#"CREATE TABLE IF NOT EXISTS revoked_tokens (
+id INTEGER PRIMARY KEY AUTOINCREMENT,
+token_hash TEXT NOT NULL UNIQUE,
+expires INTEGER NOT NULL,
+created_on TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP)"#;
+
+// once a token expires it is rejected anyway, so its revocation can go
+const DELETE_EXPIRED_REVOKED_TOKENS_SQL: &'static str =
+    r#"DELETE FROM revoked_tokens WHERE expires < $1"#;
+
+const REVOKE_TOKEN_SQL: &'static str = r#"INSERT INTO revoked_tokens
+(token_hash, expires)
+VALUES($1, $2)
+ON CONFLICT(token_hash) DO NOTHING"#;
+
+const FIND_REVOKED_TOKEN_SQL: &'static str =
+    r#"SELECT token_hash FROM revoked_tokens WHERE token_hash = $1 LIMIT 1"#;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TokenActionReq {
+    pub token: String,
+    /// Accepted for compatibility, the token's own claims say what it is
+    pub token_type_hint: Option<String>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+///
+/// RFC 7662 introspection response. Inactive tokens are only ever
+/// `{"active": false}` so nothing is revealed about why.
+///
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct IntrospectResp {
+    pub active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exp: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+}
+
+fn claim(info: &TokenInfo, name: &str) -> Option<String> {
+    match info.claims.get(name).and_then(|v| v.as_str()) {
+        Some("") | None => None,
+        Some(value) => Some(value.to_string()),
+    }
+}
+
+///
+/// Decode a token if it was signed by us and has not expired. Tokens are
+/// checked the same way whether they are JWTs or PASETOs.
+///
+fn valid_token(token: &str, config: &TokenConfig, key: &PublicKey) -> Option<TokenInfo> {
+    let info = inspect_token(token, key).ok()?;
+
+    if !info.signature_valid() || info.expired() {
+        return None;
+    }
+
+    if info.format == TokenFormat::Jwt
+        && !config.issuer.is_empty()
+        && claim(&info, "iss").as_deref() != Some(config.issuer.as_str())
+    {
+        return None;
+    }
+
+    Some(info)
+}
+
+impl UserDb {
+    ///
+    /// Add a token to the revocation store until it expires. Only a hash is
+    /// kept so the store cannot leak usable tokens.
+    ///
+    pub async fn revoke_token(&self, token: &str, expires: i64) -> AuthResult<()> {
+        sqlx::query(DELETE_EXPIRED_REVOKED_TOKENS_SQL)
+            .bind(Utc::now().timestamp())
+            .execute(&self.pool)
+            .await?;
+
+        sqlx::query(REVOKE_TOKEN_SQL)
+            .bind(code_hash(token))
+            .bind(expires)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn is_token_revoked(&self, token: &str) -> AuthResult<bool> {
+        Ok(sqlx::query_scalar::<_, String>(FIND_REVOKED_TOKEN_SQL)
+            .bind(code_hash(token))
+            .fetch_optional(&self.pool)
+            .await?
+            .is_some())
+    }
+}
+
+///
+/// Whether a token is currently usable, and if so what it says.
+///
+pub async fn introspect_token(
+    user_db: &UserDb,
+    config: &TokenConfig,
+    key: &PublicKey,
+    token: &str,
+) -> AuthResult<IntrospectResp> {
+    let info = match valid_token(token, config, key) {
+        Some(info) => info,
+        None => return Ok(IntrospectResp::default()),
+    };
+
+    if user_db.is_token_revoked(token).await? {
+        return Ok(IntrospectResp::default());
+    }
+
+    Ok(IntrospectResp {
+        active: true,
+        exp: info.expires.map(|expires| expires.unix_timestamp()),
+        scope: claim(&info, "scope"),
+        client_id: claim(&info, "client_id"),
+        iss: claim(&info, "iss"),
+        sub: info.uuid,
+        token_type: info.token_type,
+    })
+}
+
+///
+/// Revoke a token for a client. Clients can revoke tokens issued to them
+/// and first party tokens presented to them, but not another client's.
+/// Tokens that are already invalid are accepted and ignored, as RFC 7009
+/// requires.
+///
+pub async fn revoke_client_token(
+    user_db: &UserDb,
+    config: &TokenConfig,
+    key: &PublicKey,
+    client: &OAuthClient,
+    token: &str,
+) -> AuthResult<()> {
+    let info = match valid_token(token, config, key) {
+        Some(info) => info,
+        None => return Ok(()),
+    };
+
+    if let Some(client_id) = claim(&info, "client_id") {
+        if client_id != client.client_id {
+            return Err(oauth_error(UNAUTHORIZED_CLIENT, "the token was issued to another client"));
+        }
+    }
+
+    let expires = match info.expires {
+        Some(expires) => expires.unix_timestamp(),
+        None => i64::MAX,
+    };
+
+    user_db.revoke_token(token, expires).await
+}
+
+///
+/// Only confidential clients, such as backend services, may use these
+/// endpoints.
+///
+async fn authenticate_service(
+    state: &AppState,
+    headers: &HeaderMap,
+    req: &TokenActionReq,
+) -> AuthResult<(OAuthClient, PublicKey)> {
+    check_oauth_enabled(state)?;
+
+    let client = authenticate_client(
+        &state.user_db,
+        basic_credentials(headers)?,
+        &req.client_id,
+        &req.client_secret,
+    )
+    .await?;
+
+    if !client.is_confidential() {
+        return Err(oauth_error(UNAUTHORIZED_CLIENT, "client credentials are required"));
+    }
+
+    Ok((client, PublicKey::from_jwk(&state.jwt_public_jwk)?))
+}
+
+async fn introspect_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(req): Form<TokenActionReq>,
+) -> AuthResult<([(HeaderName, &'static str); 1], Json<IntrospectResp>)> {
+    let (_, key) = authenticate_service(&state, &headers, &req).await?;
+
+    let resp = introspect_token(&state.user_db, &state.config.tokens, &key, &req.token).await?;
+
+    Ok(([(CACHE_CONTROL, "no-store")], Json(resp)))
+}
+
+async fn revoke_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(req): Form<TokenActionReq>,
+) -> AuthResult<StatusCode> {
+    let (client, key) = authenticate_service(&state, &headers, &req).await?;
+
+    revoke_client_token(&state.user_db, &state.config.tokens, &key, &client, &req.token).await?;
+
+    Ok(StatusCode::OK)
+}
+
+///
+/// RFC 7662 introspection and RFC 7009 revocation for services that cannot
+/// check EdDSA signatures themselves.
+///
+pub fn introspect_router() -> Router<AppState> {
+    Router::new()
+        .route("/introspect", post(introspect_handler))
+        .route("/revoke", post(revoke_handler))
+}
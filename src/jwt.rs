@@ -1,4 +1,4 @@
-use std::fmt;
+use std::{fmt, str::FromStr, sync::Arc};
 
 use axum::{
     async_trait,
@@ -6,21 +6,33 @@ use axum::{
     http::{header::AUTHORIZATION, request::Parts, StatusCode},
 };
 
-use chrono::Utc;
 use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
 
 use serde::{Deserialize, Serialize};
  
 
-use crate::{create_otp, email::Mailer, AuthError, AuthResult, User, UserDb};
+use crate::{
+    config::{AuthConfig, TokenConfig},
+    create_otp,
+    email::Mailer,
+    keys::Jwk,
+    outbox::Outbox,
+    AuthError, AuthResult, User, UserDb,
+};
 
+// defaults for TokenConfig
 pub const TOKEN_TYPE_REFRESH_TTL_HOURS: i64 = 24;
 pub const TOKEN_TYPE_ACCESS_TTL_HOURS: i64 = 1;
 pub const TOKEN_TYPE_SHORT_TIME_TTL_MINS: i64 = 10;
+// long enough for the owner of the old address to notice a change
+pub const TOKEN_TYPE_REVERT_EMAIL_CHANGE_TTL_HOURS: i64 = 24 * 7;
 
 pub const TOKEN_PASSWORDLESS: &str = "passwordless";
 pub const TOKEN_VERIFY_EMAIL: &str = "verify_email";
 pub const TOKEN_RESET_PASSWORD: &str = "reset_password";
+pub const TOKEN_CHANGE_EMAIL: &str = "change_email";
+pub const TOKEN_REVERT_EMAIL_CHANGE: &str = "revert_email_change";
+pub const TOKEN_SERVICE: &str = "service";
 
 
 
@@ -31,6 +43,10 @@ pub enum TokenType {
     Passwordless,
     ResetPassword,
     VerifyEmail,
+    ChangeEmail,
+    RevertEmailChange,
+    /// Issued to an OAuth client acting for itself rather than a user
+    Service,
 }
 
 impl fmt::Display for TokenType {
@@ -41,6 +57,27 @@ impl fmt::Display for TokenType {
             TokenType::Passwordless => write!(f, "{}", TOKEN_PASSWORDLESS),
             TokenType::ResetPassword => write!(f, "{}", TOKEN_RESET_PASSWORD),
             TokenType::VerifyEmail => write!(f, "{}", TOKEN_VERIFY_EMAIL),
+            TokenType::ChangeEmail => write!(f, "{}", TOKEN_CHANGE_EMAIL),
+            TokenType::RevertEmailChange => write!(f, "{}", TOKEN_REVERT_EMAIL_CHANGE),
+            TokenType::Service => write!(f, "{}", TOKEN_SERVICE),
+        }
+    }
+}
+
+impl FromStr for TokenType {
+    type Err = AuthError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "refresh" => Ok(TokenType::Refresh),
+            "access" => Ok(TokenType::Access),
+            TOKEN_PASSWORDLESS => Ok(TokenType::Passwordless),
+            TOKEN_RESET_PASSWORD => Ok(TokenType::ResetPassword),
+            TOKEN_VERIFY_EMAIL => Ok(TokenType::VerifyEmail),
+            TOKEN_CHANGE_EMAIL => Ok(TokenType::ChangeEmail),
+            TOKEN_REVERT_EMAIL_CHANGE => Ok(TokenType::RevertEmailChange),
+            TOKEN_SERVICE => Ok(TokenType::Service),
+            _ => Err(AuthError::TokenError(format!("unknown token type {}", s))),
         }
     }
 }
@@ -50,16 +87,42 @@ pub struct JwtClaims {
     pub uuid: String,
     pub token_type: String,
     pub otp: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub iss: String,
+    /// OAuth client the token was issued to, empty for first party tokens
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub client_id: String,
+    /// Space separated scopes granted to the client
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub scope: String,
     pub exp: usize,
 }
 
+impl JwtClaims {
+    ///
+    /// Whether the token was issued to a service rather than a user, in
+    /// which case `uuid` is the client's id.
+    ///
+    pub fn is_service(&self) -> bool {
+        self.token_type == TokenType::Service.to_string()
+    }
+
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scope.split_whitespace().any(|s| s == scope)
+    }
+}
+
 
 #[derive(Clone)]
 pub struct AppState {
+    pub config: Arc<AuthConfig>,
     pub user_db: UserDb,
     pub mailer: Mailer,
+    pub outbox: Outbox,
     pub jwt_public_key: DecodingKey,
-    pub jwt_private_key: EncodingKey
+    pub jwt_private_key: EncodingKey,
+    /// `jwt_public_key` as published to OpenID Connect clients
+    pub jwt_public_jwk: Jwk,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -93,13 +156,152 @@ where
 
         //&DecodingKey::from_secret(secret().as_bytes())
 
-        match decode::<JwtClaims>(&token, &state.jwt_public_key, &Validation::new(Algorithm::EdDSA)) {
-            Ok(data) => Ok(JwtToken(data.claims)),
+        let claims = match config_decode_jwt(token, &state.config.tokens, &state.jwt_public_key) {
+            Ok(claims) => claims,
             Err(err) => return Err((StatusCode::UNAUTHORIZED, err.to_string())),
+        };
+
+        // tokens can only be revoked through the OAuth endpoints
+        if state.config.features.oauth {
+            match state.user_db.is_token_revoked(token).await {
+                Ok(false) => (),
+                Ok(true) => {
+                    return Err((StatusCode::UNAUTHORIZED, "token has been revoked".to_string()))
+                }
+                Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+            }
+        }
+
+        // other token types are checked by the endpoints that accept them, and
+        // verify email tokens must work before the account is verified
+        if state.config.features.check_account_on_request
+            && claims.token_type == TokenType::Access.to_string()
+        {
+            let user = match state.user_db.find_user_by_uuid(&claims.uuid).await {
+                Ok(user) => user,
+                Err(err) => return Err((StatusCode::UNAUTHORIZED, err.to_string())),
+            };
+
+            if let Err(err) = user.check_can_signin() {
+                return Err((StatusCode::FORBIDDEN, err.to_string()));
+            }
+        }
+
+        // deleting a client cuts off its services straight away
+        if state.config.features.check_account_on_request && claims.is_service() {
+            if let Err(err) = state.user_db.find_oauth_client(&claims.client_id).await {
+                return Err((StatusCode::UNAUTHORIZED, err.to_string()));
+            }
+        }
+
+        Ok(JwtToken(claims))
+    }
+}
+
+///
+/// Who an access token was issued to. Use this rather than `JwtToken` on
+/// endpoints that accept both users and services, and only access or
+/// service tokens get through.
+///
+#[derive(Clone, Debug)]
+pub enum Principal {
+    User(JwtClaims),
+    Service(JwtClaims),
+}
+
+impl Principal {
+    pub fn claims(&self) -> &JwtClaims {
+        match self {
+            Principal::User(claims) | Principal::Service(claims) => claims,
         }
     }
 }
 
+#[async_trait]
+impl<S> FromRequestParts<S> for Principal
+where
+    AppState: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let JwtToken(claims) = JwtToken::from_request_parts(parts, state).await?;
+
+        if claims.is_service() {
+            Ok(Principal::Service(claims))
+        } else if claims.token_type == TokenType::Access.to_string() {
+            Ok(Principal::User(claims))
+        } else {
+            Err((
+                StatusCode::UNAUTHORIZED,
+                format!("{} tokens cannot be used here", claims.token_type),
+            ))
+        }
+    }
+}
+
+///
+/// Extracts an access token issued to a service, rejecting user tokens.
+///
+#[derive(Clone, Debug)]
+pub struct ServiceToken(pub JwtClaims);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ServiceToken
+where
+    AppState: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        match Principal::from_request_parts(parts, state).await? {
+            Principal::Service(claims) => Ok(ServiceToken(claims)),
+            Principal::User(_) => Err((
+                StatusCode::FORBIDDEN,
+                "only services can use this endpoint".to_string(),
+            )),
+        }
+    }
+}
+
+///
+/// Extracts a user's own access token, rejecting tokens issued to OAuth
+/// clients. Use this on endpoints that manage the account itself.
+///
+#[derive(Clone, Debug)]
+pub struct FirstPartyToken(pub JwtClaims);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for FirstPartyToken
+where
+    AppState: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let JwtToken(claims) = JwtToken::from_request_parts(parts, state).await?;
+
+        if claims.token_type != TokenType::Access.to_string() {
+            return Err((
+                StatusCode::UNAUTHORIZED,
+                format!("{} tokens cannot be used here", claims.token_type),
+            ));
+        }
+
+        if !claims.client_id.is_empty() {
+            return Err((
+                StatusCode::FORBIDDEN,
+                "tokens issued to OAuth clients cannot be used here".to_string(),
+            ));
+        }
+
+        Ok(FirstPartyToken(claims))
+    }
+}
+
 // #[derive(Debug, Deserialize, Serialize)]
 // pub struct JWTResp {
 //     pub token: String,
@@ -160,91 +362,128 @@ where
 //     }
 // }
 
-pub fn refresh_jwt(uuid: &str, key: &EncodingKey) -> AuthResult<String> {
-    let expiration = Utc::now()
-        .checked_add_signed(chrono::Duration::hours(TOKEN_TYPE_REFRESH_TTL_HOURS))
-        .expect("valid timestamp")
-        .timestamp();
-
-    eprint!("exp {}", expiration);
+pub fn refresh_jwt(uuid: &str, config: &TokenConfig, key: &EncodingKey) -> AuthResult<String> {
+    config_jwt(uuid, &TokenType::Refresh, "", config, key)
+}
 
-    jwt(uuid, &TokenType::Refresh, key, expiration)
+pub fn access_jwt(uuid: &str, config: &TokenConfig, key: &EncodingKey) -> AuthResult<String> {
+    config_jwt(uuid, &TokenType::Access, "", config, key)
 }
 
-pub fn access_jwt(uuid: &str, key: &EncodingKey) -> AuthResult<String> {
-    let expiration = Utc::now()
-        .checked_add_signed(chrono::Duration::hours(TOKEN_TYPE_ACCESS_TTL_HOURS))
-        .expect("valid timestamp")
-        .timestamp();
+pub fn verify_email_jwt(uuid: &str, config: &TokenConfig, key: &EncodingKey) -> AuthResult<String> {
+    config_jwt(uuid, &TokenType::VerifyEmail, "", config, key)
+}
 
-    jwt(uuid, &TokenType::Access, key, expiration)
+pub fn reset_password_jwt(
+    user: &User,
+    config: &TokenConfig,
+    key: &EncodingKey,
+) -> AuthResult<String> {
+    otp_jwt(user, &TokenType::ResetPassword, config, key)
 }
 
-pub fn verify_email_jwt(uuid: &str, key: &EncodingKey) -> AuthResult<String> {
-    short_jwt(uuid, &TokenType::VerifyEmail, key)
+pub fn passwordless_jwt(uuid: &str, config: &TokenConfig, key: &EncodingKey) -> AuthResult<String> {
+    config_jwt(uuid, &TokenType::Passwordless, "", config, key)
 }
 
-pub fn reset_password_jwt(user: &User, key: &EncodingKey) -> AuthResult<String> {
-    let expiration = Utc::now()
-        .checked_add_signed(chrono::Duration::minutes(TOKEN_TYPE_SHORT_TIME_TTL_MINS))
-        .expect("valid timestamp")
-        .timestamp();
+pub fn otp_jwt(
+    user: &User,
+    token_type: &TokenType,
+    config: &TokenConfig,
+    key: &EncodingKey,
+) -> AuthResult<String> {
+    config_jwt(&user.uuid, token_type, &create_otp(user), config, key)
+}
 
+///
+/// Issue a token using the configured issuer and the TTL for its type.
+///
+pub fn config_jwt(
+    uuid: &str,
+    token_type: &TokenType,
+    otp: &str,
+    config: &TokenConfig,
+    key: &EncodingKey,
+) -> AuthResult<String> {
     let claims: JwtClaims = JwtClaims {
-        uuid: user.uuid.to_string(),
-        token_type: TokenType::ResetPassword.to_string(),
-        otp: create_otp(user),
-        exp: expiration as usize,
+        uuid: uuid.to_string(),
+        token_type: token_type.to_string(),
+        otp: otp.to_string(),
+        iss: config.issuer.clone(),
+        client_id: String::new(),
+        scope: String::new(),
+        exp: config.expiration(token_type) as usize,
     };
 
     base_jwt(&claims, key)
 }
 
-pub fn passwordless_jwt(uuid: &str, key: &EncodingKey) -> AuthResult<String> {
-    short_jwt(uuid, &TokenType::Passwordless, key)
-}
-
-pub fn otp_jwt(
-    user: &User,
+///
+/// Issue a token to an OAuth client for a user, recording the client and
+/// the scope it was granted.
+///
+pub fn client_jwt(
+    uuid: &str,
     token_type: &TokenType,
+    client_id: &str,
+    scope: &str,
+    config: &TokenConfig,
     key: &EncodingKey,
 ) -> AuthResult<String> {
-    let expiration = Utc::now()
-        .checked_add_signed(chrono::Duration::minutes(TOKEN_TYPE_SHORT_TIME_TTL_MINS))
-        .expect("valid timestamp")
-        .timestamp();
-
-    basic_jwt(
-        &user.uuid,
-        token_type,
-        &create_otp(user),
-        key,
-        expiration,
-    )
+    let claims: JwtClaims = JwtClaims {
+        uuid: uuid.to_string(),
+        token_type: token_type.to_string(),
+        otp: String::new(),
+        iss: config.issuer.clone(),
+        client_id: client_id.to_string(),
+        scope: scope.to_string(),
+        exp: config.expiration(token_type) as usize,
+    };
+
+    base_jwt(&claims, key)
 }
 
-pub fn short_jwt(uuid: &str, token_type: &TokenType, key: &EncodingKey) -> AuthResult<String> {
-    let expiration = Utc::now()
-        .checked_add_signed(chrono::Duration::minutes(TOKEN_TYPE_SHORT_TIME_TTL_MINS))
-        .expect("valid timestamp")
-        .timestamp();
+///
+/// Decode a token, checking its signature, expiry and the configured
+/// issuer.
+///
+pub fn config_decode_jwt(
+    token: &str,
+    config: &TokenConfig,
+    key: &DecodingKey,
+) -> AuthResult<JwtClaims> {
+    let mut validation = Validation::new(Algorithm::EdDSA);
+
+    if !config.issuer.is_empty() {
+        validation.set_issuer(&[&config.issuer]);
+    }
 
-    jwt(uuid, token_type, key, expiration)
+    match decode::<JwtClaims>(token, key, &validation) {
+        Ok(data) => Ok(data.claims),
+        Err(err) => Err(AuthError::TokenError(err.to_string())),
+    }
 }
 
 pub fn jwt(
     uuid: &str,
     token_type: &TokenType,
+    config: &TokenConfig,
     key: &EncodingKey,
     expiration: i64,
 ) -> AuthResult<String> {
-    basic_jwt(uuid, token_type, "", key, expiration)
+    basic_jwt(uuid, token_type, "", config, key, expiration)
 }
 
+///
+/// Issue a token with an explicit expiry rather than the TTL for its
+/// type. The issuer still comes from the config so the token is accepted
+/// wherever one is checked.
+///
 pub fn basic_jwt(
     uuid: &str,
     token_type: &TokenType,
     otp: &str,
+    config: &TokenConfig,
     key: &EncodingKey,
     expiration: i64,
 ) -> AuthResult<String> {
@@ -252,6 +491,9 @@ pub fn basic_jwt(
         uuid: uuid.to_string(),
         token_type: token_type.to_string(),
         otp: otp.to_string(),
+        iss: config.issuer.clone(),
+        client_id: String::new(),
+        scope: String::new(),
         exp: expiration as usize,
     };
 
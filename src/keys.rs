@@ -0,0 +1,367 @@
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::Path,
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ed25519_dalek::{
+    pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePrivateKey, EncodePublicKey, LineEnding},
+    SigningKey, VerifyingKey,
+};
+use jsonwebtoken::{DecodingKey, EncodingKey};
+use rand::rngs::OsRng;
+use rusty_paseto::core::Key;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{AuthError, AuthResult};
+
+pub const PEM_PREFIX: &str = "-----BEGIN";
+
+fn crypto_error(error: impl ToString) -> AuthError {
+    AuthError::CryptographyError(error.to_string())
+}
+
+fn read_key_file(path: &Path) -> AuthResult<String> {
+    match fs::read_to_string(path) {
+        Ok(material) => Ok(material),
+        Err(err) => Err(crypto_error(format!(
+            "could not read key file {}: {}",
+            path.display(),
+            err
+        ))),
+    }
+}
+
+///
+/// Read key material from the env var `name`, or from the file named by
+/// `{name}_FILE` so keys can be mounted as secrets.
+///
+fn read_key_env(name: &str) -> AuthResult<String> {
+    if let Ok(material) = env::var(name) {
+        return Ok(material);
+    }
+
+    match env::var(format!("{}_FILE", name)) {
+        Ok(path) => read_key_file(Path::new(&path)),
+        Err(_) => Err(crypto_error(format!("neither {} nor {}_FILE is set", name, name))),
+    }
+}
+
+fn write_key_file(
+    path: &Path,
+    contents: &str,
+    private: bool,
+    overwrite: bool,
+) -> std::io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true);
+
+    if overwrite {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+        let mode = if private { 0o600 } else { 0o644 };
+        options.mode(mode);
+
+        let mut file = options.open(path)?;
+
+        // the mode only applies to new files, so fix up replaced ones
+        file.set_permissions(fs::Permissions::from_mode(mode))?;
+        file.write_all(contents.as_bytes())
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = private;
+        options.open(path)?.write_all(contents.as_bytes())
+    }
+}
+
+fn decode_hex(material: &str) -> AuthResult<Vec<u8>> {
+    hex::decode(material.trim()).map_err(crypto_error)
+}
+
+///
+/// An Ed25519 signing key usable for both JWT (EdDSA) and PASETO
+/// (v4.public) tokens.
+///
+#[derive(Clone, Debug)]
+pub struct KeyPair {
+    signing_key: SigningKey,
+}
+
+impl KeyPair {
+    pub fn generate() -> Self {
+        Self {
+            signing_key: SigningKey::generate(&mut OsRng),
+        }
+    }
+
+    ///
+    /// Parse a PKCS#8 PEM private key, or hex encoding of either the 32
+    /// byte secret or the 64 byte secret + public key used by PASETO.
+    ///
+    pub fn parse(material: &str) -> AuthResult<Self> {
+        let material = material.trim();
+
+        if material.starts_with(PEM_PREFIX) {
+            Self::from_pem(material)
+        } else {
+            Self::from_hex(material)
+        }
+    }
+
+    pub fn from_pem(pem: &str) -> AuthResult<Self> {
+        Ok(Self {
+            signing_key: SigningKey::from_pkcs8_pem(pem).map_err(crypto_error)?,
+        })
+    }
+
+    pub fn from_hex(material: &str) -> AuthResult<Self> {
+        let bytes = decode_hex(material)?;
+
+        let signing_key = match bytes.len() {
+            32 => SigningKey::from_bytes(&bytes[..32].try_into().map_err(crypto_error)?),
+            // from_keypair_bytes checks the public half matches the secret
+            64 => SigningKey::from_keypair_bytes(&bytes[..].try_into().map_err(crypto_error)?)
+                .map_err(crypto_error)?,
+            n => {
+                return Err(crypto_error(format!(
+                    "expected 32 or 64 hex encoded bytes but got {}",
+                    n
+                )))
+            }
+        };
+
+        Ok(Self { signing_key })
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> AuthResult<Self> {
+        Self::parse(&read_key_file(path.as_ref())?)
+    }
+
+    pub fn from_env(name: &str) -> AuthResult<Self> {
+        Self::parse(&read_key_env(name)?)
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey {
+            verifying_key: self.signing_key.verifying_key(),
+        }
+    }
+
+    pub fn private_pem(&self) -> AuthResult<String> {
+        Ok(self
+            .signing_key
+            .to_pkcs8_pem(LineEnding::LF)
+            .map_err(crypto_error)?
+            .to_string())
+    }
+
+    ///
+    /// Hex of the secret and public key concatenated, which is the form
+    /// PASETO expects.
+    ///
+    pub fn private_hex(&self) -> String {
+        hex::encode(self.signing_key.to_keypair_bytes())
+    }
+
+    pub fn jwt_encoding_key(&self) -> AuthResult<EncodingKey> {
+        let der = self.signing_key.to_pkcs8_der().map_err(crypto_error)?;
+
+        Ok(EncodingKey::from_ed_der(der.as_bytes()))
+    }
+
+    pub fn jwt_decoding_key(&self) -> DecodingKey {
+        self.public_key().jwt_decoding_key()
+    }
+
+    ///
+    /// Key material for `PasetoAsymmetricPrivateKey::<V4, Public>::try_from`.
+    ///
+    pub fn paseto_private_key(&self) -> Key<64> {
+        Key::<64>::from(&self.signing_key.to_keypair_bytes())
+    }
+
+    ///
+    /// Write `{name}.pem`, `{name}.pub.pem`, `{name}.hex` and
+    /// `{name}.pub.hex` to `dir`. Private keys are only readable by their
+    /// owner. Existing files are left alone unless `overwrite` is set.
+    ///
+    pub fn write_files(
+        &self,
+        dir: impl AsRef<Path>,
+        name: &str,
+        overwrite: bool,
+    ) -> AuthResult<()> {
+        let dir = dir.as_ref();
+        let public_key = self.public_key();
+
+        let files = [
+            (format!("{}.pem", name), self.private_pem()?, true),
+            (format!("{}.pub.pem", name), public_key.pem()?, false),
+            (format!("{}.hex", name), format!("{}\n", self.private_hex()), true),
+            (format!("{}.pub.hex", name), format!("{}\n", public_key.hex()), false),
+        ];
+
+        // check them all first so a clash does not leave a mismatched set
+        if !overwrite {
+            for (file, _, _) in &files {
+                let path = dir.join(file);
+
+                if path.exists() {
+                    return Err(crypto_error(format!(
+                        "{} already exists, use --force to replace it",
+                        path.display()
+                    )));
+                }
+            }
+        }
+
+        for (file, contents, private) in files {
+            let path = dir.join(file);
+
+            if let Err(err) = write_key_file(&path, &contents, private, overwrite) {
+                return Err(crypto_error(format!(
+                    "could not write {}: {}",
+                    path.display(),
+                    err
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+///
+/// An Ed25519 public key in JSON Web Key form (RFC 8037), as published
+/// for clients verifying tokens.
+///
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Jwk {
+    pub kty: String,
+    pub crv: String,
+    pub x: String,
+    pub kid: String,
+    pub alg: String,
+    #[serde(rename = "use")]
+    pub key_use: String,
+}
+
+///
+/// An Ed25519 public key for verifying JWT and PASETO tokens.
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PublicKey {
+    verifying_key: VerifyingKey,
+}
+
+impl PublicKey {
+    ///
+    /// Parse an SPKI PEM public key or 32 byte hex public key.
+    ///
+    pub fn parse(material: &str) -> AuthResult<Self> {
+        let material = material.trim();
+
+        let verifying_key = if material.starts_with(PEM_PREFIX) {
+            VerifyingKey::from_public_key_pem(material).map_err(crypto_error)?
+        } else {
+            let bytes: [u8; 32] = match decode_hex(material)?.try_into() {
+                Ok(bytes) => bytes,
+                Err(bytes) => {
+                    return Err(crypto_error(format!(
+                        "expected 32 hex encoded bytes but got {}",
+                        bytes.len()
+                    )))
+                }
+            };
+
+            VerifyingKey::from_bytes(&bytes).map_err(crypto_error)?
+        };
+
+        Ok(Self { verifying_key })
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> AuthResult<Self> {
+        Self::parse(&read_key_file(path.as_ref())?)
+    }
+
+    pub fn from_env(name: &str) -> AuthResult<Self> {
+        Self::parse(&read_key_env(name)?)
+    }
+
+    pub fn from_jwk(jwk: &Jwk) -> AuthResult<Self> {
+        let bytes = URL_SAFE_NO_PAD.decode(&jwk.x).map_err(crypto_error)?;
+
+        let bytes: [u8; 32] = match bytes.try_into() {
+            Ok(bytes) => bytes,
+            Err(bytes) => {
+                return Err(crypto_error(format!(
+                    "expected a 32 byte key but got {}",
+                    bytes.len()
+                )))
+            }
+        };
+
+        Ok(Self {
+            verifying_key: VerifyingKey::from_bytes(&bytes).map_err(crypto_error)?,
+        })
+    }
+
+    pub fn verifying_key(&self) -> &VerifyingKey {
+        &self.verifying_key
+    }
+
+    pub fn pem(&self) -> AuthResult<String> {
+        self.verifying_key
+            .to_public_key_pem(LineEnding::LF)
+            .map_err(crypto_error)
+    }
+
+    pub fn hex(&self) -> String {
+        hex::encode(self.verifying_key.to_bytes())
+    }
+
+    pub fn jwt_decoding_key(&self) -> DecodingKey {
+        // EdDSA decoding keys are the raw public key bytes
+        DecodingKey::from_ed_der(&self.verifying_key.to_bytes())
+    }
+
+    ///
+    /// Key material for `PasetoAsymmetricPublicKey::<V4, Public>::from`.
+    ///
+    pub fn paseto_public_key(&self) -> Key<32> {
+        Key::<32>::from(&self.verifying_key.to_bytes())
+    }
+
+    ///
+    /// The key as a JWK. Its id is the RFC 7638 thumbprint so it changes
+    /// when the key is rotated.
+    ///
+    pub fn jwk(&self) -> Jwk {
+        let x = URL_SAFE_NO_PAD.encode(self.verifying_key.to_bytes());
+
+        // members in lexicographic order with no whitespace, as the
+        // thumbprint requires
+        let thumbprint = format!(r#"{{"crv":"Ed25519","kty":"OKP","x":"{}"}}"#, x);
+
+        Jwk {
+            kty: "OKP".to_string(),
+            crv: "Ed25519".to_string(),
+            kid: URL_SAFE_NO_PAD.encode(Sha256::digest(thumbprint.as_bytes())),
+            x,
+            alg: "EdDSA".to_string(),
+            key_use: "sig".to_string(),
+        }
+    }
+}
@@ -0,0 +1,335 @@
+use std::collections::HashMap;
+
+use axum::async_trait;
+use ldap3::{ldap_escape, Ldap, LdapConnAsync, LdapConnSettings, LdapError, Scope, SearchEntry};
+
+use crate::{
+    config::{LdapAttributes, LdapConfig},
+    oauth::random_token,
+    AuthError, AuthResult, Credentials, User, UserDb,
+};
+
+// LDAP result code for a failed bind
+const LDAP_INVALID_CREDENTIALS: u32 = 49;
+
+pub const CREATE_DIRECTORY_USERS_TABLE_SQL: &'static str =
+    r#"CREATE TABLE IF NOT EXISTS directory_users (
+id INTEGER PRIMARY KEY AUTOINCREMENT,
+user_uuid TEXT NOT NULL UNIQUE,
+dn TEXT NOT NULL UNIQUE,
+created_on TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP)"#;
+
+const FIND_DIRECTORY_USER_SQL: &'static str =
+    r#"SELECT user_uuid FROM directory_users WHERE directory_users.dn = $1 LIMIT 1"#;
+
+const FIND_DIRECTORY_DN_SQL: &'static str =
+    r#"SELECT dn FROM directory_users WHERE directory_users.user_uuid = $1 LIMIT 1"#;
+
+const LINK_DIRECTORY_USER_SQL: &'static str = r#"INSERT INTO directory_users
+(user_uuid, dn)
+VALUES($1, $2)"#;
+
+///
+/// A directory user's details, mapped from their entry's attributes.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectoryUser {
+    /// Distinguished name, which identifies the user in the directory
+    pub dn: String,
+    pub username: String,
+    pub email: String,
+    pub first_name: String,
+    pub last_name: String,
+}
+
+impl LdapAttributes {
+    pub fn user(
+        &self,
+        dn: &str,
+        attrs: &HashMap<String, Vec<String>>,
+    ) -> AuthResult<DirectoryUser> {
+        let attr = |name: &str| match attrs.get(name).and_then(|values| values.first()) {
+            Some(value) => value.trim().to_string(),
+            None => String::new(),
+        };
+
+        let username = attr(&self.username);
+
+        if username.is_empty() {
+            return Err(AuthError::UserDoesNotExistError(format!(
+                "{} has no {} attribute",
+                dn, self.username
+            )));
+        }
+
+        Ok(DirectoryUser {
+            dn: dn.to_string(),
+            email: attr(&self.email),
+            first_name: attr(&self.first_name),
+            last_name: attr(&self.last_name),
+            username,
+        })
+    }
+}
+
+///
+/// Somewhere passwords can be checked other than the local hashes.
+///
+#[async_trait]
+pub trait Directory: Send + Sync {
+    ///
+    /// Check the password of a directory user. Returns `None` if the
+    /// directory has no such user so they can sign in locally instead,
+    /// and `DirectoryError` if it could not be asked.
+    ///
+    async fn authenticate(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> AuthResult<Option<DirectoryUser>>;
+}
+
+fn ldap_error(error: LdapError) -> AuthError {
+    AuthError::DirectoryError(error.to_string())
+}
+
+///
+/// Binds to an LDAP or Active Directory server.
+///
+#[derive(Debug, Clone)]
+pub struct LdapDirectory {
+    config: LdapConfig,
+}
+
+impl LdapDirectory {
+    pub fn new(config: &LdapConfig) -> Self {
+        Self {
+            config: config.clone(),
+        }
+    }
+
+    async fn connect(&self) -> AuthResult<Ldap> {
+        let settings = LdapConnSettings::new().set_starttls(self.config.starttls);
+
+        let (conn, ldap) = LdapConnAsync::with_settings(settings, &self.config.url)
+            .await
+            .map_err(ldap_error)?;
+
+        ldap3::drive!(conn);
+
+        Ok(ldap)
+    }
+
+    async fn find_user(&self, ldap: &mut Ldap, username: &str) -> AuthResult<Option<SearchEntry>> {
+        if !self.config.bind_dn.is_empty() {
+            ldap.simple_bind(&self.config.bind_dn, &self.config.bind_password)
+                .await
+                .and_then(|result| result.success())
+                .map_err(ldap_error)?;
+        }
+
+        let filter = self.config.user_filter.replace("{username}", &ldap_escape(username));
+        let attributes = &self.config.attributes;
+
+        let (entries, _) = ldap
+            .search(
+                &self.config.base_dn,
+                Scope::Subtree,
+                &filter,
+                vec![
+                    attributes.username.as_str(),
+                    attributes.email.as_str(),
+                    attributes.first_name.as_str(),
+                    attributes.last_name.as_str(),
+                ],
+            )
+            .await
+            .and_then(|result| result.success())
+            .map_err(ldap_error)?;
+
+        match entries.len() {
+            0 => Ok(None),
+            1 => Ok(entries.into_iter().next().map(SearchEntry::construct)),
+            _ => Err(AuthError::DirectoryError(format!("more than one entry for {}", username))),
+        }
+    }
+}
+
+#[async_trait]
+impl Directory for LdapDirectory {
+    async fn authenticate(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> AuthResult<Option<DirectoryUser>> {
+        let mut ldap = self.connect().await?;
+
+        let entry = match self.find_user(&mut ldap, username).await? {
+            Some(entry) => entry,
+            None => {
+                let _ = ldap.unbind().await;
+                return Ok(None);
+            }
+        };
+
+        let result = ldap.simple_bind(&entry.dn, password).await.map_err(ldap_error)?;
+        let _ = ldap.unbind().await;
+
+        if result.rc == LDAP_INVALID_CREDENTIALS {
+            return Err(AuthError::PasswordError(
+                "username or password is incorrect".to_string(),
+            ));
+        }
+
+        result.success().map_err(ldap_error)?;
+
+        Ok(Some(self.config.attributes.user(&entry.dn, &entry.attrs)?))
+    }
+}
+
+impl UserDb {
+    pub async fn create_directory_users_table(&self) -> AuthResult<()> {
+        sqlx::query(CREATE_DIRECTORY_USERS_TABLE_SQL)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn find_directory_user(&self, dn: &str) -> AuthResult<Option<String>> {
+        Ok(sqlx::query_scalar::<_, String>(FIND_DIRECTORY_USER_SQL)
+            .bind(dn)
+            .fetch_optional(&self.pool)
+            .await?)
+    }
+
+    ///
+    /// The directory entry a user was provisioned from, `None` for local
+    /// accounts.
+    ///
+    pub async fn find_directory_dn(&self, user_uuid: &str) -> AuthResult<Option<String>> {
+        Ok(sqlx::query_scalar::<_, String>(FIND_DIRECTORY_DN_SQL)
+            .bind(user_uuid)
+            .fetch_optional(&self.pool)
+            .await?)
+    }
+
+    ///
+    /// Whether the user's password lives in the configured directory, so
+    /// it must not be checked or reset locally.
+    ///
+    pub async fn is_directory_user(&self, user_uuid: &str) -> AuthResult<bool> {
+        if self.directory().is_none() {
+            return Ok(false);
+        }
+
+        Ok(self.find_directory_dn(user_uuid).await?.is_some())
+    }
+
+    ///
+    /// Create the local account for a directory user signing in for the
+    /// first time. Their password stays in the directory, so the local one
+    /// is random and never shown to anyone.
+    ///
+    pub async fn provision_directory_user(&self, entry: &DirectoryUser) -> AuthResult<User> {
+        // random tokens are long but may lack some character classes a
+        // policy requires
+        let password = format!("{}aA1!", random_token());
+
+        let user = self
+            .create_user(&Credentials {
+                username: entry.username.clone(),
+                password,
+                email: Some(entry.email.clone()).filter(|email| !email.is_empty()),
+                first_name: Some(entry.first_name.clone()),
+                last_name: Some(entry.last_name.clone()),
+                callback_url: None,
+                url: None,
+                locale: None,
+            })
+            .await?;
+
+        // the directory vouches for its own email addresses
+        self.user_verified(&user.uuid).await?;
+
+        sqlx::query(LINK_DIRECTORY_USER_SQL)
+            .bind(&user.uuid)
+            .bind(&entry.dn)
+            .execute(&self.pool)
+            .await?;
+
+        self.find_user_by_uuid(&user.uuid).await
+    }
+}
+
+///
+/// Sign in with directory credentials if a directory is configured and
+/// knows the user, returning `None` otherwise so the local password is
+/// checked instead. Accounts are created on first sign in and their
+/// names kept in step with the directory after that. Local accounts are
+/// never taken over by a directory user with the same username, and
+/// skip the directory entirely. Accounts provisioned from the directory
+/// are only ever checked against it: they can't sign in while it is
+/// down, and are disabled once they are removed from it.
+///
+pub async fn directory_sign_in(
+    user_db: &UserDb,
+    username: &str,
+    password: &str,
+) -> AuthResult<Option<User>> {
+    let directory = match user_db.directory() {
+        Some(directory) => directory,
+        None => return Ok(None),
+    };
+
+    // LDAP treats a bind with no password as anonymous, which succeeds
+    if password.is_empty() {
+        return Err(AuthError::PasswordError("password is required".to_string()));
+    }
+
+    let linked = match user_db.find_user_by_id(username).await {
+        Ok(user) if user_db.is_directory_user(&user.uuid).await? => Some(user),
+        Ok(_) => return Ok(None),
+        Err(_) => None,
+    };
+
+    // linked accounts may be signing in with their email, which the
+    // directory's filter need not match
+    let username = linked.as_ref().map_or(username, |user| user.username.as_str());
+
+    let entry = match (directory.authenticate(username, password).await, &linked) {
+        (Ok(Some(entry)), _) => entry,
+        (Ok(None), None) => return Ok(None),
+        (Ok(None), Some(user)) => {
+            user_db.set_can_signin(&user.uuid, false).await?;
+
+            return Err(AuthError::PasswordError(
+                "username or password is incorrect".to_string(),
+            ));
+        }
+        (Err(AuthError::DirectoryError(err)), None) => {
+            eprintln!("directory unavailable, checking the local password: {}", err);
+            return Ok(None);
+        }
+        (Err(err), _) => return Err(err),
+    };
+
+    let user = match user_db.find_directory_user(&entry.dn).await? {
+        Some(uuid) => {
+            let user = user_db.find_user_by_uuid(&uuid).await?;
+
+            if user.first_name != entry.first_name || user.last_name != entry.last_name {
+                user_db
+                    .update_user(&user.uuid, &user.username, &entry.first_name, &entry.last_name)
+                    .await?;
+            }
+
+            user_db.find_user_by_uuid(&uuid).await?
+        }
+        None => user_db.provision_directory_user(&entry).await?,
+    };
+
+    user.check_can_signin()?;
+
+    Ok(Some(user))
+}
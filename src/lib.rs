@@ -1,57 +1,197 @@
-use std::fmt::{self, Display};
+use std::{
+    fmt::{self, Display},
+    sync::Arc,
+};
 
 use axum::{
-    http::StatusCode,
+    http::{header::CONTENT_TYPE, StatusCode},
     response::{IntoResponse, Response},
+    Json,
 };
 
-use password_auth::{generate_hash, verify_password, VerifyError};
+use password_auth::{generate_hash, VerifyError};
 use rusty_paseto::generic::{GenericBuilderError, PasetoClaimError};
-use sqlx::{FromRow, Pool, Sqlite};
+use sqlx::{FromRow, Pool, Sqlite, SqliteConnection};
 use tokio::task::JoinError;
 use uuid::Uuid;
 
 use axum_login::AuthUser;
+use email::MailerError;
+use hashing::{verify_hash, Argon2Hasher};
+use ldap::Directory;
+use password_policy::{user_inputs, PasswordPolicy, PasswordRule};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 
+pub mod breach;
+pub mod config;
+pub mod device;
 pub mod email;
+pub mod email_change;
+pub mod hashing;
+pub mod i18n;
+pub mod inspect;
+pub mod introspect;
 pub mod jwt;
+pub mod keys;
+pub mod ldap;
+pub mod oauth;
+pub mod oidc;
+pub mod outbox;
+pub mod password;
+pub mod password_policy;
 pub mod paseto;
+pub mod saml;
+pub mod scim;
+pub mod signin;
+pub mod social;
+pub mod verify;
 mod tests;
 
 //const USER_SQL: &'static str = "SELECT id, uuid, first_name, last_name, username, email, password, strftime('%s', updated_on) as updated_on FROM users";
 
 const FIND_USER_BY_ID_SQL: &'static str = r#"SELECT
-id, uuid, first_name, last_name, username, email, password, strftime('%s', updated_on) as updated_on, can_signin, email_verified
+id, uuid, first_name, last_name, username, email, password, strftime('%s', updated_on) as updated_on, can_signin, email_verified, passwordless_only, locale
 FROM users
 WHERE users.uuid = $1 OR users.username = $1 OR users.email = $1 LIMIT 1"#;
 
 const FIND_USER_BY_UUID_SQL: &'static str = r#"SELECT
-id, uuid, first_name, last_name, username, email, password, strftime('%s', updated_on) as updated_on, can_signin, email_verified
+id, uuid, first_name, last_name, username, email, password, strftime('%s', updated_on) as updated_on, can_signin, email_verified, passwordless_only, locale
 FROM users
 WHERE users.uuid = $1 LIMIT 1"#;
 
 const FIND_USER_BY_USERNAME_SQL: &'static str = r#"SELECT
-id, uuid, first_name, last_name, username, email, password, strftime('%s', updated_on) as updated_on, can_signin, email_verified
+id, uuid, first_name, last_name, username, email, password, strftime('%s', updated_on) as updated_on, can_signin, email_verified, passwordless_only, locale
 FROM users
 WHERE users.username = $1 LIMIT 1"#;
 
 const FIND_USER_BY_EMAIL_SQL: &'static str = r#"SELECT
-id, uuid, first_name, last_name, username, email, password, strftime('%s', updated_on) as updated_on, can_signin, email_verified
+id, uuid, first_name, last_name, username, email, password, strftime('%s', updated_on) as updated_on, can_signin, email_verified, passwordless_only, locale
 FROM users
 WHERE users.email = $1 LIMIT 1"#;
 
 const EMAIL_VERIFIED_SQL: &'static str =
     r#"UPDATE users SET email_verified = 1 WHERE users.uuid = $1"#;
 
-const UPDATE_PASSWORD_SQL: &'static str = r#"UPDATE users SET password = $2 WHERE users.uuid = $1"#;
+// bumping updated_on invalidates reset tokens, whose otp hashes it. It
+// always moves forward at least a second since it is read in seconds.
+const UPDATE_PASSWORD_SQL: &'static str = r#"UPDATE users
+SET password = $2, updated_on = MAX(CURRENT_TIMESTAMP, datetime(updated_on, '+1 second'))
+WHERE users.uuid = $1"#;
+
+const REHASH_PASSWORD_SQL: &'static str =
+    r#"UPDATE users SET password = $2 WHERE users.uuid = $1"#;
 
-const UPDATE_USER_SQL: &'static str = r#"UPDATE users 
-SET username = $2, email = $3, first_name = $4, last_name = $5 
+const UPDATE_USER_SQL: &'static str = r#"UPDATE users
+SET username = $2, first_name = $3, last_name = $4
 WHERE users.uuid = $1"#;
 
-const CREATE_USER_SQL: &'static str =
-    "INSERT INTO users (uuid, username, email, password) VALUES($1, $2, $3, $4)";
+const PASSWORDLESS_ONLY_SQL: &'static str =
+    r#"UPDATE users SET passwordless_only = $2 WHERE users.uuid = $1"#;
+
+const UPDATE_LOCALE_SQL: &'static str = r#"UPDATE users SET locale = $2 WHERE users.uuid = $1"#;
+
+const LIST_USERS_SQL: &'static str = r#"SELECT
+id, uuid, first_name, last_name, username, email, password, strftime('%s', updated_on) as updated_on, can_signin, email_verified, passwordless_only, locale
+FROM users
+ORDER BY users.username
+LIMIT $1 OFFSET $2"#;
+
+const CAN_SIGNIN_SQL: &'static str = r#"UPDATE users SET can_signin = $2 WHERE users.uuid = $1"#;
+
+const DELETE_USER_SQL: &'static str = r#"DELETE FROM users WHERE users.uuid = $1"#;
+
+const USERS_COLUMNS_SQL: &'static str = r#"SELECT name FROM pragma_table_info('users')"#;
+
+// columns added to users after the original schema, with defaults so
+// existing rows stay valid
+const USERS_COLUMN_MIGRATIONS: [(&'static str, &'static str); 2] = [
+    (
+        "locale",
+        r#"ALTER TABLE users ADD COLUMN locale TEXT NOT NULL DEFAULT 'en'"#,
+    ),
+    (
+        "passwordless_only",
+        r#"ALTER TABLE users ADD COLUMN passwordless_only BOOLEAN NOT NULL DEFAULT 0"#,
+    ),
+];
+
+pub const CREATE_PASSWORD_HISTORY_TABLE_SQL: &'static str =
+    r#"CREATE TABLE IF NOT EXISTS password_history (
+id INTEGER PRIMARY KEY AUTOINCREMENT,
+user_uuid TEXT NOT NULL,
+password TEXT NOT NULL,
+created_on TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP)"#;
+
+const INSERT_PASSWORD_HISTORY_SQL: &'static str =
+    r#"INSERT INTO password_history (user_uuid, password) VALUES($1, $2)"#;
+
+const PASSWORD_HISTORY_SQL: &'static str = r#"SELECT password FROM password_history
+WHERE user_uuid = $1
+ORDER BY id DESC
+LIMIT $2"#;
+
+const PRUNE_PASSWORD_HISTORY_SQL: &'static str = r#"DELETE FROM password_history
+WHERE user_uuid = $1 AND id NOT IN
+(SELECT id FROM password_history WHERE user_uuid = $1 ORDER BY id DESC LIMIT $2)"#;
+
+const TABLES_SQL: &'static str = r#"SELECT name FROM sqlite_master WHERE type = 'table'"#;
+
+// tables with rows for a user, which are deleted along with them. Each
+// is only there once its feature's tables have been created.
+const USER_TABLES: [&'static str; 11] = [
+    "password_history",
+    "email_changes",
+    "oauth_codes",
+    "oauth_consents",
+    "oauth_device_codes",
+    "social_identities",
+    "social_states",
+    "directory_users",
+    "saml_users",
+    "scim_users",
+    "scim_group_members",
+];
+
+pub const EMAIL_CHANGE_STATUS_PENDING: &str = "pending";
+pub const EMAIL_CHANGE_STATUS_CONFIRMED: &str = "confirmed";
+pub const EMAIL_CHANGE_STATUS_CANCELLED: &str = "cancelled";
+pub const EMAIL_CHANGE_STATUS_REVERTED: &str = "reverted";
+
+pub const CREATE_EMAIL_CHANGES_TABLE_SQL: &'static str =
+    r#"CREATE TABLE IF NOT EXISTS email_changes (
+id INTEGER PRIMARY KEY AUTOINCREMENT,
+uuid TEXT NOT NULL UNIQUE,
+user_uuid TEXT NOT NULL,
+old_email TEXT NOT NULL,
+new_email TEXT NOT NULL,
+status TEXT NOT NULL DEFAULT 'pending',
+created_on TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP)"#;
+
+const CANCEL_PENDING_EMAIL_CHANGES_SQL: &'static str = r#"UPDATE email_changes
+SET status = 'cancelled'
+WHERE user_uuid = $1 AND status = 'pending'"#;
+
+const CREATE_EMAIL_CHANGE_SQL: &'static str =
+    r#"INSERT INTO email_changes (uuid, user_uuid, old_email, new_email) VALUES($1, $2, $3, $4)"#;
+
+const FIND_EMAIL_CHANGE_SQL: &'static str = r#"SELECT
+uuid, user_uuid, old_email, new_email, status
+FROM email_changes
+WHERE email_changes.uuid = $1 LIMIT 1"#;
+
+const EMAIL_CHANGE_STATUS_SQL: &'static str =
+    r#"UPDATE email_changes SET status = $3 WHERE uuid = $1 AND status = $2"#;
+
+// only switch if the account still has the address the change was made
+// from, so stale changes cannot clobber a newer one
+const SWITCH_EMAIL_SQL: &'static str = r#"UPDATE users
+SET email = $3, email_verified = 1
+WHERE users.uuid = $1 AND users.email = $2"#;
+
+const CREATE_USER_SQL: &'static str = r#"INSERT INTO users
+(uuid, username, email, first_name, last_name, password, locale)
+VALUES($1, $2, $3, $4, $5, $6, $7)"#;
 
 #[derive(Debug, Clone)]
 pub enum AuthError {
@@ -62,6 +202,21 @@ pub enum AuthError {
     CryptographyError(String),
     TokenError(String),
     PasswordError(String),
+    PasswordPolicyError(Vec<PasswordRule>),
+    PasswordlessOnlyError(String),
+    AccountDisabledError(String),
+    EmailNotVerifiedError(String),
+    MailerError(String),
+    ConfigError(String),
+    FeatureDisabledError(String),
+    /// An OAuth error code and its description
+    OAuthError(String, String),
+    SocialLoginError(String),
+    SamlError(String),
+    /// The directory could not be reached or searched
+    DirectoryError(String),
+    /// A SCIM HTTP status, scimType and detail
+    ScimError(u16, String, String),
 }
 
 impl std::error::Error for AuthError {}
@@ -96,6 +251,12 @@ impl From<GenericBuilderError> for AuthError {
     }
 }
 
+impl From<MailerError> for AuthError {
+    fn from(error: MailerError) -> Self {
+        AuthError::MailerError(error.to_string())
+    }
+}
+
 impl From<sqlx::Error> for AuthError {
     fn from(error: sqlx::Error) -> Self {
         AuthError::DatabaseError(error.to_string())
@@ -118,13 +279,82 @@ impl Display for AuthError {
             AuthError::CryptographyError(error) => write!(f, "{}", error),
             AuthError::TokenError(error) => write!(f, "{}", error),
             AuthError::PasswordError(error) => write!(f, "{}", error),
+            AuthError::PasswordPolicyError(rules) => write!(
+                f,
+                "password {}",
+                rules
+                    .iter()
+                    .map(|rule| rule.to_string())
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+            AuthError::PasswordlessOnlyError(user) => {
+                write!(f, "account for {} only allows passwordless sign in", user)
+            }
+            AuthError::AccountDisabledError(user) => write!(f, "account for {} is disabled", user),
+            AuthError::EmailNotVerifiedError(user) => {
+                write!(f, "email address for {} has not been verified", user)
+            }
+            AuthError::MailerError(error) => write!(f, "{}", error),
+            AuthError::ConfigError(error) => write!(f, "invalid config: {}", error),
+            AuthError::FeatureDisabledError(feature) => write!(f, "{} is disabled", feature),
+            AuthError::OAuthError(error, description) => write!(f, "{}: {}", error, description),
+            AuthError::SocialLoginError(error) => write!(f, "social sign in failed: {}", error),
+            AuthError::SamlError(error) => write!(f, "SAML sign in failed: {}", error),
+            AuthError::DirectoryError(error) => write!(f, "directory error: {}", error),
+            AuthError::ScimError(_, _, detail) => write!(f, "{}", detail),
         }
     }
 }
 
 impl IntoResponse for AuthError {
     fn into_response(self) -> Response {
-        (StatusCode::BAD_REQUEST, self.to_string()).into_response()
+        match &self {
+            // list each failed rule so clients can show them next to the
+            // password field
+            AuthError::PasswordPolicyError(rules) => (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": self.to_string(), "rules": rules })),
+            )
+                .into_response(),
+            AuthError::AccountDisabledError(_) | AuthError::EmailNotVerifiedError(_) => {
+                (StatusCode::FORBIDDEN, self.to_string()).into_response()
+            }
+            // OAuth clients expect errors in the RFC 6749 format
+            AuthError::OAuthError(error, description) => {
+                let status = match error.as_str() {
+                    oauth::INVALID_CLIENT => StatusCode::UNAUTHORIZED,
+                    oidc::INSUFFICIENT_SCOPE => StatusCode::FORBIDDEN,
+                    _ => StatusCode::BAD_REQUEST,
+                };
+
+                (
+                    status,
+                    Json(json!({ "error": error, "error_description": description })),
+                )
+                    .into_response()
+            }
+            // SCIM clients expect errors in the RFC 7644 format
+            AuthError::ScimError(status, scim_type, detail) => {
+                let mut body = json!({
+                    "schemas": [scim::SCIM_ERROR_SCHEMA],
+                    "status": status.to_string(),
+                    "detail": detail,
+                });
+
+                if !scim_type.is_empty() {
+                    body["scimType"] = json!(scim_type);
+                }
+
+                (
+                    StatusCode::from_u16(*status).unwrap_or(StatusCode::BAD_REQUEST),
+                    [(CONTENT_TYPE, scim::SCIM_CONTENT_TYPE)],
+                    Json(body),
+                )
+                    .into_response()
+            }
+            _ => (StatusCode::BAD_REQUEST, self.to_string()).into_response(),
+        }
     }
 }
 
@@ -155,6 +385,8 @@ pub struct User {
     pub can_signin: bool,
     #[serde(skip_serializing)]
     pub email_verified: bool,
+    pub passwordless_only: bool,
+    pub locale: String,
     #[serde(skip_serializing)]
     pub updated_on: String,
 }
@@ -174,8 +406,37 @@ impl AuthUser for User {
 }
 
 impl User {
+    #[deprecated(note = "use UserDb::verify_password so the configured pepper is applied")]
     pub fn check_pwd(&self, pwd: &str) -> Result<(), AuthError> {
-        check_pwd(pwd, &self.password)
+        verify_hash(pwd, &self.password)
+    }
+
+    ///
+    /// Check the account may be issued tokens: it must not have been
+    /// disabled and its email address must be verified.
+    ///
+    pub fn check_can_signin(&self) -> AuthResult<()> {
+        if !self.can_signin {
+            return Err(AuthError::AccountDisabledError(self.username.clone()));
+        }
+
+        if !self.email_verified {
+            return Err(AuthError::EmailNotVerifiedError(self.username.clone()));
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Name to greet the user by in emails, falling back to their
+    /// username if no first name is set.
+    ///
+    pub fn display_name(&self) -> String {
+        if self.first_name.is_empty() {
+            self.username.clone()
+        } else {
+            self.first_name.clone()
+        }
     }
 
     // pub fn to_public(&self) -> PublicUser {
@@ -190,25 +451,47 @@ impl User {
 }
 
 ///
-/// Verify a password matches its hash
+/// Verify a password matches its hash, which may be a legacy PBKDF2 or
+/// bcrypt hash
 ///
+#[deprecated(note = "use UserDb::verify_password so the configured pepper is applied")]
 pub fn check_pwd(pwd: impl AsRef<[u8]>, hash: &str) -> Result<(), AuthError> {
-    Ok(verify_password(pwd, hash)?)
+    verify_hash(pwd, hash)
 }
 
 ///
 /// Create a password hash
 ///
+#[deprecated(note = "use UserDb::hash_password so the configured hasher is applied")]
 pub fn hash_pwd(pwd: &str) -> String {
     return generate_hash(pwd);
 }
 
+///
+/// Tie a token to the account's last update so changing the password
+/// revokes it. This is not a password so the pepper is not needed.
+///
 pub fn create_otp(user: &User) -> String {
-    return hash_pwd(&user.updated_on);
+    return generate_hash(&user.updated_on);
 }
 
 pub fn check_otp_valid(user: &User, otp: &str) -> bool {
-    return check_pwd(&user.updated_on, otp).is_ok();
+    return verify_hash(&user.updated_on, otp).is_ok();
+}
+
+///
+/// A request to move an account to a new email address. It stays pending
+/// until the new address is confirmed and can be reverted from the old
+/// address.
+///
+#[derive(Serialize, Debug, PartialEq, Eq, Clone, FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct EmailChange {
+    pub uuid: String,
+    pub user_uuid: String,
+    pub old_email: String,
+    pub new_email: String,
+    pub status: String,
 }
 
 #[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
@@ -221,9 +504,11 @@ pub struct Credentials {
     pub last_name: Option<String>,
     pub callback_url: Option<String>,
     pub url: Option<String>,
+    pub locale: Option<String>,
 }
 
 impl Credentials {
+    #[deprecated(note = "use UserDb::hash_password so the configured hasher is applied")]
     pub fn hash_password(&self) -> String {
         generate_hash(&self.password)
     }
@@ -235,14 +520,246 @@ impl fmt::Display for Credentials {
     }
 }
 
+///
+/// A user whose credentials have been checked and password hashed, so
+/// it can be inserted inside a transaction without further lookups.
+///
+#[derive(Debug, Clone)]
+pub(crate) struct NewUser {
+    pub uuid: String,
+    pub username: String,
+    pub email: String,
+    pub first_name: String,
+    pub last_name: String,
+    pub hash: String,
+    pub locale: &'static str,
+}
+
 #[derive(Clone)]
 pub struct UserDb {
     pool: Pool<Sqlite>,
+    password_policy: PasswordPolicy,
+    hasher: Argon2Hasher,
+    directory: Option<Arc<dyn Directory>>,
 }
 
 impl UserDb {
     pub fn new(pool: Pool<Sqlite>) -> Self {
-        Self { pool }
+        Self {
+            pool,
+            password_policy: PasswordPolicy::default(),
+            hasher: Argon2Hasher::default(),
+            directory: None,
+        }
+    }
+
+    ///
+    /// Check passwords against a corporate directory before local hashes,
+    /// see `ldap::directory_sign_in`.
+    ///
+    pub fn with_directory(mut self, directory: Arc<dyn Directory>) -> Self {
+        self.directory = Some(directory);
+        self
+    }
+
+    pub fn directory(&self) -> Option<&Arc<dyn Directory>> {
+        self.directory.as_ref()
+    }
+
+    ///
+    /// Hash new passwords with these Argon2 costs and pepper rather than
+    /// the defaults.
+    ///
+    pub fn with_hasher(mut self, hasher: Argon2Hasher) -> Self {
+        self.hasher = hasher;
+        self
+    }
+
+    pub fn hasher(&self) -> &Argon2Hasher {
+        &self.hasher
+    }
+
+    ///
+    /// Hash with the configured hasher off the async runtime, since a
+    /// full Argon2 run would otherwise stall other requests.
+    ///
+    pub async fn hash_password(&self, pwd: &str) -> AuthResult<String> {
+        let hasher = self.hasher.clone();
+        let pwd = pwd.to_string();
+
+        tokio::task::spawn_blocking(move || hasher.hash(&pwd)).await?
+    }
+
+    ///
+    /// Verify with the configured hasher off the async runtime.
+    ///
+    pub async fn verify_password(&self, pwd: &str, hash: &str) -> AuthResult<()> {
+        let hasher = self.hasher.clone();
+        let pwd = pwd.to_string();
+        let hash = hash.to_string();
+
+        tokio::task::spawn_blocking(move || hasher.verify(&pwd, &hash)).await?
+    }
+
+    ///
+    /// Replace the default policy new passwords are checked against.
+    ///
+    pub fn with_password_policy(mut self, policy: &PasswordPolicy) -> Self {
+        self.password_policy = policy.clone();
+        self
+    }
+
+    pub fn password_policy(&self) -> &PasswordPolicy {
+        &self.password_policy
+    }
+
+    ///
+    /// Check a new password against the policy, including the breached
+    /// password file if one is configured, reporting every failed rule.
+    ///
+    pub async fn check_password(&self, pwd: &str, user_inputs: &[&str]) -> AuthResult<()> {
+        let rules = self.password_violations(pwd, user_inputs).await?;
+
+        if rules.is_empty() {
+            Ok(())
+        } else {
+            Err(AuthError::PasswordPolicyError(rules))
+        }
+    }
+
+    async fn password_violations(
+        &self,
+        pwd: &str,
+        user_inputs: &[&str],
+    ) -> AuthResult<Vec<PasswordRule>> {
+        let mut rules = self.password_policy.violations(pwd, user_inputs);
+
+        let policy = self.password_policy.clone();
+        let password = pwd.to_string();
+
+        if let Some(rule) =
+            tokio::task::spawn_blocking(move || policy.check_breached(&password)).await??
+        {
+            rules.push(rule);
+        }
+
+        Ok(rules)
+    }
+
+    ///
+    /// Bring an existing database up to date by adding the users columns
+    /// this version reads and the password history table. Safe to run on
+    /// every start.
+    ///
+    pub async fn migrate(&self) -> AuthResult<()> {
+        let columns = sqlx::query_scalar::<_, String>(USERS_COLUMNS_SQL)
+            .fetch_all(&self.pool)
+            .await?;
+
+        for (column, sql) in USERS_COLUMN_MIGRATIONS {
+            if !columns.iter().any(|name| name == column) {
+                sqlx::query(sql).execute(&self.pool).await?;
+            }
+        }
+
+        self.create_password_history_table().await
+    }
+
+    ///
+    /// Create the table previous password hashes are kept in. Required
+    /// unless the policy's history size is 0.
+    ///
+    pub async fn create_password_history_table(&self) -> AuthResult<()> {
+        sqlx::query(CREATE_PASSWORD_HISTORY_TABLE_SQL)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    ///
+    /// The user's most recent password hashes, newest first, up to the
+    /// policy's history size.
+    ///
+    pub async fn password_history(&self, uuid: &str) -> AuthResult<Vec<String>> {
+        if self.password_policy.history_size == 0 {
+            return Ok(Vec::new());
+        }
+
+        Ok(sqlx::query_scalar::<_, String>(PASSWORD_HISTORY_SQL)
+            .bind(uuid)
+            .bind(self.password_policy.history_size as i64)
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    ///
+    /// The reuse rule if `pwd` matches the user's current password or one
+    /// in their history.
+    ///
+    async fn check_password_history(
+        &self,
+        user: &User,
+        pwd: &str,
+    ) -> AuthResult<Option<PasswordRule>> {
+        if self.password_policy.history_size == 0 {
+            return Ok(None);
+        }
+
+        let mut hashes = self.password_history(&user.uuid).await?;
+
+        // accounts created before history was kept only have their current
+        // password to compare against
+        if !user.password.is_empty() {
+            hashes.push(user.password.clone());
+        }
+
+        let hasher = self.hasher.clone();
+        let password = pwd.to_string();
+
+        // each check is a full argon2 verification
+        let reused = tokio::task::spawn_blocking(move || {
+            hashes.iter().any(|hash| hasher.verify(&password, hash).is_ok())
+        })
+        .await?;
+
+        if reused {
+            Ok(Some(PasswordRule::RecentlyUsed {
+                history: self.password_policy.history_size,
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+
+    ///
+    /// Record a new password hash and drop entries beyond the history
+    /// size, on the caller's transaction so the history can't drift from
+    /// the stored password.
+    ///
+    async fn add_password_history(
+        &self,
+        conn: &mut SqliteConnection,
+        uuid: &str,
+        hash: &str,
+    ) -> AuthResult<()> {
+        if self.password_policy.history_size == 0 {
+            return Ok(());
+        }
+
+        sqlx::query(INSERT_PASSWORD_HISTORY_SQL)
+            .bind(uuid)
+            .bind(hash)
+            .execute(&mut *conn)
+            .await?;
+
+        sqlx::query(PRUNE_PASSWORD_HISTORY_SQL)
+            .bind(uuid)
+            .bind(self.password_policy.history_size as i64)
+            .execute(&mut *conn)
+            .await?;
+
+        Ok(())
     }
 
     pub async fn find_user_by_uuid(&self, uuid: &str) -> AuthResult<User> {
@@ -312,25 +829,90 @@ impl UserDb {
     pub async fn create_user(&self, user: &Credentials) -> AuthResult<User> {
         eprintln!("Creating user");
 
+        let new_user = self.prepare_user(user).await?;
+
+        let mut tx = self.pool.begin().await?;
+
+        self.insert_user(&mut *tx, &new_user).await?;
+
+        tx.commit().await?;
+
+        self.find_user_by_id(&user.username).await
+    }
+
+    ///
+    /// Check a new user's credentials against existing accounts and the
+    /// password policy, and hash their password, ready for `insert_user`.
+    ///
+    pub(crate) async fn prepare_user(&self, user: &Credentials) -> AuthResult<NewUser> {
         if self.username_exists(&user.username).await {
             return Err(AuthError::UserAlreadyExistsError(user.username.clone()));
         }
 
-        let user_id = uuid();
+        let email = user.email.as_deref().unwrap_or("");
+        let first_name = user.first_name.as_deref().unwrap_or("");
+        let last_name = user.last_name.as_deref().unwrap_or("");
 
-        let hash = user.hash_password();
+        self.check_password(
+            &user.password,
+            &user_inputs(&user.username, email, first_name, last_name),
+        )
+        .await?;
 
-        match sqlx::query(&CREATE_USER_SQL)
-            .bind(&user_id)
-            .bind(&user.username)
+        let hash = self.hash_password(&user.password).await?;
+
+        let locale = match &user.locale {
+            Some(locale) => i18n::normalize_locale(locale).unwrap_or(i18n::DEFAULT_LOCALE),
+            None => i18n::DEFAULT_LOCALE,
+        };
+
+        // usernames were used as the email before a separate email was
+        // collected, so keep doing that if none is given
+        let email = match email {
+            "" => &user.username,
+            email => email,
+        };
+
+        if email != user.username && self.find_user_by_id(email).await.is_ok() {
+            return Err(AuthError::UserAlreadyExistsError(email.to_string()));
+        }
+
+        Ok(NewUser {
+            uuid: uuid(),
+            username: user.username.clone(),
+            email: email.to_string(),
+            first_name: first_name.to_string(),
+            last_name: last_name.to_string(),
+            hash,
+            locale,
+        })
+    }
+
+    ///
+    /// Insert a user from `prepare_user`, with their first password
+    /// history entry, on the caller's transaction.
+    ///
+    pub(crate) async fn insert_user(
+        &self,
+        conn: &mut SqliteConnection,
+        user: &NewUser,
+    ) -> AuthResult<()> {
+        if sqlx::query(&CREATE_USER_SQL)
+            .bind(&user.uuid)
             .bind(&user.username)
-            .bind(hash)
-            .execute(&self.pool)
+            .bind(&user.email)
+            .bind(&user.first_name)
+            .bind(&user.last_name)
+            .bind(&user.hash)
+            .bind(user.locale)
+            .execute(&mut *conn)
             .await
+            .is_err()
         {
-            Ok(_) => self.find_user_by_id(&user.username).await,
-            Err(_) => Err(AuthError::CouldNotCreateUserError(user.username.clone())),
+            return Err(AuthError::CouldNotCreateUserError(user.username.clone()));
         }
+
+        self.add_password_history(&mut *conn, &user.uuid, &user.hash).await
     }
 
     pub async fn user_verified(&self, uuid: &str) -> AuthResult<()> {
@@ -345,11 +927,118 @@ impl UserDb {
     }
 
     pub async fn update_password(&self, uuid: &str, pwd: &str) -> AuthResult<()> {
-        let hash = hash_pwd(pwd);
+        let user = self.find_user_by_uuid(uuid).await?;
+        let hash = self.check_new_password(&user, pwd).await?;
+
+        let mut tx = self.pool.begin().await?;
+
+        self.write_password(&mut *tx, uuid, &hash, true).await?;
 
-        match sqlx::query(&UPDATE_PASSWORD_SQL)
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    ///
+    /// Check a user's new password against the policy and their history,
+    /// returning its hash for `write_password`.
+    ///
+    pub(crate) async fn check_new_password(&self, user: &User, pwd: &str) -> AuthResult<String> {
+        let mut rules = self
+            .password_violations(
+                pwd,
+                &user_inputs(&user.username, &user.email, &user.first_name, &user.last_name),
+            )
+            .await?;
+
+        if let Some(rule) = self.check_password_history(user, pwd).await? {
+            rules.push(rule);
+        }
+
+        if !rules.is_empty() {
+            return Err(AuthError::PasswordPolicyError(rules));
+        }
+
+        self.hash_password(pwd).await
+    }
+
+    ///
+    /// Re-hash a password that has just been verified so outdated or
+    /// imported hashes move to the current Argon2 parameters. The password
+    /// is unchanged so the policy and history are not checked.
+    ///
+    pub async fn rehash_password(&self, uuid: &str, pwd: &str) -> AuthResult<()> {
+        let hash = self.hash_password(pwd).await?;
+
+        let mut tx = self.pool.begin().await?;
+
+        self.write_password(&mut *tx, uuid, &hash, false).await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    ///
+    /// Store a password hash on the caller's transaction, recording it in
+    /// the history unless it is a rehash of the same password.
+    ///
+    pub(crate) async fn write_password(
+        &self,
+        conn: &mut SqliteConnection,
+        uuid: &str,
+        hash: &str,
+        add_history: bool,
+    ) -> AuthResult<()> {
+        // a rehash keeps the same password so outstanding tokens stay valid
+        let sql = if add_history {
+            UPDATE_PASSWORD_SQL
+        } else {
+            REHASH_PASSWORD_SQL
+        };
+
+        sqlx::query(sql)
             .bind(uuid)
             .bind(hash)
+            .execute(&mut *conn)
+            .await?;
+
+        if add_history {
+            self.add_password_history(&mut *conn, uuid, hash).await?;
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Enable or disable passwordless only sign in for a user.
+    ///
+    pub async fn set_passwordless_only(
+        &self,
+        uuid: &str,
+        passwordless_only: bool,
+    ) -> AuthResult<()> {
+        match sqlx::query(&PASSWORDLESS_ONLY_SQL)
+            .bind(uuid)
+            .bind(passwordless_only)
+            .execute(&self.pool)
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(err) => Err(AuthError::DatabaseError(err.to_string())),
+        }
+    }
+
+    ///
+    /// Set the user's preferred locale for emails. Unsupported locales
+    /// are stored as the default locale.
+    ///
+    pub async fn update_locale(&self, uuid: &str, locale: &str) -> AuthResult<()> {
+        let locale = i18n::normalize_locale(locale).unwrap_or(i18n::DEFAULT_LOCALE);
+
+        match sqlx::query(&UPDATE_LOCALE_SQL)
+            .bind(uuid)
+            .bind(locale)
             .execute(&self.pool)
             .await
         {
@@ -358,18 +1047,89 @@ impl UserDb {
         }
     }
 
+    pub async fn list_users(&self, offset: i64, limit: i64) -> AuthResult<Vec<User>> {
+        Ok(sqlx::query_as::<_, User>(LIST_USERS_SQL)
+            .bind(limit)
+            .bind(offset)
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    ///
+    /// Allow or block a user from signing in without deleting them.
+    ///
+    pub async fn set_can_signin(&self, uuid: &str, can_signin: bool) -> AuthResult<()> {
+        match sqlx::query(&CAN_SIGNIN_SQL)
+            .bind(uuid)
+            .bind(can_signin)
+            .execute(&self.pool)
+            .await
+        {
+            Ok(result) if result.rows_affected() > 0 => Ok(()),
+            Ok(_) => Err(AuthError::UserDoesNotExistError(uuid.to_string())),
+            Err(err) => Err(AuthError::DatabaseError(err.to_string())),
+        }
+    }
+
+    pub async fn delete_user(&self, uuid: &str) -> AuthResult<()> {
+        let mut tx = self.pool.begin().await?;
+
+        self.remove_user(&mut *tx, uuid).await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    ///
+    /// Delete a user and everything kept for them, such as their linked
+    /// identities and consents, on the caller's transaction. Rows left
+    /// behind would otherwise stop the same identities being linked again.
+    ///
+    pub(crate) async fn remove_user(
+        &self,
+        conn: &mut SqliteConnection,
+        uuid: &str,
+    ) -> AuthResult<()> {
+        let result = sqlx::query(&DELETE_USER_SQL)
+            .bind(uuid)
+            .execute(&mut *conn)
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(AuthError::UserDoesNotExistError(uuid.to_string()));
+        }
+
+        let tables = sqlx::query_scalar::<_, String>(TABLES_SQL)
+            .fetch_all(&mut *conn)
+            .await?;
+
+        for table in USER_TABLES {
+            if tables.iter().any(|name| name == table) {
+                sqlx::query(&format!("DELETE FROM {} WHERE user_uuid = $1", table))
+                    .bind(uuid)
+                    .execute(&mut *conn)
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Update a user's profile. Email addresses are changed with
+    /// `create_email_change` so the new address is confirmed first.
+    ///
     pub async fn update_user(
         &self,
         uuid: &str,
         username: &str,
-        email: &str,
         first_name: &str,
         last_name: &str,
     ) -> AuthResult<()> {
         match sqlx::query(&UPDATE_USER_SQL)
             .bind(uuid)
             .bind(username)
-            .bind(email)
             .bind(first_name)
             .bind(last_name)
             .execute(&self.pool)
@@ -380,7 +1140,161 @@ impl UserDb {
         }
     }
 
-    // Returns element
+    pub async fn create_email_change_table(&self) -> AuthResult<()> {
+        sqlx::query(CREATE_EMAIL_CHANGES_TABLE_SQL)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    ///
+    /// Record a pending change of the user's email address, replacing any
+    /// change still waiting to be confirmed.
+    ///
+    pub async fn create_email_change(
+        &self,
+        user: &User,
+        new_email: &str,
+    ) -> AuthResult<EmailChange> {
+        if self.find_user_by_id(new_email).await.is_ok() {
+            return Err(AuthError::UserAlreadyExistsError(new_email.to_string()));
+        }
+
+        let change = EmailChange {
+            uuid: uuid(),
+            user_uuid: user.uuid.clone(),
+            old_email: user.email.clone(),
+            new_email: new_email.to_string(),
+            status: EMAIL_CHANGE_STATUS_PENDING.to_string(),
+        };
+
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query(CANCEL_PENDING_EMAIL_CHANGES_SQL)
+            .bind(&user.uuid)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query(CREATE_EMAIL_CHANGE_SQL)
+            .bind(&change.uuid)
+            .bind(&change.user_uuid)
+            .bind(&change.old_email)
+            .bind(&change.new_email)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(change)
+    }
+
+    pub async fn find_email_change(&self, uuid: &str) -> AuthResult<EmailChange> {
+        match sqlx::query_as::<_, EmailChange>(FIND_EMAIL_CHANGE_SQL)
+            .bind(uuid)
+            .fetch_one(&self.pool)
+            .await
+        {
+            Ok(change) => Ok(change),
+            Err(sqlx::Error::RowNotFound) => {
+                Err(AuthError::TokenError(format!("email change {} does not exist", uuid)))
+            }
+            Err(err) => Err(AuthError::DatabaseError(err.to_string())),
+        }
+    }
+
+    ///
+    /// Switch the account to the new address once it has been confirmed.
+    ///
+    pub async fn confirm_email_change(&self, change: &EmailChange) -> AuthResult<()> {
+        if self.find_user_by_email(&change.new_email).await.is_ok() {
+            return Err(AuthError::UserAlreadyExistsError(change.new_email.clone()));
+        }
+
+        self.switch_email(
+            change,
+            &change.old_email,
+            &change.new_email,
+            EMAIL_CHANGE_STATUS_PENDING,
+            EMAIL_CHANGE_STATUS_CONFIRMED,
+        )
+        .await
+    }
+
+    ///
+    /// Undo an email change from the old address. A pending change is
+    /// cancelled, a confirmed one switches the account back.
+    ///
+    pub async fn revert_email_change(&self, change: &EmailChange) -> AuthResult<()> {
+        match change.status.as_str() {
+            EMAIL_CHANGE_STATUS_PENDING => {
+                match sqlx::query(EMAIL_CHANGE_STATUS_SQL)
+                    .bind(&change.uuid)
+                    .bind(EMAIL_CHANGE_STATUS_PENDING)
+                    .bind(EMAIL_CHANGE_STATUS_CANCELLED)
+                    .execute(&self.pool)
+                    .await
+                {
+                    Ok(result) if result.rows_affected() > 0 => Ok(()),
+                    Ok(_) => Err(AuthError::TokenError(
+                        "email change is no longer pending".to_string(),
+                    )),
+                    Err(err) => Err(AuthError::DatabaseError(err.to_string())),
+                }
+            }
+            EMAIL_CHANGE_STATUS_CONFIRMED => {
+                self.switch_email(
+                    change,
+                    &change.new_email,
+                    &change.old_email,
+                    EMAIL_CHANGE_STATUS_CONFIRMED,
+                    EMAIL_CHANGE_STATUS_REVERTED,
+                )
+                .await
+            }
+            status => Err(AuthError::TokenError(format!("email change is already {}", status))),
+        }
+    }
+
+    async fn switch_email(
+        &self,
+        change: &EmailChange,
+        from: &str,
+        to: &str,
+        status: &str,
+        new_status: &str,
+    ) -> AuthResult<()> {
+        let mut tx = self.pool.begin().await?;
+
+        let result = sqlx::query(EMAIL_CHANGE_STATUS_SQL)
+            .bind(&change.uuid)
+            .bind(status)
+            .bind(new_status)
+            .execute(&mut *tx)
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(AuthError::TokenError(format!("email change is no longer {}", status)));
+        }
+
+        let result = sqlx::query(SWITCH_EMAIL_SQL)
+            .bind(&change.user_uuid)
+            .bind(from)
+            .bind(to)
+            .execute(&mut *tx)
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(AuthError::TokenError(format!(
+                "account email is no longer {}",
+                from
+            )));
+        }
+
+        tx.commit().await?;
+
+        Ok(())
+    }
 }
 
 // Make a cached statement
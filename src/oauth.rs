@@ -0,0 +1,1194 @@
+use axum::{
+    extract::{Query, State},
+    http::{
+        header::{AUTHORIZATION, CACHE_CONTROL},
+        HeaderMap, HeaderName,
+    },
+    routing::{get, post},
+    Form, Json, Router,
+};
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use chrono::{Duration, Utc};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::FromRow;
+use url::Url;
+
+use crate::{
+    device::{exchange_device_code, CREATE_DEVICE_CODES_TABLE_SQL, GRANT_DEVICE_CODE},
+    introspect::CREATE_REVOKED_TOKENS_TABLE_SQL,
+    jwt::{client_jwt, config_decode_jwt, AppState, FirstPartyToken, JwtClaims, TokenType},
+    oidc::{id_token, SCOPE_OPENID},
+    signin::check_first_party,
+    uuid, AuthError, AuthResult, User, UserDb,
+};
+
+// error codes from RFC 6749 and RFC 7591
+pub const INVALID_REQUEST: &str = "invalid_request";
+pub const INVALID_CLIENT: &str = "invalid_client";
+pub const INVALID_GRANT: &str = "invalid_grant";
+pub const INVALID_SCOPE: &str = "invalid_scope";
+pub const UNAUTHORIZED_CLIENT: &str = "unauthorized_client";
+pub const INVALID_REDIRECT_URI: &str = "invalid_redirect_uri";
+pub const INVALID_CLIENT_METADATA: &str = "invalid_client_metadata";
+pub const ACCESS_DENIED: &str = "access_denied";
+pub const UNSUPPORTED_RESPONSE_TYPE: &str = "unsupported_response_type";
+pub const UNSUPPORTED_GRANT_TYPE: &str = "unsupported_grant_type";
+
+pub const GRANT_AUTHORIZATION_CODE: &str = "authorization_code";
+pub const GRANT_REFRESH_TOKEN: &str = "refresh_token";
+pub const GRANT_CLIENT_CREDENTIALS: &str = "client_credentials";
+
+pub const GRANT_TYPES: [&str; 4] = [
+    GRANT_AUTHORIZATION_CODE,
+    GRANT_REFRESH_TOKEN,
+    GRANT_CLIENT_CREDENTIALS,
+    GRANT_DEVICE_CODE,
+];
+
+// what clients registered without saying otherwise may use
+pub const DEFAULT_GRANT_TYPES: &str = "authorization_code refresh_token";
+
+pub const RESPONSE_TYPE_CODE: &str = "code";
+pub const PKCE_S256: &str = "S256";
+
+// codes are exchanged straight after the redirect so only need to live
+// long enough for that round trip
+pub const AUTHORIZATION_CODE_TTL_MINS: i64 = 5;
+
+pub const CREATE_OAUTH_CLIENTS_TABLE_SQL: &'static str =
+    r#"CREATE TABLE IF NOT EXISTS oauth_clients (
+id INTEGER PRIMARY KEY AUTOINCREMENT,
+client_id TEXT NOT NULL UNIQUE,
+name TEXT NOT NULL,
+secret TEXT NOT NULL DEFAULT '',
+redirect_uris TEXT NOT NULL,
+scope TEXT NOT NULL DEFAULT '',
+grant_types TEXT NOT NULL DEFAULT 'authorization_code refresh_token',
+created_on TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP)"#;
+
+const OAUTH_CLIENTS_COLUMNS_SQL: &'static str =
+    r#"SELECT name FROM pragma_table_info('oauth_clients')"#;
+
+// clients registered before grant types were recorded get the defaults
+const ADD_OAUTH_CLIENT_GRANT_TYPES_SQL: &'static str = r#"ALTER TABLE oauth_clients
+ADD COLUMN grant_types TEXT NOT NULL DEFAULT 'authorization_code refresh_token'"#;
+
+pub const CREATE_OAUTH_CODES_TABLE_SQL: &'static str = r#"CREATE TABLE IF NOT EXISTS oauth_codes (
+id INTEGER PRIMARY KEY AUTOINCREMENT,
+code TEXT NOT NULL UNIQUE,
+client_id TEXT NOT NULL,
+user_uuid TEXT NOT NULL,
+redirect_uri TEXT NOT NULL,
+scope TEXT NOT NULL,
+code_challenge TEXT NOT NULL,
+nonce TEXT NOT NULL DEFAULT '',
+expires INTEGER NOT NULL,
+used INTEGER NOT NULL DEFAULT 0,
+created_on TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP)"#;
+
+pub const CREATE_OAUTH_CONSENTS_TABLE_SQL: &'static str =
+    r#"CREATE TABLE IF NOT EXISTS oauth_consents (
+id INTEGER PRIMARY KEY AUTOINCREMENT,
+user_uuid TEXT NOT NULL,
+client_id TEXT NOT NULL,
+scope TEXT NOT NULL,
+created_on TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+UNIQUE(user_uuid, client_id))"#;
+
+const CREATE_OAUTH_CLIENT_SQL: &'static str = r#"INSERT INTO oauth_clients
+(client_id, name, secret, redirect_uris, scope, grant_types)
+VALUES($1, $2, $3, $4, $5, $6)"#;
+
+const FIND_OAUTH_CLIENT_SQL: &'static str = r#"SELECT
+client_id, name, secret, redirect_uris, scope, grant_types
+FROM oauth_clients
+WHERE oauth_clients.client_id = $1 LIMIT 1"#;
+
+const UPDATE_OAUTH_CLIENT_SECRET_SQL: &'static str =
+    r#"UPDATE oauth_clients SET secret = $2 WHERE client_id = $1"#;
+
+const UPDATE_OAUTH_CLIENT_GRANT_TYPES_SQL: &'static str =
+    r#"UPDATE oauth_clients SET grant_types = $2 WHERE client_id = $1"#;
+
+const DELETE_OAUTH_CLIENT_SQL: &'static str = r#"DELETE FROM oauth_clients WHERE client_id = $1"#;
+
+const DELETE_OAUTH_CLIENT_CODES_SQL: &'static str =
+    r#"DELETE FROM oauth_codes WHERE client_id = $1"#;
+
+const DELETE_OAUTH_CLIENT_CONSENTS_SQL: &'static str =
+    r#"DELETE FROM oauth_consents WHERE client_id = $1"#;
+
+const CREATE_AUTHORIZATION_CODE_SQL: &'static str = r#"INSERT INTO oauth_codes
+(code, client_id, user_uuid, redirect_uri, scope, code_challenge, nonce, expires)
+VALUES($1, $2, $3, $4, $5, $6, $7, $8)"#;
+
+const FIND_AUTHORIZATION_CODE_SQL: &'static str = r#"SELECT
+client_id, user_uuid, redirect_uri, scope, code_challenge, nonce
+FROM oauth_codes
+WHERE oauth_codes.code = $1 LIMIT 1"#;
+
+// codes are single use, so spending one only succeeds once
+const USE_AUTHORIZATION_CODE_SQL: &'static str =
+    r#"UPDATE oauth_codes SET used = 1 WHERE code = $1 AND used = 0 AND expires > $2"#;
+
+const FIND_OAUTH_CONSENT_SQL: &'static str =
+    r#"SELECT scope FROM oauth_consents WHERE user_uuid = $1 AND client_id = $2"#;
+
+const GRANT_OAUTH_CONSENT_SQL: &'static str = r#"INSERT INTO oauth_consents
+(user_uuid, client_id, scope)
+VALUES($1, $2, $3)
+ON CONFLICT(user_uuid, client_id) DO UPDATE SET scope = excluded.scope"#;
+
+///
+/// A registered OAuth client. Public clients such as SPAs and mobile apps
+/// have no secret and rely on PKCE alone.
+///
+#[derive(Serialize, Debug, Clone, FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct OAuthClient {
+    pub client_id: String,
+    pub name: String,
+    /// Hash of the client secret, empty for public clients
+    #[serde(skip_serializing)]
+    pub secret: String,
+    /// Space separated redirect URIs
+    pub redirect_uris: String,
+    /// Space separated scopes the client may ask for
+    pub scope: String,
+    /// Space separated grant types the client may use at the token
+    /// endpoint
+    pub grant_types: String,
+}
+
+impl OAuthClient {
+    pub fn is_confidential(&self) -> bool {
+        !self.secret.is_empty()
+    }
+
+    pub fn redirect_uris(&self) -> Vec<&str> {
+        self.redirect_uris.split_whitespace().collect()
+    }
+
+    ///
+    /// Redirect URIs must match one registered for the client exactly.
+    ///
+    pub fn has_redirect_uri(&self, uri: &str) -> bool {
+        self.redirect_uris().contains(&uri)
+    }
+
+    pub fn allows_grant(&self, grant_type: &str) -> bool {
+        self.grant_types.split_whitespace().any(|g| g == grant_type)
+    }
+}
+
+///
+/// An authorization code that has been redeemed.
+///
+#[derive(Debug, Clone, FromRow)]
+pub struct AuthorizationCode {
+    pub client_id: String,
+    pub user_uuid: String,
+    pub redirect_uri: String,
+    pub scope: String,
+    pub code_challenge: String,
+    /// OpenID Connect nonce to repeat in the ID token, empty if none
+    pub nonce: String,
+}
+
+///
+/// Authorization request parameters, as sent to `/authorize`.
+///
+#[derive(Deserialize, Debug, Clone)]
+pub struct AuthorizeReq {
+    pub response_type: String,
+    pub client_id: String,
+    pub redirect_uri: Option<String>,
+    pub scope: Option<String>,
+    pub state: Option<String>,
+    pub code_challenge: Option<String>,
+    pub code_challenge_method: Option<String>,
+    pub nonce: Option<String>,
+}
+
+///
+/// The user's answer on the consent screen.
+///
+#[derive(Deserialize, Debug, Clone)]
+pub struct ConsentReq {
+    #[serde(flatten)]
+    pub request: AuthorizeReq,
+    pub approve: bool,
+}
+
+///
+/// What the consent screen needs to show. If `consented` is set the user
+/// has already granted the scope and the screen can be skipped.
+///
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ConsentResp {
+    pub client_id: String,
+    pub client_name: String,
+    pub scope: String,
+    pub redirect_uri: String,
+    pub consented: bool,
+}
+
+///
+/// Where to send the user's browser to finish the authorization request.
+///
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthorizeResp {
+    pub redirect_uri: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TokenReq {
+    pub grant_type: String,
+    pub code: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub code_verifier: Option<String>,
+    pub refresh_token: Option<String>,
+    pub device_code: Option<String>,
+    pub scope: Option<String>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct OAuthTokenResp {
+    pub access_token: String,
+    pub token_type: String,
+    pub expires_in: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub scope: String,
+    /// Set when the `openid` scope was granted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<String>,
+}
+
+///
+/// A checked authorization request.
+///
+#[derive(Debug, Clone)]
+pub struct Authorization {
+    pub client: OAuthClient,
+    /// Where to send the user back to
+    pub redirect_uri: String,
+    /// The redirect_uri parameter as sent, which the token request must
+    /// repeat. Empty if it was left out.
+    pub requested_redirect_uri: String,
+    pub scope: String,
+    pub state: Option<String>,
+    pub code_challenge: String,
+    pub nonce: String,
+}
+
+pub fn oauth_error(error: &str, description: impl Into<String>) -> AuthError {
+    AuthError::OAuthError(error.to_string(), description.into())
+}
+
+pub(crate) fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+// codes are stored hashed so a database leak does not leak usable codes
+pub(crate) fn code_hash(code: &str) -> String {
+    hex::encode(Sha256::digest(code.as_bytes()))
+}
+
+///
+/// The S256 code challenge for a PKCE code verifier.
+///
+pub fn pkce_challenge(verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+fn is_pkce_challenge(challenge: &str) -> bool {
+    challenge.len() == 43
+        && challenge
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn is_pkce_verifier(verifier: &str) -> bool {
+    (43..=128).contains(&verifier.len())
+        && verifier
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_' | '~'))
+}
+
+///
+/// Check a redirect URI can be registered. It must be https, http on the
+/// loopback interface for native apps, or a private use scheme named
+/// after a reverse domain such as `com.example.app:/callback`.
+///
+pub fn check_redirect_uri(uri: &str) -> AuthResult<()> {
+    let url = match Url::parse(uri) {
+        Ok(url) => url,
+        Err(err) => return Err(oauth_error(INVALID_REDIRECT_URI, format!("{}: {}", uri, err))),
+    };
+
+    if url.fragment().is_some() {
+        return Err(oauth_error(
+            INVALID_REDIRECT_URI,
+            format!("{} must not have a fragment", uri),
+        ));
+    }
+
+    let allowed = match url.scheme() {
+        "https" => true,
+        "http" => matches!(url.host_str(), Some("localhost" | "127.0.0.1" | "[::1]")),
+        scheme => scheme.contains('.'),
+    };
+
+    if !allowed {
+        return Err(oauth_error(
+            INVALID_REDIRECT_URI,
+            format!(
+                "{} must use https, a loopback address or a reverse domain scheme",
+                uri
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+///
+/// The scope to grant out of those allowed. Nothing requested means
+/// everything allowed, otherwise every requested scope must be allowed.
+///
+pub fn narrow_scope(allowed: &str, requested: Option<&str>) -> AuthResult<String> {
+    let allowed: Vec<&str> = allowed.split_whitespace().collect();
+
+    let requested = match requested {
+        Some(requested) if !requested.trim().is_empty() => requested,
+        _ => return Ok(allowed.join(" ")),
+    };
+
+    let mut scopes: Vec<&str> = Vec::new();
+
+    for scope in requested.split_whitespace() {
+        if !allowed.contains(&scope) {
+            return Err(oauth_error(INVALID_SCOPE, format!("scope {} is not allowed", scope)));
+        }
+
+        if !scopes.contains(&scope) {
+            scopes.push(scope);
+        }
+    }
+
+    Ok(scopes.join(" "))
+}
+
+fn redirect_link(redirect_uri: &str, params: &[(&str, &str)]) -> AuthResult<String> {
+    let mut url = match Url::parse(redirect_uri) {
+        Ok(url) => url,
+        Err(err) => return Err(oauth_error(INVALID_REQUEST, err.to_string())),
+    };
+
+    url.query_pairs_mut().extend_pairs(params);
+
+    Ok(url.to_string())
+}
+
+///
+/// Check the grant types a client is being registered with. Only
+/// confidential clients can act for themselves, and redirect URIs are
+/// needed exactly when users are sent through `/authorize`.
+///
+pub fn check_grant_types(
+    grant_types: &[String],
+    has_redirect_uris: bool,
+    confidential: bool,
+) -> AuthResult<String> {
+    let mut grants: Vec<&str> = Vec::new();
+
+    for grant_type in grant_types.iter().flat_map(|g| g.split_whitespace()) {
+        if !GRANT_TYPES.contains(&grant_type) {
+            return Err(oauth_error(
+                INVALID_CLIENT_METADATA,
+                format!("grant_type {} is not supported", grant_type),
+            ));
+        }
+
+        if !grants.contains(&grant_type) {
+            grants.push(grant_type);
+        }
+    }
+
+    if grants.is_empty() {
+        return Err(oauth_error(
+            INVALID_CLIENT_METADATA,
+            "at least one grant type is required",
+        ));
+    }
+
+    if grants.contains(&GRANT_CLIENT_CREDENTIALS) && !confidential {
+        return Err(oauth_error(
+            INVALID_CLIENT_METADATA,
+            "public clients cannot use the client_credentials grant",
+        ));
+    }
+
+    if grants.contains(&GRANT_AUTHORIZATION_CODE) && !has_redirect_uris {
+        return Err(oauth_error(
+            INVALID_REDIRECT_URI,
+            "at least one redirect uri is required",
+        ));
+    }
+
+    Ok(grants.join(" "))
+}
+
+pub(crate) fn required<'a>(value: &'a Option<String>, name: &str) -> AuthResult<&'a str> {
+    match value.as_deref() {
+        Some(value) if !value.is_empty() => Ok(value),
+        _ => Err(oauth_error(INVALID_REQUEST, format!("{} is required", name))),
+    }
+}
+
+impl UserDb {
+    pub async fn create_oauth_tables(&self) -> AuthResult<()> {
+        for sql in [
+            CREATE_OAUTH_CLIENTS_TABLE_SQL,
+            CREATE_OAUTH_CODES_TABLE_SQL,
+            CREATE_OAUTH_CONSENTS_TABLE_SQL,
+            CREATE_DEVICE_CODES_TABLE_SQL,
+            CREATE_REVOKED_TOKENS_TABLE_SQL,
+        ] {
+            sqlx::query(sql).execute(&self.pool).await?;
+        }
+
+        let columns = sqlx::query_scalar::<_, String>(OAUTH_CLIENTS_COLUMNS_SQL)
+            .fetch_all(&self.pool)
+            .await?;
+
+        if !columns.iter().any(|name| name == "grant_types") {
+            sqlx::query(ADD_OAUTH_CLIENT_GRANT_TYPES_SQL)
+                .execute(&self.pool)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Register a client. Confidential clients are given a secret, which
+    /// is only ever returned here.
+    ///
+    pub async fn create_oauth_client(
+        &self,
+        name: &str,
+        redirect_uris: &[String],
+        scope: &str,
+        grant_types: &[String],
+        confidential: bool,
+    ) -> AuthResult<(OAuthClient, Option<String>)> {
+        let grant_types =
+            check_grant_types(grant_types, !redirect_uris.is_empty(), confidential)?;
+
+        for uri in redirect_uris {
+            check_redirect_uri(uri)?;
+        }
+
+        let secret = if confidential {
+            Some(random_token())
+        } else {
+            None
+        };
+
+        let client = OAuthClient {
+            client_id: uuid(),
+            name: name.to_string(),
+            secret: match &secret {
+                Some(secret) => self.hash_password(secret).await?,
+                None => String::new(),
+            },
+            redirect_uris: redirect_uris.join(" "),
+            scope: narrow_scope(scope, None)?,
+            grant_types,
+        };
+
+        sqlx::query(CREATE_OAUTH_CLIENT_SQL)
+            .bind(&client.client_id)
+            .bind(&client.name)
+            .bind(&client.secret)
+            .bind(&client.redirect_uris)
+            .bind(&client.scope)
+            .bind(&client.grant_types)
+            .execute(&self.pool)
+            .await?;
+
+        Ok((client, secret))
+    }
+
+    pub async fn find_oauth_client(&self, client_id: &str) -> AuthResult<OAuthClient> {
+        match sqlx::query_as::<_, OAuthClient>(FIND_OAUTH_CLIENT_SQL)
+            .bind(client_id)
+            .fetch_one(&self.pool)
+            .await
+        {
+            Ok(client) => Ok(client),
+            Err(sqlx::Error::RowNotFound) => Err(oauth_error(
+                INVALID_CLIENT,
+                format!("client {} does not exist", client_id),
+            )),
+            Err(err) => Err(AuthError::DatabaseError(err.to_string())),
+        }
+    }
+
+    ///
+    /// Give a confidential client a new secret, returning it. The old
+    /// secret stops working immediately.
+    ///
+    pub async fn rotate_oauth_client_secret(&self, client_id: &str) -> AuthResult<String> {
+        let client = self.find_oauth_client(client_id).await?;
+
+        if !client.is_confidential() {
+            return Err(oauth_error(
+                INVALID_CLIENT,
+                format!("client {} is public so has no secret", client_id),
+            ));
+        }
+
+        let secret = random_token();
+        let hash = self.hash_password(&secret).await?;
+
+        sqlx::query(UPDATE_OAUTH_CLIENT_SECRET_SQL)
+            .bind(client_id)
+            .bind(hash)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(secret)
+    }
+
+    ///
+    /// Replace the grant types a client may use, such as to let a client
+    /// registered before they were recorded keep using client_credentials.
+    ///
+    pub async fn set_oauth_client_grant_types(
+        &self,
+        client_id: &str,
+        grant_types: &[String],
+    ) -> AuthResult<OAuthClient> {
+        let client = self.find_oauth_client(client_id).await?;
+
+        let grant_types = check_grant_types(
+            grant_types,
+            !client.redirect_uris().is_empty(),
+            client.is_confidential(),
+        )?;
+
+        sqlx::query(UPDATE_OAUTH_CLIENT_GRANT_TYPES_SQL)
+            .bind(client_id)
+            .bind(&grant_types)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(OAuthClient {
+            grant_types,
+            ..client
+        })
+    }
+
+    pub async fn delete_oauth_client(&self, client_id: &str) -> AuthResult<()> {
+        let mut tx = self.pool.begin().await?;
+
+        for sql in [
+            DELETE_OAUTH_CLIENT_CODES_SQL,
+            DELETE_OAUTH_CLIENT_CONSENTS_SQL,
+            DELETE_OAUTH_CLIENT_SQL,
+        ] {
+            sqlx::query(sql).bind(client_id).execute(&mut *tx).await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    ///
+    /// The scope the user last granted the client, if they have.
+    ///
+    pub async fn oauth_consent(
+        &self,
+        user_uuid: &str,
+        client_id: &str,
+    ) -> AuthResult<Option<String>> {
+        Ok(sqlx::query_scalar::<_, String>(FIND_OAUTH_CONSENT_SQL)
+            .bind(user_uuid)
+            .bind(client_id)
+            .fetch_optional(&self.pool)
+            .await?)
+    }
+
+    pub async fn grant_oauth_consent(
+        &self,
+        user_uuid: &str,
+        client_id: &str,
+        scope: &str,
+    ) -> AuthResult<()> {
+        sqlx::query(GRANT_OAUTH_CONSENT_SQL)
+            .bind(user_uuid)
+            .bind(client_id)
+            .bind(scope)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    ///
+    /// Issue a single use code for an approved authorization request.
+    ///
+    pub async fn create_authorization_code(
+        &self,
+        user_uuid: &str,
+        authorization: &Authorization,
+    ) -> AuthResult<String> {
+        let code = random_token();
+        let expires = (Utc::now() + Duration::minutes(AUTHORIZATION_CODE_TTL_MINS)).timestamp();
+
+        sqlx::query(CREATE_AUTHORIZATION_CODE_SQL)
+            .bind(code_hash(&code))
+            .bind(&authorization.client.client_id)
+            .bind(user_uuid)
+            .bind(&authorization.requested_redirect_uri)
+            .bind(&authorization.scope)
+            .bind(&authorization.code_challenge)
+            .bind(&authorization.nonce)
+            .bind(expires)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(code)
+    }
+
+    ///
+    /// Spend an authorization code. It must not have expired or been used
+    /// and the request must come from the same client, repeat the same
+    /// redirect_uri and prove it holds the PKCE code verifier.
+    ///
+    pub async fn redeem_authorization_code(
+        &self,
+        code: &str,
+        client_id: &str,
+        redirect_uri: &str,
+        code_verifier: &str,
+    ) -> AuthResult<AuthorizationCode> {
+        let hash = code_hash(code);
+
+        let grant = match sqlx::query_as::<_, AuthorizationCode>(FIND_AUTHORIZATION_CODE_SQL)
+            .bind(&hash)
+            .fetch_one(&self.pool)
+            .await
+        {
+            Ok(grant) => grant,
+            Err(sqlx::Error::RowNotFound) => {
+                return Err(oauth_error(INVALID_GRANT, "authorization code is invalid"))
+            }
+            Err(err) => return Err(AuthError::DatabaseError(err.to_string())),
+        };
+
+        // spend the code before checking the request so a failed attempt
+        // cannot be retried
+        let result = sqlx::query(USE_AUTHORIZATION_CODE_SQL)
+            .bind(&hash)
+            .bind(Utc::now().timestamp())
+            .execute(&self.pool)
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(oauth_error(
+                INVALID_GRANT,
+                "authorization code has expired or already been used",
+            ));
+        }
+
+        if grant.client_id != client_id {
+            return Err(oauth_error(
+                INVALID_GRANT,
+                "authorization code was issued to another client",
+            ));
+        }
+
+        if grant.redirect_uri != redirect_uri {
+            return Err(oauth_error(
+                INVALID_GRANT,
+                "redirect_uri does not match the authorization request",
+            ));
+        }
+
+        if !is_pkce_verifier(code_verifier)
+            || pkce_challenge(code_verifier) != grant.code_challenge
+        {
+            return Err(oauth_error(
+                INVALID_GRANT,
+                "code_verifier does not match the code_challenge",
+            ));
+        }
+
+        Ok(grant)
+    }
+}
+
+pub(crate) fn check_oauth_enabled(state: &AppState) -> AuthResult<()> {
+    if !state.config.features.oauth {
+        return Err(AuthError::FeatureDisabledError("oauth".to_string()));
+    }
+
+    Ok(())
+}
+
+///
+/// Check an authorization request against the client's registration. The
+/// client and redirect URI are checked first since errors before then
+/// must not be sent to the redirect URI.
+///
+pub async fn check_authorize_request(
+    user_db: &UserDb,
+    req: &AuthorizeReq,
+) -> AuthResult<Authorization> {
+    let client = user_db.find_oauth_client(&req.client_id).await?;
+
+    let redirect_uri = match &req.redirect_uri {
+        Some(uri) if client.has_redirect_uri(uri) => uri.clone(),
+        Some(uri) => {
+            return Err(oauth_error(
+                INVALID_REQUEST,
+                format!("redirect_uri {} is not registered", uri),
+            ))
+        }
+        // can only be left out if there is no choice
+        None => match client.redirect_uris().as_slice() {
+            [uri] => uri.to_string(),
+            _ => return Err(oauth_error(INVALID_REQUEST, "redirect_uri is required")),
+        },
+    };
+
+    if req.response_type != RESPONSE_TYPE_CODE {
+        return Err(oauth_error(
+            UNSUPPORTED_RESPONSE_TYPE,
+            format!("response_type {} is not supported", req.response_type),
+        ));
+    }
+
+    if !client.allows_grant(GRANT_AUTHORIZATION_CODE) {
+        return Err(oauth_error(
+            UNAUTHORIZED_CLIENT,
+            "client is not registered for the authorization_code grant",
+        ));
+    }
+
+    let code_challenge = match (&req.code_challenge, req.code_challenge_method.as_deref()) {
+        (Some(challenge), Some(PKCE_S256)) if is_pkce_challenge(challenge) => challenge.clone(),
+        (Some(_), Some(PKCE_S256)) => {
+            return Err(oauth_error(INVALID_REQUEST, "code_challenge is not a S256 challenge"))
+        }
+        (Some(_), _) => {
+            return Err(oauth_error(INVALID_REQUEST, "code_challenge_method must be S256"))
+        }
+        (None, _) => return Err(oauth_error(INVALID_REQUEST, "code_challenge is required")),
+    };
+
+    let scope = narrow_scope(&client.scope, req.scope.as_deref())?;
+
+    Ok(Authorization {
+        client,
+        redirect_uri,
+        requested_redirect_uri: req.redirect_uri.clone().unwrap_or_default(),
+        scope,
+        state: req.state.clone(),
+        code_challenge,
+        nonce: req.nonce.clone().unwrap_or_default(),
+    })
+}
+
+///
+/// Describe an authorization request for the consent screen.
+///
+pub async fn consent(state: &AppState, user: &User, req: &AuthorizeReq) -> AuthResult<ConsentResp> {
+    check_oauth_enabled(state)?;
+
+    let authorization = check_authorize_request(&state.user_db, req).await?;
+
+    let consented = match state
+        .user_db
+        .oauth_consent(&user.uuid, &authorization.client.client_id)
+        .await?
+    {
+        Some(granted) => narrow_scope(&granted, Some(authorization.scope.as_str())).is_ok(),
+        None => false,
+    };
+
+    Ok(ConsentResp {
+        client_id: authorization.client.client_id,
+        client_name: authorization.client.name,
+        scope: authorization.scope,
+        redirect_uri: authorization.redirect_uri,
+        consented,
+    })
+}
+
+///
+/// Answer an authorization request for the signed in user, returning
+/// where to redirect them with either a code or an access_denied error.
+///
+pub async fn authorize(
+    state: &AppState,
+    user: &User,
+    req: &AuthorizeReq,
+    approve: bool,
+) -> AuthResult<String> {
+    check_oauth_enabled(state)?;
+
+    let authorization = check_authorize_request(&state.user_db, req).await?;
+
+    let code = if approve {
+        state
+            .user_db
+            .grant_oauth_consent(
+                &user.uuid,
+                &authorization.client.client_id,
+                &authorization.scope,
+            )
+            .await?;
+
+        Some(
+            state
+                .user_db
+                .create_authorization_code(&user.uuid, &authorization)
+                .await?,
+        )
+    } else {
+        None
+    };
+
+    let mut params: Vec<(&str, &str)> = match &code {
+        Some(code) => vec![("code", code.as_str())],
+        None => vec![("error", ACCESS_DENIED)],
+    };
+
+    if let Some(state) = &authorization.state {
+        params.push(("state", state.as_str()));
+    }
+
+    redirect_link(&authorization.redirect_uri, &params)
+}
+
+///
+/// Client credentials from an HTTP Basic authorization header, if one was
+/// sent.
+///
+pub(crate) fn basic_credentials(headers: &HeaderMap) -> AuthResult<Option<(String, String)>> {
+    let encoded = match headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Basic "))
+    {
+        Some(encoded) => encoded,
+        None => return Ok(None),
+    };
+
+    let decoded = match STANDARD.decode(encoded.trim()) {
+        Ok(decoded) => String::from_utf8(decoded).unwrap_or_default(),
+        Err(_) => String::new(),
+    };
+
+    match decoded.split_once(':') {
+        Some((client_id, secret)) => Ok(Some((client_id.to_string(), secret.to_string()))),
+        None => Err(oauth_error(INVALID_CLIENT, "malformed basic authorization header")),
+    }
+}
+
+///
+/// Identify the client making a request, using either HTTP Basic or
+/// `client_id` and `client_secret` in the body. Confidential clients must
+/// send their secret and public clients must not have one.
+///
+pub async fn authenticate_client(
+    user_db: &UserDb,
+    basic: Option<(String, String)>,
+    body_client_id: &Option<String>,
+    body_secret: &Option<String>,
+) -> AuthResult<OAuthClient> {
+    let (client_id, secret) = match basic {
+        Some(_) if body_secret.is_some() => {
+            return Err(oauth_error(
+                INVALID_REQUEST,
+                "only one client authentication method may be used",
+            ))
+        }
+        Some((client_id, secret)) => (client_id, Some(secret)),
+        None => (
+            required(body_client_id, "client_id")?.to_string(),
+            body_secret.clone(),
+        ),
+    };
+
+    if let Some(body_client_id) = body_client_id {
+        if *body_client_id != client_id {
+            return Err(oauth_error(INVALID_CLIENT, "client_id does not match"));
+        }
+    }
+
+    let client = user_db.find_oauth_client(&client_id).await?;
+
+    match (client.is_confidential(), secret) {
+        (true, Some(secret)) => {
+            if user_db.verify_password(&secret, &client.secret).await.is_err() {
+                return Err(oauth_error(INVALID_CLIENT, "client authentication failed"));
+            }
+        }
+        (true, None) => return Err(oauth_error(INVALID_CLIENT, "client secret is required")),
+        (false, Some(_)) => {
+            return Err(oauth_error(INVALID_CLIENT, "public clients do not have a secret"))
+        }
+        (false, None) => (),
+    }
+
+    Ok(client)
+}
+
+///
+/// Find the user a grant is for, who must still be allowed to sign in.
+///
+pub(crate) async fn grant_user(user_db: &UserDb, uuid: &str) -> AuthResult<User> {
+    let user = match user_db.find_user_by_uuid(uuid).await {
+        Ok(user) => user,
+        Err(err) => return Err(oauth_error(INVALID_GRANT, err.to_string())),
+    };
+
+    if let Err(err) = user.check_can_signin() {
+        return Err(oauth_error(INVALID_GRANT, err.to_string()));
+    }
+
+    Ok(user)
+}
+
+pub(crate) fn client_tokens(
+    state: &AppState,
+    client: &OAuthClient,
+    user: &User,
+    scope: &str,
+    nonce: &str,
+) -> AuthResult<OAuthTokenResp> {
+    let tokens = &state.config.tokens;
+    let key = &state.jwt_private_key;
+    let client_id = &client.client_id;
+    let uuid = &user.uuid;
+
+    let id_token = if scope.split_whitespace().any(|s| s == SCOPE_OPENID) {
+        Some(id_token(state, client, user, scope, nonce)?)
+    } else {
+        None
+    };
+
+    Ok(OAuthTokenResp {
+        access_token: client_jwt(uuid, &TokenType::Access, client_id, scope, tokens, key)?,
+        token_type: "Bearer".to_string(),
+        expires_in: tokens.access_ttl_mins * 60,
+        refresh_token: Some(client_jwt(
+            uuid,
+            &TokenType::Refresh,
+            client_id,
+            scope,
+            tokens,
+            key,
+        )?),
+        scope: scope.to_string(),
+        id_token,
+    })
+}
+
+async fn exchange_code(
+    state: &AppState,
+    client: &OAuthClient,
+    req: &TokenReq,
+) -> AuthResult<OAuthTokenResp> {
+    let grant = state
+        .user_db
+        .redeem_authorization_code(
+            required(&req.code, "code")?,
+            &client.client_id,
+            req.redirect_uri.as_deref().unwrap_or_default(),
+            required(&req.code_verifier, "code_verifier")?,
+        )
+        .await?;
+
+    let user = grant_user(&state.user_db, &grant.user_uuid).await?;
+
+    client_tokens(state, client, &user, &grant.scope, &grant.nonce)
+}
+
+async fn exchange_refresh_token(
+    state: &AppState,
+    client: &OAuthClient,
+    req: &TokenReq,
+) -> AuthResult<OAuthTokenResp> {
+    let token = required(&req.refresh_token, "refresh_token")?;
+
+    let claims = match config_decode_jwt(token, &state.config.tokens, &state.jwt_public_key) {
+        Ok(claims) => claims,
+        Err(err) => return Err(oauth_error(INVALID_GRANT, err.to_string())),
+    };
+
+    if claims.token_type != TokenType::Refresh.to_string()
+        || claims.client_id != client.client_id
+    {
+        return Err(oauth_error(
+            INVALID_GRANT,
+            "refresh token was not issued to this client",
+        ));
+    }
+
+    if state.user_db.is_token_revoked(token).await? {
+        return Err(oauth_error(INVALID_GRANT, "refresh token has been revoked"));
+    }
+
+    // a refresh can narrow the scope but never widen it
+    let scope = narrow_scope(&claims.scope, req.scope.as_deref())?;
+
+    let user = grant_user(&state.user_db, &claims.uuid).await?;
+
+    // nonces only apply to the ID token from the authorization request
+    client_tokens(state, client, &user, &scope, "")
+}
+
+///
+/// A client acting for itself, such as a backend job, gets an access
+/// token for its own service principal. Only confidential clients can do
+/// this, and there is no refresh token since the client can simply ask
+/// again.
+///
+async fn exchange_client_credentials(
+    state: &AppState,
+    client: &OAuthClient,
+    req: &TokenReq,
+) -> AuthResult<OAuthTokenResp> {
+    if !client.is_confidential() {
+        return Err(oauth_error(
+            UNAUTHORIZED_CLIENT,
+            "public clients cannot use the client_credentials grant",
+        ));
+    }
+
+    let scope = narrow_scope(&client.scope, req.scope.as_deref())?;
+    let tokens = &state.config.tokens;
+
+    Ok(OAuthTokenResp {
+        access_token: client_jwt(
+            &client.client_id,
+            &TokenType::Service,
+            &client.client_id,
+            &scope,
+            tokens,
+            &state.jwt_private_key,
+        )?,
+        token_type: "Bearer".to_string(),
+        expires_in: tokens.service_ttl_mins * 60,
+        refresh_token: None,
+        scope,
+        id_token: None,
+    })
+}
+
+///
+/// Handle a token endpoint request for an authenticated client.
+///
+pub async fn token(
+    state: &AppState,
+    basic: Option<(String, String)>,
+    req: &TokenReq,
+) -> AuthResult<OAuthTokenResp> {
+    check_oauth_enabled(state)?;
+
+    let client =
+        authenticate_client(&state.user_db, basic, &req.client_id, &req.client_secret).await?;
+
+    // unknown grant types fall through to unsupported_grant_type below
+    if GRANT_TYPES.contains(&req.grant_type.as_str()) && !client.allows_grant(&req.grant_type) {
+        return Err(oauth_error(
+            UNAUTHORIZED_CLIENT,
+            format!("client is not registered for the {} grant", req.grant_type),
+        ));
+    }
+
+    match req.grant_type.as_str() {
+        GRANT_AUTHORIZATION_CODE => exchange_code(state, &client, req).await,
+        GRANT_REFRESH_TOKEN => exchange_refresh_token(state, &client, req).await,
+        GRANT_CLIENT_CREDENTIALS => exchange_client_credentials(state, &client, req).await,
+        GRANT_DEVICE_CODE => exchange_device_code(state, &client, req).await,
+        grant_type => Err(oauth_error(
+            UNSUPPORTED_GRANT_TYPE,
+            format!("grant_type {} is not supported", grant_type),
+        )),
+    }
+}
+
+pub(crate) async fn signed_in_user(state: &AppState, claims: &JwtClaims) -> AuthResult<User> {
+    check_first_party(claims)?;
+
+    let user = state.user_db.find_user_by_uuid(&claims.uuid).await?;
+
+    user.check_can_signin()?;
+
+    Ok(user)
+}
+
+async fn consent_handler(
+    State(state): State<AppState>,
+    FirstPartyToken(claims): FirstPartyToken,
+    Query(req): Query<AuthorizeReq>,
+) -> AuthResult<Json<ConsentResp>> {
+    let user = signed_in_user(&state, &claims).await?;
+
+    Ok(Json(consent(&state, &user, &req).await?))
+}
+
+async fn authorize_handler(
+    State(state): State<AppState>,
+    FirstPartyToken(claims): FirstPartyToken,
+    Json(req): Json<ConsentReq>,
+) -> AuthResult<Json<AuthorizeResp>> {
+    let user = signed_in_user(&state, &claims).await?;
+
+    Ok(Json(AuthorizeResp {
+        redirect_uri: authorize(&state, &user, &req.request, req.approve).await?,
+    }))
+}
+
+async fn token_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(req): Form<TokenReq>,
+) -> AuthResult<([(HeaderName, &'static str); 1], Json<OAuthTokenResp>)> {
+    let resp = token(&state, basic_credentials(&headers)?, &req).await?;
+
+    // token responses must not be cached
+    Ok(([(CACHE_CONTROL, "no-store")], Json(resp)))
+}
+
+///
+/// OAuth 2.0 authorization server endpoints. The consent screen is the
+/// front end's; it reads the request from `GET /oauth/authorize` and
+/// posts the user's answer back to get the redirect.
+///
+pub fn oauth_router() -> Router<AppState> {
+    Router::new()
+        .route("/oauth/authorize", get(consent_handler).post(authorize_handler))
+        .route("/oauth/token", post(token_handler))
+}
@@ -0,0 +1,224 @@
+use axum::{extract::State, routing::get, Json, Router};
+use chrono::{Duration, Utc};
+use jsonwebtoken::{encode, Algorithm, Header};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    device::GRANT_DEVICE_CODE,
+    jwt::{AppState, JwtToken, TokenType},
+    oauth::{
+        oauth_error, OAuthClient, GRANT_AUTHORIZATION_CODE, GRANT_CLIENT_CREDENTIALS,
+        GRANT_REFRESH_TOKEN, PKCE_S256, RESPONSE_TYPE_CODE,
+    },
+    signin::check_token_type,
+    AuthError, AuthResult, User,
+};
+
+pub const SCOPE_OPENID: &str = "openid";
+pub const SCOPE_PROFILE: &str = "profile";
+pub const SCOPE_EMAIL: &str = "email";
+
+pub const INSUFFICIENT_SCOPE: &str = "insufficient_scope";
+
+///
+/// Standard claims about a user, limited to those the granted scopes
+/// allow. Returned from `/userinfo` and included in ID tokens.
+///
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct UserInfo {
+    pub sub: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub given_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub family_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preferred_username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locale: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email_verified: Option<bool>,
+}
+
+impl UserInfo {
+    pub fn new(user: &User, scope: &str) -> Self {
+        let scopes: Vec<&str> = scope.split_whitespace().collect();
+
+        let mut info = UserInfo {
+            sub: user.uuid.clone(),
+            ..Default::default()
+        };
+
+        if scopes.contains(&SCOPE_PROFILE) {
+            let name = format!("{} {}", user.first_name, user.last_name);
+
+            info.name = Some(match name.trim() {
+                "" => user.username.clone(),
+                name => name.to_string(),
+            });
+            info.given_name = non_empty(&user.first_name);
+            info.family_name = non_empty(&user.last_name);
+            info.preferred_username = Some(user.username.clone());
+            info.locale = non_empty(&user.locale);
+        }
+
+        if scopes.contains(&SCOPE_EMAIL) {
+            info.email = Some(user.email.clone());
+            info.email_verified = Some(user.email_verified);
+        }
+
+        info
+    }
+}
+
+fn non_empty(value: &str) -> Option<String> {
+    match value {
+        "" => None,
+        value => Some(value.to_string()),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IdTokenClaims {
+    pub iss: String,
+    pub aud: String,
+    pub exp: i64,
+    pub iat: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    #[serde(flatten)]
+    pub info: UserInfo,
+}
+
+///
+/// Sign an ID token for the client with the same EdDSA key as access
+/// tokens. It lives as long as an access token and its header names the
+/// key in the JWKS.
+///
+pub fn id_token(
+    state: &AppState,
+    client: &OAuthClient,
+    user: &User,
+    scope: &str,
+    nonce: &str,
+) -> AuthResult<String> {
+    let now = Utc::now();
+
+    let claims = IdTokenClaims {
+        iss: state.config.tokens.issuer.clone(),
+        aud: client.client_id.clone(),
+        exp: (now + Duration::minutes(state.config.tokens.access_ttl_mins)).timestamp(),
+        iat: now.timestamp(),
+        nonce: non_empty(nonce),
+        info: UserInfo::new(user, scope),
+    };
+
+    let mut header = Header::new(Algorithm::EdDSA);
+    header.kid = Some(state.jwt_public_jwk.kid.clone());
+
+    match encode(&header, &claims, &state.jwt_private_key) {
+        Ok(token) => Ok(token),
+        Err(err) => Err(AuthError::TokenError(err.to_string())),
+    }
+}
+
+///
+/// The OpenID Connect discovery document. Endpoints are assumed to be
+/// served from the root of the public url.
+///
+pub fn discovery(state: &AppState) -> AuthResult<Value> {
+    let base = match &state.config.urls.public_url {
+        Some(url) => url.trim_end_matches('/'),
+        None => return Err(AuthError::ConfigError("urls.public_url is not set".to_string())),
+    };
+
+    Ok(json!({
+        "issuer": state.config.tokens.issuer,
+        "authorization_endpoint": format!("{}/oauth/authorize", base),
+        "token_endpoint": format!("{}/oauth/token", base),
+        "device_authorization_endpoint": format!("{}/oauth/device_authorization", base),
+        "userinfo_endpoint": format!("{}/userinfo", base),
+        "introspection_endpoint": format!("{}/introspect", base),
+        "revocation_endpoint": format!("{}/revoke", base),
+        "jwks_uri": format!("{}/.well-known/jwks.json", base),
+        "scopes_supported": [SCOPE_OPENID, SCOPE_PROFILE, SCOPE_EMAIL],
+        "response_types_supported": [RESPONSE_TYPE_CODE],
+        "grant_types_supported": [
+            GRANT_AUTHORIZATION_CODE,
+            GRANT_REFRESH_TOKEN,
+            GRANT_CLIENT_CREDENTIALS,
+            GRANT_DEVICE_CODE,
+        ],
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": ["EdDSA"],
+        "token_endpoint_auth_methods_supported": [
+            "client_secret_basic",
+            "client_secret_post",
+            "none",
+        ],
+        "introspection_endpoint_auth_methods_supported": [
+            "client_secret_basic",
+            "client_secret_post",
+        ],
+        "revocation_endpoint_auth_methods_supported": [
+            "client_secret_basic",
+            "client_secret_post",
+        ],
+        "code_challenge_methods_supported": [PKCE_S256],
+        "claims_supported": [
+            "sub",
+            "iss",
+            "aud",
+            "exp",
+            "iat",
+            "nonce",
+            "name",
+            "given_name",
+            "family_name",
+            "preferred_username",
+            "locale",
+            "email",
+            "email_verified",
+        ],
+    }))
+}
+
+async fn discovery_handler(State(state): State<AppState>) -> AuthResult<Json<Value>> {
+    Ok(Json(discovery(&state)?))
+}
+
+async fn jwks_handler(State(state): State<AppState>) -> Json<Value> {
+    Json(json!({ "keys": [state.jwt_public_jwk] }))
+}
+
+async fn userinfo_handler(
+    State(state): State<AppState>,
+    JwtToken(claims): JwtToken,
+) -> AuthResult<Json<UserInfo>> {
+    check_token_type(&claims, &TokenType::Access)?;
+
+    if !claims.has_scope(SCOPE_OPENID) {
+        return Err(oauth_error(INSUFFICIENT_SCOPE, "the openid scope is required"));
+    }
+
+    let user = state.user_db.find_user_by_uuid(&claims.uuid).await?;
+
+    user.check_can_signin()?;
+
+    Ok(Json(UserInfo::new(&user, &claims.scope)))
+}
+
+///
+/// OpenID Connect discovery, keys and userinfo, alongside the
+/// `oauth_router` endpoints.
+///
+pub fn oidc_router() -> Router<AppState> {
+    Router::new()
+        .route("/.well-known/openid-configuration", get(discovery_handler))
+        .route("/.well-known/jwks.json", get(jwks_handler))
+        .route("/userinfo", get(userinfo_handler).post(userinfo_handler))
+}
@@ -0,0 +1,442 @@
+use std::{fmt, time::Duration};
+
+use askama::Template;
+use axum::{
+    extract::{Path, Query, State},
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, Pool, Sqlite};
+use tokio::task::JoinHandle;
+
+use crate::{
+    config::OutboxConfig,
+    email::{EmailTemplate, Mailer},
+    jwt::{AppState, JwtClaims, ServiceToken},
+    oauth::oauth_error,
+    oidc::INSUFFICIENT_SCOPE,
+    AuthError, AuthResult,
+};
+
+pub const OUTBOX_DEFAULT_MAX_ATTEMPTS: i64 = 8;
+pub const OUTBOX_DEFAULT_BASE_DELAY_SECS: i64 = 30;
+pub const OUTBOX_DEFAULT_MAX_DELAY_SECS: i64 = 6 * 60 * 60;
+pub const OUTBOX_BATCH_SIZE: i64 = 20;
+/// How long a worker holds the messages it has claimed before another
+/// worker may try them, in case the first one dies mid-batch
+pub const OUTBOX_CLAIM_SECS: i64 = 5 * 60;
+
+/// Scope a service needs to inspect and requeue outbox messages
+pub const SCOPE_OUTBOX: &str = "outbox";
+
+pub const OUTBOX_STATUS_PENDING: &str = "pending";
+pub const OUTBOX_STATUS_SENT: &str = "sent";
+pub const OUTBOX_STATUS_DEAD: &str = "dead";
+
+pub const CREATE_OUTBOX_TABLE_SQL: &'static str = r#"CREATE TABLE IF NOT EXISTS email_outbox (
+id INTEGER PRIMARY KEY AUTOINCREMENT,
+to_addr TEXT NOT NULL,
+subject TEXT NOT NULL,
+text_body TEXT NOT NULL DEFAULT '',
+body TEXT NOT NULL,
+status TEXT NOT NULL DEFAULT 'pending',
+attempts INTEGER NOT NULL DEFAULT 0,
+last_error TEXT NOT NULL DEFAULT '',
+next_attempt_on INTEGER NOT NULL,
+created_on INTEGER NOT NULL)"#;
+
+const OUTBOX_COLUMNS: &'static str = r#"id, to_addr, subject, text_body, body, status, attempts,
+last_error, next_attempt_on, created_on"#;
+
+const ENQUEUE_SQL: &'static str = r#"INSERT INTO email_outbox
+(to_addr, subject, text_body, body, status, next_attempt_on, created_on)
+VALUES($1, $2, $3, $4, 'pending', $5, $5)"#;
+
+// a single UPDATE is atomic, so pushing next_attempt_on past the claim
+// hides the rows from every other worker until they are sent or fail
+const CLAIM_DUE_MESSAGES_SQL: &'static str = r#"UPDATE email_outbox
+SET next_attempt_on = $2
+WHERE id IN (SELECT id FROM email_outbox
+    WHERE status = 'pending' AND next_attempt_on <= $1
+    ORDER BY next_attempt_on
+    LIMIT $3)
+RETURNING
+id, to_addr, subject, text_body, body, status, attempts, last_error, next_attempt_on, created_on"#;
+
+const MARK_SENT_SQL: &'static str = r#"UPDATE email_outbox
+SET status = 'sent', attempts = attempts + 1, last_error = ''
+WHERE id = $1"#;
+
+const MARK_FAILED_SQL: &'static str = r#"UPDATE email_outbox
+SET status = $2, attempts = $3, last_error = $4, next_attempt_on = $5
+WHERE id = $1"#;
+
+const REQUEUE_SQL: &'static str = r#"UPDATE email_outbox
+SET status = 'pending', attempts = 0, last_error = '', next_attempt_on = $2
+WHERE id = $1 AND status = 'dead'"#;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutboxStatus {
+    Pending,
+    Sent,
+    Dead,
+}
+
+impl fmt::Display for OutboxStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OutboxStatus::Pending => write!(f, "{}", OUTBOX_STATUS_PENDING),
+            OutboxStatus::Sent => write!(f, "{}", OUTBOX_STATUS_SENT),
+            OutboxStatus::Dead => write!(f, "{}", OUTBOX_STATUS_DEAD),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq, Clone, FromRow)]
+pub struct OutboxMessage {
+    pub id: i64,
+    pub to_addr: String,
+    pub subject: String,
+    #[serde(skip_serializing)]
+    pub text_body: String,
+    #[serde(skip_serializing)]
+    pub body: String,
+    pub status: String,
+    pub attempts: i64,
+    pub last_error: String,
+    pub next_attempt_on: i64,
+    pub created_on: i64,
+}
+
+///
+/// Persistent queue of outgoing emails. Handlers enqueue messages and
+/// a background worker (see `spawn_outbox_worker`) delivers them,
+/// retrying with exponential backoff until `max_attempts` is reached,
+/// at which point the message is dead-lettered.
+///
+#[derive(Clone)]
+pub struct Outbox {
+    pool: Pool<Sqlite>,
+    max_attempts: i64,
+    base_delay_secs: i64,
+    max_delay_secs: i64,
+}
+
+impl Outbox {
+    pub fn new(pool: Pool<Sqlite>) -> Self {
+        Self {
+            pool,
+            max_attempts: OUTBOX_DEFAULT_MAX_ATTEMPTS,
+            base_delay_secs: OUTBOX_DEFAULT_BASE_DELAY_SECS,
+            max_delay_secs: OUTBOX_DEFAULT_MAX_DELAY_SECS,
+        }
+    }
+
+    pub fn with_retries(
+        mut self,
+        max_attempts: i64,
+        base_delay_secs: i64,
+        max_delay_secs: i64,
+    ) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self.base_delay_secs = base_delay_secs.max(1);
+        self.max_delay_secs = max_delay_secs.max(self.base_delay_secs);
+        self
+    }
+
+    pub fn with_config(self, config: &OutboxConfig) -> Self {
+        self.with_retries(
+            config.max_attempts,
+            config.base_delay_secs,
+            config.max_delay_secs,
+        )
+    }
+
+    ///
+    /// Create the outbox table if it does not exist.
+    ///
+    pub async fn create_table(&self) -> AuthResult<()> {
+        sqlx::query(CREATE_OUTBOX_TABLE_SQL)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn enqueue_html_email<T: EmailTemplate>(
+        &self,
+        to: &str,
+        subject: &str,
+        body: &T,
+    ) -> AuthResult<i64> {
+        let html = match body.render() {
+            Ok(html) => html,
+            Err(err) => return Err(AuthError::MailerError(err.to_string())),
+        };
+
+        let text = match body.render_text(&html) {
+            Ok(text) => text,
+            Err(err) => return Err(AuthError::MailerError(err.to_string())),
+        };
+
+        self.enqueue(to, subject, &text, &html).await
+    }
+
+    pub async fn enqueue(
+        &self,
+        to: &str,
+        subject: &str,
+        text: &str,
+        html: &str,
+    ) -> AuthResult<i64> {
+        let result = sqlx::query(ENQUEUE_SQL)
+            .bind(to)
+            .bind(subject)
+            .bind(text)
+            .bind(html)
+            .bind(Utc::now().timestamp())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.last_insert_rowid())
+    }
+
+    pub async fn find_message(&self, id: i64) -> AuthResult<OutboxMessage> {
+        let sql = format!(
+            "SELECT {} FROM email_outbox WHERE id = $1",
+            OUTBOX_COLUMNS
+        );
+
+        match sqlx::query_as::<_, OutboxMessage>(&sql)
+            .bind(id)
+            .fetch_one(&self.pool)
+            .await
+        {
+            Ok(message) => Ok(message),
+            Err(_) => Err(AuthError::MailerError(format!(
+                "outbox message {} does not exist",
+                id
+            ))),
+        }
+    }
+
+    ///
+    /// List messages with a given status, most recent first.
+    ///
+    pub async fn list_messages(
+        &self,
+        status: &OutboxStatus,
+        limit: i64,
+    ) -> AuthResult<Vec<OutboxMessage>> {
+        let sql = format!(
+            "SELECT {} FROM email_outbox WHERE status = $1 ORDER BY created_on DESC LIMIT $2",
+            OUTBOX_COLUMNS
+        );
+
+        Ok(sqlx::query_as::<_, OutboxMessage>(&sql)
+            .bind(status.to_string())
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    ///
+    /// Move a dead-lettered message back into the queue so the worker
+    /// will attempt to deliver it again from scratch.
+    ///
+    pub async fn requeue(&self, id: i64) -> AuthResult<()> {
+        let result = sqlx::query(REQUEUE_SQL)
+            .bind(id)
+            .bind(Utc::now().timestamp())
+            .execute(&self.pool)
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(AuthError::MailerError(format!(
+                "outbox message {} is not dead-lettered",
+                id
+            )));
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Claim a batch of due messages for this worker. Claimed messages
+    /// are not due again until `OUTBOX_CLAIM_SECS` have passed, so
+    /// several workers or instances never send the same message at once.
+    ///
+    pub async fn claim_due(&self, now: i64) -> AuthResult<Vec<OutboxMessage>> {
+        Ok(sqlx::query_as::<_, OutboxMessage>(CLAIM_DUE_MESSAGES_SQL)
+            .bind(now)
+            .bind(now + OUTBOX_CLAIM_SECS)
+            .bind(OUTBOX_BATCH_SIZE)
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    async fn mark_sent(&self, id: i64) -> AuthResult<()> {
+        sqlx::query(MARK_SENT_SQL)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    ///
+    /// Record a failed delivery, either scheduling a retry or
+    /// dead-lettering the message once it has run out of attempts.
+    ///
+    pub async fn mark_failed(&self, message: &OutboxMessage, error: &str) -> AuthResult<()> {
+        let attempts = message.attempts + 1;
+
+        let status = if attempts >= self.max_attempts {
+            OutboxStatus::Dead
+        } else {
+            OutboxStatus::Pending
+        };
+
+        let next_attempt_on = Utc::now().timestamp() + self.backoff_secs(attempts);
+
+        sqlx::query(MARK_FAILED_SQL)
+            .bind(message.id)
+            .bind(status.to_string())
+            .bind(attempts)
+            .bind(error)
+            .bind(next_attempt_on)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    ///
+    /// Delay before the next attempt, doubling with each failure
+    /// and capped at `max_delay_secs`.
+    ///
+    pub fn backoff_secs(&self, attempts: i64) -> i64 {
+        let exp = (attempts - 1).clamp(0, 30) as u32;
+
+        self.base_delay_secs
+            .saturating_mul(2_i64.pow(exp))
+            .min(self.max_delay_secs)
+    }
+
+    ///
+    /// Attempt to deliver every message currently due. Returns the
+    /// number of messages that were sent.
+    ///
+    pub async fn process_due(&self, mailer: &Mailer) -> AuthResult<usize> {
+        let mut sent = 0;
+
+        for message in self.claim_due(Utc::now().timestamp()).await? {
+            let m = mailer.clone();
+            let to = message.to_addr.clone();
+            let subject = message.subject.clone();
+            let text = message.text_body.clone();
+            let html = message.body.clone();
+
+            // lettre's SmtpTransport is blocking so keep it off the runtime threads
+            let result = tokio::task::spawn_blocking(move || {
+                m.send_multipart_email(&to, &subject, &text, &html)
+            })
+            .await?;
+
+            match result {
+                Ok(_) => {
+                    self.mark_sent(message.id).await?;
+                    sent += 1;
+                }
+                Err(err) => {
+                    eprintln!("outbox message {} failed: {}", message.id, err);
+                    self.mark_failed(&message, &err.to_string()).await?
+                }
+            }
+        }
+
+        Ok(sent)
+    }
+}
+
+///
+/// Run the outbox delivery loop in the background, polling for due
+/// messages every `poll_interval`.
+///
+pub fn spawn_outbox_worker(
+    outbox: Outbox,
+    mailer: Mailer,
+    poll_interval: Duration,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(poll_interval);
+
+        loop {
+            interval.tick().await;
+
+            if let Err(err) = outbox.process_due(&mailer).await {
+                eprintln!("outbox worker error: {}", err);
+            }
+        }
+    })
+}
+
+#[derive(Deserialize, Debug)]
+pub struct OutboxQuery {
+    pub status: Option<OutboxStatus>,
+    pub limit: Option<i64>,
+}
+
+///
+/// Messages hold users' email addresses, so only services granted the
+/// outbox scope may see them.
+///
+pub fn check_outbox_token(claims: &JwtClaims) -> AuthResult<()> {
+    if !claims.has_scope(SCOPE_OUTBOX) {
+        return Err(oauth_error(INSUFFICIENT_SCOPE, "the outbox scope is required"));
+    }
+
+    Ok(())
+}
+
+async fn list_messages_handler(
+    State(state): State<AppState>,
+    ServiceToken(claims): ServiceToken,
+    Query(query): Query<OutboxQuery>,
+) -> AuthResult<Json<Vec<OutboxMessage>>> {
+    check_outbox_token(&claims)?;
+
+    let status = query.status.unwrap_or(OutboxStatus::Dead);
+
+    Ok(Json(
+        state
+            .outbox
+            .list_messages(&status, query.limit.unwrap_or(100))
+            .await?,
+    ))
+}
+
+async fn requeue_handler(
+    State(state): State<AppState>,
+    ServiceToken(claims): ServiceToken,
+    Path(id): Path<i64>,
+) -> AuthResult<Json<OutboxMessage>> {
+    check_outbox_token(&claims)?;
+
+    state.outbox.requeue(id).await?;
+
+    Ok(Json(state.outbox.find_message(id).await?))
+}
+
+///
+/// Routes for inspecting and requeuing outbox messages, for services
+/// holding a token with the outbox scope.
+///
+pub fn outbox_router() -> Router<AppState> {
+    Router::new()
+        .route("/messages", get(list_messages_handler))
+        .route("/messages/:id/requeue", post(requeue_handler))
+}
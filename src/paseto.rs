@@ -4,11 +4,9 @@ use rusty_paseto::{
     prelude::PasetoBuilder,
 };
 
-use ed25519_dalek::{SigningKey, VerifyingKey};
-use rand::rngs::OsRng;
 use time::{format_description::well_known::Rfc3339, OffsetDateTime};
 
-use crate::{jwt::TokenType, AuthResult};
+use crate::{jwt::TokenType, keys::KeyPair, AuthResult};
 
 //PASETO: Platform-Agnostic Security Tokens
 
@@ -60,17 +58,12 @@ pub fn base_pasesto(
  * as hex, so we can use the ed25519-dalek lib to create this for us.
  */
 pub fn generate_key() {
-    let mut csprng = OsRng;
-    let signing_key: SigningKey = SigningKey::generate(&mut csprng);
+    let key_pair = KeyPair::generate();
 
-    let verifying_key: VerifyingKey = signing_key.verifying_key();
+    let private_hex = key_pair.private_hex();
 
-    println!("private {}", hex::encode(signing_key.to_bytes()));
-    println!("public {}", hex::encode(verifying_key.to_bytes()));
-
-    println!(
-        "sign {}{}",
-        hex::encode(signing_key.to_bytes()),
-        hex::encode(verifying_key.to_bytes())
-    );
+    // the first 32 bytes are the secret, the rest the public key
+    println!("private {}", &private_hex[..64]);
+    println!("public {}", key_pair.public_key().hex());
+    println!("sign {}", private_hex);
 }
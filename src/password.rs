@@ -0,0 +1,178 @@
+use axum::{extract::State, http::StatusCode, routing::post, Json, Router};
+use serde::Deserialize;
+
+use crate::{
+    check_otp_valid,
+    email::{
+        callback_link, EmailPasswordUpdatedTemplate, EmailResetPasswordTemplate,
+        EmailResetPasswordWebTemplate, EmailSwitchToPasswordlessTemplate,
+    },
+    i18n::Messages,
+    jwt::{reset_password_jwt, AppState, FirstPartyToken, JwtClaims, JwtToken, TokenType},
+    signin::{check_token_type, email_callback_url, EmailLinkReq},
+    AuthError, AuthResult, User,
+};
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PasswordUpdateReq {
+    pub password: String,
+}
+
+///
+/// Email a one time password reset token, either as a link for web
+/// clients or as a code for API clients.
+///
+pub async fn send_reset_password_email(
+    state: &AppState,
+    user: &User,
+    callback_url: Option<&str>,
+    url: Option<&str>,
+) -> AuthResult<()> {
+    if !state.config.features.password_reset {
+        return Err(AuthError::FeatureDisabledError("password reset".to_string()));
+    }
+
+    let t = Messages::for_user(user);
+    let token = reset_password_jwt(user, &state.config.tokens, &state.jwt_private_key)?;
+    let time = t.minutes(state.config.tokens.reset_password_ttl_mins);
+    let subject = t.get("password.reset.subject");
+
+    match email_callback_url(&state.config, callback_url) {
+        Some(callback_url) => {
+            let body = EmailResetPasswordWebTemplate {
+                name: user.display_name(),
+                link: callback_link(callback_url, &token, url)?,
+                time,
+                t,
+            };
+
+            state.outbox.enqueue_html_email(&user.email, subject, &body).await?;
+        }
+        None => {
+            let body = EmailResetPasswordTemplate {
+                name: user.display_name(),
+                link: token,
+                time,
+                t,
+            };
+
+            state.outbox.enqueue_html_email(&user.email, subject, &body).await?;
+        }
+    }
+
+    Ok(())
+}
+
+///
+/// Set a new password using a reset token. The token's otp is tied to
+/// the user's last update so each token can only be used once.
+///
+pub async fn reset_password(
+    state: &AppState,
+    claims: &JwtClaims,
+    password: &str,
+) -> AuthResult<User> {
+    if !state.config.features.password_reset {
+        return Err(AuthError::FeatureDisabledError("password reset".to_string()));
+    }
+
+    check_token_type(claims, &TokenType::ResetPassword)?;
+
+    let user = state.user_db.find_user_by_uuid(&claims.uuid).await?;
+
+    if !check_otp_valid(&user, &claims.otp) {
+        return Err(AuthError::TokenError(
+            "reset password token has already been used".to_string(),
+        ));
+    }
+
+    state.user_db.update_password(&user.uuid, password).await?;
+
+    let t = Messages::for_user(&user);
+    let subject = t.get("password.updated.subject");
+
+    let body = EmailPasswordUpdatedTemplate {
+        name: user.display_name(),
+        t,
+    };
+
+    state.outbox.enqueue_html_email(&user.email, subject, &body).await?;
+
+    Ok(user)
+}
+
+///
+/// Switch an account to passwordless only sign in. Password sign in is
+/// refused from then on and the user signs in with emailed links.
+///
+pub async fn switch_to_passwordless(state: &AppState, user: &User) -> AuthResult<()> {
+    state.user_db.set_passwordless_only(&user.uuid, true).await?;
+
+    let t = Messages::for_user(user);
+    let subject = t.get("password.switch_to_passwordless.subject");
+
+    let body = EmailSwitchToPasswordlessTemplate {
+        name: user.display_name(),
+        t,
+    };
+
+    state.outbox.enqueue_html_email(&user.email, subject, &body).await?;
+
+    Ok(())
+}
+
+///
+/// Email a reset token if the account exists. Unknown accounts succeed
+/// too so the response does not reveal who has an account, as do
+/// directory accounts, whose passwords are changed in the directory.
+///
+pub async fn request_password_reset(state: &AppState, req: &EmailLinkReq) -> AuthResult<()> {
+    let user = match state.user_db.find_user_by_id(&req.username).await {
+        Ok(user) => user,
+        Err(AuthError::UserDoesNotExistError(_)) => return Ok(()),
+        Err(err) => return Err(err),
+    };
+
+    if state.user_db.is_directory_user(&user.uuid).await? {
+        return Ok(());
+    }
+
+    send_reset_password_email(state, &user, req.callback_url.as_deref(), req.url.as_deref()).await
+}
+
+async fn reset_email_handler(
+    State(state): State<AppState>,
+    Json(req): Json<EmailLinkReq>,
+) -> AuthResult<StatusCode> {
+    request_password_reset(&state, &req).await?;
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+async fn reset_handler(
+    State(state): State<AppState>,
+    JwtToken(claims): JwtToken,
+    Json(req): Json<PasswordUpdateReq>,
+) -> AuthResult<StatusCode> {
+    reset_password(&state, &claims, &req.password).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn switch_to_passwordless_handler(
+    State(state): State<AppState>,
+    FirstPartyToken(claims): FirstPartyToken,
+) -> AuthResult<StatusCode> {
+    let user = state.user_db.find_user_by_uuid(&claims.uuid).await?;
+
+    switch_to_passwordless(&state, &user).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub fn password_router() -> Router<AppState> {
+    Router::new()
+        .route("/reset/email", post(reset_email_handler))
+        .route("/reset", post(reset_handler))
+        .route("/passwordless", post(switch_to_passwordless_handler))
+}
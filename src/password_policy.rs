@@ -0,0 +1,360 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{breach::BreachedPasswords, AuthError, AuthResult};
+
+pub const PASSWORD_DEFAULT_MIN_LENGTH: usize = 8;
+// long enough for any passphrase, short enough to bound hashing cost
+pub const PASSWORD_DEFAULT_MAX_LENGTH: usize = 128;
+pub const PASSWORD_DEFAULT_MIN_STRENGTH: u8 = 2;
+pub const PASSWORD_MAX_STRENGTH: u8 = 4;
+pub const PASSWORD_DEFAULT_BREACH_THRESHOLD: u64 = 1;
+pub const PASSWORD_DEFAULT_HISTORY_SIZE: usize = 5;
+
+// user inputs shorter than this are too likely to occur by chance
+const MIN_USER_INPUT_LEN: usize = 3;
+
+// score thresholds in bits, roughly zxcvbn's 10^3, 10^6, 10^8 and 10^10
+// guesses
+const STRENGTH_THRESHOLDS: [f64; 4] = [10.0, 20.0, 27.0, 33.0];
+
+// bits for a match against the common password list or user inputs,
+// about log2 of the number of entries
+const DICTIONARY_BITS: f64 = 6.0;
+
+const KEYBOARD_ROWS: [&str; 4] = ["1234567890", "qwertyuiop", "asdfghjkl", "zxcvbnm"];
+
+const COMMON_PASSWORDS: &[&str] = &[
+    "password", "passw0rd", "123456", "12345678", "123456789", "qwerty", "abc123", "111111",
+    "letmein", "welcome", "monkey", "dragon", "master", "sunshine", "princess", "football",
+    "baseball", "iloveyou", "trustno1", "superman", "batman", "shadow", "michael", "jennifer",
+    "hunter", "charlie", "donald", "freedom", "whatever", "starwars", "computer", "internet",
+    "secret", "summer", "winter", "spring", "autumn", "hello", "login", "admin", "administrator",
+    "root", "access", "changeme", "default", "guest", "qazwsx", "zaq12wsx", "mustang", "ninja",
+    "pokemon", "soccer", "hockey", "killer", "cheese", "flower", "banana", "orange", "purple",
+    "family", "london", "google", "matrix",
+];
+
+///
+/// Rules a new password must satisfy. Loaded as the `[passwords]`
+/// section of the config.
+///
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub max_length: usize,
+    pub require_lowercase: bool,
+    pub require_uppercase: bool,
+    pub require_digit: bool,
+    pub require_symbol: bool,
+    /// Reject passwords containing the username, email or names
+    pub disallow_user_info: bool,
+    /// Minimum estimated strength from 0 (trivial) to 4 (very strong)
+    pub min_strength: u8,
+    /// Sorted `HASH:COUNT` SHA-1 file in the Pwned Passwords format
+    pub breached_passwords_file: Option<String>,
+    /// Reject passwords seen in at least this many breaches
+    pub breach_threshold: u64,
+    /// Number of previous password hashes kept per user and checked
+    /// against, 0 to allow reuse
+    pub history_size: usize,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            min_length: PASSWORD_DEFAULT_MIN_LENGTH,
+            max_length: PASSWORD_DEFAULT_MAX_LENGTH,
+            require_lowercase: false,
+            require_uppercase: false,
+            require_digit: false,
+            require_symbol: false,
+            disallow_user_info: true,
+            min_strength: PASSWORD_DEFAULT_MIN_STRENGTH,
+            breached_passwords_file: None,
+            breach_threshold: PASSWORD_DEFAULT_BREACH_THRESHOLD,
+            history_size: PASSWORD_DEFAULT_HISTORY_SIZE,
+        }
+    }
+}
+
+///
+/// A rule a password failed, serialized as e.g.
+/// `{"rule": "too_short", "min": 8}`.
+///
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "rule", rename_all = "snake_case")]
+pub enum PasswordRule {
+    TooShort { min: usize },
+    TooLong { max: usize },
+    MissingLowercase,
+    MissingUppercase,
+    MissingDigit,
+    MissingSymbol,
+    ContainsUserInfo,
+    TooWeak { score: u8, min: u8 },
+    Breached { count: u64 },
+    RecentlyUsed { history: usize },
+}
+
+impl fmt::Display for PasswordRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PasswordRule::TooShort { min } => write!(f, "must be at least {} characters", min),
+            PasswordRule::TooLong { max } => write!(f, "must be at most {} characters", max),
+            PasswordRule::MissingLowercase => write!(f, "must contain a lowercase letter"),
+            PasswordRule::MissingUppercase => write!(f, "must contain an uppercase letter"),
+            PasswordRule::MissingDigit => write!(f, "must contain a digit"),
+            PasswordRule::MissingSymbol => write!(f, "must contain a symbol"),
+            PasswordRule::ContainsUserInfo => {
+                write!(f, "must not contain your username, email or name")
+            }
+            PasswordRule::TooWeak { score, min } => {
+                write!(f, "is too easy to guess (strength {} of {})", score, min)
+            }
+            PasswordRule::Breached { count } => {
+                write!(f, "has appeared in {} known data breaches", count)
+            }
+            PasswordRule::RecentlyUsed { history } => {
+                write!(f, "must not be one of your last {} passwords", history)
+            }
+        }
+    }
+}
+
+impl PasswordPolicy {
+    ///
+    /// Every rule `password` fails. `user_inputs` are the username, email
+    /// and names of the account the password is for.
+    ///
+    pub fn violations(&self, password: &str, user_inputs: &[&str]) -> Vec<PasswordRule> {
+        let mut rules: Vec<PasswordRule> = Vec::new();
+
+        let length = password.chars().count();
+
+        if length < self.min_length {
+            rules.push(PasswordRule::TooShort {
+                min: self.min_length,
+            });
+        }
+
+        if length > self.max_length {
+            rules.push(PasswordRule::TooLong {
+                max: self.max_length,
+            });
+        }
+
+        if self.require_lowercase && !password.chars().any(|c| c.is_lowercase()) {
+            rules.push(PasswordRule::MissingLowercase);
+        }
+
+        if self.require_uppercase && !password.chars().any(|c| c.is_uppercase()) {
+            rules.push(PasswordRule::MissingUppercase);
+        }
+
+        if self.require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+            rules.push(PasswordRule::MissingDigit);
+        }
+
+        if self.require_symbol && !password.chars().any(|c| !c.is_alphanumeric()) {
+            rules.push(PasswordRule::MissingSymbol);
+        }
+
+        if self.disallow_user_info && contains_user_input(password, user_inputs) {
+            rules.push(PasswordRule::ContainsUserInfo);
+        }
+
+        let score = estimate_strength(password, user_inputs);
+
+        if score < self.min_strength {
+            rules.push(PasswordRule::TooWeak {
+                score,
+                min: self.min_strength,
+            });
+        }
+
+        rules
+    }
+
+    pub fn breached_passwords(&self) -> Option<BreachedPasswords> {
+        self.breached_passwords_file.as_ref().map(BreachedPasswords::new)
+    }
+
+    ///
+    /// The breach rule if `password` is in the breached password file
+    /// often enough to be rejected. This reads from disk, so async callers
+    /// should run it with `spawn_blocking`.
+    ///
+    pub fn check_breached(&self, password: &str) -> AuthResult<Option<PasswordRule>> {
+        let breached = match self.breached_passwords() {
+            Some(breached) => breached,
+            None => return Ok(None),
+        };
+
+        let count = breached.count(password)?;
+
+        if count >= self.breach_threshold {
+            Ok(Some(PasswordRule::Breached { count }))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn check(&self, password: &str, user_inputs: &[&str]) -> AuthResult<()> {
+        let rules = self.violations(password, user_inputs);
+
+        if rules.is_empty() {
+            Ok(())
+        } else {
+            Err(AuthError::PasswordPolicyError(rules))
+        }
+    }
+}
+
+///
+/// The parts of an account a password should not contain. Emails
+/// contribute both the full address and the part before the @.
+///
+pub fn user_inputs<'a>(
+    username: &'a str,
+    email: &'a str,
+    first_name: &'a str,
+    last_name: &'a str,
+) -> Vec<&'a str> {
+    let mut inputs = vec![username, email, first_name, last_name];
+
+    if let Some((local, _)) = email.split_once('@') {
+        inputs.push(local);
+    }
+
+    inputs
+        .into_iter()
+        .filter(|input| input.chars().count() >= MIN_USER_INPUT_LEN)
+        .collect()
+}
+
+fn contains_user_input(password: &str, user_inputs: &[&str]) -> bool {
+    let password = normalize(password);
+
+    user_inputs
+        .iter()
+        .filter(|input| input.chars().count() >= MIN_USER_INPUT_LEN)
+        .any(|input| password.contains(&normalize(input)))
+}
+
+///
+/// Lowercase and undo common leet substitutions so `P@ssw0rd` matches
+/// `password`.
+///
+fn normalize(s: &str) -> String {
+    s.chars()
+        .map(|c| match c.to_ascii_lowercase() {
+            '4' | '@' => 'a',
+            '3' => 'e',
+            '1' | '!' => 'i',
+            '0' => 'o',
+            '5' | '$' => 's',
+            '7' => 't',
+            c => c,
+        })
+        .collect()
+}
+
+fn pool_bits(c: char) -> f64 {
+    let pool: f64 = if c.is_ascii_alphabetic() {
+        26.0
+    } else if c.is_ascii_digit() {
+        10.0
+    } else if c.is_ascii() {
+        33.0
+    } else {
+        // treat other scripts as a large alphabet
+        100.0
+    };
+
+    pool.log2()
+}
+
+fn keyboard_adjacent(a: char, b: char) -> bool {
+    let (a, b) = (a.to_ascii_lowercase(), b.to_ascii_lowercase());
+
+    KEYBOARD_ROWS.iter().any(|row| {
+        match (row.find(a), row.find(b)) {
+            (Some(i), Some(j)) => i.abs_diff(j) == 1,
+            _ => false,
+        }
+    })
+}
+
+///
+/// Characters that continue a repeat (`aaa`), sequence (`abc`, `321`) or
+/// keyboard walk (`qwer`) are nearly free to guess.
+///
+fn predictable(prev: char, c: char) -> bool {
+    let step = c as i64 - prev as i64;
+
+    step.abs() <= 1 || keyboard_adjacent(prev, c)
+}
+
+///
+/// Estimate how hard a password is to guess on zxcvbn's 0 to 4 scale.
+/// Like zxcvbn it looks for dictionary words, the user's own details,
+/// repeats, sequences and keyboard walks rather than just counting
+/// character classes, but it is a much smaller model.
+///
+pub fn estimate_strength(password: &str, user_inputs: &[&str]) -> u8 {
+    let chars: Vec<char> = password.chars().collect();
+
+    // bits contributed by each character
+    let mut bits: Vec<f64> = chars
+        .iter()
+        .enumerate()
+        .map(|(i, c)| {
+            if i > 0 && predictable(chars[i - 1], *c) {
+                1.0
+            } else {
+                pool_bits(*c)
+            }
+        })
+        .collect();
+
+    // a dictionary word or user detail costs about as much as picking it
+    // from the list, however long it is
+    let normalized: Vec<char> = normalize(password).chars().collect();
+
+    let words = COMMON_PASSWORDS
+        .iter()
+        .map(|word| normalize(word))
+        .chain(user_inputs.iter().map(|input| normalize(input)))
+        .filter(|word| word.chars().count() >= MIN_USER_INPUT_LEN);
+
+    for word in words {
+        let word: Vec<char> = word.chars().collect();
+
+        if word.len() > normalized.len() {
+            continue;
+        }
+
+        for start in 0..=normalized.len() - word.len() {
+            let end = start + word.len();
+
+            if normalized[start..end] == word[..] {
+                let span: f64 = bits[start..end].iter().sum();
+
+                if span > DICTIONARY_BITS {
+                    bits[start] = DICTIONARY_BITS;
+                    bits[start + 1..end].iter_mut().for_each(|b| *b = 0.0);
+                }
+            }
+        }
+    }
+
+    let total: f64 = bits.iter().sum();
+
+    STRENGTH_THRESHOLDS
+        .iter()
+        .filter(|threshold| total >= **threshold)
+        .count() as u8
+}
@@ -0,0 +1,1086 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write,
+};
+
+use axum::{
+    extract::{Query, State},
+    http::{header::CONTENT_TYPE, HeaderName},
+    routing::{get, post},
+    Form, Json, Router,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use flate2::{write::DeflateEncoder, Compression};
+use roxmltree::{Document, Node, NodeId, NodeType};
+use rsa::{
+    pkcs1v15::{Signature, VerifyingKey},
+    pkcs8::DecodePublicKey,
+    signature::Verifier,
+    RsaPublicKey,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use url::Url;
+use x509_cert::{
+    der::{Decode, Encode},
+    Certificate,
+};
+
+use crate::{
+    config::{AuthConfig, SamlAttributes, SamlConfig},
+    jwt::AppState,
+    oauth::random_token,
+    signin::{tokens, TokensResp},
+    AuthError, AuthResult, Credentials, User, UserDb,
+};
+
+pub const SAML_PROTOCOL_NS: &str = "urn:oasis:names:tc:SAML:2.0:protocol";
+pub const SAML_ASSERTION_NS: &str = "urn:oasis:names:tc:SAML:2.0:assertion";
+pub const SAML_METADATA_NS: &str = "urn:oasis:names:tc:SAML:2.0:metadata";
+pub const XMLDSIG_NS: &str = "http://www.w3.org/2000/09/xmldsig#";
+
+pub const EXC_C14N: &str = "http://www.w3.org/2001/10/xml-exc-c14n#";
+pub const ENVELOPED_SIGNATURE: &str = "http://www.w3.org/2000/09/xmldsig#enveloped-signature";
+pub const RSA_SHA256: &str = "http://www.w3.org/2001/04/xmldsig-more#rsa-sha256";
+pub const SHA256_DIGEST: &str = "http://www.w3.org/2001/04/xmlenc#sha256";
+
+const STATUS_SUCCESS: &str = "urn:oasis:names:tc:SAML:2.0:status:Success";
+const BEARER: &str = "urn:oasis:names:tc:SAML:2.0:cm:bearer";
+const HTTP_POST_BINDING: &str = "urn:oasis:names:tc:SAML:2.0:bindings:HTTP-POST";
+
+pub const SAML_DEFAULT_CLOCK_SKEW_SECS: i64 = 120;
+
+// long enough to sign in at the identity provider, including any 2FA
+pub const SAML_REQUEST_TTL_MINS: i64 = 10;
+
+pub const CREATE_SAML_REQUESTS_TABLE_SQL: &'static str =
+    r#"CREATE TABLE IF NOT EXISTS saml_requests (
+id INTEGER PRIMARY KEY AUTOINCREMENT,
+request_id TEXT NOT NULL UNIQUE,
+expires INTEGER NOT NULL,
+created_on TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP)"#;
+
+pub const CREATE_SAML_ASSERTIONS_TABLE_SQL: &'static str =
+    r#"CREATE TABLE IF NOT EXISTS saml_assertions (
+id INTEGER PRIMARY KEY AUTOINCREMENT,
+assertion_id TEXT NOT NULL UNIQUE,
+expires INTEGER NOT NULL,
+created_on TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP)"#;
+
+pub const CREATE_SAML_USERS_TABLE_SQL: &'static str = r#"CREATE TABLE IF NOT EXISTS saml_users (
+id INTEGER PRIMARY KEY AUTOINCREMENT,
+user_uuid TEXT NOT NULL UNIQUE,
+idp TEXT NOT NULL,
+name_id TEXT NOT NULL,
+created_on TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+UNIQUE(idp, name_id))"#;
+
+const DELETE_EXPIRED_SAML_REQUESTS_SQL: &'static str =
+    r#"DELETE FROM saml_requests WHERE expires < $1"#;
+
+const CREATE_SAML_REQUEST_SQL: &'static str = r#"INSERT INTO saml_requests
+(request_id, expires)
+VALUES($1, $2)"#;
+
+// requests are deleted as they are answered so each can only be used once
+const TAKE_SAML_REQUEST_SQL: &'static str = r#"DELETE FROM saml_requests
+WHERE request_id = $1
+RETURNING expires"#;
+
+// an expired assertion is rejected anyway, so it need not be remembered
+const DELETE_EXPIRED_SAML_ASSERTIONS_SQL: &'static str =
+    r#"DELETE FROM saml_assertions WHERE expires < $1"#;
+
+const USE_SAML_ASSERTION_SQL: &'static str = r#"INSERT INTO saml_assertions
+(assertion_id, expires)
+VALUES($1, $2)"#;
+
+const FIND_SAML_USER_SQL: &'static str =
+    r#"SELECT user_uuid FROM saml_users WHERE saml_users.idp = $1 AND saml_users.name_id = $2"#;
+
+const LINK_SAML_USER_SQL: &'static str = r#"INSERT INTO saml_users
+(user_uuid, idp, name_id)
+VALUES($1, $2, $3)"#;
+
+///
+/// What a validated assertion says about the user.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SamlAssertion {
+    pub id: String,
+    pub name_id: String,
+    /// The request being answered, `None` if the sign in was started at
+    /// the identity provider
+    pub in_response_to: Option<String>,
+    pub session_index: Option<String>,
+    /// Unix time after which the assertion must not be accepted
+    pub expires: i64,
+    pub attributes: HashMap<String, Vec<String>>,
+}
+
+///
+/// A user's details, mapped from an assertion's attributes.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SamlUser {
+    pub name_id: String,
+    pub username: String,
+    pub email: String,
+    pub first_name: String,
+    pub last_name: String,
+}
+
+fn saml_error(error: impl ToString) -> AuthError {
+    AuthError::SamlError(error.to_string())
+}
+
+fn crypto_error(error: impl ToString) -> AuthError {
+    AuthError::CryptographyError(error.to_string())
+}
+
+impl SamlAttributes {
+    pub fn user(&self, assertion: &SamlAssertion) -> AuthResult<SamlUser> {
+        let attr = |name: &str| match assertion.attributes.get(name).and_then(|v| v.first()) {
+            Some(value) => value.trim().to_string(),
+            None => String::new(),
+        };
+
+        let username = match &self.username {
+            Some(name) => attr(name),
+            None => assertion.name_id.clone(),
+        };
+
+        if username.is_empty() {
+            return Err(saml_error(format!(
+                "the assertion for {} has no username",
+                assertion.name_id
+            )));
+        }
+
+        Ok(SamlUser {
+            name_id: assertion.name_id.clone(),
+            email: attr(&self.email),
+            first_name: attr(&self.first_name),
+            last_name: attr(&self.last_name),
+            username,
+        })
+    }
+}
+
+///
+/// The RSA key of the identity provider's certificate. The certificate is
+/// trusted because it is configured, so only its key is used.
+///
+pub fn idp_public_key(certificate: &str) -> AuthResult<RsaPublicKey> {
+    // metadata has the bare base64 of the certificate rather than PEM
+    let base64: String = certificate
+        .lines()
+        .filter(|line| !line.starts_with("-----"))
+        .flat_map(|line| line.split_whitespace())
+        .collect();
+
+    let der = STANDARD.decode(base64).map_err(crypto_error)?;
+    let certificate = Certificate::from_der(&der).map_err(crypto_error)?;
+
+    let spki = certificate
+        .tbs_certificate
+        .subject_public_key_info
+        .to_der()
+        .map_err(crypto_error)?;
+
+    RsaPublicKey::from_public_key_der(&spki).map_err(crypto_error)
+}
+
+// canonical XML escapes text and attribute values differently
+fn escape_text(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '\r' => escaped.push_str("&#xD;"),
+            _ => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+fn escape_attribute(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\t' => escaped.push_str("&#x9;"),
+            '\n' => escaped.push_str("&#xA;"),
+            '\r' => escaped.push_str("&#xD;"),
+            _ => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+fn qname_prefix(qname: &str) -> &str {
+    qname.split_once(':').map_or("", |(prefix, _)| prefix)
+}
+
+// roxmltree resolves prefixes to namespaces, but canonical XML keeps the
+// prefixes as written
+fn element_qname<'input>(node: Node<'_, 'input>) -> &'input str {
+    let tag = &node.document().input_text()[node.range()];
+    let end = tag
+        .find(|c: char| c.is_whitespace() || c == '/' || c == '>')
+        .unwrap_or(tag.len());
+
+    &tag[1..end]
+}
+
+///
+/// Exclusive XML canonicalization, without comments, of `node` leaving out
+/// the `exclude` subtree as the enveloped signature transform requires.
+/// `inclusive` prefixes, from an `InclusiveNamespaces` list, are rendered
+/// wherever they are in scope rather than only where they are used.
+///
+pub fn canonicalize(node: Node, exclude: Option<NodeId>, inclusive: &[String]) -> String {
+    let mut out = String::new();
+
+    write_canonical(node, exclude, inclusive, &mut Vec::new(), &mut out);
+
+    out
+}
+
+fn write_canonical(
+    node: Node,
+    exclude: Option<NodeId>,
+    inclusive: &[String],
+    rendered: &mut Vec<(String, String)>,
+    out: &mut String,
+) {
+    if Some(node.id()) == exclude {
+        return;
+    }
+
+    match node.node_type() {
+        NodeType::Element => (),
+        NodeType::Text => {
+            out.push_str(&escape_text(node.text().unwrap_or("")));
+            return;
+        }
+        NodeType::PI => {
+            if let Some(pi) = node.pi() {
+                out.push_str("<?");
+                out.push_str(pi.target);
+
+                if let Some(value) = pi.value {
+                    out.push(' ');
+                    out.push_str(value);
+                }
+
+                out.push_str("?>");
+            }
+
+            return;
+        }
+        // comments are left out
+        _ => return,
+    }
+
+    let input = node.document().input_text();
+    let qname = element_qname(node);
+
+    // namespaces are only rendered where they are used, by the element's
+    // own name or its attributes' names
+    let mut prefixes = vec![qname_prefix(qname)];
+
+    for attribute in node.attributes() {
+        match qname_prefix(&input[attribute.range_qname()]) {
+            // unprefixed attributes are in no namespace, not the default
+            "" | "xml" => (),
+            prefix => prefixes.push(prefix),
+        }
+    }
+
+    prefixes.extend(inclusive.iter().map(String::as_str));
+    prefixes.sort_unstable();
+    prefixes.dedup();
+
+    let depth = rendered.len();
+
+    out.push('<');
+    out.push_str(qname);
+
+    for prefix in prefixes {
+        let uri = node
+            .lookup_namespace_uri(Some(prefix).filter(|prefix| !prefix.is_empty()))
+            .unwrap_or("");
+
+        // skip namespaces an output ancestor already declared
+        let current = rendered
+            .iter()
+            .rev()
+            .find(|(rendered_prefix, _)| rendered_prefix == prefix)
+            .map_or("", |(_, uri)| uri.as_str());
+
+        if uri == current {
+            continue;
+        }
+
+        out.push_str(" xmlns");
+
+        if !prefix.is_empty() {
+            out.push(':');
+            out.push_str(prefix);
+        }
+
+        out.push_str("=\"");
+        out.push_str(&escape_attribute(uri));
+        out.push('"');
+
+        rendered.push((prefix.to_string(), uri.to_string()));
+    }
+
+    let mut attributes: Vec<_> = node.attributes().collect();
+    attributes.sort_by_key(|attribute| (attribute.namespace().unwrap_or(""), attribute.name()));
+
+    for attribute in attributes {
+        out.push(' ');
+        out.push_str(&input[attribute.range_qname()]);
+        out.push_str("=\"");
+        out.push_str(&escape_attribute(attribute.value()));
+        out.push('"');
+    }
+
+    out.push('>');
+
+    for child in node.children() {
+        write_canonical(child, exclude, inclusive, rendered, out);
+    }
+
+    out.push_str("</");
+    out.push_str(qname);
+    out.push('>');
+
+    rendered.truncate(depth);
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, ns: &str, name: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|child| child.has_tag_name((ns, name)))
+}
+
+fn required_child<'a, 'input>(
+    node: Node<'a, 'input>,
+    ns: &str,
+    name: &str,
+) -> AuthResult<Node<'a, 'input>> {
+    match child(node, ns, name) {
+        Some(child) => Ok(child),
+        None => Err(saml_error(format!(
+            "{} has no {}",
+            node.tag_name().name(),
+            name
+        ))),
+    }
+}
+
+fn text(node: Node) -> String {
+    node.text().unwrap_or("").trim().to_string()
+}
+
+fn algorithm<'a>(node: Node<'a, '_>) -> &'a str {
+    node.attribute("Algorithm").unwrap_or("")
+}
+
+fn timestamp(node: Node, name: &str) -> AuthResult<Option<i64>> {
+    match node.attribute(name) {
+        Some(value) => match DateTime::parse_from_rfc3339(value) {
+            Ok(time) => Ok(Some(time.timestamp())),
+            Err(_) => Err(saml_error(format!("{} {} is not a valid time", name, value))),
+        },
+        None => Ok(None),
+    }
+}
+
+fn inclusive_prefixes(node: Node) -> Vec<String> {
+    match child(node, EXC_C14N, "InclusiveNamespaces").and_then(|n| n.attribute("PrefixList")) {
+        Some(prefixes) => prefixes
+            .split_whitespace()
+            .map(|prefix| match prefix {
+                "#default" => String::new(),
+                prefix => prefix.to_string(),
+            })
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+///
+/// Check the enveloped signature of `element`, returning whether it has
+/// one. Only the configured key is trusted, keys sent with the signature
+/// are ignored.
+///
+fn verify_signature(element: Node, key: &RsaPublicKey) -> AuthResult<bool> {
+    let signature = match child(element, XMLDSIG_NS, "Signature") {
+        Some(signature) => signature,
+        None => return Ok(false),
+    };
+
+    let signed_info = required_child(signature, XMLDSIG_NS, "SignedInfo")?;
+    let c14n_method = required_child(signed_info, XMLDSIG_NS, "CanonicalizationMethod")?;
+    let signature_method = required_child(signed_info, XMLDSIG_NS, "SignatureMethod")?;
+
+    if algorithm(c14n_method) != EXC_C14N {
+        return Err(saml_error(format!(
+            "unsupported canonicalization {}",
+            algorithm(c14n_method)
+        )));
+    }
+
+    if algorithm(signature_method) != RSA_SHA256 {
+        return Err(saml_error(format!(
+            "unsupported signature method {}",
+            algorithm(signature_method)
+        )));
+    }
+
+    // the signature must cover exactly the element it is in, or a signed
+    // element could be wrapped around forged content
+    let references: Vec<Node> = signed_info
+        .children()
+        .filter(|child| child.has_tag_name((XMLDSIG_NS, "Reference")))
+        .collect();
+
+    let reference = match references.as_slice() {
+        [reference] => *reference,
+        _ => return Err(saml_error("signature must have exactly one reference")),
+    };
+
+    let id = element.attribute("ID").unwrap_or("");
+
+    if id.is_empty() || reference.attribute("URI") != Some(format!("#{}", id).as_str()) {
+        return Err(saml_error("signature does not reference the signed element"));
+    }
+
+    let mut inclusive = Vec::new();
+
+    if let Some(transforms) = child(reference, XMLDSIG_NS, "Transforms") {
+        for transform in transforms.children().filter(Node::is_element) {
+            match algorithm(transform) {
+                ENVELOPED_SIGNATURE => (),
+                EXC_C14N => inclusive = inclusive_prefixes(transform),
+                other => return Err(saml_error(format!("unsupported transform {}", other))),
+            }
+        }
+    }
+
+    let digest_method = required_child(reference, XMLDSIG_NS, "DigestMethod")?;
+
+    if algorithm(digest_method) != SHA256_DIGEST {
+        return Err(saml_error(format!(
+            "unsupported digest method {}",
+            algorithm(digest_method)
+        )));
+    }
+
+    let signature_value: String = text(required_child(signature, XMLDSIG_NS, "SignatureValue")?)
+        .split_whitespace()
+        .collect();
+
+    let signature_value = match STANDARD.decode(signature_value) {
+        Ok(bytes) => Signature::try_from(bytes.as_slice()).map_err(saml_error)?,
+        Err(_) => return Err(saml_error("signature value is not base64")),
+    };
+
+    let signed_info = canonicalize(signed_info, None, &inclusive_prefixes(c14n_method));
+
+    if VerifyingKey::<Sha256>::new(key.clone())
+        .verify(signed_info.as_bytes(), &signature_value)
+        .is_err()
+    {
+        return Err(saml_error("signature is invalid"));
+    }
+
+    let digest = STANDARD.encode(Sha256::digest(
+        canonicalize(element, Some(signature.id()), &inclusive).as_bytes(),
+    ));
+
+    let expected: String = text(required_child(reference, XMLDSIG_NS, "DigestValue")?)
+        .split_whitespace()
+        .collect();
+
+    if digest != expected {
+        return Err(saml_error(format!(
+            "{} has been changed since it was signed",
+            element.tag_name().name()
+        )));
+    }
+
+    Ok(true)
+}
+
+///
+/// This service as a SAML 2.0 service provider of one identity provider.
+///
+#[derive(Debug, Clone)]
+pub struct SamlServiceProvider {
+    pub config: SamlConfig,
+    pub acs_url: String,
+    idp_key: RsaPublicKey,
+}
+
+impl SamlServiceProvider {
+    pub fn new(config: SamlConfig, acs_url: &str) -> AuthResult<Self> {
+        Ok(Self {
+            idp_key: idp_public_key(&config.idp_certificate)?,
+            acs_url: acs_url.to_string(),
+            config,
+        })
+    }
+
+    pub fn from_config(config: &AuthConfig) -> AuthResult<Self> {
+        let saml = match &config.saml {
+            Some(saml) => saml,
+            None => return Err(AuthError::FeatureDisabledError("SAML sign in".to_string())),
+        };
+
+        let acs_url = match (&saml.acs_url, &config.urls.public_url) {
+            (Some(acs_url), _) => acs_url.clone(),
+            (None, Some(url)) => format!("{}/saml/acs", url.trim_end_matches('/')),
+            (None, None) => {
+                return Err(AuthError::ConfigError(
+                    "saml.acs_url or urls.public_url must be set".to_string(),
+                ))
+            }
+        };
+
+        Self::new(saml.clone(), &acs_url)
+    }
+
+    ///
+    /// Metadata describing this service provider, for registering it with
+    /// the identity provider.
+    ///
+    pub fn metadata(&self) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<md:EntityDescriptor xmlns:md="{}" entityID="{}">
+  <md:SPSSODescriptor AuthnRequestsSigned="false" WantAssertionsSigned="true"
+      protocolSupportEnumeration="{}">
+    <md:AssertionConsumerService Binding="{}" Location="{}"
+        index="0" isDefault="true"/>
+  </md:SPSSODescriptor>
+</md:EntityDescriptor>
+"#,
+            SAML_METADATA_NS,
+            escape_attribute(&self.config.entity_id),
+            SAML_PROTOCOL_NS,
+            HTTP_POST_BINDING,
+            escape_attribute(&self.acs_url)
+        )
+    }
+
+    pub fn authn_request(&self, id: &str, now: DateTime<Utc>) -> String {
+        format!(
+            r#"<samlp:AuthnRequest xmlns:samlp="{}" xmlns:saml="{}" ID="{}" Version="2.0"
+    IssueInstant="{}" Destination="{}"
+    AssertionConsumerServiceURL="{}" ProtocolBinding="{}">
+  <saml:Issuer>{}</saml:Issuer>
+</samlp:AuthnRequest>"#,
+            SAML_PROTOCOL_NS,
+            SAML_ASSERTION_NS,
+            escape_attribute(id),
+            now.to_rfc3339_opts(SecondsFormat::Secs, true),
+            escape_attribute(&self.config.idp_sso_url),
+            escape_attribute(&self.acs_url),
+            HTTP_POST_BINDING,
+            escape_attribute(&self.config.entity_id)
+        )
+    }
+
+    ///
+    /// Identity provider url to send the user to, with the AuthnRequest in
+    /// the HTTP-Redirect binding's deflated form.
+    ///
+    pub fn redirect_url(
+        &self,
+        id: &str,
+        relay_state: Option<&str>,
+        now: DateTime<Utc>,
+    ) -> AuthResult<String> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+
+        encoder
+            .write_all(self.authn_request(id, now).as_bytes())
+            .map_err(saml_error)?;
+
+        let request = STANDARD.encode(encoder.finish().map_err(saml_error)?);
+
+        let mut url = match Url::parse(&self.config.idp_sso_url) {
+            Ok(url) => url,
+            Err(err) => return Err(AuthError::ConfigError(err.to_string())),
+        };
+
+        url.query_pairs_mut().append_pair("SAMLRequest", &request);
+
+        if let Some(relay_state) = relay_state {
+            url.query_pairs_mut().append_pair("RelayState", relay_state);
+        }
+
+        Ok(url.to_string())
+    }
+
+    fn check_issuer(&self, issuer: Node) -> AuthResult<()> {
+        if text(issuer) != self.config.idp_entity_id {
+            return Err(saml_error(format!("unexpected issuer {}", text(issuer))));
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Check a base64 encoded response from the HTTP-POST binding at the
+    /// time `now`. Either the response or its assertion must be signed by
+    /// the identity provider, and the assertion must be meant for us and
+    /// still valid. Replay is checked separately, see `saml_acs`.
+    ///
+    pub fn validate_response(
+        &self,
+        saml_response: &str,
+        now: DateTime<Utc>,
+    ) -> AuthResult<SamlAssertion> {
+        let saml_response: String = saml_response.split_whitespace().collect();
+
+        let xml = match STANDARD.decode(saml_response).map(String::from_utf8) {
+            Ok(Ok(xml)) => xml,
+            _ => return Err(saml_error("SAMLResponse is not base64 encoded XML")),
+        };
+
+        // DTDs are refused by default, so entities cannot be expanded
+        let doc = Document::parse(&xml).map_err(saml_error)?;
+        let response = doc.root_element();
+
+        if !response.has_tag_name((SAML_PROTOCOL_NS, "Response")) {
+            return Err(saml_error("not a SAML response"));
+        }
+
+        // signatures find what they cover by ID, so IDs must be unique
+        let mut ids = HashSet::new();
+
+        for id in doc.descendants().filter_map(|node| node.attribute("ID")) {
+            if !ids.insert(id) {
+                return Err(saml_error(format!("ID {} is used more than once", id)));
+            }
+        }
+
+        if let Some(destination) = response.attribute("Destination") {
+            if destination != self.acs_url {
+                return Err(saml_error(format!("response was sent to {}", destination)));
+            }
+        }
+
+        let status = required_child(response, SAML_PROTOCOL_NS, "Status")?;
+        let status_code = required_child(status, SAML_PROTOCOL_NS, "StatusCode")?;
+
+        if status_code.attribute("Value") != Some(STATUS_SUCCESS) {
+            let message = child(status, SAML_PROTOCOL_NS, "StatusMessage").map(text);
+
+            return Err(saml_error(format!(
+                "identity provider returned {} {}",
+                status_code.attribute("Value").unwrap_or(""),
+                message.unwrap_or_default()
+            )));
+        }
+
+        if let Some(issuer) = child(response, SAML_ASSERTION_NS, "Issuer") {
+            self.check_issuer(issuer)?;
+        }
+
+        if child(response, SAML_ASSERTION_NS, "EncryptedAssertion").is_some() {
+            return Err(saml_error("encrypted assertions are not supported"));
+        }
+
+        let assertions: Vec<Node> = response
+            .children()
+            .filter(|child| child.has_tag_name((SAML_ASSERTION_NS, "Assertion")))
+            .collect();
+
+        let assertion = match assertions.as_slice() {
+            [assertion] => *assertion,
+            _ => return Err(saml_error("response must have exactly one assertion")),
+        };
+
+        let response_signed = verify_signature(response, &self.idp_key)?;
+        let assertion_signed = verify_signature(assertion, &self.idp_key)?;
+
+        if !response_signed && !assertion_signed {
+            return Err(saml_error("neither the response nor its assertion is signed"));
+        }
+
+        self.check_issuer(required_child(assertion, SAML_ASSERTION_NS, "Issuer")?)?;
+
+        let now = now.timestamp();
+        let skew = self.config.clock_skew_secs;
+
+        let conditions = required_child(assertion, SAML_ASSERTION_NS, "Conditions")?;
+
+        if let Some(not_before) = timestamp(conditions, "NotBefore")? {
+            if now + skew < not_before {
+                return Err(saml_error("assertion is not valid yet"));
+            }
+        }
+
+        let restrictions: Vec<Node> = conditions
+            .children()
+            .filter(|child| child.has_tag_name((SAML_ASSERTION_NS, "AudienceRestriction")))
+            .collect();
+
+        // every restriction applies, so each must name us
+        let for_us = |restriction: &Node| {
+            restriction
+                .children()
+                .filter(|child| child.has_tag_name((SAML_ASSERTION_NS, "Audience")))
+                .any(|audience| text(audience) == self.config.entity_id)
+        };
+
+        if restrictions.is_empty() || !restrictions.iter().all(for_us) {
+            return Err(saml_error("assertion is for another audience"));
+        }
+
+        let subject = required_child(assertion, SAML_ASSERTION_NS, "Subject")?;
+        let name_id = text(required_child(subject, SAML_ASSERTION_NS, "NameID")?);
+
+        if name_id.is_empty() {
+            return Err(saml_error("assertion has no NameID"));
+        }
+
+        // bearer confirmation says where the assertion may be presented and
+        // until when
+        let confirmation = match subject.children().find(|child| {
+            child.has_tag_name((SAML_ASSERTION_NS, "SubjectConfirmation"))
+                && child.attribute("Method") == Some(BEARER)
+        }) {
+            Some(confirmation) => confirmation,
+            None => return Err(saml_error("assertion has no bearer subject confirmation")),
+        };
+
+        let data = required_child(confirmation, SAML_ASSERTION_NS, "SubjectConfirmationData")?;
+
+        if data.attribute("Recipient") != Some(self.acs_url.as_str()) {
+            return Err(saml_error("assertion is for another recipient"));
+        }
+
+        let confirmation_expires = match timestamp(data, "NotOnOrAfter")? {
+            Some(expires) => expires,
+            None => return Err(saml_error("subject confirmation has no NotOnOrAfter")),
+        };
+
+        let expires = match timestamp(conditions, "NotOnOrAfter")? {
+            Some(expires) => expires.min(confirmation_expires),
+            None => confirmation_expires,
+        };
+
+        if now - skew >= expires {
+            return Err(saml_error("assertion has expired"));
+        }
+
+        // the response's InResponseTo is only trusted if it was signed
+        let in_response_to = match (data.attribute("InResponseTo"), response_signed) {
+            (Some(id), _) => Some(id),
+            (None, true) => response.attribute("InResponseTo"),
+            (None, false) => None,
+        };
+
+        if let (Some(id), Some(response_id)) = (in_response_to, response.attribute("InResponseTo"))
+        {
+            if id != response_id {
+                return Err(saml_error("response and assertion answer different requests"));
+            }
+        }
+
+        let mut attributes: HashMap<String, Vec<String>> = HashMap::new();
+
+        for attribute in assertion
+            .children()
+            .filter(|child| child.has_tag_name((SAML_ASSERTION_NS, "AttributeStatement")))
+            .flat_map(|statement| statement.children())
+            .filter(|child| child.has_tag_name((SAML_ASSERTION_NS, "Attribute")))
+        {
+            let values = attribute
+                .children()
+                .filter(|child| child.has_tag_name((SAML_ASSERTION_NS, "AttributeValue")))
+                .map(text);
+
+            attributes
+                .entry(attribute.attribute("Name").unwrap_or("").to_string())
+                .or_default()
+                .extend(values);
+        }
+
+        let session_index = child(assertion, SAML_ASSERTION_NS, "AuthnStatement")
+            .and_then(|statement| statement.attribute("SessionIndex"));
+
+        Ok(SamlAssertion {
+            id: assertion.attribute("ID").unwrap_or("").to_string(),
+            name_id,
+            in_response_to: in_response_to.map(str::to_string),
+            session_index: session_index.map(str::to_string),
+            expires,
+            attributes,
+        })
+    }
+}
+
+impl UserDb {
+    pub async fn create_saml_tables(&self) -> AuthResult<()> {
+        for sql in [
+            CREATE_SAML_REQUESTS_TABLE_SQL,
+            CREATE_SAML_ASSERTIONS_TABLE_SQL,
+            CREATE_SAML_USERS_TABLE_SQL,
+        ] {
+            sqlx::query(sql).execute(&self.pool).await?;
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Remember an AuthnRequest so its response can be matched to it.
+    ///
+    pub async fn save_saml_request(&self, request_id: &str) -> AuthResult<()> {
+        let now = Utc::now();
+
+        sqlx::query(DELETE_EXPIRED_SAML_REQUESTS_SQL)
+            .bind(now.timestamp())
+            .execute(&self.pool)
+            .await?;
+
+        sqlx::query(CREATE_SAML_REQUEST_SQL)
+            .bind(request_id)
+            .bind((now + Duration::minutes(SAML_REQUEST_TTL_MINS)).timestamp())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn take_saml_request(&self, request_id: &str, now: DateTime<Utc>) -> AuthResult<()> {
+        let expires = sqlx::query_scalar::<_, i64>(TAKE_SAML_REQUEST_SQL)
+            .bind(request_id)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        match expires {
+            Some(expires) if expires > now.timestamp() => Ok(()),
+            Some(_) => Err(saml_error("the sign in request has expired")),
+            None => Err(saml_error("response does not answer a pending sign in request")),
+        }
+    }
+
+    ///
+    /// Record an assertion as used, failing if it already has been so a
+    /// captured response cannot be replayed.
+    ///
+    pub async fn use_saml_assertion(
+        &self,
+        assertion_id: &str,
+        expires: i64,
+        now: DateTime<Utc>,
+    ) -> AuthResult<()> {
+        sqlx::query(DELETE_EXPIRED_SAML_ASSERTIONS_SQL)
+            .bind(now.timestamp())
+            .execute(&self.pool)
+            .await?;
+
+        match sqlx::query(USE_SAML_ASSERTION_SQL)
+            .bind(assertion_id)
+            .bind(expires)
+            .execute(&self.pool)
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+                Err(saml_error("assertion has already been used"))
+            }
+            Err(err) => Err(AuthError::DatabaseError(err.to_string())),
+        }
+    }
+
+    pub async fn find_saml_user(&self, idp: &str, name_id: &str) -> AuthResult<Option<String>> {
+        Ok(sqlx::query_scalar::<_, String>(FIND_SAML_USER_SQL)
+            .bind(idp)
+            .bind(name_id)
+            .fetch_optional(&self.pool)
+            .await?)
+    }
+
+    ///
+    /// Create the local account for someone signing in through the
+    /// identity provider for the first time. They never sign in with a
+    /// password, so the local one is random.
+    ///
+    pub async fn provision_saml_user(&self, idp: &str, saml_user: &SamlUser) -> AuthResult<User> {
+        // random tokens are long but may lack some character classes a
+        // policy requires
+        let password = format!("{}aA1!", random_token());
+
+        let user = self
+            .create_user(&Credentials {
+                username: saml_user.username.clone(),
+                password,
+                email: Some(saml_user.email.clone()).filter(|email| !email.is_empty()),
+                first_name: Some(saml_user.first_name.clone()),
+                last_name: Some(saml_user.last_name.clone()),
+                callback_url: None,
+                url: None,
+                locale: None,
+            })
+            .await?;
+
+        // the identity provider vouches for its own email addresses
+        self.user_verified(&user.uuid).await?;
+
+        sqlx::query(LINK_SAML_USER_SQL)
+            .bind(&user.uuid)
+            .bind(idp)
+            .bind(&saml_user.name_id)
+            .execute(&self.pool)
+            .await?;
+
+        self.find_user_by_uuid(&user.uuid).await
+    }
+}
+
+///
+/// Start signing in, returning the identity provider url to send the user
+/// to. `relay_state` is returned with the response, for example to say
+/// which page to go back to.
+///
+pub async fn saml_login(
+    user_db: &UserDb,
+    sp: &SamlServiceProvider,
+    relay_state: Option<&str>,
+) -> AuthResult<String> {
+    // IDs must not start with a digit
+    let request_id = format!("_{}", random_token());
+
+    user_db.save_saml_request(&request_id).await?;
+
+    sp.redirect_url(&request_id, relay_state, Utc::now())
+}
+
+///
+/// Check a response posted to the assertion consumer service at the time
+/// `now`, returning the user it signs in. Each assertion is only accepted
+/// once, and must answer one of our requests unless sign ins started at
+/// the identity provider are allowed. Accounts are created on first sign
+/// in and their names kept in step with the identity provider after that.
+///
+pub async fn saml_acs(
+    user_db: &UserDb,
+    sp: &SamlServiceProvider,
+    saml_response: &str,
+    now: DateTime<Utc>,
+) -> AuthResult<User> {
+    let assertion = sp.validate_response(saml_response, now)?;
+
+    match &assertion.in_response_to {
+        Some(request_id) => user_db.take_saml_request(request_id, now).await?,
+        None if sp.config.allow_idp_initiated => (),
+        None => return Err(saml_error("sign ins started at the identity provider are disabled")),
+    }
+
+    user_db.use_saml_assertion(&assertion.id, assertion.expires, now).await?;
+
+    let saml_user = sp.config.attributes.user(&assertion)?;
+    let idp = &sp.config.idp_entity_id;
+
+    let user = match user_db.find_saml_user(idp, &saml_user.name_id).await? {
+        Some(uuid) => {
+            let user = user_db.find_user_by_uuid(&uuid).await?;
+
+            if user.first_name != saml_user.first_name || user.last_name != saml_user.last_name {
+                user_db
+                    .update_user(
+                        &user.uuid,
+                        &user.username,
+                        &saml_user.first_name,
+                        &saml_user.last_name,
+                    )
+                    .await?;
+            }
+
+            user_db.find_user_by_uuid(&uuid).await?
+        }
+        None => user_db.provision_saml_user(idp, &saml_user).await?,
+    };
+
+    user.check_can_signin()?;
+
+    Ok(user)
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SamlLoginReq {
+    pub relay_state: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct SamlLoginResp {
+    pub url: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SamlAcsReq {
+    #[serde(rename = "SAMLResponse")]
+    pub saml_response: String,
+    #[serde(rename = "RelayState")]
+    pub relay_state: Option<String>,
+}
+
+async fn metadata_handler(
+    State(state): State<AppState>,
+) -> AuthResult<([(HeaderName, &'static str); 1], String)> {
+    let sp = SamlServiceProvider::from_config(&state.config)?;
+
+    Ok(([(CONTENT_TYPE, "application/samlmetadata+xml")], sp.metadata()))
+}
+
+async fn login_handler(
+    State(state): State<AppState>,
+    Query(req): Query<SamlLoginReq>,
+) -> AuthResult<Json<SamlLoginResp>> {
+    let sp = SamlServiceProvider::from_config(&state.config)?;
+
+    Ok(Json(SamlLoginResp {
+        url: saml_login(&state.user_db, &sp, req.relay_state.as_deref()).await?,
+    }))
+}
+
+async fn acs_handler(
+    State(state): State<AppState>,
+    Form(req): Form<SamlAcsReq>,
+) -> AuthResult<Json<TokensResp>> {
+    let sp = SamlServiceProvider::from_config(&state.config)?;
+
+    let user = saml_acs(&state.user_db, &sp, &req.saml_response, Utc::now()).await?;
+
+    Ok(Json(tokens(&user.uuid, &state.config.tokens, &state.jwt_private_key)?))
+}
+
+///
+/// SAML 2.0 single sign on. Register the metadata with the identity
+/// provider, send users to the url from `login`, and the identity provider
+/// posts their assertion back to `acs` to be exchanged for tokens.
+///
+pub fn saml_router() -> Router<AppState> {
+    Router::new()
+        .route("/saml/metadata", get(metadata_handler))
+        .route("/saml/login", get(login_handler))
+        .route("/saml/acs", post(acs_handler))
+}
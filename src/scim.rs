@@ -0,0 +1,1783 @@
+use std::{collections::HashMap, fmt::Display, str::FromStr};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{header::CONTENT_TYPE, HeaderName, StatusCode},
+    routing::get,
+    Json, Router,
+};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{json, Map, Number, Value};
+use sqlx::{FromRow, SqliteConnection};
+
+use crate::{
+    jwt::{AppState, JwtClaims, JwtToken, TokenType},
+    oauth::random_token,
+    oidc::INSUFFICIENT_SCOPE,
+    signin::check_token_type,
+    uuid, AuthError, AuthResult, Credentials, User, UserDb,
+};
+
+pub const SCIM_USER_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:User";
+pub const SCIM_GROUP_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:Group";
+pub const SCIM_LIST_RESPONSE_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:ListResponse";
+pub const SCIM_PATCH_OP_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:PatchOp";
+pub const SCIM_ERROR_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:Error";
+pub const SCIM_SERVICE_PROVIDER_CONFIG_SCHEMA: &str =
+    "urn:ietf:params:scim:schemas:core:2.0:ServiceProviderConfig";
+
+pub const SCIM_CONTENT_TYPE: &str = "application/scim+json";
+
+/// Scope a client must be granted to use the SCIM endpoints
+pub const SCOPE_SCIM: &str = "scim";
+
+pub const SCIM_DEFAULT_PAGE_SIZE: i64 = 100;
+pub const SCIM_MAX_PAGE_SIZE: i64 = 1000;
+
+// scimType values from RFC 7644 section 3.12
+pub const SCIM_INVALID_FILTER: &str = "invalidFilter";
+pub const SCIM_INVALID_PATH: &str = "invalidPath";
+pub const SCIM_INVALID_SYNTAX: &str = "invalidSyntax";
+pub const SCIM_INVALID_VALUE: &str = "invalidValue";
+pub const SCIM_NO_TARGET: &str = "noTarget";
+pub const SCIM_UNIQUENESS: &str = "uniqueness";
+
+// every user and group belongs to the client that provisioned it, and
+// clients can only see and change their own
+pub const CREATE_SCIM_USERS_TABLE_SQL: &'static str = r#"CREATE TABLE IF NOT EXISTS scim_users (
+id INTEGER PRIMARY KEY AUTOINCREMENT,
+user_uuid TEXT NOT NULL UNIQUE,
+external_id TEXT NOT NULL,
+client_id TEXT NOT NULL DEFAULT '',
+created_on TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP)"#;
+
+pub const CREATE_SCIM_GROUPS_TABLE_SQL: &'static str = r#"CREATE TABLE IF NOT EXISTS scim_groups (
+id INTEGER PRIMARY KEY AUTOINCREMENT,
+uuid TEXT NOT NULL UNIQUE,
+display_name TEXT NOT NULL,
+external_id TEXT NOT NULL DEFAULT '',
+client_id TEXT NOT NULL DEFAULT '',
+created_on TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+UNIQUE(client_id, display_name))"#;
+
+pub const CREATE_SCIM_GROUP_MEMBERS_TABLE_SQL: &'static str =
+    r#"CREATE TABLE IF NOT EXISTS scim_group_members (
+id INTEGER PRIMARY KEY AUTOINCREMENT,
+group_uuid TEXT NOT NULL,
+user_uuid TEXT NOT NULL,
+created_on TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+UNIQUE(group_uuid, user_uuid))"#;
+
+const SCIM_USERS_COLUMNS_SQL: &'static str = r#"SELECT name FROM pragma_table_info('scim_users')"#;
+
+const SCIM_GROUPS_COLUMNS_SQL: &'static str =
+    r#"SELECT name FROM pragma_table_info('scim_groups')"#;
+
+// rows from before owners were recorded have no client, so no client can
+// reach them
+const ADD_SCIM_USERS_CLIENT_ID_SQL: &'static str =
+    r#"ALTER TABLE scim_users ADD COLUMN client_id TEXT NOT NULL DEFAULT ''"#;
+
+const ADD_SCIM_GROUPS_CLIENT_ID_SQL: &'static str =
+    r#"ALTER TABLE scim_groups ADD COLUMN client_id TEXT NOT NULL DEFAULT ''"#;
+
+const SCIM_GROUPS_SCHEMA_SQL: &'static str =
+    r#"SELECT sql FROM sqlite_master WHERE type = 'table' AND name = 'scim_groups'"#;
+
+// names were unique across every client before groups had owners, which
+// SQLite can only change by copying the groups into a new table
+const COPY_SCIM_GROUPS_SQL: &'static str = r#"INSERT INTO scim_groups_rebuild
+(id, uuid, display_name, external_id, client_id, created_on)
+SELECT id, uuid, display_name, external_id, client_id, created_on FROM scim_groups"#;
+
+const DROP_SCIM_GROUPS_SQL: &'static str = r#"DROP TABLE scim_groups"#;
+
+const RENAME_SCIM_GROUPS_SQL: &'static str =
+    r#"ALTER TABLE scim_groups_rebuild RENAME TO scim_groups"#;
+
+const CREATE_SCIM_USER_SQL: &'static str = r#"INSERT INTO scim_users
+(user_uuid, external_id, client_id)
+VALUES($1, $2, $3)"#;
+
+const SET_EXTERNAL_ID_SQL: &'static str =
+    r#"UPDATE scim_users SET external_id = $2 WHERE scim_users.user_uuid = $1"#;
+
+const FIND_EXTERNAL_ID_SQL: &'static str = r#"SELECT external_id FROM scim_users
+WHERE scim_users.user_uuid = $1 AND scim_users.client_id = $2
+LIMIT 1"#;
+
+// joined to users so accounts deleted some other way drop out
+const LIST_SCIM_USERS_SQL: &'static str = r#"SELECT
+users.id, users.uuid, users.first_name, users.last_name, users.username, users.email, users.password, strftime('%s', users.updated_on) as updated_on, users.can_signin, users.email_verified, users.passwordless_only, users.locale, scim_users.external_id
+FROM scim_users
+JOIN users ON users.uuid = scim_users.user_uuid
+WHERE scim_users.client_id = $1
+ORDER BY users.username
+LIMIT $2 OFFSET $3"#;
+
+const COUNT_SCIM_USERS_SQL: &'static str = r#"SELECT COUNT(*)
+FROM scim_users
+JOIN users ON users.uuid = scim_users.user_uuid
+WHERE scim_users.client_id = $1"#;
+
+// the identity provider vouches for the addresses it pushes when it
+// creates a user
+const SCIM_USER_CREATED_SQL: &'static str =
+    r#"UPDATE users SET email_verified = 1, can_signin = $2 WHERE users.uuid = $1"#;
+
+const UPDATE_SCIM_USER_SQL: &'static str = r#"UPDATE users
+SET username = $2, first_name = $3, last_name = $4, can_signin = $5
+WHERE users.uuid = $1"#;
+
+const UPDATE_EMAIL_SQL: &'static str =
+    r#"UPDATE users SET email = $2, email_verified = 0 WHERE users.uuid = $1"#;
+
+const CREATE_GROUP_SQL: &'static str = r#"INSERT INTO scim_groups
+(uuid, display_name, external_id, client_id)
+VALUES($1, $2, $3, $4)"#;
+
+const UPDATE_GROUP_SQL: &'static str = r#"UPDATE scim_groups
+SET display_name = $2, external_id = $3
+WHERE scim_groups.uuid = $1"#;
+
+const FIND_GROUP_SQL: &'static str = r#"SELECT uuid, display_name, external_id
+FROM scim_groups
+WHERE scim_groups.uuid = $1 AND scim_groups.client_id = $2
+LIMIT 1"#;
+
+const FIND_GROUP_BY_NAME_SQL: &'static str = r#"SELECT uuid FROM scim_groups
+WHERE scim_groups.display_name = $1 AND scim_groups.client_id = $2
+LIMIT 1"#;
+
+const LIST_GROUPS_SQL: &'static str = r#"SELECT uuid, display_name, external_id
+FROM scim_groups
+WHERE scim_groups.client_id = $1
+ORDER BY display_name"#;
+
+const DELETE_GROUP_SQL: &'static str = r#"DELETE FROM scim_groups WHERE scim_groups.uuid = $1"#;
+
+const ADD_GROUP_MEMBER_SQL: &'static str = r#"INSERT OR IGNORE INTO scim_group_members
+(group_uuid, user_uuid)
+VALUES($1, $2)"#;
+
+const DELETE_GROUP_MEMBERS_SQL: &'static str =
+    r#"DELETE FROM scim_group_members WHERE scim_group_members.group_uuid = $1"#;
+
+// members are joined to their users so deleted accounts drop out
+const GROUP_MEMBERS_SQL: &'static str = r#"SELECT users.uuid, users.username
+FROM scim_group_members
+JOIN users ON users.uuid = scim_group_members.user_uuid
+WHERE scim_group_members.group_uuid = $1
+ORDER BY users.username"#;
+
+const USER_GROUPS_SQL: &'static str = r#"SELECT scim_groups.uuid, scim_groups.display_name
+FROM scim_group_members
+JOIN scim_groups ON scim_groups.uuid = scim_group_members.group_uuid
+WHERE scim_group_members.user_uuid = $1 AND scim_groups.client_id = $2
+ORDER BY scim_groups.display_name"#;
+
+// memberships of the same page of users as LIST_SCIM_USERS_SQL
+const LIST_MEMBERSHIPS_SQL: &'static str =
+    r#"SELECT scim_group_members.user_uuid, scim_groups.uuid, scim_groups.display_name
+FROM scim_group_members
+JOIN scim_groups ON scim_groups.uuid = scim_group_members.group_uuid
+WHERE scim_groups.client_id = $1 AND scim_group_members.user_uuid IN (
+    SELECT scim_users.user_uuid
+    FROM scim_users
+    JOIN users ON users.uuid = scim_users.user_uuid
+    WHERE scim_users.client_id = $1
+    ORDER BY users.username
+    LIMIT $2 OFFSET $3)
+ORDER BY scim_groups.display_name"#;
+
+pub fn scim_error(status: u16, scim_type: &str, detail: impl ToString) -> AuthError {
+    AuthError::ScimError(status, scim_type.to_string(), detail.to_string())
+}
+
+///
+/// Convert an error from the rest of the crate into the status and
+/// scimType a SCIM client expects.
+///
+pub fn to_scim_error(error: AuthError) -> AuthError {
+    match &error {
+        AuthError::ScimError(..) => error,
+        AuthError::UserDoesNotExistError(_) => scim_error(404, "", error),
+        AuthError::UserAlreadyExistsError(_) => scim_error(409, SCIM_UNIQUENESS, error),
+        AuthError::PasswordPolicyError(_) => scim_error(400, SCIM_INVALID_VALUE, error),
+        AuthError::TokenError(_) => scim_error(401, "", error),
+        AuthError::OAuthError(code, _) if code == INSUFFICIENT_SCOPE => scim_error(403, "", error),
+        AuthError::DatabaseError(_) => scim_error(500, "", error),
+        _ => scim_error(400, "", error),
+    }
+}
+
+fn invalid_filter(detail: impl Display) -> AuthError {
+    scim_error(400, SCIM_INVALID_FILTER, format!("invalid filter: {}", detail))
+}
+
+///
+/// SCIM is only for identity providers, which sign in with the client
+/// credentials grant and must have been granted the scim scope.
+///
+pub fn check_scim_token(claims: &JwtClaims) -> AuthResult<()> {
+    check_token_type(claims, &TokenType::Service).map_err(to_scim_error)?;
+
+    // resources are owned by the client, so there must be one
+    if claims.client_id.is_empty() {
+        return Err(scim_error(401, "", "the token was not issued to a client"));
+    }
+
+    if !claims.has_scope(SCOPE_SCIM) {
+        return Err(scim_error(403, "", "the scim scope is required"));
+    }
+
+    Ok(())
+}
+
+fn default_active() -> bool {
+    true
+}
+
+// Azure AD sends some booleans as "True" and "False"
+fn bool_or_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    match Value::deserialize(deserializer)? {
+        Value::Bool(value) => Ok(value),
+        Value::String(value) if value.eq_ignore_ascii_case("true") => Ok(true),
+        Value::String(value) if value.eq_ignore_ascii_case("false") => Ok(false),
+        value => Err(serde::de::Error::custom(format!("expected a boolean but got {}", value))),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimName {
+    #[serde(default)]
+    pub given_name: String,
+    #[serde(default)]
+    pub family_name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ScimEmail {
+    pub value: String,
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub email_type: Option<String>,
+    #[serde(default, deserialize_with = "bool_or_string")]
+    pub primary: bool,
+}
+
+///
+/// A reference to a user from a group or a group from a user.
+///
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ScimMember {
+    pub value: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimMeta {
+    pub resource_type: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimUser {
+    #[serde(default)]
+    pub schemas: Vec<String>,
+    #[serde(default)]
+    pub id: String,
+    /// The identity provider's own id for the user
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub external_id: Option<String>,
+    #[serde(default)]
+    pub user_name: String,
+    #[serde(default)]
+    pub name: ScimName,
+    #[serde(default)]
+    pub display_name: String,
+    #[serde(default)]
+    pub emails: Vec<ScimEmail>,
+    #[serde(default = "default_active", deserialize_with = "bool_or_string")]
+    pub active: bool,
+    /// Can be set by the client but is never returned
+    #[serde(default, skip_serializing)]
+    pub password: Option<String>,
+    /// Read only, groups are changed through their members
+    #[serde(default)]
+    pub groups: Vec<ScimMember>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<ScimMeta>,
+}
+
+impl ScimUser {
+    pub fn new(user: &User, external_id: Option<String>, groups: Vec<ScimMember>) -> Self {
+        let display_name = format!("{} {}", user.first_name, user.last_name).trim().to_string();
+
+        ScimUser {
+            schemas: vec![SCIM_USER_SCHEMA.to_string()],
+            id: user.uuid.clone(),
+            external_id,
+            user_name: user.username.clone(),
+            name: ScimName {
+                given_name: user.first_name.clone(),
+                family_name: user.last_name.clone(),
+            },
+            display_name: match display_name.is_empty() {
+                true => user.username.clone(),
+                false => display_name,
+            },
+            emails: vec![ScimEmail {
+                value: user.email.clone(),
+                email_type: Some("work".to_string()),
+                primary: true,
+            }],
+            active: user.can_signin,
+            password: None,
+            groups,
+            meta: Some(ScimMeta {
+                resource_type: "User".to_string(),
+            }),
+        }
+    }
+
+    ///
+    /// The primary email address, or the first one if none is marked.
+    ///
+    pub fn primary_email(&self) -> Option<&str> {
+        self.emails
+            .iter()
+            .find(|email| email.primary)
+            .or(self.emails.first())
+            .map(|email| email.value.trim())
+            .filter(|email| !email.is_empty())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimGroup {
+    #[serde(default)]
+    pub schemas: Vec<String>,
+    #[serde(default)]
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub external_id: Option<String>,
+    #[serde(default)]
+    pub display_name: String,
+    #[serde(default)]
+    pub members: Vec<ScimMember>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<ScimMeta>,
+}
+
+#[derive(FromRow, Debug, Clone)]
+struct ScimUserRow {
+    #[sqlx(flatten)]
+    user: User,
+    external_id: String,
+}
+
+#[derive(FromRow, Debug, Clone)]
+struct ScimGroupRow {
+    uuid: String,
+    display_name: String,
+    external_id: String,
+}
+
+impl ScimGroup {
+    fn new(row: ScimGroupRow, members: Vec<ScimMember>) -> Self {
+        ScimGroup {
+            schemas: vec![SCIM_GROUP_SCHEMA.to_string()],
+            id: row.uuid,
+            external_id: Some(row.external_id).filter(|id| !id.is_empty()),
+            display_name: row.display_name,
+            members,
+            meta: Some(ScimMeta {
+                resource_type: "Group".to_string(),
+            }),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimListQuery {
+    pub filter: Option<String>,
+    /// 1 based index of the first result
+    pub start_index: Option<i64>,
+    pub count: Option<i64>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimListResponse<T> {
+    pub schemas: Vec<String>,
+    pub total_results: usize,
+    pub start_index: i64,
+    pub items_per_page: usize,
+    #[serde(rename = "Resources")]
+    pub resources: Vec<T>,
+}
+
+impl ScimListQuery {
+    // out of range values are clamped rather than rejected, as RFC 7644
+    // asks
+    pub fn start_index(&self) -> i64 {
+        self.start_index.unwrap_or(1).max(1)
+    }
+
+    pub fn count(&self) -> i64 {
+        self.count.unwrap_or(SCIM_DEFAULT_PAGE_SIZE).clamp(0, SCIM_MAX_PAGE_SIZE)
+    }
+}
+
+impl<T> ScimListResponse<T> {
+    pub fn new(resources: Vec<T>, total_results: usize, start_index: i64) -> Self {
+        Self {
+            schemas: vec![SCIM_LIST_RESPONSE_SCHEMA.to_string()],
+            total_results,
+            start_index,
+            items_per_page: resources.len(),
+            resources,
+        }
+    }
+}
+
+///
+/// Filter resources then return the page asked for.
+///
+pub fn list_response<T: Serialize>(
+    resources: Vec<T>,
+    query: &ScimListQuery,
+) -> AuthResult<ScimListResponse<T>> {
+    let filter = match &query.filter {
+        Some(filter) => Some(filter.parse::<ScimFilter>()?),
+        None => None,
+    };
+
+    let mut matched = Vec::new();
+
+    for resource in resources {
+        let json = serde_json::to_value(&resource).map_err(|err| scim_error(500, "", err))?;
+
+        if filter.iter().all(|filter| filter.matches(&json)) {
+            matched.push(resource);
+        }
+    }
+
+    let start_index = query.start_index();
+    let total_results = matched.len();
+
+    let resources: Vec<T> = matched
+        .into_iter()
+        .skip(start_index as usize - 1)
+        .take(query.count() as usize)
+        .collect();
+
+    Ok(ScimListResponse::new(resources, total_results, start_index))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Co,
+    Sw,
+    Ew,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+impl FromStr for CompareOp {
+    type Err = AuthError;
+
+    fn from_str(op: &str) -> AuthResult<Self> {
+        match op.to_lowercase().as_str() {
+            "eq" => Ok(CompareOp::Eq),
+            "ne" => Ok(CompareOp::Ne),
+            "co" => Ok(CompareOp::Co),
+            "sw" => Ok(CompareOp::Sw),
+            "ew" => Ok(CompareOp::Ew),
+            "gt" => Ok(CompareOp::Gt),
+            "ge" => Ok(CompareOp::Ge),
+            "lt" => Ok(CompareOp::Lt),
+            "le" => Ok(CompareOp::Le),
+            _ => Err(invalid_filter(format!("unknown operator {}", op))),
+        }
+    }
+}
+
+impl CompareOp {
+    fn compare(&self, actual: &Value, expected: &Value) -> bool {
+        // complex values such as emails compare on their value
+        let actual = match actual {
+            Value::Object(object) => object.get("value").unwrap_or(&Value::Null),
+            actual => actual,
+        };
+
+        match (actual, expected) {
+            (Value::String(actual), Value::String(expected)) => {
+                let actual = actual.to_lowercase();
+                let expected = expected.to_lowercase();
+
+                match self {
+                    CompareOp::Eq => actual == expected,
+                    CompareOp::Ne => actual != expected,
+                    CompareOp::Co => actual.contains(&expected),
+                    CompareOp::Sw => actual.starts_with(&expected),
+                    CompareOp::Ew => actual.ends_with(&expected),
+                    CompareOp::Gt => actual > expected,
+                    CompareOp::Ge => actual >= expected,
+                    CompareOp::Lt => actual < expected,
+                    CompareOp::Le => actual <= expected,
+                }
+            }
+            (Value::Number(actual), Value::Number(expected)) => {
+                let actual = actual.as_f64().unwrap_or(f64::NAN);
+                let expected = expected.as_f64().unwrap_or(f64::NAN);
+
+                match self {
+                    CompareOp::Eq => actual == expected,
+                    CompareOp::Ne => actual != expected,
+                    CompareOp::Gt => actual > expected,
+                    CompareOp::Ge => actual >= expected,
+                    CompareOp::Lt => actual < expected,
+                    CompareOp::Le => actual <= expected,
+                    _ => false,
+                }
+            }
+            (actual, expected) => match self {
+                CompareOp::Eq => actual == expected,
+                CompareOp::Ne => actual != expected,
+                _ => false,
+            },
+        }
+    }
+}
+
+///
+/// A parsed SCIM filter, e.g. `userName eq "ada" and active eq true`.
+/// Attribute names and string comparisons ignore case.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScimFilter {
+    And(Box<ScimFilter>, Box<ScimFilter>),
+    Or(Box<ScimFilter>, Box<ScimFilter>),
+    Not(Box<ScimFilter>),
+    Present(String),
+    Compare(String, CompareOp, Value),
+    /// `attr[filter]`, true if any value of a multi-valued attribute matches
+    Values(String, Box<ScimFilter>),
+}
+
+impl ScimFilter {
+    pub fn matches(&self, resource: &Value) -> bool {
+        match self {
+            ScimFilter::And(left, right) => left.matches(resource) && right.matches(resource),
+            ScimFilter::Or(left, right) => left.matches(resource) || right.matches(resource),
+            ScimFilter::Not(filter) => !filter.matches(resource),
+            ScimFilter::Present(path) => {
+                attribute_values(resource, path).iter().any(|value| match value {
+                    Value::String(value) => !value.is_empty(),
+                    Value::Array(values) => !values.is_empty(),
+                    Value::Object(object) => !object.is_empty(),
+                    _ => true,
+                })
+            }
+            // an attribute with no values is not equal to anything
+            ScimFilter::Compare(path, CompareOp::Ne, expected) => !attribute_values(resource, path)
+                .iter()
+                .any(|value| CompareOp::Eq.compare(value, expected)),
+            ScimFilter::Compare(path, op, expected) => attribute_values(resource, path)
+                .iter()
+                .any(|value| op.compare(value, expected)),
+            ScimFilter::Values(path, filter) => attribute_values(resource, path)
+                .iter()
+                .any(|value| filter.matches(value)),
+        }
+    }
+}
+
+impl FromStr for ScimFilter {
+    type Err = AuthError;
+
+    fn from_str(filter: &str) -> AuthResult<Self> {
+        let mut parser = FilterParser {
+            tokens: tokenize(filter)?,
+            pos: 0,
+        };
+
+        let parsed = parser.or()?;
+
+        if let Some(token) = parser.peek() {
+            return Err(invalid_filter(format!("unexpected {:?}", token)));
+        }
+
+        Ok(parsed)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    OpenBracket,
+    CloseBracket,
+    Word(String),
+    Str(String),
+}
+
+fn tokenize(filter: &str) -> AuthResult<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = filter.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            '(' | ')' | '[' | ']' => {
+                chars.next();
+
+                tokens.push(match c {
+                    '(' => Token::Open,
+                    ')' => Token::Close,
+                    '[' => Token::OpenBracket,
+                    _ => Token::CloseBracket,
+                });
+            }
+            '"' => {
+                chars.next();
+
+                let mut value = String::new();
+
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) => value.push(c),
+                            None => return Err(invalid_filter("unterminated string")),
+                        },
+                        Some(c) => value.push(c),
+                        None => return Err(invalid_filter("unterminated string")),
+                    }
+                }
+
+                tokens.push(Token::Str(value));
+            }
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            _ => {
+                let mut word = String::new();
+
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || "()[]\"".contains(c) {
+                        break;
+                    }
+
+                    word.push(c);
+                    chars.next();
+                }
+
+                tokens.push(Token::Word(word));
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+// or binds more loosely than and, which binds more loosely than not
+struct FilterParser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl FilterParser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> AuthResult<Token> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| invalid_filter("unexpected end"))?;
+
+        self.pos += 1;
+
+        Ok(token)
+    }
+
+    fn keyword(&mut self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword) => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expect(&mut self, expected: Token) -> AuthResult<()> {
+        match self.next()? {
+            token if token == expected => Ok(()),
+            token => Err(invalid_filter(format!("expected {:?} but got {:?}", expected, token))),
+        }
+    }
+
+    fn or(&mut self) -> AuthResult<ScimFilter> {
+        let mut filter = self.and()?;
+
+        while self.keyword("or") {
+            filter = ScimFilter::Or(Box::new(filter), Box::new(self.and()?));
+        }
+
+        Ok(filter)
+    }
+
+    fn and(&mut self) -> AuthResult<ScimFilter> {
+        let mut filter = self.not()?;
+
+        while self.keyword("and") {
+            filter = ScimFilter::And(Box::new(filter), Box::new(self.not()?));
+        }
+
+        Ok(filter)
+    }
+
+    fn not(&mut self) -> AuthResult<ScimFilter> {
+        if self.keyword("not") {
+            self.expect(Token::Open)?;
+            let filter = self.or()?;
+            self.expect(Token::Close)?;
+
+            return Ok(ScimFilter::Not(Box::new(filter)));
+        }
+
+        if self.peek() == Some(&Token::Open) {
+            self.pos += 1;
+            let filter = self.or()?;
+            self.expect(Token::Close)?;
+
+            return Ok(filter);
+        }
+
+        let path = match self.next()? {
+            Token::Word(path) => path,
+            token => {
+                return Err(invalid_filter(format!("expected an attribute but got {:?}", token)))
+            }
+        };
+
+        if self.peek() == Some(&Token::OpenBracket) {
+            self.pos += 1;
+            let filter = self.or()?;
+            self.expect(Token::CloseBracket)?;
+
+            return Ok(ScimFilter::Values(path, Box::new(filter)));
+        }
+
+        if self.keyword("pr") {
+            return Ok(ScimFilter::Present(path));
+        }
+
+        let op = match self.next()? {
+            Token::Word(op) => op.parse::<CompareOp>()?,
+            token => {
+                return Err(invalid_filter(format!("expected an operator but got {:?}", token)))
+            }
+        };
+
+        let value = match self.next()? {
+            Token::Str(value) => Value::String(value),
+            Token::Word(word) => match word.to_lowercase().as_str() {
+                "true" => Value::Bool(true),
+                "false" => Value::Bool(false),
+                "null" => Value::Null,
+                _ => Value::Number(
+                    word.parse::<Number>()
+                        .map_err(|_| invalid_filter(format!("{} is not a value", word)))?,
+                ),
+            },
+            token => return Err(invalid_filter(format!("expected a value but got {:?}", token))),
+        };
+
+        Ok(ScimFilter::Compare(path, op, value))
+    }
+}
+
+// attributes may be qualified with their schema, e.g.
+// urn:ietf:params:scim:schemas:core:2.0:User:userName
+fn strip_schema(path: &str) -> &str {
+    if !path.to_lowercase().starts_with("urn:") {
+        return path;
+    }
+
+    let end = path.find('[').unwrap_or(path.len());
+
+    match path[..end].rfind(':') {
+        Some(colon) => &path[colon + 1..],
+        None => path,
+    }
+}
+
+fn key_of(object: &Map<String, Value>, name: &str) -> String {
+    object
+        .keys()
+        .find(|key| key.eq_ignore_ascii_case(name))
+        .cloned()
+        .unwrap_or_else(|| name.to_string())
+}
+
+///
+/// The values at a dotted attribute path, with multi-valued attributes
+/// flattened so each of their values can be tested.
+///
+fn attribute_values<'a>(resource: &'a Value, path: &str) -> Vec<&'a Value> {
+    let mut values = vec![resource];
+
+    for name in strip_schema(path).split('.') {
+        let mut next = Vec::new();
+
+        for value in values {
+            let found = match value {
+                Value::Object(object) => object
+                    .iter()
+                    .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                    .map(|(_, value)| value),
+                _ => None,
+            };
+
+            match found {
+                Some(Value::Array(elements)) => next.extend(elements),
+                Some(Value::Null) | None => {}
+                Some(value) => next.push(value),
+            }
+        }
+
+        values = next;
+    }
+
+    values
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ScimPatchOp {
+    pub op: String,
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(default)]
+    pub value: Value,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ScimPatch {
+    #[serde(default)]
+    pub schemas: Vec<String>,
+    #[serde(rename = "Operations", alias = "operations")]
+    pub operations: Vec<ScimPatchOp>,
+}
+
+// objects are merged so patching one sub-attribute keeps the others
+fn merge(target: &mut Value, value: &Value) {
+    match (target, value) {
+        (Value::Object(target), Value::Object(values)) => {
+            for (name, value) in values {
+                let key = key_of(target, name);
+                target.insert(key, value.clone());
+            }
+        }
+        (target, value) => *target = value.clone(),
+    }
+}
+
+///
+/// Apply the operations of a PATCH request to a resource's JSON.
+///
+pub fn apply_patch(resource: &mut Value, patch: &ScimPatch) -> AuthResult<()> {
+    for operation in &patch.operations {
+        let op = operation.op.to_lowercase();
+
+        if !matches!(op.as_str(), "add" | "replace" | "remove") {
+            return Err(scim_error(
+                400,
+                SCIM_INVALID_SYNTAX,
+                format!("unknown operation {}", operation.op),
+            ));
+        }
+
+        match (&operation.path, &operation.value) {
+            (Some(path), value) => patch_path(resource, &op, path, value)?,
+            (None, _) if op == "remove" => {
+                return Err(scim_error(400, SCIM_NO_TARGET, "remove needs a path"))
+            }
+            // without a path the value holds the attributes to change
+            (None, Value::Object(values)) => {
+                for (path, value) in values {
+                    patch_path(resource, &op, path, value)?;
+                }
+            }
+            (None, _) => {
+                return Err(scim_error(
+                    400,
+                    SCIM_INVALID_VALUE,
+                    "value must be an object when there is no path",
+                ))
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn patch_path(resource: &mut Value, op: &str, path: &str, value: &Value) -> AuthResult<()> {
+    let invalid_path = || scim_error(400, SCIM_INVALID_PATH, format!("invalid path {}", path));
+
+    // a value filter picks which values of a multi-valued attribute to
+    // change, e.g. members[value eq "..."] or emails[type eq "work"].value
+    let (attr, value_filter, sub) = match strip_schema(path).split_once('[') {
+        Some((attr, rest)) => {
+            let (filter, sub) = rest.rsplit_once(']').ok_or_else(invalid_path)?;
+
+            let sub = match sub {
+                "" => None,
+                sub => Some(sub.strip_prefix('.').ok_or_else(invalid_path)?),
+            };
+
+            (attr, Some(filter.parse::<ScimFilter>()?), sub)
+        }
+        None => (strip_schema(path), None, None),
+    };
+
+    let mut names: Vec<&str> = attr.split('.').collect();
+    let name = names.pop().filter(|name| !name.is_empty()).ok_or_else(invalid_path)?;
+
+    let mut target = resource;
+
+    for parent in names {
+        let object = target.as_object_mut().ok_or_else(invalid_path)?;
+        let key = key_of(object, parent);
+        target = object.entry(key).or_insert_with(|| Value::Object(Map::new()));
+    }
+
+    let object = target.as_object_mut().ok_or_else(invalid_path)?;
+    let key = key_of(object, name);
+
+    let value_filter = match value_filter {
+        Some(value_filter) => value_filter,
+        None => {
+            if op == "remove" {
+                object.remove(&key);
+                return Ok(());
+            }
+
+            match object.get_mut(&key) {
+                // adding to a multi-valued attribute keeps its current values
+                Some(Value::Array(values)) if op == "add" => {
+                    let added = match value {
+                        Value::Array(added) => added.clone(),
+                        value => vec![value.clone()],
+                    };
+
+                    for value in added {
+                        if !values.contains(&value) {
+                            values.push(value);
+                        }
+                    }
+                }
+                Some(existing) => merge(existing, value),
+                None => {
+                    object.insert(key, value.clone());
+                }
+            }
+
+            return Ok(());
+        }
+    };
+
+    let values = match object.get_mut(&key) {
+        Some(Value::Array(values)) => values,
+        _ if op == "remove" => return Ok(()),
+        _ => return Err(scim_error(400, SCIM_NO_TARGET, format!("nothing matches {}", path))),
+    };
+
+    if op == "remove" && sub.is_none() {
+        values.retain(|value| !value_filter.matches(value));
+        return Ok(());
+    }
+
+    let mut matched = false;
+
+    for element in values.iter_mut().filter(|value| value_filter.matches(value)) {
+        matched = true;
+
+        match (element, sub) {
+            (Value::Object(element), Some(sub)) => {
+                let key = key_of(element, sub);
+
+                match op {
+                    "remove" => element.remove(&key),
+                    _ => element.insert(key, value.clone()),
+                };
+            }
+            (element, None) => merge(element, value),
+            _ => return Err(invalid_path()),
+        }
+    }
+
+    if !matched && op != "remove" {
+        return Err(scim_error(400, SCIM_NO_TARGET, format!("nothing matches {}", path)));
+    }
+
+    Ok(())
+}
+
+impl UserDb {
+    pub async fn create_scim_tables(&self) -> AuthResult<()> {
+        sqlx::query(CREATE_SCIM_USERS_TABLE_SQL)
+            .execute(&self.pool)
+            .await?;
+
+        sqlx::query(CREATE_SCIM_GROUPS_TABLE_SQL)
+            .execute(&self.pool)
+            .await?;
+
+        sqlx::query(CREATE_SCIM_GROUP_MEMBERS_TABLE_SQL)
+            .execute(&self.pool)
+            .await?;
+
+        for (columns_sql, add_sql) in [
+            (SCIM_USERS_COLUMNS_SQL, ADD_SCIM_USERS_CLIENT_ID_SQL),
+            (SCIM_GROUPS_COLUMNS_SQL, ADD_SCIM_GROUPS_CLIENT_ID_SQL),
+        ] {
+            let columns = sqlx::query_scalar::<_, String>(columns_sql)
+                .fetch_all(&self.pool)
+                .await?;
+
+            if !columns.iter().any(|name| name == "client_id") {
+                sqlx::query(add_sql).execute(&self.pool).await?;
+            }
+        }
+
+        let schema = sqlx::query_scalar::<_, String>(SCIM_GROUPS_SCHEMA_SQL)
+            .fetch_one(&self.pool)
+            .await?;
+
+        if !schema.contains("UNIQUE(client_id, display_name)") {
+            let create_sql =
+                CREATE_SCIM_GROUPS_TABLE_SQL.replace("scim_groups", "scim_groups_rebuild");
+
+            let mut tx = self.pool.begin().await?;
+
+            for sql in [
+                create_sql.as_str(),
+                COPY_SCIM_GROUPS_SQL,
+                DROP_SCIM_GROUPS_SQL,
+                RENAME_SCIM_GROUPS_SQL,
+            ] {
+                sqlx::query(sql).execute(&mut *tx).await?;
+            }
+
+            tx.commit().await?;
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// The external id of a user the client provisioned. Users belonging
+    /// to anyone else are reported as not existing.
+    ///
+    async fn scim_external_id(&self, client_id: &str, uuid: &str) -> AuthResult<String> {
+        sqlx::query_scalar::<_, String>(FIND_EXTERNAL_ID_SQL)
+            .bind(uuid)
+            .bind(client_id)
+            .fetch_optional(&self.pool)
+            .await?
+            .ok_or_else(|| AuthError::UserDoesNotExistError(uuid.to_string()))
+    }
+
+    pub async fn scim_user(&self, client_id: &str, uuid: &str) -> AuthResult<ScimUser> {
+        let external_id = self.scim_external_id(client_id, uuid).await?;
+        let user = self.find_user_by_uuid(uuid).await?;
+
+        let groups = sqlx::query_as::<_, (String, String)>(USER_GROUPS_SQL)
+            .bind(uuid)
+            .bind(client_id)
+            .fetch_all(&self.pool)
+            .await?
+            .into_iter()
+            .map(|(value, display)| ScimMember {
+                value,
+                display: Some(display),
+            })
+            .collect();
+
+        Ok(ScimUser::new(&user, Some(external_id).filter(|id| !id.is_empty()), groups))
+    }
+
+    ///
+    /// A page of the users the client provisioned, in username order.
+    /// Groups are loaded for the whole page rather than per user.
+    ///
+    pub async fn scim_users(
+        &self,
+        client_id: &str,
+        offset: i64,
+        limit: i64,
+    ) -> AuthResult<Vec<ScimUser>> {
+        let rows = sqlx::query_as::<_, ScimUserRow>(LIST_SCIM_USERS_SQL)
+            .bind(client_id)
+            .bind(limit)
+            .bind(offset)
+            .fetch_all(&self.pool)
+            .await?;
+
+        let memberships = sqlx::query_as::<_, (String, String, String)>(LIST_MEMBERSHIPS_SQL)
+            .bind(client_id)
+            .bind(limit)
+            .bind(offset)
+            .fetch_all(&self.pool)
+            .await?;
+
+        let mut groups: HashMap<String, Vec<ScimMember>> = HashMap::new();
+
+        for (user_uuid, value, display) in memberships {
+            groups.entry(user_uuid).or_default().push(ScimMember {
+                value,
+                display: Some(display),
+            });
+        }
+
+        Ok(rows
+            .into_iter()
+            .map(|row| {
+                ScimUser::new(
+                    &row.user,
+                    Some(row.external_id).filter(|id| !id.is_empty()),
+                    groups.remove(&row.user.uuid).unwrap_or_default(),
+                )
+            })
+            .collect())
+    }
+
+    ///
+    /// Answer a list request for the client's users. Unfiltered lists are
+    /// paged in SQL, filters match on the SCIM representation so are
+    /// applied to the client's users in memory.
+    ///
+    pub async fn list_scim_users(
+        &self,
+        client_id: &str,
+        query: &ScimListQuery,
+    ) -> AuthResult<ScimListResponse<ScimUser>> {
+        if query.filter.is_some() {
+            let users = self.scim_users(client_id, 0, i64::MAX).await?;
+
+            return list_response(users, query);
+        }
+
+        let total_results = sqlx::query_scalar::<_, i64>(COUNT_SCIM_USERS_SQL)
+            .bind(client_id)
+            .fetch_one(&self.pool)
+            .await?;
+
+        let start_index = query.start_index();
+        let users = self.scim_users(client_id, start_index - 1, query.count()).await?;
+
+        Ok(ScimListResponse::new(users, total_results as usize, start_index))
+    }
+
+    ///
+    /// Create a user pushed by an identity provider and record the client
+    /// as its owner. Unless the provider sets one the password is random,
+    /// since they sign in through the provider.
+    ///
+    pub async fn create_scim_user(
+        &self,
+        client_id: &str,
+        resource: &ScimUser,
+    ) -> AuthResult<ScimUser> {
+        if resource.user_name.trim().is_empty() {
+            return Err(scim_error(400, SCIM_INVALID_VALUE, "userName is required"));
+        }
+
+        // random tokens are long but may lack some character classes a
+        // policy requires
+        let password = match &resource.password {
+            Some(password) => password.clone(),
+            None => format!("{}aA1!", random_token()),
+        };
+
+        let user = self
+            .prepare_user(&Credentials {
+                username: resource.user_name.trim().to_string(),
+                password,
+                email: resource.primary_email().map(str::to_string),
+                first_name: Some(resource.name.given_name.clone()),
+                last_name: Some(resource.name.family_name.clone()),
+                callback_url: None,
+                url: None,
+                locale: None,
+            })
+            .await?;
+
+        let mut tx = self.pool.begin().await?;
+
+        self.insert_user(&mut *tx, &user).await?;
+
+        sqlx::query(SCIM_USER_CREATED_SQL)
+            .bind(&user.uuid)
+            .bind(resource.active)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query(CREATE_SCIM_USER_SQL)
+            .bind(&user.uuid)
+            .bind(resource.external_id.as_deref().unwrap_or(""))
+            .bind(client_id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+
+        self.scim_user(client_id, &user.uuid).await
+    }
+
+    ///
+    /// Replace a user's attributes with those from the identity provider.
+    /// Unlike `update_user` this also changes the email address without
+    /// a confirmation link, since the provider owns the account, but the
+    /// new address is no longer marked verified. Clearing `active` blocks
+    /// sign in without deleting anything.
+    ///
+    pub async fn replace_scim_user(
+        &self,
+        client_id: &str,
+        uuid: &str,
+        resource: &ScimUser,
+    ) -> AuthResult<ScimUser> {
+        self.scim_external_id(client_id, uuid).await?;
+
+        let user = self.find_user_by_uuid(uuid).await?;
+        let username = resource.user_name.trim();
+
+        if username.is_empty() {
+            return Err(scim_error(400, SCIM_INVALID_VALUE, "userName is required"));
+        }
+
+        if username != user.username && self.username_exists(username).await {
+            return Err(AuthError::UserAlreadyExistsError(username.to_string()));
+        }
+
+        let email = resource.primary_email().filter(|email| *email != user.email);
+
+        if let Some(email) = email {
+            if self.find_user_by_id(email).await.is_ok() {
+                return Err(AuthError::UserAlreadyExistsError(email.to_string()));
+            }
+        }
+
+        // everything is checked before writing so a rejected password
+        // leaves the user as it was
+        let hash = match &resource.password {
+            Some(password) => Some(self.check_new_password(&user, password).await?),
+            None => None,
+        };
+
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query(UPDATE_SCIM_USER_SQL)
+            .bind(uuid)
+            .bind(username)
+            .bind(&resource.name.given_name)
+            .bind(&resource.name.family_name)
+            .bind(resource.active)
+            .execute(&mut *tx)
+            .await?;
+
+        if let Some(email) = email {
+            sqlx::query(UPDATE_EMAIL_SQL)
+                .bind(uuid)
+                .bind(email)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        if let Some(hash) = &hash {
+            self.write_password(&mut *tx, uuid, hash, true).await?;
+        }
+
+        sqlx::query(SET_EXTERNAL_ID_SQL)
+            .bind(uuid)
+            .bind(resource.external_id.as_deref().unwrap_or(""))
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+
+        self.scim_user(client_id, uuid).await
+    }
+
+    pub async fn delete_scim_user(&self, client_id: &str, uuid: &str) -> AuthResult<()> {
+        self.scim_external_id(client_id, uuid).await?;
+
+        let mut tx = self.pool.begin().await?;
+
+        // takes the user's memberships and scim_users row with it
+        self.remove_user(&mut *tx, uuid).await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn scim_group_members(&self, uuid: &str) -> AuthResult<Vec<ScimMember>> {
+        Ok(sqlx::query_as::<_, (String, String)>(GROUP_MEMBERS_SQL)
+            .bind(uuid)
+            .fetch_all(&self.pool)
+            .await?
+            .into_iter()
+            .map(|(value, display)| ScimMember {
+                value,
+                display: Some(display),
+            })
+            .collect())
+    }
+
+    // groups may only hold users the same client provisioned
+    async fn check_group_members(&self, client_id: &str, members: &[ScimMember]) -> AuthResult<()> {
+        for member in members {
+            if self.scim_external_id(client_id, &member.value).await.is_err() {
+                return Err(scim_error(
+                    400,
+                    SCIM_INVALID_VALUE,
+                    format!("member {} is not a user", member.value),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn set_scim_group_members(
+        &self,
+        conn: &mut SqliteConnection,
+        uuid: &str,
+        members: &[ScimMember],
+    ) -> AuthResult<()> {
+        sqlx::query(DELETE_GROUP_MEMBERS_SQL)
+            .bind(uuid)
+            .execute(&mut *conn)
+            .await?;
+
+        for member in members {
+            sqlx::query(ADD_GROUP_MEMBER_SQL)
+                .bind(uuid)
+                .bind(&member.value)
+                .execute(&mut *conn)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn check_group_name(
+        &self,
+        client_id: &str,
+        uuid: &str,
+        display_name: &str,
+    ) -> AuthResult<()> {
+        if display_name.trim().is_empty() {
+            return Err(scim_error(400, SCIM_INVALID_VALUE, "displayName is required"));
+        }
+
+        let existing = sqlx::query_scalar::<_, String>(FIND_GROUP_BY_NAME_SQL)
+            .bind(display_name.trim())
+            .bind(client_id)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        match existing {
+            Some(existing) if existing != uuid => Err(scim_error(
+                409,
+                SCIM_UNIQUENESS,
+                format!("group {} already exists", display_name),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub async fn scim_group(&self, client_id: &str, uuid: &str) -> AuthResult<ScimGroup> {
+        let row = sqlx::query_as::<_, ScimGroupRow>(FIND_GROUP_SQL)
+            .bind(uuid)
+            .bind(client_id)
+            .fetch_optional(&self.pool)
+            .await?
+            .ok_or_else(|| scim_error(404, "", format!("group {} does not exist", uuid)))?;
+
+        let members = self.scim_group_members(uuid).await?;
+
+        Ok(ScimGroup::new(row, members))
+    }
+
+    pub async fn scim_groups(&self, client_id: &str) -> AuthResult<Vec<ScimGroup>> {
+        let rows = sqlx::query_as::<_, ScimGroupRow>(LIST_GROUPS_SQL)
+            .bind(client_id)
+            .fetch_all(&self.pool)
+            .await?;
+
+        let mut groups = Vec::new();
+
+        for row in rows {
+            let members = self.scim_group_members(&row.uuid).await?;
+            groups.push(ScimGroup::new(row, members));
+        }
+
+        Ok(groups)
+    }
+
+    pub async fn create_scim_group(
+        &self,
+        client_id: &str,
+        resource: &ScimGroup,
+    ) -> AuthResult<ScimGroup> {
+        let uuid = uuid();
+
+        self.check_group_name(client_id, &uuid, &resource.display_name).await?;
+        self.check_group_members(client_id, &resource.members).await?;
+
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query(CREATE_GROUP_SQL)
+            .bind(&uuid)
+            .bind(resource.display_name.trim())
+            .bind(resource.external_id.as_deref().unwrap_or(""))
+            .bind(client_id)
+            .execute(&mut *tx)
+            .await?;
+
+        self.set_scim_group_members(&mut *tx, &uuid, &resource.members).await?;
+
+        tx.commit().await?;
+
+        self.scim_group(client_id, &uuid).await
+    }
+
+    ///
+    /// Replace a group's name and members.
+    ///
+    pub async fn replace_scim_group(
+        &self,
+        client_id: &str,
+        uuid: &str,
+        resource: &ScimGroup,
+    ) -> AuthResult<ScimGroup> {
+        self.scim_group(client_id, uuid).await?;
+        self.check_group_name(client_id, uuid, &resource.display_name).await?;
+        self.check_group_members(client_id, &resource.members).await?;
+
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query(UPDATE_GROUP_SQL)
+            .bind(uuid)
+            .bind(resource.display_name.trim())
+            .bind(resource.external_id.as_deref().unwrap_or(""))
+            .execute(&mut *tx)
+            .await?;
+
+        self.set_scim_group_members(&mut *tx, uuid, &resource.members).await?;
+
+        tx.commit().await?;
+
+        self.scim_group(client_id, uuid).await
+    }
+
+    pub async fn delete_scim_group(&self, client_id: &str, uuid: &str) -> AuthResult<()> {
+        self.scim_group(client_id, uuid).await?;
+
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query(DELETE_GROUP_SQL)
+            .bind(uuid)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query(DELETE_GROUP_MEMBERS_SQL)
+            .bind(uuid)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    ///
+    /// Apply a PATCH request to a user by patching its SCIM resource and
+    /// then replacing the user with the result.
+    ///
+    pub async fn patch_scim_user(
+        &self,
+        client_id: &str,
+        uuid: &str,
+        patch: &ScimPatch,
+    ) -> AuthResult<ScimUser> {
+        let mut json = serde_json::to_value(self.scim_user(client_id, uuid).await?)
+            .map_err(|err| scim_error(500, "", err))?;
+
+        apply_patch(&mut json, patch)?;
+
+        let resource = serde_json::from_value::<ScimUser>(json)
+            .map_err(|err| scim_error(400, SCIM_INVALID_VALUE, err))?;
+
+        self.replace_scim_user(client_id, uuid, &resource).await
+    }
+
+    pub async fn patch_scim_group(
+        &self,
+        client_id: &str,
+        uuid: &str,
+        patch: &ScimPatch,
+    ) -> AuthResult<ScimGroup> {
+        let mut json = serde_json::to_value(self.scim_group(client_id, uuid).await?)
+            .map_err(|err| scim_error(500, "", err))?;
+
+        apply_patch(&mut json, patch)?;
+
+        let resource = serde_json::from_value::<ScimGroup>(json)
+            .map_err(|err| scim_error(400, SCIM_INVALID_VALUE, err))?;
+
+        self.replace_scim_group(client_id, uuid, &resource).await
+    }
+}
+
+type ScimJson<T> = ([(HeaderName, &'static str); 1], Json<T>);
+
+fn scim_json<T>(value: T) -> ScimJson<T> {
+    ([(CONTENT_TYPE, SCIM_CONTENT_TYPE)], Json(value))
+}
+
+async fn service_provider_config_handler(
+    JwtToken(claims): JwtToken,
+) -> AuthResult<ScimJson<Value>> {
+    check_scim_token(&claims)?;
+
+    Ok(scim_json(json!({
+        "schemas": [SCIM_SERVICE_PROVIDER_CONFIG_SCHEMA],
+        "patch": { "supported": true },
+        "bulk": { "supported": false, "maxOperations": 0, "maxPayloadSize": 0 },
+        "filter": { "supported": true, "maxResults": SCIM_MAX_PAGE_SIZE },
+        "changePassword": { "supported": true },
+        "sort": { "supported": false },
+        "etag": { "supported": false },
+        "authenticationSchemes": [{
+            "type": "oauthbearertoken",
+            "name": "OAuth Bearer Token",
+            "description": "A client credentials token with the scim scope",
+        }],
+    })))
+}
+
+async fn list_users_handler(
+    State(state): State<AppState>,
+    JwtToken(claims): JwtToken,
+    Query(query): Query<ScimListQuery>,
+) -> AuthResult<ScimJson<ScimListResponse<ScimUser>>> {
+    check_scim_token(&claims)?;
+
+    let users = state
+        .user_db
+        .list_scim_users(&claims.client_id, &query)
+        .await
+        .map_err(to_scim_error)?;
+
+    Ok(scim_json(users))
+}
+
+async fn create_user_handler(
+    State(state): State<AppState>,
+    JwtToken(claims): JwtToken,
+    Json(resource): Json<ScimUser>,
+) -> AuthResult<(StatusCode, ScimJson<ScimUser>)> {
+    check_scim_token(&claims)?;
+
+    let user = state
+        .user_db
+        .create_scim_user(&claims.client_id, &resource)
+        .await
+        .map_err(to_scim_error)?;
+
+    Ok((StatusCode::CREATED, scim_json(user)))
+}
+
+async fn get_user_handler(
+    State(state): State<AppState>,
+    JwtToken(claims): JwtToken,
+    Path(id): Path<String>,
+) -> AuthResult<ScimJson<ScimUser>> {
+    check_scim_token(&claims)?;
+
+    Ok(scim_json(state.user_db.scim_user(&claims.client_id, &id).await.map_err(to_scim_error)?))
+}
+
+async fn replace_user_handler(
+    State(state): State<AppState>,
+    JwtToken(claims): JwtToken,
+    Path(id): Path<String>,
+    Json(resource): Json<ScimUser>,
+) -> AuthResult<ScimJson<ScimUser>> {
+    check_scim_token(&claims)?;
+
+    Ok(scim_json(
+        state
+            .user_db
+            .replace_scim_user(&claims.client_id, &id, &resource)
+            .await
+            .map_err(to_scim_error)?,
+    ))
+}
+
+async fn patch_user_handler(
+    State(state): State<AppState>,
+    JwtToken(claims): JwtToken,
+    Path(id): Path<String>,
+    Json(patch): Json<ScimPatch>,
+) -> AuthResult<ScimJson<ScimUser>> {
+    check_scim_token(&claims)?;
+
+    Ok(scim_json(
+        state
+            .user_db
+            .patch_scim_user(&claims.client_id, &id, &patch)
+            .await
+            .map_err(to_scim_error)?,
+    ))
+}
+
+async fn delete_user_handler(
+    State(state): State<AppState>,
+    JwtToken(claims): JwtToken,
+    Path(id): Path<String>,
+) -> AuthResult<StatusCode> {
+    check_scim_token(&claims)?;
+
+    state.user_db.delete_scim_user(&claims.client_id, &id).await.map_err(to_scim_error)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn list_groups_handler(
+    State(state): State<AppState>,
+    JwtToken(claims): JwtToken,
+    Query(query): Query<ScimListQuery>,
+) -> AuthResult<ScimJson<ScimListResponse<ScimGroup>>> {
+    check_scim_token(&claims)?;
+
+    let groups = state.user_db.scim_groups(&claims.client_id).await.map_err(to_scim_error)?;
+
+    Ok(scim_json(list_response(groups, &query)?))
+}
+
+async fn create_group_handler(
+    State(state): State<AppState>,
+    JwtToken(claims): JwtToken,
+    Json(resource): Json<ScimGroup>,
+) -> AuthResult<(StatusCode, ScimJson<ScimGroup>)> {
+    check_scim_token(&claims)?;
+
+    let group = state
+        .user_db
+        .create_scim_group(&claims.client_id, &resource)
+        .await
+        .map_err(to_scim_error)?;
+
+    Ok((StatusCode::CREATED, scim_json(group)))
+}
+
+async fn get_group_handler(
+    State(state): State<AppState>,
+    JwtToken(claims): JwtToken,
+    Path(id): Path<String>,
+) -> AuthResult<ScimJson<ScimGroup>> {
+    check_scim_token(&claims)?;
+
+    Ok(scim_json(state.user_db.scim_group(&claims.client_id, &id).await.map_err(to_scim_error)?))
+}
+
+async fn replace_group_handler(
+    State(state): State<AppState>,
+    JwtToken(claims): JwtToken,
+    Path(id): Path<String>,
+    Json(resource): Json<ScimGroup>,
+) -> AuthResult<ScimJson<ScimGroup>> {
+    check_scim_token(&claims)?;
+
+    Ok(scim_json(
+        state
+            .user_db
+            .replace_scim_group(&claims.client_id, &id, &resource)
+            .await
+            .map_err(to_scim_error)?,
+    ))
+}
+
+async fn patch_group_handler(
+    State(state): State<AppState>,
+    JwtToken(claims): JwtToken,
+    Path(id): Path<String>,
+    Json(patch): Json<ScimPatch>,
+) -> AuthResult<ScimJson<ScimGroup>> {
+    check_scim_token(&claims)?;
+
+    Ok(scim_json(
+        state
+            .user_db
+            .patch_scim_group(&claims.client_id, &id, &patch)
+            .await
+            .map_err(to_scim_error)?,
+    ))
+}
+
+async fn delete_group_handler(
+    State(state): State<AppState>,
+    JwtToken(claims): JwtToken,
+    Path(id): Path<String>,
+) -> AuthResult<StatusCode> {
+    check_scim_token(&claims)?;
+
+    state.user_db.delete_scim_group(&claims.client_id, &id).await.map_err(to_scim_error)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+///
+/// SCIM 2.0 provisioning endpoints for identity providers to push users
+/// and groups. Callers need a client credentials token with the scim
+/// scope, and only see the users and groups their client created.
+///
+pub fn scim_router() -> Router<AppState> {
+    Router::new()
+        .route("/scim/v2/ServiceProviderConfig", get(service_provider_config_handler))
+        .route("/scim/v2/Users", get(list_users_handler).post(create_user_handler))
+        .route(
+            "/scim/v2/Users/:id",
+            get(get_user_handler)
+                .put(replace_user_handler)
+                .patch(patch_user_handler)
+                .delete(delete_user_handler),
+        )
+        .route("/scim/v2/Groups", get(list_groups_handler).post(create_group_handler))
+        .route(
+            "/scim/v2/Groups/:id",
+            get(get_group_handler)
+                .put(replace_group_handler)
+                .patch(patch_group_handler)
+                .delete(delete_group_handler),
+        )
+}
@@ -0,0 +1,257 @@
+use axum::{extract::State, http::StatusCode, routing::post, Json, Router};
+use jsonwebtoken::EncodingKey;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    email::{callback_link, PasswordlessEmailTemplate, PasswordlessEmailWebTemplate},
+    i18n::Messages,
+    config::{AuthConfig, TokenConfig},
+    jwt::{access_jwt, passwordless_jwt, refresh_jwt, AppState, JwtClaims, JwtToken, TokenType},
+    ldap::directory_sign_in,
+    AuthError, AuthResult, Credentials, User, UserDb,
+};
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TokensResp {
+    pub access_token: String,
+    pub refresh_token: String,
+}
+
+///
+/// Request for an emailed link or code. If `callback_url` is set the
+/// email contains a link for web clients, otherwise the raw token is
+/// sent as a code for API clients.
+///
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct EmailLinkReq {
+    pub username: String,
+    pub callback_url: Option<String>,
+    pub url: Option<String>,
+}
+
+pub fn tokens(uuid: &str, config: &TokenConfig, key: &EncodingKey) -> AuthResult<TokensResp> {
+    Ok(TokensResp {
+        access_token: access_jwt(uuid, config, key)?,
+        refresh_token: refresh_jwt(uuid, config, key)?,
+    })
+}
+
+///
+/// Check a token has the expected type before acting on it.
+///
+pub fn check_token_type(claims: &JwtClaims, token_type: &TokenType) -> AuthResult<()> {
+    if claims.token_type != token_type.to_string() {
+        return Err(AuthError::TokenError(format!(
+            "expected {} token but got {}",
+            token_type, claims.token_type
+        )));
+    }
+
+    Ok(())
+}
+
+///
+/// Check a token is a user's own access token rather than one issued to
+/// an OAuth client, which only carries the scopes the user consented to.
+///
+pub fn check_first_party(claims: &JwtClaims) -> AuthResult<()> {
+    check_token_type(claims, &TokenType::Access)?;
+
+    if !claims.client_id.is_empty() {
+        return Err(AuthError::TokenError(format!(
+            "tokens issued to client {} cannot be used here",
+            claims.client_id
+        )));
+    }
+
+    Ok(())
+}
+
+///
+/// Callback for an emailed link, falling back to the configured default.
+/// `None` means the token should be sent as a code.
+///
+pub fn email_callback_url<'a>(
+    config: &'a AuthConfig,
+    callback_url: Option<&'a str>,
+) -> Option<&'a str> {
+    callback_url.or(config.urls.callback_url.as_deref())
+}
+
+///
+/// Verify a username/password sign in. Accounts that have switched to
+/// passwordless only sign in are rejected even if the password matches,
+/// as are disabled accounts and those with an unverified email. Staff in
+/// a configured directory sign in with their directory password instead.
+///
+pub async fn password_sign_in(
+    user_db: &UserDb,
+    username: &str,
+    password: &str,
+) -> AuthResult<User> {
+    if let Some(user) = directory_sign_in(user_db, username, password).await? {
+        return Ok(user);
+    }
+
+    let user = user_db.find_user_by_id(username).await?;
+
+    user_db.verify_password(password, &user.password).await?;
+
+    // only reveal the account state once the password is known to match
+    if user.passwordless_only {
+        return Err(AuthError::PasswordlessOnlyError(username.to_string()));
+    }
+
+    user.check_can_signin()?;
+
+    // upgrade legacy hashes now we know the plain password. Failing to do
+    // so should not stop the user signing in.
+    if user_db.hasher().needs_rehash(&user.password) {
+        if let Err(err) = user_db.rehash_password(&user.uuid, password).await {
+            eprintln!("could not rehash password for {}: {}", user.uuid, err);
+        }
+    }
+
+    Ok(user)
+}
+
+pub async fn send_passwordless_email(
+    state: &AppState,
+    user: &User,
+    callback_url: Option<&str>,
+    url: Option<&str>,
+) -> AuthResult<()> {
+    if !state.config.features.passwordless {
+        return Err(AuthError::FeatureDisabledError("passwordless sign in".to_string()));
+    }
+
+    let t = Messages::for_user(user);
+    let token = passwordless_jwt(&user.uuid, &state.config.tokens, &state.jwt_private_key)?;
+    let time = t.minutes(state.config.tokens.passwordless_ttl_mins);
+    let subject = t.get("passwordless.subject");
+
+    match email_callback_url(&state.config, callback_url) {
+        Some(callback_url) => {
+            let body = PasswordlessEmailWebTemplate {
+                name: user.display_name(),
+                link: callback_link(callback_url, &token, url)?,
+                time,
+                t,
+            };
+
+            state.outbox.enqueue_html_email(&user.email, subject, &body).await?;
+        }
+        None => {
+            let body = PasswordlessEmailTemplate {
+                name: user.display_name(),
+                link: token,
+                time,
+                t,
+            };
+
+            state.outbox.enqueue_html_email(&user.email, subject, &body).await?;
+        }
+    }
+
+    Ok(())
+}
+
+///
+/// Email a passwordless sign in link if the account exists. Unknown
+/// accounts succeed too so the response does not reveal who has an
+/// account.
+///
+pub async fn request_passwordless_email(state: &AppState, req: &EmailLinkReq) -> AuthResult<()> {
+    if !state.config.features.passwordless {
+        return Err(AuthError::FeatureDisabledError("passwordless sign in".to_string()));
+    }
+
+    let user = match state.user_db.find_user_by_id(&req.username).await {
+        Ok(user) => user,
+        Err(AuthError::UserDoesNotExistError(_)) => return Ok(()),
+        Err(err) => return Err(err),
+    };
+
+    send_passwordless_email(state, &user, req.callback_url.as_deref(), req.url.as_deref()).await
+}
+
+pub async fn passwordless_sign_in(state: &AppState, claims: &JwtClaims) -> AuthResult<User> {
+    if !state.config.features.passwordless {
+        return Err(AuthError::FeatureDisabledError("passwordless sign in".to_string()));
+    }
+
+    check_token_type(claims, &TokenType::Passwordless)?;
+
+    let user = state.user_db.find_user_by_uuid(&claims.uuid).await?;
+
+    user.check_can_signin()?;
+
+    Ok(user)
+}
+
+///
+/// Exchange a refresh token for a new pair of tokens. The account is
+/// checked again so disabling a user stops them refreshing.
+///
+pub async fn refresh_tokens(state: &AppState, claims: &JwtClaims) -> AuthResult<TokensResp> {
+    check_token_type(claims, &TokenType::Refresh)?;
+
+    // client tokens are refreshed at the OAuth token endpoint so they keep
+    // their scope
+    if !claims.client_id.is_empty() {
+        return Err(AuthError::TokenError(
+            "refresh token was issued to an OAuth client".to_string(),
+        ));
+    }
+
+    let user = state.user_db.find_user_by_uuid(&claims.uuid).await?;
+
+    user.check_can_signin()?;
+
+    tokens(&user.uuid, &state.config.tokens, &state.jwt_private_key)
+}
+
+async fn signin_handler(
+    State(state): State<AppState>,
+    Json(credentials): Json<Credentials>,
+) -> AuthResult<Json<TokensResp>> {
+    let user =
+        password_sign_in(&state.user_db, &credentials.username, &credentials.password).await?;
+
+    Ok(Json(tokens(&user.uuid, &state.config.tokens, &state.jwt_private_key)?))
+}
+
+async fn passwordless_email_handler(
+    State(state): State<AppState>,
+    Json(req): Json<EmailLinkReq>,
+) -> AuthResult<StatusCode> {
+    request_passwordless_email(&state, &req).await?;
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+async fn passwordless_signin_handler(
+    State(state): State<AppState>,
+    JwtToken(claims): JwtToken,
+) -> AuthResult<Json<TokensResp>> {
+    let user = passwordless_sign_in(&state, &claims).await?;
+
+    Ok(Json(tokens(&user.uuid, &state.config.tokens, &state.jwt_private_key)?))
+}
+
+async fn refresh_handler(
+    State(state): State<AppState>,
+    JwtToken(claims): JwtToken,
+) -> AuthResult<Json<TokensResp>> {
+    Ok(Json(refresh_tokens(&state, &claims).await?))
+}
+
+pub fn signin_router() -> Router<AppState> {
+    Router::new()
+        .route("/signin", post(signin_handler))
+        .route("/passwordless/email", post(passwordless_email_handler))
+        .route("/passwordless/signin", post(passwordless_signin_handler))
+        .route("/tokens/refresh", post(refresh_handler))
+}
@@ -0,0 +1,890 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::{
+        header::{COOKIE, SET_COOKIE},
+        HeaderMap, HeaderName, StatusCode,
+    },
+    routing::{delete, get, post},
+    Json, Router,
+};
+use chrono::{Duration, Utc};
+use reqwest::{header::ACCEPT, Client};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::FromRow;
+use url::Url;
+
+use crate::{
+    config::{AuthConfig, SocialProviderConfig},
+    i18n,
+    jwt::{AppState, FirstPartyToken},
+    oauth::{code_hash, pkce_challenge, random_token, PKCE_S256},
+    signin::{tokens, TokensResp},
+    uuid, AuthError, AuthResult, User, UserDb, CREATE_USER_SQL,
+};
+
+pub const DEFAULT_SOCIAL_SCOPE: &str = "openid email profile";
+
+// long enough to sign in at the provider, including any 2FA
+pub const SOCIAL_STATE_TTL_MINS: i64 = 10;
+
+/// Cookie holding the nonce that ties a state to the browser that
+/// started the sign in
+pub const SOCIAL_NONCE_COOKIE: &str = "social_nonce";
+
+/// Providers that can be configured by name alone, see `social_preset`
+pub const SOCIAL_PRESETS: [&str; 3] = ["google", "github", "microsoft"];
+
+pub const CREATE_SOCIAL_IDENTITIES_TABLE_SQL: &'static str =
+    r#"CREATE TABLE IF NOT EXISTS social_identities (
+id INTEGER PRIMARY KEY AUTOINCREMENT,
+user_uuid TEXT NOT NULL,
+provider TEXT NOT NULL,
+subject TEXT NOT NULL,
+email TEXT NOT NULL DEFAULT '',
+created_on TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+UNIQUE(provider, subject),
+UNIQUE(user_uuid, provider))"#;
+
+pub const CREATE_SOCIAL_STATES_TABLE_SQL: &'static str =
+    r#"CREATE TABLE IF NOT EXISTS social_states (
+id INTEGER PRIMARY KEY AUTOINCREMENT,
+state TEXT NOT NULL UNIQUE,
+provider TEXT NOT NULL,
+code_verifier TEXT NOT NULL,
+user_uuid TEXT NOT NULL DEFAULT '',
+nonce TEXT NOT NULL DEFAULT '',
+expires INTEGER NOT NULL,
+created_on TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP)"#;
+
+const SOCIAL_STATES_COLUMNS_SQL: &'static str =
+    r#"SELECT name FROM pragma_table_info('social_states')"#;
+
+// states made before nonces were kept can never be matched so just expire
+const ADD_SOCIAL_STATE_NONCE_SQL: &'static str =
+    r#"ALTER TABLE social_states ADD COLUMN nonce TEXT NOT NULL DEFAULT ''"#;
+
+const DELETE_EXPIRED_SOCIAL_STATES_SQL: &'static str =
+    r#"DELETE FROM social_states WHERE expires < $1"#;
+
+const CREATE_SOCIAL_STATE_SQL: &'static str = r#"INSERT INTO social_states
+(state, provider, code_verifier, user_uuid, nonce, expires)
+VALUES($1, $2, $3, $4, $5, $6)"#;
+
+// states are deleted as they are read so each can only be used once
+const TAKE_SOCIAL_STATE_SQL: &'static str = r#"DELETE FROM social_states
+WHERE state = $1
+RETURNING provider, code_verifier, user_uuid, nonce, expires"#;
+
+const FIND_SOCIAL_IDENTITY_SQL: &'static str = r#"SELECT
+user_uuid, provider, subject, email, created_on
+FROM social_identities
+WHERE social_identities.provider = $1 AND social_identities.subject = $2 LIMIT 1"#;
+
+const DELETE_ORPHANED_SOCIAL_IDENTITY_SQL: &'static str = r#"DELETE FROM social_identities
+WHERE provider = $1 AND subject = $2 AND user_uuid NOT IN (SELECT uuid FROM users)"#;
+
+const SOCIAL_IDENTITIES_SQL: &'static str = r#"SELECT
+user_uuid, provider, subject, email, created_on
+FROM social_identities
+WHERE social_identities.user_uuid = $1
+ORDER BY provider"#;
+
+const LINK_SOCIAL_IDENTITY_SQL: &'static str = r#"INSERT INTO social_identities
+(user_uuid, provider, subject, email)
+VALUES($1, $2, $3, $4)"#;
+
+const UNLINK_SOCIAL_IDENTITY_SQL: &'static str =
+    r#"DELETE FROM social_identities WHERE user_uuid = $1 AND provider = $2"#;
+
+///
+/// An account at a provider linked to a user, who can then sign in with it.
+///
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct SocialIdentity {
+    #[serde(skip_serializing)]
+    pub user_uuid: String,
+    pub provider: String,
+    pub subject: String,
+    pub email: String,
+    pub created_on: String,
+}
+
+///
+/// A sign in started at a provider, waiting for the user to come back.
+/// `user_uuid` is set when a signed in user is linking an identity.
+///
+#[derive(Debug, Clone, FromRow)]
+pub struct SocialState {
+    pub provider: String,
+    pub code_verifier: String,
+    pub user_uuid: String,
+    /// Hash of the nonce the browser that started the sign in holds
+    pub nonce: String,
+    pub expires: i64,
+}
+
+///
+/// What a provider says about the user, from OpenID Connect userinfo or
+/// the provider's own profile format.
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SocialProfile {
+    pub subject: String,
+    pub email: String,
+    pub email_verified: bool,
+    pub first_name: String,
+    pub last_name: String,
+    pub locale: String,
+}
+
+fn social_error(error: impl ToString) -> AuthError {
+    AuthError::SocialLoginError(error.to_string())
+}
+
+fn json_string(value: &Value, name: &str) -> String {
+    match value.get(name) {
+        Some(Value::String(s)) => s.clone(),
+        // GitHub and others use numeric ids
+        Some(Value::Number(n)) => n.to_string(),
+        _ => String::new(),
+    }
+}
+
+impl SocialProfile {
+    pub fn from_json(value: &Value, config: &SocialProviderConfig) -> AuthResult<Self> {
+        let subject = json_string(value, &config.subject_claim);
+
+        if subject.is_empty() {
+            return Err(social_error(format!("profile has no {} field", config.subject_claim)));
+        }
+
+        let mut first_name = json_string(value, "given_name");
+        let mut last_name = json_string(value, "family_name");
+
+        // providers without OIDC claims only give a full name
+        if first_name.is_empty() && last_name.is_empty() {
+            let name = json_string(value, "name");
+            let mut parts = name.trim().splitn(2, ' ');
+
+            first_name = parts.next().unwrap_or("").to_string();
+            last_name = parts.next().unwrap_or("").trim().to_string();
+        }
+
+        let email_verified = match value.get("email_verified") {
+            Some(Value::Bool(verified)) => *verified,
+            // some providers send it as a string
+            Some(Value::String(verified)) => verified == "true",
+            _ => config.trust_email,
+        };
+
+        Ok(SocialProfile {
+            subject,
+            email: json_string(value, "email"),
+            email_verified,
+            first_name,
+            last_name,
+            locale: json_string(value, "locale"),
+        })
+    }
+}
+
+impl SocialProviderConfig {
+    pub fn google(client_id: &str, client_secret: &str) -> Self {
+        Self {
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+            authorization_url: "https://accounts.google.com/o/oauth2/v2/auth".to_string(),
+            token_url: "https://oauth2.googleapis.com/token".to_string(),
+            userinfo_url: "https://openidconnect.googleapis.com/v1/userinfo".to_string(),
+            ..Default::default()
+        }
+    }
+
+    ///
+    /// GitHub is plain OAuth 2.0, so the profile is its user api and
+    /// verified addresses come from the emails api.
+    ///
+    pub fn github(client_id: &str, client_secret: &str) -> Self {
+        Self {
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+            authorization_url: "https://github.com/login/oauth/authorize".to_string(),
+            token_url: "https://github.com/login/oauth/access_token".to_string(),
+            userinfo_url: "https://api.github.com/user".to_string(),
+            emails_url: Some("https://api.github.com/user/emails".to_string()),
+            scope: "read:user user:email".to_string(),
+            subject_claim: "id".to_string(),
+            ..Default::default()
+        }
+    }
+
+    ///
+    /// Microsoft accounts from any tenant. Microsoft does not say whether
+    /// emails are verified, so set `trust_email` only for tenants you
+    /// control.
+    ///
+    pub fn microsoft(client_id: &str, client_secret: &str) -> Self {
+        let base = "https://login.microsoftonline.com/common/oauth2/v2.0";
+
+        Self {
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+            authorization_url: format!("{}/authorize", base),
+            token_url: format!("{}/token", base),
+            userinfo_url: "https://graph.microsoft.com/oidc/userinfo".to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+pub fn social_preset(
+    name: &str,
+    client_id: &str,
+    client_secret: &str,
+) -> Option<SocialProviderConfig> {
+    match name {
+        "google" => Some(SocialProviderConfig::google(client_id, client_secret)),
+        "github" => Some(SocialProviderConfig::github(client_id, client_secret)),
+        "microsoft" => Some(SocialProviderConfig::microsoft(client_id, client_secret)),
+        _ => None,
+    }
+}
+
+///
+/// A configured provider and the client used to talk to it.
+///
+#[derive(Debug, Clone)]
+pub struct SocialProvider {
+    pub name: String,
+    pub config: SocialProviderConfig,
+    pub redirect_uri: String,
+    http: Client,
+}
+
+impl SocialProvider {
+    pub fn new(name: &str, config: SocialProviderConfig, redirect_uri: &str) -> AuthResult<Self> {
+        // GitHub rejects api requests without a user agent
+        let http = match Client::builder().user_agent("auth").build() {
+            Ok(http) => http,
+            Err(err) => return Err(social_error(err)),
+        };
+
+        Ok(Self {
+            name: name.to_string(),
+            config,
+            redirect_uri: redirect_uri.to_string(),
+            http,
+        })
+    }
+
+    pub fn from_config(config: &AuthConfig, name: &str) -> AuthResult<Self> {
+        let provider = match config.social.get(name) {
+            Some(provider) => provider,
+            None => return Err(social_error(format!("unknown provider {}", name))),
+        };
+
+        let redirect_uri = match (&provider.redirect_uri, &config.urls.public_url) {
+            (Some(redirect_uri), _) => redirect_uri.clone(),
+            (None, Some(url)) => {
+                format!("{}/social/{}/callback", url.trim_end_matches('/'), name)
+            }
+            (None, None) => {
+                return Err(AuthError::ConfigError(format!(
+                    "social.{}.redirect_uri or urls.public_url must be set",
+                    name
+                )))
+            }
+        };
+
+        Self::new(name, provider.clone(), &redirect_uri)
+    }
+
+    pub fn authorize_url(&self, state: &str, code_verifier: &str) -> AuthResult<String> {
+        let challenge = pkce_challenge(code_verifier);
+
+        match Url::parse_with_params(
+            &self.config.authorization_url,
+            [
+                ("response_type", "code"),
+                ("client_id", self.config.client_id.as_str()),
+                ("redirect_uri", self.redirect_uri.as_str()),
+                ("scope", self.config.scope.as_str()),
+                ("state", state),
+                ("code_challenge", challenge.as_str()),
+                ("code_challenge_method", PKCE_S256),
+            ],
+        ) {
+            Ok(url) => Ok(url.to_string()),
+            Err(err) => Err(AuthError::ConfigError(err.to_string())),
+        }
+    }
+
+    async fn get_json(&self, url: &str, access_token: &str) -> AuthResult<Value> {
+        let resp = match self
+            .http
+            .get(url)
+            .bearer_auth(access_token)
+            .header(ACCEPT, "application/json")
+            .send()
+            .await
+        {
+            Ok(resp) => resp,
+            Err(err) => return Err(social_error(err)),
+        };
+
+        if !resp.status().is_success() {
+            return Err(social_error(format!("{} returned {}", url, resp.status())));
+        }
+
+        resp.json::<Value>().await.map_err(social_error)
+    }
+
+    ///
+    /// Exchange the authorization code for an access token.
+    ///
+    pub async fn exchange_code(&self, code: &str, code_verifier: &str) -> AuthResult<String> {
+        let resp = match self
+            .http
+            .post(&self.config.token_url)
+            // GitHub answers in form encoding unless asked for json
+            .header(ACCEPT, "application/json")
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", self.redirect_uri.as_str()),
+                ("client_id", self.config.client_id.as_str()),
+                ("client_secret", self.config.client_secret.as_str()),
+                ("code_verifier", code_verifier),
+            ])
+            .send()
+            .await
+        {
+            Ok(resp) => resp,
+            Err(err) => return Err(social_error(err)),
+        };
+
+        let body = resp.json::<Value>().await.map_err(social_error)?;
+
+        match body.get("access_token").and_then(|token| token.as_str()) {
+            Some(token) => Ok(token.to_string()),
+            None => Err(social_error(format!(
+                "{}: {}",
+                json_string(&body, "error"),
+                json_string(&body, "error_description")
+            ))),
+        }
+    }
+
+    pub async fn profile(&self, access_token: &str) -> AuthResult<SocialProfile> {
+        let json = self.get_json(&self.config.userinfo_url, access_token).await?;
+
+        let mut profile = SocialProfile::from_json(&json, &self.config)?;
+
+        // use the primary address if the provider has confirmed it
+        if let Some(emails_url) = &self.config.emails_url {
+            let emails = self.get_json(emails_url, access_token).await?;
+
+            let primary = emails.as_array().and_then(|emails| {
+                emails.iter().find(|email| {
+                    email.get("primary").and_then(|p| p.as_bool()).unwrap_or(false)
+                })
+            });
+
+            if let Some(primary) = primary {
+                profile.email = json_string(primary, "email");
+                profile.email_verified =
+                    primary.get("verified").and_then(|v| v.as_bool()).unwrap_or(false);
+            }
+        }
+
+        Ok(profile)
+    }
+}
+
+impl UserDb {
+    pub async fn create_social_tables(&self) -> AuthResult<()> {
+        for sql in [CREATE_SOCIAL_IDENTITIES_TABLE_SQL, CREATE_SOCIAL_STATES_TABLE_SQL] {
+            sqlx::query(sql).execute(&self.pool).await?;
+        }
+
+        let columns = sqlx::query_scalar::<_, String>(SOCIAL_STATES_COLUMNS_SQL)
+            .fetch_all(&self.pool)
+            .await?;
+
+        if !columns.iter().any(|name| name == "nonce") {
+            sqlx::query(ADD_SOCIAL_STATE_NONCE_SQL)
+                .execute(&self.pool)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Remember a sign in being started, returning the state to send the
+    /// provider, the PKCE verifier for the code and the nonce the browser
+    /// must present at the callback.
+    ///
+    pub async fn create_social_state(
+        &self,
+        provider: &str,
+        user_uuid: &str,
+    ) -> AuthResult<(String, String, String)> {
+        let now = Utc::now();
+
+        sqlx::query(DELETE_EXPIRED_SOCIAL_STATES_SQL)
+            .bind(now.timestamp())
+            .execute(&self.pool)
+            .await?;
+
+        let state = random_token();
+        let code_verifier = random_token();
+        let nonce = random_token();
+
+        sqlx::query(CREATE_SOCIAL_STATE_SQL)
+            .bind(code_hash(&state))
+            .bind(provider)
+            .bind(&code_verifier)
+            .bind(user_uuid)
+            .bind(code_hash(&nonce))
+            .bind((now + Duration::minutes(SOCIAL_STATE_TTL_MINS)).timestamp())
+            .execute(&self.pool)
+            .await?;
+
+        Ok((state, code_verifier, nonce))
+    }
+
+    ///
+    /// Spend a state. The state alone can be seen in the provider's
+    /// redirect, so it only counts with the nonce from the browser that
+    /// started the sign in, and for links only for the user who asked.
+    ///
+    pub async fn take_social_state(
+        &self,
+        state: &str,
+        provider: &str,
+        nonce: &str,
+        user_uuid: &str,
+    ) -> AuthResult<SocialState> {
+        let social_state = match sqlx::query_as::<_, SocialState>(TAKE_SOCIAL_STATE_SQL)
+            .bind(code_hash(state))
+            .fetch_optional(&self.pool)
+            .await?
+        {
+            Some(social_state) => social_state,
+            None => return Err(social_error("state is invalid or has already been used")),
+        };
+
+        if social_state.provider != provider || social_state.expires <= Utc::now().timestamp() {
+            return Err(social_error("state is invalid or has expired"));
+        }
+
+        if nonce.is_empty() || social_state.nonce != code_hash(nonce) {
+            return Err(social_error("state was started in another browser"));
+        }
+
+        if social_state.user_uuid != user_uuid {
+            return Err(social_error(if user_uuid.is_empty() {
+                "finish linking with the access token of the user who started it"
+            } else {
+                "state was started by another user"
+            }));
+        }
+
+        Ok(social_state)
+    }
+
+    ///
+    /// The identity for a provider's subject. Identities left behind by
+    /// users deleted before their identities went with them are unlinked
+    /// rather than returned, so the provider account can be used again.
+    ///
+    pub async fn find_social_identity(
+        &self,
+        provider: &str,
+        subject: &str,
+    ) -> AuthResult<Option<SocialIdentity>> {
+        sqlx::query(DELETE_ORPHANED_SOCIAL_IDENTITY_SQL)
+            .bind(provider)
+            .bind(subject)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(sqlx::query_as::<_, SocialIdentity>(FIND_SOCIAL_IDENTITY_SQL)
+            .bind(provider)
+            .bind(subject)
+            .fetch_optional(&self.pool)
+            .await?)
+    }
+
+    pub async fn social_identities(&self, user_uuid: &str) -> AuthResult<Vec<SocialIdentity>> {
+        Ok(sqlx::query_as::<_, SocialIdentity>(SOCIAL_IDENTITIES_SQL)
+            .bind(user_uuid)
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    pub async fn link_social_identity(
+        &self,
+        user_uuid: &str,
+        provider: &str,
+        profile: &SocialProfile,
+    ) -> AuthResult<()> {
+        match sqlx::query(LINK_SOCIAL_IDENTITY_SQL)
+            .bind(user_uuid)
+            .bind(provider)
+            .bind(&profile.subject)
+            .bind(&profile.email)
+            .execute(&self.pool)
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(sqlx::Error::Database(err)) if err.is_unique_violation() => Err(social_error(
+                format!("a {} account is already linked", provider),
+            )),
+            Err(err) => Err(AuthError::DatabaseError(err.to_string())),
+        }
+    }
+
+    ///
+    /// Unlink an identity. Accounts made by social sign in have no password
+    /// the user knows, so their last identity cannot be unlinked.
+    ///
+    pub async fn unlink_social_identity(&self, user_uuid: &str, provider: &str) -> AuthResult<()> {
+        let user = self.find_user_by_uuid(user_uuid).await?;
+        let identities = self.social_identities(user_uuid).await?;
+
+        if !identities.iter().any(|identity| identity.provider == provider) {
+            return Err(social_error(format!("no {} account is linked", provider)));
+        }
+
+        if user.passwordless_only && identities.len() == 1 {
+            return Err(social_error(
+                "set a password before unlinking the last social account",
+            ));
+        }
+
+        sqlx::query(UNLINK_SOCIAL_IDENTITY_SQL)
+            .bind(user_uuid)
+            .bind(provider)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    ///
+    /// Create an account for someone signing in with a provider for the
+    /// first time. Their email is their username, and since they never
+    /// chose a password they sign in without one.
+    ///
+    pub async fn create_social_user(
+        &self,
+        provider: &str,
+        profile: &SocialProfile,
+    ) -> AuthResult<User> {
+        // every account needs a verified email so it can be recovered
+        if profile.email.is_empty() || !profile.email_verified {
+            return Err(social_error(format!(
+                "{} did not share a verified email address",
+                provider
+            )));
+        }
+
+        if self.username_exists(&profile.email).await {
+            return Err(AuthError::UserAlreadyExistsError(profile.email.clone()));
+        }
+
+        let user_uuid = uuid();
+        let hash = self.hash_password(&random_token()).await?;
+        let locale = i18n::normalize_locale(&profile.locale).unwrap_or(i18n::DEFAULT_LOCALE);
+
+        if let Err(err) = sqlx::query(&CREATE_USER_SQL)
+            .bind(&user_uuid)
+            .bind(&profile.email)
+            .bind(&profile.email)
+            .bind(&profile.first_name)
+            .bind(&profile.last_name)
+            .bind(&hash)
+            .bind(locale)
+            .execute(&self.pool)
+            .await
+        {
+            return Err(AuthError::CouldNotCreateUserError(err.to_string()));
+        }
+
+        self.user_verified(&user_uuid).await?;
+        self.set_passwordless_only(&user_uuid, true).await?;
+        self.link_social_identity(&user_uuid, provider, profile).await?;
+
+        self.find_user_by_uuid(&user_uuid).await
+    }
+}
+
+///
+/// Start signing in, or linking an identity to `user_uuid` if it is not
+/// empty. Returns the provider url to send the user to and the nonce the
+/// browser must hand back at the callback.
+///
+pub async fn social_authorize(
+    user_db: &UserDb,
+    provider: &SocialProvider,
+    user_uuid: &str,
+) -> AuthResult<(String, String)> {
+    let (state, code_verifier, nonce) =
+        user_db.create_social_state(&provider.name, user_uuid).await?;
+
+    Ok((provider.authorize_url(&state, &code_verifier)?, nonce))
+}
+
+///
+/// Finish at the provider's callback, returning the user to sign in, or
+/// to link to when `user_uuid` is the signed in user who started a link.
+/// Identities are matched by the provider's subject. A new identity is
+/// linked to an existing account with the same email only if both the
+/// provider and the account have verified it, otherwise a new account is
+/// made.
+///
+pub async fn social_callback(
+    user_db: &UserDb,
+    provider: &SocialProvider,
+    code: &str,
+    state: &str,
+    nonce: &str,
+    user_uuid: &str,
+) -> AuthResult<User> {
+    let social_state = user_db
+        .take_social_state(state, &provider.name, nonce, user_uuid)
+        .await?;
+
+    let access_token = provider.exchange_code(code, &social_state.code_verifier).await?;
+    let profile = provider.profile(&access_token).await?;
+
+    let identity = user_db.find_social_identity(&provider.name, &profile.subject).await?;
+
+    if !social_state.user_uuid.is_empty() {
+        match identity {
+            Some(identity) if identity.user_uuid != social_state.user_uuid => {
+                return Err(social_error(format!(
+                    "this {} account is linked to another user",
+                    provider.name
+                )))
+            }
+            Some(_) => (),
+            None => {
+                user_db
+                    .link_social_identity(&social_state.user_uuid, &provider.name, &profile)
+                    .await?
+            }
+        }
+
+        return user_db.find_user_by_uuid(&social_state.user_uuid).await;
+    }
+
+    if let Some(identity) = identity {
+        return user_db.find_user_by_uuid(&identity.user_uuid).await;
+    }
+
+    if profile.email_verified && !profile.email.is_empty() {
+        if let Ok(user) = user_db.find_user_by_email(&profile.email).await {
+            if !user.email_verified {
+                return Err(social_error(
+                    "an account with this email is not verified, sign in and link it instead",
+                ));
+            }
+
+            user_db.link_social_identity(&user.uuid, &provider.name, &profile).await?;
+
+            return Ok(user);
+        }
+    }
+
+    user_db.create_social_user(&provider.name, &profile).await
+}
+
+///
+/// Where to send the user, and the nonce to hand back at the callback.
+/// The nonce is also set as a cookie for callbacks that come straight
+/// from the provider.
+///
+#[derive(Serialize, Debug, Clone)]
+pub struct SocialAuthorizeResp {
+    pub url: String,
+    pub nonce: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SocialCallbackReq {
+    pub code: Option<String>,
+    pub state: String,
+    /// Falls back to the nonce cookie if not sent
+    pub nonce: Option<String>,
+    pub error: Option<String>,
+    pub error_description: Option<String>,
+}
+
+fn nonce_cookie(nonce: &str) -> String {
+    format!(
+        "{}={}; Path=/; Max-Age={}; HttpOnly; Secure; SameSite=Lax",
+        SOCIAL_NONCE_COOKIE,
+        nonce,
+        SOCIAL_STATE_TTL_MINS * 60
+    )
+}
+
+fn cookie_nonce(headers: &HeaderMap) -> Option<String> {
+    let prefix = format!("{}=", SOCIAL_NONCE_COOKIE);
+
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .find_map(|cookie| cookie.trim().strip_prefix(&prefix).map(|nonce| nonce.to_string()))
+}
+
+async fn authorize_resp(
+    state: &AppState,
+    provider: &str,
+    user_uuid: &str,
+) -> AuthResult<([(HeaderName, String); 1], Json<SocialAuthorizeResp>)> {
+    let provider = SocialProvider::from_config(&state.config, provider)?;
+
+    let (url, nonce) = social_authorize(&state.user_db, &provider, user_uuid).await?;
+
+    Ok(([(SET_COOKIE, nonce_cookie(&nonce))], Json(SocialAuthorizeResp { url, nonce })))
+}
+
+async fn authorize_handler(
+    State(state): State<AppState>,
+    Path(provider): Path<String>,
+) -> AuthResult<([(HeaderName, String); 1], Json<SocialAuthorizeResp>)> {
+    authorize_resp(&state, &provider, "").await
+}
+
+async fn link_handler(
+    State(state): State<AppState>,
+    Path(provider): Path<String>,
+    FirstPartyToken(claims): FirstPartyToken,
+) -> AuthResult<([(HeaderName, String); 1], Json<SocialAuthorizeResp>)> {
+    authorize_resp(&state, &provider, &claims.uuid).await
+}
+
+async fn callback(
+    state: &AppState,
+    provider: &str,
+    headers: &HeaderMap,
+    req: &SocialCallbackReq,
+    user_uuid: &str,
+) -> AuthResult<User> {
+    let provider = SocialProvider::from_config(&state.config, provider)?;
+
+    if let Some(error) = &req.error {
+        return Err(social_error(format!(
+            "{}: {}",
+            error,
+            req.error_description.as_deref().unwrap_or("")
+        )));
+    }
+
+    let code = match &req.code {
+        Some(code) => code,
+        None => return Err(social_error("code is required")),
+    };
+
+    let nonce = match &req.nonce {
+        Some(nonce) => nonce.clone(),
+        None => cookie_nonce(headers).unwrap_or_default(),
+    };
+
+    social_callback(&state.user_db, &provider, code, &req.state, &nonce, user_uuid).await
+}
+
+async fn sign_in_callback(
+    state: &AppState,
+    provider: &str,
+    headers: &HeaderMap,
+    req: &SocialCallbackReq,
+) -> AuthResult<TokensResp> {
+    let user = callback(state, provider, headers, req, "").await?;
+
+    user.check_can_signin()?;
+
+    tokens(&user.uuid, &state.config.tokens, &state.jwt_private_key)
+}
+
+async fn callback_handler(
+    State(state): State<AppState>,
+    Path(provider): Path<String>,
+    headers: HeaderMap,
+    Query(req): Query<SocialCallbackReq>,
+) -> AuthResult<Json<TokensResp>> {
+    Ok(Json(sign_in_callback(&state, &provider, &headers, &req).await?))
+}
+
+async fn callback_post_handler(
+    State(state): State<AppState>,
+    Path(provider): Path<String>,
+    headers: HeaderMap,
+    Json(req): Json<SocialCallbackReq>,
+) -> AuthResult<Json<TokensResp>> {
+    Ok(Json(sign_in_callback(&state, &provider, &headers, &req).await?))
+}
+
+///
+/// Finish linking. The user already has tokens, so this only needs their
+/// access token and answers with their identities.
+///
+async fn link_callback_handler(
+    State(state): State<AppState>,
+    Path(provider): Path<String>,
+    FirstPartyToken(claims): FirstPartyToken,
+    headers: HeaderMap,
+    Json(req): Json<SocialCallbackReq>,
+) -> AuthResult<Json<Vec<SocialIdentity>>> {
+    callback(&state, &provider, &headers, &req, &claims.uuid).await?;
+
+    Ok(Json(state.user_db.social_identities(&claims.uuid).await?))
+}
+
+async fn identities_handler(
+    State(state): State<AppState>,
+    FirstPartyToken(claims): FirstPartyToken,
+) -> AuthResult<Json<Vec<SocialIdentity>>> {
+    Ok(Json(state.user_db.social_identities(&claims.uuid).await?))
+}
+
+async fn unlink_handler(
+    State(state): State<AppState>,
+    Path(provider): Path<String>,
+    FirstPartyToken(claims): FirstPartyToken,
+) -> AuthResult<StatusCode> {
+    state
+        .user_db
+        .unlink_social_identity(&claims.uuid, &provider)
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+///
+/// Sign in with configured providers. Front ends send users to the url
+/// from `authorize` or `link`, and the provider sends them back to the
+/// callback, either here directly or via a page that posts the code.
+/// Links are finished by posting the code to `link/callback` with the
+/// user's access token.
+///
+pub fn social_router() -> Router<AppState> {
+    Router::new()
+        .route("/social/identities", get(identities_handler))
+        .route("/social/:provider", delete(unlink_handler))
+        .route("/social/:provider/authorize", get(authorize_handler))
+        .route("/social/:provider/link", post(link_handler))
+        .route("/social/:provider/link/callback", post(link_callback_handler))
+        .route(
+            "/social/:provider/callback",
+            get(callback_handler).post(callback_post_handler),
+        )
+}
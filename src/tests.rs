@@ -13,7 +13,7 @@ struct EmailTemplate {
     name: String,
     link: String,
     time: String,
-    do_not_reply: String,
+    t: crate::i18n::Messages,
 }
 
  
@@ -57,4 +57,3089 @@ struct EmailTemplate {
 fn test_generate_key() {
  
     generate_key();
-}
\ No newline at end of file
+}
+#[cfg(test)]
+async fn test_pool() -> sqlx::Pool<sqlx::Sqlite> {
+    sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap()
+}
+
+#[cfg(test)]
+const CREATE_USERS_TABLE_SQL: &'static str = r#"CREATE TABLE users (
+id INTEGER PRIMARY KEY AUTOINCREMENT,
+uuid TEXT NOT NULL UNIQUE,
+first_name TEXT NOT NULL DEFAULT '',
+last_name TEXT NOT NULL DEFAULT '',
+username TEXT NOT NULL UNIQUE,
+email TEXT NOT NULL UNIQUE,
+password TEXT NOT NULL DEFAULT '',
+can_signin BOOLEAN NOT NULL DEFAULT 1,
+email_verified BOOLEAN NOT NULL DEFAULT 0,
+passwordless_only BOOLEAN NOT NULL DEFAULT 0,
+locale TEXT NOT NULL DEFAULT 'en',
+updated_on TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP)"#;
+
+#[cfg(test)]
+async fn test_user_db(policy: &crate::password_policy::PasswordPolicy) -> crate::UserDb {
+    let pool = test_pool().await;
+
+    sqlx::query(CREATE_USERS_TABLE_SQL).execute(&pool).await.unwrap();
+
+    let user_db = crate::UserDb::new(pool).with_password_policy(policy);
+
+    user_db.create_password_history_table().await.unwrap();
+
+    user_db
+}
+
+///
+/// App state over the user database's pool, with an outbox to capture
+/// emails and a mailer that is never used.
+///
+#[cfg(test)]
+async fn test_app_state(
+    user_db: crate::UserDb,
+    config: crate::config::AuthConfig,
+) -> crate::jwt::AppState {
+    use std::sync::Arc;
+
+    use crate::{config::SmtpConfig, email::Mailer, keys::KeyPair, outbox::Outbox};
+
+    let outbox = Outbox::new(user_db.pool.clone());
+    outbox.create_table().await.unwrap();
+
+    let mailer = Mailer::new(&SmtpConfig {
+        name: "Auth".to_string(),
+        from: "auth@example.com".to_string(),
+        host: "localhost".to_string(),
+        ..Default::default()
+    })
+    .unwrap();
+
+    let key_pair = KeyPair::generate();
+
+    crate::jwt::AppState {
+        config: Arc::new(config),
+        user_db,
+        mailer,
+        outbox,
+        jwt_public_key: key_pair.jwt_decoding_key(),
+        jwt_private_key: key_pair.jwt_encoding_key().unwrap(),
+        jwt_public_jwk: key_pair.public_key().jwk(),
+    }
+}
+
+#[tokio::test]
+async fn test_outbox_dead_letter_and_requeue() {
+    use crate::{
+        jwt::{JwtClaims, TokenType},
+        outbox::{check_outbox_token, Outbox, OutboxStatus, OUTBOX_CLAIM_SECS},
+        AuthError,
+    };
+
+    let claims = |scope: &str| JwtClaims {
+        uuid: "ops".to_string(),
+        token_type: TokenType::Service.to_string(),
+        otp: String::new(),
+        iss: String::new(),
+        client_id: "ops".to_string(),
+        scope: scope.to_string(),
+        exp: 0,
+    };
+
+    assert!(check_outbox_token(&claims("outbox")).is_ok());
+    assert!(matches!(check_outbox_token(&claims("openid")), Err(AuthError::OAuthError(_, _))));
+
+    let outbox = Outbox::new(test_pool().await).with_retries(2, 10, 60);
+
+    outbox.create_table().await.unwrap();
+
+    let id = outbox
+        .enqueue("user@example.com", "Test", "test", "<p>test</p>")
+        .await
+        .unwrap();
+
+    assert_eq!(outbox.backoff_secs(1), 10);
+    assert_eq!(outbox.backoff_secs(3), 40);
+    assert_eq!(outbox.backoff_secs(10), 60);
+
+    let message = outbox.find_message(id).await.unwrap();
+    outbox.mark_failed(&message, "smtp down").await.unwrap();
+
+    let message = outbox.find_message(id).await.unwrap();
+    assert_eq!(message.status, OutboxStatus::Pending.to_string());
+
+    outbox.mark_failed(&message, "smtp down").await.unwrap();
+
+    let dead = outbox.list_messages(&OutboxStatus::Dead, 10).await.unwrap();
+    assert_eq!(dead.len(), 1);
+    assert_eq!(dead[0].last_error, "smtp down");
+
+    outbox.requeue(id).await.unwrap();
+
+    let message = outbox.find_message(id).await.unwrap();
+    assert_eq!(message.status, OutboxStatus::Pending.to_string());
+    assert_eq!(message.attempts, 0);
+
+    // a due message is handed to one worker only until its claim expires
+    let now = message.next_attempt_on;
+
+    assert_eq!(outbox.claim_due(now).await.unwrap().len(), 1);
+    assert!(outbox.claim_due(now).await.unwrap().is_empty());
+    assert_eq!(outbox.claim_due(now + OUTBOX_CLAIM_SECS).await.unwrap().len(), 1);
+}
+
+#[test]
+fn test_html_to_text() {
+    use crate::{
+        email::{EmailTemplate, EmailVerificationWebTemplate},
+        i18n::Messages,
+    };
+
+    let body = EmailVerificationWebTemplate {
+        name: "Antony".to_string(),
+        link: "https://example.com/verify?token=a&url=b".to_string(),
+        time: Messages::default().minutes(10),
+        t: Messages::default(),
+    };
+
+    let html = body.render().unwrap();
+    let text = body.render_text(&html).unwrap();
+
+    assert_eq!(
+        text,
+        "Hi Antony,\n\nPlease verify your email address using this link: https://example.com/verify?token=a&url=b\n\nThis link is valid for 10 minutes.\n\nPlease do not reply to this message. It was sent from a notification-only email address that we don't monitor."
+    );
+}
+
+#[test]
+fn test_negotiate_locale() {
+    use crate::i18n::{negotiate_locale, Messages};
+
+    assert_eq!(negotiate_locale("fr-CH, fr;q=0.9, en;q=0.8"), "fr");
+    assert_eq!(negotiate_locale("de-DE, es;q=0.5, en;q=0.7"), "en");
+    assert_eq!(negotiate_locale("de, *;q=0.5"), "en");
+
+    let t = Messages::new("es-MX");
+
+    assert_eq!(t.minutes(1), "1 minuto");
+    assert_eq!(t.minutes(10), "10 minutos");
+    assert_eq!(t.get("missing.key"), "missing.key");
+}
+
+#[tokio::test]
+async fn test_migrate_users_table() {
+    let pool = test_pool().await;
+
+    // the users table as it was before locales and passwordless only
+    // accounts
+    sqlx::query(
+        r#"CREATE TABLE users (
+id INTEGER PRIMARY KEY AUTOINCREMENT,
+uuid TEXT NOT NULL UNIQUE,
+first_name TEXT NOT NULL DEFAULT '',
+last_name TEXT NOT NULL DEFAULT '',
+username TEXT NOT NULL UNIQUE,
+email TEXT NOT NULL UNIQUE,
+password TEXT NOT NULL DEFAULT '',
+can_signin BOOLEAN NOT NULL DEFAULT 1,
+email_verified BOOLEAN NOT NULL DEFAULT 0,
+updated_on TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP)"#,
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+
+    sqlx::query("INSERT INTO users (uuid, username, email) VALUES('1', 'ada', 'ada@example.com')")
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    let user_db = crate::UserDb::new(pool);
+
+    assert!(user_db.find_user_by_username("ada").await.is_err());
+
+    user_db.migrate().await.unwrap();
+    user_db.migrate().await.unwrap();
+
+    let user = user_db.find_user_by_username("ada").await.unwrap();
+    assert_eq!(user.locale, "en");
+    assert!(!user.passwordless_only);
+
+    // the default policy keeps history, so the table has to exist
+    user_db.update_password(&user.uuid, "Violet-Kettle-42").await.unwrap();
+    assert_eq!(user_db.password_history(&user.uuid).await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn test_migrate_scim_groups_table() {
+    use crate::{password_policy::PasswordPolicy, scim::ScimGroup};
+
+    let user_db = test_user_db(&PasswordPolicy::default()).await;
+
+    // the groups table as it was before groups had owners
+    sqlx::query(
+        r#"CREATE TABLE scim_groups (
+id INTEGER PRIMARY KEY AUTOINCREMENT,
+uuid TEXT NOT NULL UNIQUE,
+display_name TEXT NOT NULL UNIQUE,
+external_id TEXT NOT NULL DEFAULT '',
+created_on TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP)"#,
+    )
+    .execute(&user_db.pool)
+    .await
+    .unwrap();
+
+    sqlx::query("INSERT INTO scim_groups (uuid, display_name) VALUES('1', 'Engineers')")
+        .execute(&user_db.pool)
+        .await
+        .unwrap();
+
+    user_db.create_scim_tables().await.unwrap();
+    user_db.create_scim_tables().await.unwrap();
+
+    // existing groups are kept but belong to no client
+    assert_eq!(user_db.scim_group("", "1").await.unwrap().display_name, "Engineers");
+
+    let engineers = ScimGroup {
+        schemas: vec![],
+        id: String::new(),
+        external_id: None,
+        display_name: "Engineers".to_string(),
+        members: vec![],
+        meta: None,
+    };
+
+    user_db.create_scim_group("idp", &engineers).await.unwrap();
+    user_db.create_scim_group("other", &engineers).await.unwrap();
+    assert!(user_db.create_scim_group("idp", &engineers).await.is_err());
+}
+
+///
+/// Minimal relaxed/relaxed DKIM verifier for ed25519-sha256 signatures
+/// (RFC 6376, RFC 8463) so signing can be checked without DNS.
+///
+#[cfg(test)]
+fn verify_dkim_ed25519(raw: &str, public_key: &ed25519_dalek::VerifyingKey) -> bool {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use ed25519_dalek::{Signature, Verifier};
+    use sha2::{Digest, Sha256};
+
+    fn relaxed(value: &str) -> String {
+        value.split_whitespace().collect::<Vec<&str>>().join(" ")
+    }
+
+    let (head, body) = raw.split_once("\r\n\r\n").unwrap();
+
+    // unfold headers into (name, value) pairs
+    let mut headers: Vec<(String, String)> = Vec::new();
+
+    for line in head.split("\r\n") {
+        if line.starts_with(' ') || line.starts_with('\t') {
+            headers.last_mut().unwrap().1.push_str(line);
+        } else {
+            let (name, value) = line.split_once(':').unwrap();
+            headers.push((name.to_string(), value.to_string()));
+        }
+    }
+
+    let (_, signature) = headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("DKIM-Signature"))
+        .unwrap()
+        .clone();
+
+    let tags: Vec<(String, String)> = signature
+        .split(';')
+        .filter_map(|tag| tag.split_once('='))
+        .map(|(k, v)| (k.trim().to_string(), v.split_whitespace().collect()))
+        .collect();
+
+    let tag = |name: &str| {
+        tags.iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.clone())
+            .unwrap()
+    };
+
+    assert_eq!(tag("a"), "ed25519-sha256");
+    assert_eq!(tag("c"), "relaxed/relaxed");
+
+    // relaxed body canonicalization
+    let mut lines: Vec<String> = body
+        .split("\r\n")
+        .map(|line| {
+            // collapse runs of whitespace and drop any at the end of the line
+            let mut out = String::new();
+            let mut wsp = false;
+
+            for c in line.chars() {
+                if c == ' ' || c == '\t' {
+                    wsp = true;
+                } else {
+                    if wsp {
+                        out.push(' ');
+                        wsp = false;
+                    }
+                    out.push(c);
+                }
+            }
+
+            out
+        })
+        .collect();
+
+    while lines.last().map_or(false, |line| line.is_empty()) {
+        lines.pop();
+    }
+
+    let canonical_body = if lines.is_empty() {
+        String::new()
+    } else {
+        format!("{}\r\n", lines.join("\r\n"))
+    };
+
+    if STANDARD.encode(Sha256::digest(canonical_body.as_bytes())) != tag("bh") {
+        return false;
+    }
+
+    // relaxed header canonicalization of the signed headers followed by
+    // the signature header itself with an empty b= tag
+    let mut data = String::new();
+
+    for name in tag("h").split(':') {
+        let header = headers
+            .iter()
+            .rev()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name));
+
+        if let Some((_, value)) = header {
+            data.push_str(&format!("{}:{}\r\n", name.to_lowercase(), relaxed(value)));
+        }
+    }
+
+    let unsigned: Vec<String> = signature
+        .split(';')
+        .map(|tag| match tag.split_once('=') {
+            Some((k, _)) if k.trim() == "b" => format!("{}=", k),
+            _ => tag.to_string(),
+        })
+        .collect();
+
+    data.push_str(&format!("dkim-signature:{}", relaxed(&unsigned.join(";"))));
+
+    let signature = Signature::from_slice(&STANDARD.decode(tag("b")).unwrap()).unwrap();
+
+    public_key
+        .verify(&Sha256::digest(data.as_bytes()), &signature)
+        .is_ok()
+}
+
+#[test]
+fn test_dkim_signature() {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use ed25519_dalek::SigningKey;
+    use lettre::{message::dkim::DkimSigningAlgorithm, SmtpTransport};
+    use rand::rngs::OsRng;
+
+    use crate::email::{dkim_config, Mailer};
+
+    let signing_key = SigningKey::generate(&mut OsRng);
+
+    let dkim = dkim_config(
+        "test",
+        "example.com",
+        &STANDARD.encode(signing_key.to_bytes()),
+        DkimSigningAlgorithm::Ed25519,
+    )
+    .unwrap();
+
+    let mailer = Mailer::with_transport(
+        "Test <noreply@example.com>",
+        SmtpTransport::builder_dangerous("localhost").build(),
+    )
+    .with_dkim(dkim);
+
+    let email = mailer
+        .multipart_email("user@example.com", "Test", "test", "<p>test</p>")
+        .unwrap();
+
+    let raw = String::from_utf8(email.formatted()).unwrap();
+
+    assert!(verify_dkim_ed25519(&raw, &signing_key.verifying_key()));
+
+    // tampering with the body must break the signature
+    assert!(!verify_dkim_ed25519(
+        &raw.replace("<p>test</p>", "<p>evil</p>"),
+        &signing_key.verifying_key()
+    ));
+}
+
+#[test]
+fn test_auth_config() {
+    use crate::{config::AuthConfig, jwt::TokenType};
+
+    let config = AuthConfig::from_toml_str(
+        r#"
+[tokens]
+issuer = "auth.example.com"
+access_ttl_mins = 15
+
+[smtp]
+name = "Auth"
+from = "noreply@example.com"
+host = "smtp.example.com"
+
+[features]
+passwordless = false
+"#,
+    )
+    .unwrap();
+
+    assert_eq!(config.tokens.ttl_mins(&TokenType::Access), 15);
+    assert_eq!(config.tokens.ttl_mins(&TokenType::Refresh), 24 * 60);
+    assert!(!config.features.passwordless);
+    assert!(config.features.password_reset);
+
+    // every problem should be reported, not just the first
+    let err = AuthConfig::builder()
+        .ttl_mins(&TokenType::Refresh, 0)
+        .callback_url("not a url")
+        .build()
+        .unwrap_err()
+        .to_string();
+
+    assert!(err.contains("tokens.refresh_ttl_mins must be greater than 0"));
+    assert!(err.contains("smtp.host must be set"));
+    assert!(err.contains("urls.callback_url is not a valid url"));
+
+    // tools that only use part of the config check only that part
+    let config = AuthConfig::from_toml_str_unchecked(
+        r#"
+[tokens]
+issuer = "auth.example.com"
+"#,
+    )
+    .unwrap();
+
+    assert!(config.validate().is_err());
+    assert!(config.validate_tokens().is_ok());
+    assert!(config.validate_passwords().is_ok());
+    assert!(config.validate_email().unwrap_err().to_string().contains("smtp.host must be set"));
+
+    let config = AuthConfig::from_toml_str_unchecked(
+        r#"
+[tokens]
+access_ttl_mins = 0
+"#,
+    )
+    .unwrap();
+
+    assert!(config.validate_tokens().is_err());
+
+    // secrets are left out of debug output so configs can be logged
+    let config = AuthConfig::from_toml_str_unchecked(
+        r#"
+[smtp]
+password = "smtp-secret"
+
+[hashing]
+pepper_version = 1
+peppers = { "1" = "pepper-secret" }
+
+[ldap]
+bind_password = "ldap-secret"
+
+[social.google]
+client_secret = "social-secret"
+"#,
+    )
+    .unwrap();
+
+    let debug = format!("{:?}", config);
+
+    for secret in ["smtp-secret", "pepper-secret", "ldap-secret", "social-secret"] {
+        assert!(!debug.contains(secret), "{} was printed", secret);
+    }
+
+    assert!(debug.contains("<redacted>"));
+}
+
+#[test]
+fn test_key_round_trip() {
+    use rusty_paseto::{
+        core::{PasetoAsymmetricPrivateKey, PasetoAsymmetricPublicKey, Public, V4},
+        prelude::PasetoParser,
+    };
+    use time::{Duration, OffsetDateTime};
+
+    use crate::{
+        config::TokenConfig,
+        jwt::{basic_jwt, config_decode_jwt, decode_jwt, TokenType},
+        keys::{KeyPair, PublicKey},
+        paseto::base_pasesto,
+    };
+
+    let key_pair = KeyPair::generate();
+
+    let from_pem = KeyPair::parse(&key_pair.private_pem().unwrap()).unwrap();
+    let from_hex = KeyPair::parse(&key_pair.private_hex()).unwrap();
+    assert_eq!(from_pem.private_hex(), key_pair.private_hex());
+    assert_eq!(from_hex.private_hex(), key_pair.private_hex());
+
+    let public_key = PublicKey::parse(&key_pair.public_key().pem().unwrap()).unwrap();
+    assert_eq!(public_key, PublicKey::parse(&key_pair.public_key().hex()).unwrap());
+
+    let exp = (OffsetDateTime::now_utc() + Duration::minutes(5)).unix_timestamp();
+
+    let jwt = basic_jwt(
+        "1234",
+        &TokenType::Access,
+        "",
+        &TokenConfig::default(),
+        &key_pair.jwt_encoding_key().unwrap(),
+        exp,
+    )
+    .unwrap();
+
+    let claims = decode_jwt(jwt, &public_key.jwt_decoding_key()).unwrap();
+    assert_eq!(claims.uuid, "1234");
+
+    // tokens with an explicit expiry still carry the configured issuer
+    let config = TokenConfig {
+        issuer: "auth.example.com".to_string(),
+        ..Default::default()
+    };
+
+    let jwt = basic_jwt(
+        "1234",
+        &TokenType::Access,
+        "",
+        &config,
+        &key_pair.jwt_encoding_key().unwrap(),
+        exp,
+    )
+    .unwrap();
+
+    let claims = config_decode_jwt(&jwt, &config, &public_key.jwt_decoding_key()).unwrap();
+    assert_eq!(claims.iss, "auth.example.com");
+
+    let private_key = key_pair.paseto_private_key();
+    let paseto = base_pasesto(
+        "1234",
+        &TokenType::Access,
+        "",
+        &(OffsetDateTime::now_utc() + Duration::minutes(5)),
+        &PasetoAsymmetricPrivateKey::<V4, Public>::try_from(private_key.as_slice()).unwrap(),
+    )
+    .unwrap();
+
+    let public = public_key.paseto_public_key();
+    let json = PasetoParser::<V4, Public>::default()
+        .parse(&paseto, &PasetoAsymmetricPublicKey::<V4, Public>::from(&public))
+        .unwrap();
+
+    assert_eq!(json["jti"], "1234");
+}
+
+#[test]
+fn test_write_key_files() {
+    use crate::keys::KeyPair;
+
+    let dir = std::env::temp_dir().join(format!("auth-keys-{}", crate::uuid()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let key_pair = KeyPair::generate();
+    key_pair.write_files(&dir, "auth", false).unwrap();
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mode = |file: &str| {
+            std::fs::metadata(dir.join(file)).unwrap().permissions().mode() & 0o777
+        };
+
+        assert_eq!(mode("auth.pem"), 0o600);
+        assert_eq!(mode("auth.hex"), 0o600);
+        assert_eq!(mode("auth.pub.pem"), 0o644);
+    }
+
+    // existing keys are only replaced when asked to
+    let other = KeyPair::generate();
+    assert!(other.write_files(&dir, "auth", false).is_err());
+
+    let hex = std::fs::read_to_string(dir.join("auth.hex")).unwrap();
+    assert_eq!(hex.trim(), key_pair.private_hex());
+
+    other.write_files(&dir, "auth", true).unwrap();
+
+    let hex = std::fs::read_to_string(dir.join("auth.hex")).unwrap();
+    assert_eq!(hex.trim(), other.private_hex());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_inspect_token() {
+    use rusty_paseto::core::{PasetoAsymmetricPrivateKey, Public, V4};
+    use time::{Duration, OffsetDateTime};
+
+    use crate::{
+        config::TokenConfig,
+        inspect::{inspect_token, TokenFormat},
+        jwt::{basic_jwt, TokenType},
+        keys::KeyPair,
+        paseto::base_pasesto,
+    };
+
+    let key_pair = KeyPair::generate();
+    let public_key = key_pair.public_key();
+
+    let expired = (OffsetDateTime::now_utc() - Duration::minutes(5)).unix_timestamp();
+
+    let jwt = basic_jwt(
+        "1234",
+        &"verify_email".parse::<TokenType>().unwrap(),
+        "",
+        &TokenConfig::default(),
+        &key_pair.jwt_encoding_key().unwrap(),
+        expired,
+    )
+    .unwrap();
+
+    let info = inspect_token(&format!("Bearer {}", jwt), &public_key).unwrap();
+    assert_eq!(info.format, TokenFormat::Jwt);
+    assert!(info.signature_valid());
+    assert!(info.expired());
+    assert_eq!(info.uuid.as_deref(), Some("1234"));
+    assert_eq!(info.token_type.as_deref(), Some("verify_email"));
+
+    // a token signed by someone else still decodes but fails verification
+    let info = inspect_token(&jwt, &KeyPair::generate().public_key()).unwrap();
+    assert!(!info.signature_valid());
+    assert_eq!(info.uuid.as_deref(), Some("1234"));
+
+    let private_key = key_pair.paseto_private_key();
+    let paseto = base_pasesto(
+        "5678",
+        &TokenType::Passwordless,
+        "otp",
+        &(OffsetDateTime::now_utc() + Duration::minutes(5)),
+        &PasetoAsymmetricPrivateKey::<V4, Public>::try_from(private_key.as_slice()).unwrap(),
+    )
+    .unwrap();
+
+    let info = inspect_token(&paseto, &public_key).unwrap();
+    assert_eq!(info.format, TokenFormat::Paseto);
+    assert!(info.signature_valid());
+    assert!(!info.expired());
+    assert_eq!(info.uuid.as_deref(), Some("5678"));
+    assert_eq!(info.token_type.as_deref(), Some("passwordless"));
+    assert_eq!(info.claims["otp"], "otp");
+
+    assert!(inspect_token("not.a.token", &public_key).is_err());
+}
+
+#[test]
+fn test_password_policy() {
+    use crate::password_policy::{
+        estimate_strength, user_inputs, PasswordPolicy, PasswordRule,
+    };
+
+    assert_eq!(estimate_strength("password", &[]), 0);
+    assert_eq!(estimate_strength("P@ssw0rd1", &[]), 0);
+    assert_eq!(estimate_strength("aaaaaaaaaaaaaaaa", &[]), 1);
+    assert_eq!(estimate_strength("correct horse battery staple", &[]), 4);
+
+    let inputs = user_inputs("antony", "antony.holmes@example.com", "Antony", "Holmes");
+
+    // the user's own details are no harder to guess than a common word
+    assert!(
+        estimate_strength("antony.holmes", &inputs) < estimate_strength("antony.holmes", &[])
+    );
+
+    let policy = PasswordPolicy {
+        require_uppercase: true,
+        require_digit: true,
+        ..PasswordPolicy::default()
+    };
+
+    assert_eq!(
+        policy.violations("h0lmes", &inputs),
+        vec![
+            PasswordRule::TooShort { min: 8 },
+            PasswordRule::MissingUppercase,
+            PasswordRule::ContainsUserInfo,
+            PasswordRule::TooWeak { score: 0, min: 2 },
+        ]
+    );
+
+    assert!(policy.check("Violet-Kettle-42", &inputs).is_ok());
+
+    let err = policy.check("", &inputs).unwrap_err();
+    assert!(err.to_string().starts_with("password must be at least 8 characters"));
+}
+
+#[test]
+fn test_breached_passwords() {
+    use sha1::{Digest, Sha1};
+
+    use crate::{
+        breach::BreachedPasswords,
+        password_policy::{PasswordPolicy, PasswordRule},
+    };
+
+    let mut lines: Vec<String> = ["password", "letmein", "Violet-Kettle-42"]
+        .iter()
+        .zip([3861493, 52, 1])
+        .map(|(pwd, count)| {
+            format!("{}:{}", hex::encode_upper(Sha1::digest(pwd.as_bytes())), count)
+        })
+        .collect();
+
+    // pad with hashes either side so matches land mid file as well as at
+    // the ends
+    for i in 0..200 {
+        lines.push(format!("{}:{}", hex::encode_upper(Sha1::digest(i.to_string())), i + 1));
+    }
+
+    lines.sort();
+
+    let path = std::env::temp_dir().join(format!("pwned-{}.txt", crate::uuid()));
+    std::fs::write(&path, lines.join("\r\n") + "\r\n").unwrap();
+
+    let breached = BreachedPasswords::new(&path);
+
+    assert_eq!(breached.count("password").unwrap(), 3861493);
+    assert_eq!(breached.count("letmein").unwrap(), 52);
+    assert_eq!(breached.count("not in the list").unwrap(), 0);
+
+    for line in [lines.first().unwrap(), lines.last().unwrap()] {
+        let (hash, count) = line.split_once(':').unwrap();
+        assert_eq!(breached.count_hash(&hash.to_lowercase()).unwrap(), count.parse().unwrap());
+    }
+
+    let policy = PasswordPolicy {
+        breached_passwords_file: Some(path.to_string_lossy().to_string()),
+        breach_threshold: 10,
+        ..PasswordPolicy::default()
+    };
+
+    assert_eq!(
+        policy.check_breached("letmein").unwrap(),
+        Some(PasswordRule::Breached { count: 52 })
+    );
+
+    // seen, but not often enough to reject
+    assert_eq!(policy.check_breached("Violet-Kettle-42").unwrap(), None);
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn test_request_verification_email() {
+    use crate::{
+        config::AuthConfig, outbox::OutboxStatus, password_policy::PasswordPolicy,
+        signin::EmailLinkReq, verify::request_verification_email, Credentials,
+    };
+
+    let user_db = test_user_db(&PasswordPolicy::default()).await;
+
+    let user = user_db
+        .create_user(&Credentials {
+            username: "ada".to_string(),
+            password: "Violet-Kettle-42".to_string(),
+            email: Some("ada@example.com".to_string()),
+            first_name: None,
+            last_name: None,
+            callback_url: None,
+            url: None,
+            locale: None,
+        })
+        .await
+        .unwrap();
+
+    let state = test_app_state(user_db, AuthConfig::default()).await;
+
+    let req = |username: &str| EmailLinkReq {
+        username: username.to_string(),
+        callback_url: None,
+        url: None,
+    };
+
+    let pending = || async {
+        state.outbox.list_messages(&OutboxStatus::Pending, 10).await.unwrap().len()
+    };
+
+    // unknown accounts look the same as known ones but get no email
+    request_verification_email(&state, &req("nobody")).await.unwrap();
+    assert_eq!(pending().await, 0);
+
+    request_verification_email(&state, &req("ada")).await.unwrap();
+    assert_eq!(pending().await, 1);
+
+    state.user_db.user_verified(&user.uuid).await.unwrap();
+
+    request_verification_email(&state, &req("ada")).await.unwrap();
+    assert_eq!(pending().await, 1);
+}
+
+#[tokio::test]
+async fn test_reset_password() {
+    use crate::{
+        config::AuthConfig,
+        jwt::{decode_jwt, reset_password_jwt},
+        outbox::OutboxStatus,
+        password::{request_password_reset, reset_password},
+        password_policy::PasswordPolicy,
+        signin::{password_sign_in, EmailLinkReq},
+        AuthError, Credentials,
+    };
+
+    let user_db = test_user_db(&PasswordPolicy::default()).await;
+
+    let user = user_db
+        .create_user(&Credentials {
+            username: "ada".to_string(),
+            password: "Violet-Kettle-42".to_string(),
+            email: Some("ada@example.com".to_string()),
+            first_name: None,
+            last_name: None,
+            callback_url: None,
+            url: None,
+            locale: None,
+        })
+        .await
+        .unwrap();
+
+    user_db.user_verified(&user.uuid).await.unwrap();
+
+    let state = test_app_state(user_db, AuthConfig::default()).await;
+
+    let req = |username: &str| EmailLinkReq {
+        username: username.to_string(),
+        callback_url: None,
+        url: None,
+    };
+
+    // unknown accounts look the same as known ones but get no email
+    request_password_reset(&state, &req("nobody")).await.unwrap();
+    assert!(state.outbox.list_messages(&OutboxStatus::Pending, 10).await.unwrap().is_empty());
+
+    request_password_reset(&state, &req("ada")).await.unwrap();
+
+    let sent = state.outbox.list_messages(&OutboxStatus::Pending, 10).await.unwrap();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].to_addr, "ada@example.com");
+
+    let token = reset_password_jwt(&user, &state.config.tokens, &state.jwt_private_key).unwrap();
+    let claims = decode_jwt(token, &state.jwt_public_key).unwrap();
+
+    reset_password(&state, &claims, "Amber-Teapot-17").await.unwrap();
+
+    assert!(password_sign_in(&state.user_db, "ada", "Amber-Teapot-17").await.is_ok());
+
+    // the token is spent once the password has changed
+    assert!(matches!(
+        reset_password(&state, &claims, "Copper-Spoon-99").await,
+        Err(AuthError::TokenError(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_switch_to_passwordless() {
+    use crate::{
+        config::AuthConfig,
+        outbox::OutboxStatus,
+        password::switch_to_passwordless,
+        password_policy::PasswordPolicy,
+        signin::{password_sign_in, request_passwordless_email, EmailLinkReq},
+        AuthError, Credentials,
+    };
+
+    let user_db = test_user_db(&PasswordPolicy::default()).await;
+
+    let user = user_db
+        .create_user(&Credentials {
+            username: "ada".to_string(),
+            password: "Violet-Kettle-42".to_string(),
+            email: Some("ada@example.com".to_string()),
+            first_name: None,
+            last_name: None,
+            callback_url: None,
+            url: None,
+            locale: None,
+        })
+        .await
+        .unwrap();
+
+    user_db.user_verified(&user.uuid).await.unwrap();
+
+    let state = test_app_state(user_db, AuthConfig::default()).await;
+
+    switch_to_passwordless(&state, &user).await.unwrap();
+
+    assert!(state.user_db.find_user_by_uuid(&user.uuid).await.unwrap().passwordless_only);
+
+    // the right password is no longer enough, but only the right password
+    // learns that
+    assert!(matches!(
+        password_sign_in(&state.user_db, "ada", "Violet-Kettle-42").await,
+        Err(AuthError::PasswordlessOnlyError(_))
+    ));
+    assert!(matches!(
+        password_sign_in(&state.user_db, "ada", "wrong").await,
+        Err(AuthError::PasswordError(_))
+    ));
+
+    let req = |username: &str| EmailLinkReq {
+        username: username.to_string(),
+        callback_url: None,
+        url: None,
+    };
+
+    let pending = || async {
+        state.outbox.list_messages(&OutboxStatus::Pending, 10).await.unwrap().len()
+    };
+
+    let sent = pending().await;
+
+    // unknown accounts look the same as known ones but get no email
+    request_passwordless_email(&state, &req("nobody")).await.unwrap();
+    assert_eq!(pending().await, sent);
+
+    request_passwordless_email(&state, &req("ada")).await.unwrap();
+    assert_eq!(pending().await, sent + 1);
+}
+
+#[tokio::test]
+async fn test_password_history() {
+    use crate::{
+        password_policy::{PasswordPolicy, PasswordRule},
+        AuthError, Credentials,
+    };
+
+    let user_db = test_user_db(&PasswordPolicy {
+        history_size: 2,
+        ..PasswordPolicy::default()
+    })
+    .await;
+
+    let user = user_db
+        .create_user(&Credentials {
+            username: "antony".to_string(),
+            password: "Violet-Kettle-42".to_string(),
+            email: Some("antony@example.com".to_string()),
+            first_name: None,
+            last_name: None,
+            callback_url: None,
+            url: None,
+            locale: None,
+        })
+        .await
+        .unwrap();
+
+    let reused = |result: crate::AuthResult<()>| match result {
+        Err(AuthError::PasswordPolicyError(rules)) => {
+            rules.contains(&PasswordRule::RecentlyUsed { history: 2 })
+        }
+        _ => false,
+    };
+
+    assert!(reused(user_db.update_password(&user.uuid, "Violet-Kettle-42").await));
+
+    user_db.update_password(&user.uuid, "Amber-Lantern-77").await.unwrap();
+    user_db.update_password(&user.uuid, "Copper-Meadow-19").await.unwrap();
+
+    assert_eq!(user_db.password_history(&user.uuid).await.unwrap().len(), 2);
+
+    // only the last two passwords are remembered
+    assert!(reused(user_db.update_password(&user.uuid, "Amber-Lantern-77").await));
+    assert!(user_db.update_password(&user.uuid, "Violet-Kettle-42").await.is_ok());
+}
+
+#[tokio::test]
+async fn test_password_rehash_on_sign_in() {
+    use crate::{
+        config::HashingConfig, hashing::Argon2Hasher, password_policy::PasswordPolicy,
+        signin::password_sign_in,
+    };
+
+    let hasher = Argon2Hasher::new(&HashingConfig {
+        memory_kib: 1024,
+        iterations: 1,
+        parallelism: 1,
+        ..HashingConfig::default()
+    })
+    .unwrap();
+
+    let user_db = test_user_db(&PasswordPolicy::default())
+        .await
+        .with_hasher(hasher.clone());
+
+    // an account imported with a bcrypt hash
+    let legacy = bcrypt::hash("Violet-Kettle-42", 4).unwrap();
+    assert!(hasher.needs_rehash(&legacy));
+
+    sqlx::query(
+        "INSERT INTO users (uuid, username, email, password, email_verified) \
+         VALUES($1, $2, $3, $4, 1)",
+    )
+    .bind("1234")
+    .bind("antony")
+    .bind("antony@example.com")
+    .bind(&legacy)
+    .execute(&user_db.pool)
+    .await
+    .unwrap();
+
+    assert!(password_sign_in(&user_db, "antony", "wrong").await.is_err());
+
+    password_sign_in(&user_db, "antony", "Violet-Kettle-42").await.unwrap();
+
+    let user = user_db.find_user_by_uuid("1234").await.unwrap();
+    assert!(user.password.starts_with("$argon2id$v=19$m=1024,t=1,p=1$"));
+    assert!(!hasher.needs_rehash(&user.password));
+
+    // the upgraded hash still verifies, and hashes with other costs are
+    // upgraded too
+    password_sign_in(&user_db, "antony", "Violet-Kettle-42").await.unwrap();
+    assert!(hasher.needs_rehash(&Argon2Hasher::default().hash("Violet-Kettle-42").unwrap()));
+}
+
+#[test]
+fn test_pepper_rotation() {
+    use std::collections::HashMap;
+
+    use crate::{config::HashingConfig, hashing::Argon2Hasher};
+
+    let pepper_1 = "9f4c1e0a7b3d5f2e8c6a4b1d3f5e7a9c".to_string();
+    let pepper_2 = "2b7e151628aed2a6abf7158809cf4f3c".to_string();
+
+    let config = HashingConfig {
+        memory_kib: 1024,
+        iterations: 1,
+        parallelism: 1,
+        pepper_version: 1,
+        peppers: HashMap::from([("1".to_string(), pepper_1.clone())]),
+    };
+
+    let v1 = Argon2Hasher::new(&config).unwrap();
+    let hash = v1.hash("Violet-Kettle-42").unwrap();
+
+    assert!(hash.starts_with("p1$argon2id$"));
+    assert!(v1.verify("Violet-Kettle-42", &hash).is_ok());
+    assert!(v1.verify("Violet-Kettle-43", &hash).is_err());
+    assert!(!v1.needs_rehash(&hash));
+
+    // the hash is useless without the pepper
+    assert!(Argon2Hasher::default().verify("Violet-Kettle-42", &hash).is_err());
+
+    // rotating keeps the old pepper for verification but asks for a rehash
+    let v2 = Argon2Hasher::new(&HashingConfig {
+        pepper_version: 2,
+        peppers: HashMap::from([("1".to_string(), pepper_1), ("2".to_string(), pepper_2)]),
+        ..config.clone()
+    })
+    .unwrap();
+
+    assert!(v2.verify("Violet-Kettle-42", &hash).is_ok());
+    assert!(v2.needs_rehash(&hash));
+    assert!(v2.hash("Violet-Kettle-42").unwrap().starts_with("p2$"));
+
+    // unpeppered hashes are upgraded once a pepper is configured
+    let plain = Argon2Hasher::new(&HashingConfig {
+        pepper_version: 0,
+        ..config.clone()
+    })
+    .unwrap()
+    .hash("Violet-Kettle-42")
+    .unwrap();
+
+    assert!(v1.verify("Violet-Kettle-42", &plain).is_ok());
+    assert!(v1.needs_rehash(&plain));
+
+    assert!(Argon2Hasher::new(&HashingConfig {
+        pepper_version: 3,
+        ..config
+    })
+    .is_err());
+}
+
+#[tokio::test]
+async fn test_email_change() {
+    use crate::{
+        password_policy::PasswordPolicy, AuthError, Credentials, EMAIL_CHANGE_STATUS_CANCELLED,
+    };
+
+    let user_db = test_user_db(&PasswordPolicy::default()).await;
+
+    user_db.create_email_change_table().await.unwrap();
+
+    let mut users = Vec::new();
+
+    for name in ["antony", "bob"] {
+        users.push(
+            user_db
+                .create_user(&Credentials {
+                    username: name.to_string(),
+                    password: "Violet-Kettle-42".to_string(),
+                    email: Some(format!("{}@example.com", name)),
+                    first_name: None,
+                    last_name: None,
+                    callback_url: None,
+                    url: None,
+                    locale: None,
+                })
+                .await
+                .unwrap(),
+        );
+    }
+
+    let user = &users[0];
+
+    assert!(matches!(
+        user_db.create_email_change(user, &users[1].email).await,
+        Err(AuthError::UserAlreadyExistsError(_))
+    ));
+
+    // a second request replaces the first
+    let first = user_db.create_email_change(user, "typo@example.con").await.unwrap();
+    let change = user_db.create_email_change(user, "new@example.com").await.unwrap();
+
+    let first = user_db.find_email_change(&first.uuid).await.unwrap();
+    assert_eq!(first.status, EMAIL_CHANGE_STATUS_CANCELLED);
+    assert!(user_db.confirm_email_change(&first).await.is_err());
+
+    // nothing changes until the new address is confirmed
+    assert_eq!(user_db.find_user_by_uuid(&user.uuid).await.unwrap().email, user.email);
+
+    user_db.confirm_email_change(&change).await.unwrap();
+    assert_eq!(
+        user_db.find_user_by_uuid(&user.uuid).await.unwrap().email,
+        "new@example.com"
+    );
+
+    // tokens can only be used once
+    assert!(user_db.confirm_email_change(&change).await.is_err());
+
+    let change = user_db.find_email_change(&change.uuid).await.unwrap();
+    user_db.revert_email_change(&change).await.unwrap();
+    assert_eq!(user_db.find_user_by_uuid(&user.uuid).await.unwrap().email, user.email);
+
+    let change = user_db.find_email_change(&change.uuid).await.unwrap();
+    assert!(user_db.revert_email_change(&change).await.is_err());
+}
+
+#[tokio::test]
+async fn test_sign_in_account_checks() {
+    use crate::{password_policy::PasswordPolicy, signin::password_sign_in, AuthError, Credentials};
+
+    let user_db = test_user_db(&PasswordPolicy::default()).await;
+
+    let user = user_db
+        .create_user(&Credentials {
+            username: "antony".to_string(),
+            password: "Violet-Kettle-42".to_string(),
+            email: Some("antony@example.com".to_string()),
+            first_name: Some("Antony".to_string()),
+            last_name: Some("Holmes".to_string()),
+            callback_url: None,
+            url: None,
+            locale: None,
+        })
+        .await
+        .unwrap();
+
+    assert_eq!(user.email, "antony@example.com");
+    assert_eq!(user.first_name, "Antony");
+    assert_eq!(user.last_name, "Holmes");
+
+    // new accounts must verify their email first
+    assert!(matches!(
+        password_sign_in(&user_db, "antony", "Violet-Kettle-42").await,
+        Err(AuthError::EmailNotVerifiedError(_))
+    ));
+
+    // but a wrong password is still just a wrong password
+    assert!(matches!(
+        password_sign_in(&user_db, "antony", "wrong").await,
+        Err(AuthError::PasswordError(_))
+    ));
+
+    user_db.user_verified(&user.uuid).await.unwrap();
+    password_sign_in(&user_db, "antony@example.com", "Violet-Kettle-42").await.unwrap();
+
+    user_db.set_can_signin(&user.uuid, false).await.unwrap();
+
+    assert!(matches!(
+        password_sign_in(&user_db, "antony", "Violet-Kettle-42").await,
+        Err(AuthError::AccountDisabledError(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_first_party_tokens() {
+    use crate::{
+        config::AuthConfig,
+        jwt::{access_jwt, client_jwt, config_decode_jwt, TokenType},
+        oauth::signed_in_user,
+        password_policy::PasswordPolicy,
+        signin::check_first_party,
+        Credentials,
+    };
+
+    let user_db = test_user_db(&PasswordPolicy::default()).await;
+
+    let user = user_db
+        .create_user(&Credentials {
+            username: "ada".to_string(),
+            password: "Violet-Kettle-42".to_string(),
+            email: Some("ada@example.com".to_string()),
+            first_name: None,
+            last_name: None,
+            callback_url: None,
+            url: None,
+            locale: None,
+        })
+        .await
+        .unwrap();
+
+    user_db.user_verified(&user.uuid).await.unwrap();
+
+    let state = test_app_state(user_db, AuthConfig::default()).await;
+    let config = &state.config.tokens;
+
+    let decode = |token: String| config_decode_jwt(&token, config, &state.jwt_public_key).unwrap();
+
+    let own = decode(access_jwt(&user.uuid, config, &state.jwt_private_key).unwrap());
+    check_first_party(&own).unwrap();
+    assert_eq!(signed_in_user(&state, &own).await.unwrap().uuid, user.uuid);
+
+    // an access token a client holds on the user's behalf cannot manage
+    // the account, whatever scopes it was granted
+    let delegated = decode(
+        client_jwt(
+            &user.uuid,
+            &TokenType::Access,
+            "reports",
+            "openid profile email",
+            config,
+            &state.jwt_private_key,
+        )
+        .unwrap(),
+    );
+    assert!(check_first_party(&delegated).is_err());
+    assert!(signed_in_user(&state, &delegated).await.is_err());
+}
+
+#[tokio::test]
+async fn test_oauth_authorization_code() {
+    use crate::{
+        oauth::{check_authorize_request, check_redirect_uri, pkce_challenge, AuthorizeReq},
+        password_policy::PasswordPolicy,
+        AuthError, AuthResult, Credentials,
+    };
+
+    // RFC 7636 appendix B
+    let verifier = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+    assert_eq!(pkce_challenge(verifier), "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM");
+
+    for uri in [
+        "https://app.example.com/callback",
+        "http://127.0.0.1:8080/callback",
+        "com.example.app:/callback",
+    ] {
+        assert!(check_redirect_uri(uri).is_ok(), "{}", uri);
+    }
+
+    for uri in [
+        "http://app.example.com/callback",
+        "https://app.example.com/callback#token",
+        "javascript:alert(1)",
+        "/callback",
+    ] {
+        assert!(check_redirect_uri(uri).is_err(), "{}", uri);
+    }
+
+    let user_db = test_user_db(&PasswordPolicy::default()).await;
+
+    user_db.create_oauth_tables().await.unwrap();
+
+    let user = user_db
+        .create_user(&Credentials {
+            username: "antony".to_string(),
+            password: "Violet-Kettle-42".to_string(),
+            email: Some("antony@example.com".to_string()),
+            first_name: None,
+            last_name: None,
+            callback_url: None,
+            url: None,
+            locale: None,
+        })
+        .await
+        .unwrap();
+
+    let redirect_uris = vec![
+        "https://app.example.com/callback".to_string(),
+        "com.example.app:/callback".to_string(),
+    ];
+    let grant_types = vec![crate::oauth::DEFAULT_GRANT_TYPES.to_string()];
+
+    let (client, secret) = user_db
+        .create_oauth_client("Example", &redirect_uris, "profile email", &grant_types, false)
+        .await
+        .unwrap();
+
+    assert!(secret.is_none());
+    assert!(!client.is_confidential());
+
+    let req = AuthorizeReq {
+        response_type: "code".to_string(),
+        client_id: client.client_id.clone(),
+        redirect_uri: Some("https://app.example.com/callback".to_string()),
+        scope: Some("profile".to_string()),
+        state: Some("xyz".to_string()),
+        code_challenge: Some(pkce_challenge(verifier)),
+        code_challenge_method: Some("S256".to_string()),
+        nonce: None,
+    };
+
+    fn rejected<T>(result: AuthResult<T>, code: &str) -> bool {
+        matches!(result, Err(AuthError::OAuthError(error, _)) if error == code)
+    }
+
+    for bad in [
+        AuthorizeReq {
+            redirect_uri: Some("https://evil.example.com/callback".to_string()),
+            ..req.clone()
+        },
+        // more than one registered so it must be given
+        AuthorizeReq {
+            redirect_uri: None,
+            ..req.clone()
+        },
+        AuthorizeReq {
+            code_challenge: None,
+            ..req.clone()
+        },
+        AuthorizeReq {
+            code_challenge_method: Some("plain".to_string()),
+            ..req.clone()
+        },
+    ] {
+        assert!(rejected(check_authorize_request(&user_db, &bad).await, "invalid_request"));
+    }
+
+    let bad = AuthorizeReq {
+        scope: Some("profile admin".to_string()),
+        ..req.clone()
+    };
+    assert!(rejected(check_authorize_request(&user_db, &bad).await, "invalid_scope"));
+
+    let authorization = check_authorize_request(&user_db, &req).await.unwrap();
+    assert_eq!(authorization.scope, "profile");
+
+    let redirect_uri = "https://app.example.com/callback";
+
+    // a wrong verifier spends the code
+    let code = user_db
+        .create_authorization_code(&user.uuid, &authorization)
+        .await
+        .unwrap();
+
+    let wrong_verifier = "x".repeat(43);
+
+    assert!(rejected(
+        user_db
+            .redeem_authorization_code(&code, &client.client_id, redirect_uri, &wrong_verifier)
+            .await,
+        "invalid_grant"
+    ));
+    assert!(user_db
+        .redeem_authorization_code(&code, &client.client_id, redirect_uri, verifier)
+        .await
+        .is_err());
+
+    let code = user_db
+        .create_authorization_code(&user.uuid, &authorization)
+        .await
+        .unwrap();
+
+    let grant = user_db
+        .redeem_authorization_code(&code, &client.client_id, redirect_uri, verifier)
+        .await
+        .unwrap();
+
+    assert_eq!(grant.user_uuid, user.uuid);
+    assert_eq!(grant.scope, "profile");
+
+    // codes are single use
+    assert!(user_db
+        .redeem_authorization_code(&code, &client.client_id, redirect_uri, verifier)
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_oauth_client_credentials() {
+    use crate::{
+        config::AuthConfig,
+        jwt::config_decode_jwt,
+        oauth::{authenticate_client, token, OAuthClient, TokenReq, DEFAULT_GRANT_TYPES},
+        password_policy::PasswordPolicy,
+        AuthError, AuthResult, UserDb,
+    };
+
+    let user_db = test_user_db(&PasswordPolicy::default()).await;
+
+    user_db.create_oauth_tables().await.unwrap();
+
+    let service_grants = vec!["client_credentials".to_string()];
+
+    // services never send users through /authorize so need no redirect
+    let (client, secret) = user_db
+        .create_oauth_client(
+            "Nightly jobs",
+            &[],
+            "reports:read reports:write",
+            &service_grants,
+            true,
+        )
+        .await
+        .unwrap();
+
+    let secret = secret.unwrap();
+    assert!(client.is_confidential());
+    assert_ne!(client.secret, secret);
+    assert!(client.redirect_uris().is_empty());
+
+    fn rejected<T>(result: AuthResult<T>, code: &str) -> bool {
+        matches!(result, Err(AuthError::OAuthError(error, _)) if error == code)
+    }
+
+    assert!(rejected(
+        user_db.create_oauth_client("Public", &[], "", &service_grants, false).await,
+        "invalid_client_metadata"
+    ));
+    assert!(rejected(
+        user_db
+            .create_oauth_client("Web", &[], "", &[DEFAULT_GRANT_TYPES.to_string()], true)
+            .await,
+        "invalid_redirect_uri"
+    ));
+
+    // a confidential client registered for users cannot act for itself
+    let (web, web_secret) = user_db
+        .create_oauth_client(
+            "Web",
+            &["https://app.example.com/callback".to_string()],
+            "reports:read",
+            &[DEFAULT_GRANT_TYPES.to_string()],
+            true,
+        )
+        .await
+        .unwrap();
+
+    let web_secret = web_secret.unwrap();
+
+    let req = TokenReq {
+        grant_type: "client_credentials".to_string(),
+        code: None,
+        redirect_uri: None,
+        code_verifier: None,
+        refresh_token: None,
+        device_code: None,
+        scope: Some("reports:read".to_string()),
+        client_id: None,
+        client_secret: None,
+    };
+
+    async fn authenticate(
+        user_db: &UserDb,
+        basic: Option<(String, String)>,
+        req: &TokenReq,
+    ) -> AuthResult<OAuthClient> {
+        authenticate_client(user_db, basic, &req.client_id, &req.client_secret).await
+    }
+
+    let basic = |secret: &str| Some((client.client_id.clone(), secret.to_string()));
+
+    let authenticated = authenticate(&user_db, basic(&secret), &req).await.unwrap();
+    assert_eq!(authenticated.client_id, client.client_id);
+
+    let post = TokenReq {
+        client_id: Some(client.client_id.clone()),
+        client_secret: Some(secret.clone()),
+        ..req.clone()
+    };
+    authenticate(&user_db, None, &post).await.unwrap();
+
+    assert!(rejected(
+        authenticate(&user_db, basic("wrong"), &req).await,
+        "invalid_client"
+    ));
+
+    // the secret is required, and only one way of sending it is allowed
+    let no_secret = TokenReq {
+        client_id: Some(client.client_id.clone()),
+        ..req.clone()
+    };
+    assert!(rejected(authenticate(&user_db, None, &no_secret).await, "invalid_client"));
+    assert!(rejected(
+        authenticate(&user_db, basic(&secret), &post).await,
+        "invalid_request"
+    ));
+
+    let rotated = user_db.rotate_oauth_client_secret(&client.client_id).await.unwrap();
+    assert!(authenticate(&user_db, basic(&secret), &req).await.is_err());
+    authenticate(&user_db, basic(&rotated), &req).await.unwrap();
+
+    let mut config = AuthConfig::default();
+    config.features.oauth = true;
+
+    let state = test_app_state(user_db, config).await;
+
+    // service tokens name the client rather than a user
+    let resp = token(&state, basic(&rotated), &req).await.unwrap();
+
+    assert!(resp.refresh_token.is_none());
+    assert_eq!(resp.scope, "reports:read");
+
+    let claims =
+        config_decode_jwt(&resp.access_token, &state.config.tokens, &state.jwt_public_key)
+            .unwrap();
+
+    assert!(claims.is_service());
+    assert_eq!(claims.uuid, client.client_id);
+    assert!(claims.has_scope("reports:read"));
+    assert!(!claims.has_scope("reports:write"));
+
+    let wider = TokenReq {
+        scope: Some("reports:read admin".to_string()),
+        ..req.clone()
+    };
+    assert!(rejected(token(&state, basic(&rotated), &wider).await, "invalid_scope"));
+
+    let web_basic = Some((web.client_id.clone(), web_secret.clone()));
+    assert!(rejected(token(&state, web_basic, &req).await, "unauthorized_client"));
+
+    // until it is registered for the grant
+    state
+        .user_db
+        .set_oauth_client_grant_types(
+            &web.client_id,
+            &[DEFAULT_GRANT_TYPES.to_string(), "client_credentials".to_string()],
+        )
+        .await
+        .unwrap();
+
+    let web_basic = Some((web.client_id.clone(), web_secret));
+    token(&state, web_basic, &req).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_oidc() {
+    use crate::{
+        keys::PublicKey,
+        oauth::{check_authorize_request, pkce_challenge, AuthorizeReq},
+        oidc::{IdTokenClaims, UserInfo},
+        password_policy::PasswordPolicy,
+        Credentials,
+    };
+
+    // RFC 8037 appendix A.3
+    let jwk = PublicKey::parse("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a")
+        .unwrap()
+        .jwk();
+
+    assert_eq!(jwk.x, "11qYAYKxCrfVS_7TyWQHOg7hcvPapiMlrwIaaPcHURo");
+    assert_eq!(jwk.kid, "kPrK_qmxVWaYVA9wwBF6Iuo3vVzz7TxHCTwXBygrS4k");
+
+    let user_db = test_user_db(&PasswordPolicy::default()).await;
+
+    user_db.create_oauth_tables().await.unwrap();
+
+    let user = user_db
+        .create_user(&Credentials {
+            username: "antony".to_string(),
+            password: "Violet-Kettle-42".to_string(),
+            email: Some("antony@example.com".to_string()),
+            first_name: Some("Antony".to_string()),
+            last_name: Some("Holmes".to_string()),
+            callback_url: None,
+            url: None,
+            locale: None,
+        })
+        .await
+        .unwrap();
+
+    // claims follow the granted scopes
+    let info = UserInfo::new(&user, "openid");
+    assert_eq!(info.sub, user.uuid);
+    assert!(info.name.is_none() && info.email.is_none());
+
+    let info = UserInfo::new(&user, "openid profile email");
+    assert_eq!(info.name.as_deref(), Some("Antony Holmes"));
+    assert_eq!(info.email.as_deref(), Some("antony@example.com"));
+    assert_eq!(info.email_verified, Some(false));
+
+    let claims = IdTokenClaims {
+        iss: "https://auth.example.com".to_string(),
+        aud: "client".to_string(),
+        exp: 0,
+        iat: 0,
+        nonce: Some("n-0S6_WzA2Mj".to_string()),
+        info,
+    };
+
+    let json = serde_json::to_value(&claims).unwrap();
+    assert_eq!(json["sub"], user.uuid);
+    assert_eq!(json["nonce"], "n-0S6_WzA2Mj");
+    assert_eq!(json["email_verified"], false);
+
+    // the nonce is carried from the authorization request to the code
+    let redirect_uris = vec!["https://app.example.com/callback".to_string()];
+    let grant_types = vec![crate::oauth::DEFAULT_GRANT_TYPES.to_string()];
+
+    let (client, _) = user_db
+        .create_oauth_client(
+            "Example",
+            &redirect_uris,
+            "openid profile email",
+            &grant_types,
+            false,
+        )
+        .await
+        .unwrap();
+
+    let verifier = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+
+    let authorization = check_authorize_request(
+        &user_db,
+        &AuthorizeReq {
+            response_type: "code".to_string(),
+            client_id: client.client_id.clone(),
+            redirect_uri: None,
+            scope: Some("openid email".to_string()),
+            state: None,
+            code_challenge: Some(pkce_challenge(verifier)),
+            code_challenge_method: Some("S256".to_string()),
+            nonce: Some("n-0S6_WzA2Mj".to_string()),
+        },
+    )
+    .await
+    .unwrap();
+
+    let code = user_db
+        .create_authorization_code(&user.uuid, &authorization)
+        .await
+        .unwrap();
+
+    let grant = user_db
+        .redeem_authorization_code(&code, &client.client_id, "", verifier)
+        .await
+        .unwrap();
+
+    assert_eq!(grant.scope, "openid email");
+    assert_eq!(grant.nonce, "n-0S6_WzA2Mj");
+}
+
+#[tokio::test]
+async fn test_device_flow() {
+    use crate::{
+        config::AuthConfig,
+        device::{
+            device_authorization, format_user_code, normalize_user_code, DeviceAuthorizationReq,
+            GRANT_DEVICE_CODE,
+        },
+        jwt::config_decode_jwt,
+        oauth::{token, TokenReq, DEFAULT_GRANT_TYPES},
+        password_policy::PasswordPolicy,
+        AuthError, AuthResult, Credentials,
+    };
+
+    assert_eq!(normalize_user_code(" bcdf-ghjk "), "BCDFGHJK");
+    assert_eq!(format_user_code("BCDFGHJK"), "BCDF-GHJK");
+
+    let user_db = test_user_db(&PasswordPolicy::default()).await;
+
+    user_db.create_oauth_tables().await.unwrap();
+
+    let user = user_db
+        .create_user(&Credentials {
+            username: "marcus".to_string(),
+            password: "Amber-Lantern-17".to_string(),
+            email: Some("marcus@example.com".to_string()),
+            first_name: None,
+            last_name: None,
+            callback_url: None,
+            url: None,
+            locale: None,
+        })
+        .await
+        .unwrap();
+
+    user_db.user_verified(&user.uuid).await.unwrap();
+
+    // devices have nowhere to be redirected to
+    let (client, _) = user_db
+        .create_oauth_client(
+            "Example CLI",
+            &[],
+            "reports:read",
+            &[GRANT_DEVICE_CODE.to_string()],
+            false,
+        )
+        .await
+        .unwrap();
+
+    let (web, _) = user_db
+        .create_oauth_client(
+            "Example",
+            &["https://app.example.com/callback".to_string()],
+            "reports:read",
+            &[DEFAULT_GRANT_TYPES.to_string()],
+            false,
+        )
+        .await
+        .unwrap();
+
+    let mut config = AuthConfig::default();
+    config.features.oauth = true;
+    config.urls.public_url = Some("https://auth.example.com".to_string());
+
+    let state = test_app_state(user_db, config).await;
+    let user_db = &state.user_db;
+
+    fn rejected<T>(result: AuthResult<T>, code: &str) -> bool {
+        matches!(result, Err(AuthError::OAuthError(error, _)) if error == code)
+    }
+
+    let device_req = |client_id: &str| DeviceAuthorizationReq {
+        client_id: Some(client_id.to_string()),
+        client_secret: None,
+    };
+
+    // only clients registered for the device grant can start it
+    assert!(rejected(
+        device_authorization(&state, None, &device_req(&web.client_id)).await,
+        "unauthorized_client"
+    ));
+
+    let resp = device_authorization(&state, None, &device_req(&client.client_id))
+        .await
+        .unwrap();
+
+    let device_code = resp.device_code;
+    let user_code = normalize_user_code(&resp.user_code);
+
+    assert_eq!(user_code.len(), 8);
+    assert_eq!(resp.user_code, format_user_code(&user_code));
+
+    // the user has not answered, and polling again at once is too fast
+    assert!(rejected(
+        user_db.poll_device_code(&device_code, &client.client_id).await,
+        "authorization_pending"
+    ));
+    assert!(rejected(
+        user_db.poll_device_code(&device_code, &client.client_id).await,
+        "slow_down"
+    ));
+
+    assert!(rejected(
+        user_db.poll_device_code(&device_code, "another-client").await,
+        "invalid_grant"
+    ));
+    assert!(rejected(
+        user_db.poll_device_code("not-a-code", &client.client_id).await,
+        "invalid_grant"
+    ));
+
+    // users may type the code in any case, with or without the dash
+    let typed = format_user_code(&user_code).to_lowercase();
+
+    let device = user_db.pending_device_code(&typed).await.unwrap();
+    assert_eq!(device.client_id, client.client_id);
+    assert_eq!(device.poll_interval, 10);
+
+    user_db.decide_device_code(&typed, &user.uuid, true).await.unwrap();
+
+    // answered codes can not be answered again
+    assert!(user_db.pending_device_code(&typed).await.is_err());
+    assert!(user_db.decide_device_code(&typed, &user.uuid, false).await.is_err());
+
+    let req = TokenReq {
+        grant_type: GRANT_DEVICE_CODE.to_string(),
+        code: None,
+        redirect_uri: None,
+        code_verifier: None,
+        refresh_token: None,
+        device_code: Some(device_code.clone()),
+        scope: None,
+        client_id: Some(client.client_id.clone()),
+        client_secret: None,
+    };
+
+    // the device gets tokens for the client, not the user's own
+    let resp = token(&state, None, &req).await.unwrap();
+    assert_eq!(resp.scope, "reports:read");
+
+    let claims =
+        config_decode_jwt(&resp.access_token, &state.config.tokens, &state.jwt_public_key)
+            .unwrap();
+
+    assert_eq!(claims.uuid, user.uuid);
+    assert_eq!(claims.client_id, client.client_id);
+    assert!(claims.has_scope("reports:read"));
+
+    assert!(rejected(token(&state, None, &req).await, "invalid_grant"));
+
+    // a denied request ends polling
+    let (device_code, user_code) = user_db.create_device_code(&client.client_id).await.unwrap();
+
+    user_db.decide_device_code(&user_code, &user.uuid, false).await.unwrap();
+
+    assert!(rejected(
+        user_db.poll_device_code(&device_code, &client.client_id).await,
+        "access_denied"
+    ));
+}
+
+#[tokio::test]
+async fn test_token_introspection() {
+    use rusty_paseto::core::{PasetoAsymmetricPrivateKey, Public, V4};
+    use time::{Duration, OffsetDateTime};
+
+    use crate::{
+        config::TokenConfig,
+        introspect::{introspect_token, revoke_client_token},
+        jwt::{access_jwt, client_jwt, TokenType},
+        keys::{KeyPair, PublicKey},
+        paseto::base_pasesto,
+        password_policy::PasswordPolicy,
+        AuthError,
+    };
+
+    let user_db = test_user_db(&PasswordPolicy::default()).await;
+
+    user_db.create_oauth_tables().await.unwrap();
+
+    let redirect_uris = vec!["https://legacy.example.com/callback".to_string()];
+    let grant_types = vec![crate::oauth::DEFAULT_GRANT_TYPES.to_string()];
+
+    let (client, _) = user_db
+        .create_oauth_client("Legacy", &redirect_uris, "reports:read", &grant_types, true)
+        .await
+        .unwrap();
+
+    let (other, _) = user_db
+        .create_oauth_client("Other", &redirect_uris, "reports:read", &grant_types, true)
+        .await
+        .unwrap();
+
+    let key_pair = KeyPair::generate();
+    let key = PublicKey::from_jwk(&key_pair.public_key().jwk()).unwrap();
+    assert_eq!(key, key_pair.public_key());
+
+    let config = TokenConfig::default();
+    let encoding_key = key_pair.jwt_encoding_key().unwrap();
+
+    let access = access_jwt("1234", &config, &encoding_key).unwrap();
+
+    let resp = introspect_token(&user_db, &config, &key, &access).await.unwrap();
+    assert!(resp.active);
+    assert_eq!(resp.sub.as_deref(), Some("1234"));
+    assert_eq!(resp.token_type.as_deref(), Some("access"));
+    assert!(resp.exp.is_some());
+
+    let service = client_jwt(
+        &client.client_id,
+        &TokenType::Service,
+        &client.client_id,
+        "reports:read",
+        &config,
+        &encoding_key,
+    )
+    .unwrap();
+
+    let resp = introspect_token(&user_db, &config, &key, &service).await.unwrap();
+    assert_eq!(resp.scope.as_deref(), Some("reports:read"));
+    assert_eq!(resp.client_id.as_deref(), Some(client.client_id.as_str()));
+
+    let private_key = key_pair.paseto_private_key();
+    let paseto = base_pasesto(
+        "1234",
+        &TokenType::Access,
+        "",
+        &(OffsetDateTime::now_utc() + Duration::minutes(5)),
+        &PasetoAsymmetricPrivateKey::<V4, Public>::try_from(private_key.as_slice()).unwrap(),
+    )
+    .unwrap();
+
+    let resp = introspect_token(&user_db, &config, &key, &paseto).await.unwrap();
+    assert!(resp.active);
+    assert_eq!(resp.sub.as_deref(), Some("1234"));
+
+    // tokens signed by someone else or that are not tokens at all are inactive
+    let forged =
+        access_jwt("1234", &config, &KeyPair::generate().jwt_encoding_key().unwrap()).unwrap();
+
+    for token in [forged.as_str(), "not-a-token"] {
+        let resp = introspect_token(&user_db, &config, &key, token).await.unwrap();
+        assert!(!resp.active);
+        assert_eq!(serde_json::to_string(&resp).unwrap(), r#"{"active":false}"#);
+    }
+
+    // clients can not revoke each other's tokens
+    assert!(matches!(
+        revoke_client_token(&user_db, &config, &key, &other, &service).await,
+        Err(AuthError::OAuthError(error, _)) if error == "unauthorized_client"
+    ));
+
+    for token in [access.as_str(), service.as_str(), paseto.as_str()] {
+        revoke_client_token(&user_db, &config, &key, &client, token).await.unwrap();
+        assert!(user_db.is_token_revoked(token).await.unwrap());
+
+        let resp = introspect_token(&user_db, &config, &key, token).await.unwrap();
+        assert!(!resp.active);
+    }
+
+    // revoking twice or revoking junk is not an error
+    revoke_client_token(&user_db, &config, &key, &client, &access).await.unwrap();
+    revoke_client_token(&user_db, &config, &key, &client, "not-a-token").await.unwrap();
+}
+
+#[cfg(test)]
+#[derive(Clone, Default)]
+struct MockProvider {
+    challenge: std::sync::Arc<std::sync::Mutex<String>>,
+    profile: std::sync::Arc<std::sync::Mutex<serde_json::Value>>,
+}
+
+///
+/// Serve a token endpoint and userinfo on a random local port, checking
+/// the PKCE verifier against the challenge the test last saw.
+///
+#[cfg(test)]
+async fn mock_provider(mock: MockProvider) -> String {
+    use std::collections::HashMap;
+
+    use axum::{
+        extract::State,
+        http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+        routing::{get, post},
+        Form, Json, Router,
+    };
+    use serde_json::{json, Value};
+
+    use crate::oauth::pkce_challenge;
+
+    async fn token(
+        State(mock): State<MockProvider>,
+        Form(form): Form<HashMap<String, String>>,
+    ) -> (StatusCode, Json<Value>) {
+        let verifier = form.get("code_verifier").map(|v| v.as_str()).unwrap_or("");
+
+        if form.get("code").map(|c| c.as_str()) != Some("mock-code")
+            || form.get("client_secret").map(|s| s.as_str()) != Some("mock-secret")
+            || pkce_challenge(verifier) != *mock.challenge.lock().unwrap()
+        {
+            return (StatusCode::BAD_REQUEST, Json(json!({ "error": "invalid_grant" })));
+        }
+
+        (
+            StatusCode::OK,
+            Json(json!({ "access_token": "mock-token", "token_type": "Bearer" })),
+        )
+    }
+
+    async fn userinfo(
+        State(mock): State<MockProvider>,
+        headers: HeaderMap,
+    ) -> (StatusCode, Json<Value>) {
+        match headers.get(AUTHORIZATION).and_then(|h| h.to_str().ok()) {
+            Some("Bearer mock-token") => {
+                (StatusCode::OK, Json(mock.profile.lock().unwrap().clone()))
+            }
+            _ => (StatusCode::UNAUTHORIZED, Json(json!({}))),
+        }
+    }
+
+    let app = Router::new()
+        .route("/token", post(token))
+        .route("/userinfo", get(userinfo))
+        .with_state(mock);
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+    url
+}
+
+#[tokio::test]
+async fn test_social_login() {
+    use serde_json::json;
+    use url::Url;
+
+    use crate::{
+        config::SocialProviderConfig,
+        password_policy::PasswordPolicy,
+        social::{social_authorize, social_callback, SocialProvider},
+        AuthError, Credentials, UserDb,
+    };
+
+    let user_db = test_user_db(&PasswordPolicy::default()).await;
+
+    user_db.create_social_tables().await.unwrap();
+
+    let mock = MockProvider::default();
+    let base = mock_provider(mock.clone()).await;
+
+    let config = SocialProviderConfig {
+        client_id: "mock-client".to_string(),
+        client_secret: "mock-secret".to_string(),
+        authorization_url: format!("{}/authorize", base),
+        token_url: format!("{}/token", base),
+        userinfo_url: format!("{}/userinfo", base),
+        ..Default::default()
+    };
+
+    let redirect_uri = "https://app.example.com/social/mock/callback";
+    let provider = SocialProvider::new("mock", config.clone(), redirect_uri).unwrap();
+    let other = SocialProvider::new("other", config, redirect_uri).unwrap();
+
+    // send the user to the provider, returning the state it sends back and
+    // the nonce the browser keeps
+    async fn authorize(
+        user_db: &UserDb,
+        provider: &SocialProvider,
+        user_uuid: &str,
+        mock: &MockProvider,
+    ) -> (String, String) {
+        let (url, nonce) = social_authorize(user_db, provider, user_uuid).await.unwrap();
+        let url = Url::parse(&url).unwrap();
+        let param = |name: &str| {
+            url.query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.to_string())
+                .unwrap()
+        };
+
+        assert_eq!(param("client_id"), "mock-client");
+        assert_eq!(param("code_challenge_method"), "S256");
+        assert_eq!(param("redirect_uri"), provider.redirect_uri);
+
+        *mock.challenge.lock().unwrap() = param("code_challenge");
+
+        (param("state"), nonce)
+    }
+
+    let set_profile = |profile: serde_json::Value| *mock.profile.lock().unwrap() = profile;
+
+    // first sign in creates a passwordless account
+    set_profile(json!({
+        "sub": "mock-1",
+        "email": "ada@example.com",
+        "email_verified": true,
+        "given_name": "Ada",
+        "family_name": "Lovelace",
+    }));
+
+    let (state, nonce) = authorize(&user_db, &provider, "", &mock).await;
+    let ada = social_callback(&user_db, &provider, "mock-code", &state, &nonce, "")
+        .await
+        .unwrap();
+
+    assert_eq!(ada.email, "ada@example.com");
+    assert_eq!(ada.first_name, "Ada");
+    assert!(ada.email_verified && ada.passwordless_only);
+    ada.check_can_signin().unwrap();
+
+    // states are single use, and the code must match its verifier
+    assert!(social_callback(&user_db, &provider, "mock-code", &state, &nonce, "")
+        .await
+        .is_err());
+
+    let (state, nonce) = authorize(&user_db, &provider, "", &mock).await;
+    assert!(social_callback(&user_db, &provider, "wrong-code", &state, &nonce, "")
+        .await
+        .is_err());
+
+    // a state leaked from the redirect is no use without the browser's
+    // nonce
+    let (state, _) = authorize(&user_db, &provider, "", &mock).await;
+    let (_, other_nonce) = authorize(&user_db, &provider, "", &mock).await;
+    assert!(social_callback(&user_db, &provider, "mock-code", &state, &other_nonce, "")
+        .await
+        .is_err());
+
+    let (state, _) = authorize(&user_db, &provider, "", &mock).await;
+    assert!(social_callback(&user_db, &provider, "mock-code", &state, "", "")
+        .await
+        .is_err());
+
+    // signing in again finds the same account
+    let (state, nonce) = authorize(&user_db, &provider, "", &mock).await;
+    let user = social_callback(&user_db, &provider, "mock-code", &state, &nonce, "")
+        .await
+        .unwrap();
+    assert_eq!(user.uuid, ada.uuid);
+
+    // a verified email links to the existing verified account
+    let grace = user_db
+        .create_user(&Credentials {
+            username: "grace".to_string(),
+            password: "Copper-Harbor-93".to_string(),
+            email: Some("grace@example.com".to_string()),
+            first_name: None,
+            last_name: None,
+            callback_url: None,
+            url: None,
+            locale: None,
+        })
+        .await
+        .unwrap();
+
+    user_db.user_verified(&grace.uuid).await.unwrap();
+
+    set_profile(json!({ "sub": "mock-2", "email": "grace@example.com", "email_verified": true }));
+
+    let (state, nonce) = authorize(&user_db, &provider, "", &mock).await;
+    let user = social_callback(&user_db, &provider, "mock-code", &state, &nonce, "")
+        .await
+        .unwrap();
+    assert_eq!(user.uuid, grace.uuid);
+
+    // without a verified email no account is made
+    set_profile(json!({ "sub": "mock-3", "email": "eve@example.com", "name": "Eve Smith" }));
+
+    let (state, nonce) = authorize(&user_db, &provider, "", &mock).await;
+    assert!(matches!(
+        social_callback(&user_db, &provider, "mock-code", &state, &nonce, "").await,
+        Err(AuthError::SocialLoginError(_))
+    ));
+    assert!(user_db.find_user_by_email("eve@example.com").await.is_err());
+
+    // signed in users link more identities, finishing with their own
+    // access token rather than signing in again
+    set_profile(json!({ "sub": "other-1", "email": "grace@work.example.com" }));
+
+    let (state, nonce) = authorize(&user_db, &other, &grace.uuid, &mock).await;
+    assert!(social_callback(&user_db, &other, "mock-code", &state, &nonce, "")
+        .await
+        .is_err());
+
+    let (state, nonce) = authorize(&user_db, &other, &grace.uuid, &mock).await;
+    assert!(social_callback(&user_db, &other, "mock-code", &state, &nonce, &ada.uuid)
+        .await
+        .is_err());
+
+    let (state, nonce) = authorize(&user_db, &other, &grace.uuid, &mock).await;
+    let user = social_callback(&user_db, &other, "mock-code", &state, &nonce, &grace.uuid)
+        .await
+        .unwrap();
+    assert_eq!(user.uuid, grace.uuid);
+
+    let identities = user_db.social_identities(&grace.uuid).await.unwrap();
+    let providers: Vec<&str> = identities.iter().map(|i| i.provider.as_str()).collect();
+    assert_eq!(providers, vec!["mock", "other"]);
+
+    // an identity belongs to one account
+    set_profile(json!({ "sub": "mock-1", "email": "ada@example.com", "email_verified": true }));
+
+    let (state, nonce) = authorize(&user_db, &provider, &grace.uuid, &mock).await;
+    assert!(social_callback(&user_db, &provider, "mock-code", &state, &nonce, &grace.uuid)
+        .await
+        .is_err());
+
+    user_db.unlink_social_identity(&grace.uuid, "other").await.unwrap();
+    assert_eq!(user_db.social_identities(&grace.uuid).await.unwrap().len(), 1);
+    assert!(user_db.unlink_social_identity(&grace.uuid, "other").await.is_err());
+
+    // ada has no password so must keep her only identity
+    assert!(user_db.unlink_social_identity(&ada.uuid, "mock").await.is_err());
+
+    // deleting an account takes its identities with it, so the provider
+    // account can sign up again
+    user_db.delete_user(&ada.uuid).await.unwrap();
+    assert!(user_db.social_identities(&ada.uuid).await.unwrap().is_empty());
+
+    let (state, nonce) = authorize(&user_db, &provider, "", &mock).await;
+    let user = social_callback(&user_db, &provider, "mock-code", &state, &nonce, "")
+        .await
+        .unwrap();
+    assert_ne!(user.uuid, ada.uuid);
+    assert_eq!(user.email, "ada@example.com");
+
+    // identities left behind by accounts deleted before then are unlinked
+    sqlx::query("DELETE FROM users WHERE uuid = $1")
+        .bind(&grace.uuid)
+        .execute(&user_db.pool)
+        .await
+        .unwrap();
+
+    set_profile(json!({ "sub": "mock-2", "email": "grace@example.com", "email_verified": true }));
+
+    let (state, nonce) = authorize(&user_db, &provider, "", &mock).await;
+    let user = social_callback(&user_db, &provider, "mock-code", &state, &nonce, "")
+        .await
+        .unwrap();
+    assert_ne!(user.uuid, grace.uuid);
+    assert_eq!(user.email, "grace@example.com");
+}
+
+///
+/// A stand in for an LDAP server on a random local port, answering the
+/// simple binds and equality searches `LdapDirectory` makes.
+///
+#[cfg(test)]
+type DirectoryEntry = (String, String, std::collections::HashMap<String, Vec<String>>);
+
+#[cfg(test)]
+#[derive(Default)]
+struct LdapStandIn {
+    entries: std::sync::Mutex<Vec<DirectoryEntry>>,
+}
+
+#[cfg(test)]
+fn ber(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+
+    match content.len() {
+        len if len < 0x80 => out.push(len as u8),
+        len if len < 0x100 => out.extend([0x81, len as u8]),
+        len => out.extend([0x82, (len >> 8) as u8, len as u8]),
+    }
+
+    out.extend_from_slice(content);
+    out
+}
+
+///
+/// Split the next tag, content and whatever follows off some BER, or
+/// `None` if it has not all arrived yet.
+///
+#[cfg(test)]
+fn ber_next(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = data.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+
+    let (len, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        let octets = (first & 0x7f) as usize;
+
+        if rest.len() < octets {
+            return None;
+        }
+
+        let len = rest[..octets].iter().fold(0, |len, b| (len << 8) | *b as usize);
+        (len, &rest[octets..])
+    };
+
+    if rest.len() < len {
+        return None;
+    }
+
+    Some((tag, &rest[..len], &rest[len..]))
+}
+
+#[cfg(test)]
+fn ber_items(mut data: &[u8]) -> Vec<(u8, &[u8])> {
+    let mut items = Vec::new();
+
+    while let Some((tag, content, rest)) = ber_next(data) {
+        items.push((tag, content));
+        data = rest;
+    }
+
+    items
+}
+
+///
+/// The attribute and value of every equality match in a filter, looking
+/// inside and, or and not.
+///
+#[cfg(test)]
+fn ber_filter_matches(tag: u8, content: &[u8]) -> Vec<(String, String)> {
+    let text = |bytes: &[u8]| String::from_utf8_lossy(bytes).to_string();
+
+    match tag {
+        0xa0..=0xa2 => ber_items(content)
+            .into_iter()
+            .flat_map(|(tag, content)| ber_filter_matches(tag, content))
+            .collect(),
+        0xa3 => match ber_items(content).as_slice() {
+            [(_, attr), (_, value)] => vec![(text(attr), text(value))],
+            _ => Vec::new(),
+        },
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+impl LdapStandIn {
+    fn add(&self, dn: &str, password: &str, attrs: &[(&str, &str)]) {
+        let attrs = attrs
+            .iter()
+            .map(|(name, value)| (name.to_string(), vec![value.to_string()]))
+            .collect();
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|(entry_dn, _, _)| entry_dn != dn);
+        entries.push((dn.to_string(), password.to_string(), attrs));
+    }
+
+    fn remove(&self, dn: &str) {
+        self.entries.lock().unwrap().retain(|(entry_dn, _, _)| entry_dn != dn);
+    }
+
+    ///
+    /// The replies to one LDAP message, or `None` once the client unbinds.
+    ///
+    fn answer(&self, message: &[u8]) -> Option<Vec<u8>> {
+        let items = ber_items(message);
+        let id = ber(0x02, items.first()?.1);
+        let (op, request) = *items.get(1)?;
+
+        let reply = |tag: u8, content: &[u8]| ber(0x30, &[id.clone(), ber(tag, content)].concat());
+        let result = |rc: u8| [ber(0x0a, &[rc]), ber(0x04, b""), ber(0x04, b"")].concat();
+
+        let entries = self.entries.lock().unwrap();
+
+        match op {
+            // bind, where 49 is invalid credentials
+            0x60 => {
+                let fields = ber_items(request);
+                let (dn, password) = (fields.get(1)?.1, fields.get(2)?.1);
+
+                let ok = entries.iter().any(|(entry_dn, entry_password, _)| {
+                    entry_dn.as_bytes() == dn && entry_password.as_bytes() == password
+                });
+
+                Some(reply(0x61, &result(if ok { 0 } else { 49 })))
+            }
+            // search, answering every entry the filter's equality matches fit
+            0x63 => {
+                let (tag, filter) = *ber_items(request).get(6)?;
+                let matches = ber_filter_matches(tag, filter);
+
+                let mut out = Vec::new();
+
+                for (dn, _, attrs) in entries.iter() {
+                    let found = !matches.is_empty()
+                        && matches.iter().all(|(name, value)| {
+                            attrs.get(name).map_or(false, |values| values.contains(value))
+                        });
+
+                    if !found {
+                        continue;
+                    }
+
+                    let attrs: Vec<u8> = attrs
+                        .iter()
+                        .flat_map(|(name, values)| {
+                            let values: Vec<u8> =
+                                values.iter().flat_map(|v| ber(0x04, v.as_bytes())).collect();
+
+                            ber(0x30, &[ber(0x04, name.as_bytes()), ber(0x31, &values)].concat())
+                        })
+                        .collect();
+
+                    out.extend(reply(
+                        0x64,
+                        &[ber(0x04, dn.as_bytes()), ber(0x30, &attrs)].concat(),
+                    ));
+                }
+
+                out.extend(reply(0x65, &result(0)));
+
+                Some(out)
+            }
+            _ => None,
+        }
+    }
+
+    ///
+    /// Listen on a random local port, returning the `ldap://` url.
+    ///
+    async fn serve(self: std::sync::Arc<Self>) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ldap://{}", listener.local_addr().unwrap());
+
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let directory = self.clone();
+
+                tokio::spawn(async move {
+                    let mut buf = Vec::new();
+                    let mut chunk = [0u8; 4096];
+
+                    loop {
+                        while let Some((_, message, rest)) = ber_next(&buf) {
+                            let consumed = buf.len() - rest.len();
+
+                            let replies = match directory.answer(message) {
+                                Some(replies) => replies,
+                                None => return,
+                            };
+
+                            if socket.write_all(&replies).await.is_err() {
+                                return;
+                            }
+
+                            buf.drain(..consumed);
+                        }
+
+                        match socket.read(&mut chunk).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                        }
+                    }
+                });
+            }
+        });
+
+        url
+    }
+}
+
+#[tokio::test]
+async fn test_directory_sign_in() {
+    use std::{collections::HashMap, sync::Arc};
+
+    use crate::{
+        config::{AuthConfig, LdapAttributes, LdapConfig},
+        ldap::{Directory, LdapDirectory},
+        outbox::OutboxStatus,
+        password::request_password_reset,
+        password_policy::PasswordPolicy,
+        signin::{password_sign_in, EmailLinkReq},
+        AuthError, Credentials,
+    };
+
+    // attribute mapping, with Active Directory names
+    let attributes = LdapAttributes {
+        username: "sAMAccountName".to_string(),
+        ..Default::default()
+    };
+
+    let attrs: HashMap<String, Vec<String>> = [
+        ("sAMAccountName", "jdoe"),
+        ("mail", "jdoe@corp.example.com"),
+        ("givenName", "Jane"),
+        ("sn", "Doe"),
+    ]
+    .iter()
+    .map(|(name, value)| (name.to_string(), vec![value.to_string()]))
+    .collect();
+
+    let user = attributes.user("CN=Jane Doe,DC=corp", &attrs).unwrap();
+    assert_eq!(user.username, "jdoe");
+    assert_eq!(user.email, "jdoe@corp.example.com");
+    assert_eq!(user.last_name, "Doe");
+
+    assert!(LdapAttributes::default().user("CN=Jane Doe,DC=corp", &attrs).is_err());
+
+    let directory = Arc::new(LdapStandIn::default());
+
+    let dn = "uid=jdoe,ou=staff,dc=corp";
+    let jdoe_attrs = |sn| {
+        [("uid", "jdoe"), ("mail", "jdoe@corp.example.com"), ("givenName", "Jane"), ("sn", sn)]
+    };
+
+    directory.add("cn=auth,dc=corp", "Service-Pass-0", &[]);
+    directory.add(dn, "Directory-Pass-1", &jdoe_attrs("Doe"));
+    directory.add("uid=antony,ou=staff,dc=corp", "Directory-Pass-2", &[("uid", "antony")]);
+
+    let config = LdapConfig {
+        url: directory.clone().serve().await,
+        bind_dn: "cn=auth,dc=corp".to_string(),
+        bind_password: "Service-Pass-0".to_string(),
+        base_dn: "ou=staff,dc=corp".to_string(),
+        ..Default::default()
+    };
+
+    let user_db = test_user_db(&PasswordPolicy::default()).await;
+
+    user_db.create_directory_users_table().await.unwrap();
+
+    let ldap_db = user_db.clone().with_directory(Arc::new(LdapDirectory::new(&config)));
+
+    // staff are provisioned on first sign in, already verified
+    let jdoe = password_sign_in(&ldap_db, "jdoe", "Directory-Pass-1").await.unwrap();
+    assert_eq!(jdoe.email, "jdoe@corp.example.com");
+    assert_eq!(jdoe.first_name, "Jane");
+    assert!(jdoe.email_verified);
+
+    // a failed bind is a wrong password
+    assert!(matches!(
+        password_sign_in(&ldap_db, "jdoe", "wrong").await,
+        Err(AuthError::PasswordError(_))
+    ));
+    assert!(password_sign_in(&ldap_db, "jdoe", "").await.is_err());
+
+    // names follow the directory on later sign ins
+    directory.add(dn, "Directory-Pass-1", &jdoe_attrs("Smith"));
+
+    let user = password_sign_in(&ldap_db, "jdoe", "Directory-Pass-1").await.unwrap();
+    assert_eq!(user.uuid, jdoe.uuid);
+    assert_eq!(user.last_name, "Smith");
+
+    // the service account has to be able to search
+    let wrong_service = LdapConfig {
+        bind_password: "wrong".to_string(),
+        ..config.clone()
+    };
+    assert!(matches!(
+        LdapDirectory::new(&wrong_service).authenticate("jdoe", "Directory-Pass-1").await,
+        Err(AuthError::DirectoryError(_))
+    ));
+
+    // people not in the directory sign in locally
+    let local = user_db
+        .create_user(&Credentials {
+            username: "antony".to_string(),
+            password: "Violet-Kettle-42".to_string(),
+            email: Some("antony@example.com".to_string()),
+            first_name: None,
+            last_name: None,
+            callback_url: None,
+            url: None,
+            locale: None,
+        })
+        .await
+        .unwrap();
+
+    user_db.user_verified(&local.uuid).await.unwrap();
+
+    let walter = user_db
+        .create_user(&Credentials {
+            username: "walter".to_string(),
+            password: "Pebble-Marsh-58".to_string(),
+            email: Some("walter@example.com".to_string()),
+            first_name: None,
+            last_name: None,
+            callback_url: None,
+            url: None,
+            locale: None,
+        })
+        .await
+        .unwrap();
+
+    user_db.user_verified(&walter.uuid).await.unwrap();
+    password_sign_in(&ldap_db, "walter", "Pebble-Marsh-58").await.unwrap();
+
+    // local accounts never go to the directory, so a directory user of
+    // the same name can neither take one over nor lock its owner out
+    password_sign_in(&ldap_db, "antony", "Violet-Kettle-42").await.unwrap();
+    assert!(matches!(
+        password_sign_in(&ldap_db, "antony", "Directory-Pass-2").await,
+        Err(AuthError::PasswordError(_))
+    ));
+
+    // when the directory is down local accounts still sign in, but
+    // directory accounts have to wait for it
+    let closed = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let down = LdapConfig {
+        url: format!("ldap://{}", closed.local_addr().unwrap()),
+        ..config.clone()
+    };
+    drop(closed);
+
+    let down_db = user_db.clone().with_directory(Arc::new(LdapDirectory::new(&down)));
+
+    password_sign_in(&down_db, "walter", "Pebble-Marsh-58").await.unwrap();
+    assert!(matches!(
+        password_sign_in(&down_db, "jdoe", "Directory-Pass-1").await,
+        Err(AuthError::DirectoryError(_))
+    ));
+
+    // nor can they set a local password to get round it
+    let state = test_app_state(ldap_db.clone(), AuthConfig::default()).await;
+
+    let req = |username: &str| EmailLinkReq {
+        username: username.to_string(),
+        callback_url: None,
+        url: None,
+    };
+
+    request_password_reset(&state, &req("jdoe")).await.unwrap();
+    assert!(state.outbox.list_messages(&OutboxStatus::Pending, 10).await.unwrap().is_empty());
+
+    request_password_reset(&state, &req("walter")).await.unwrap();
+    assert_eq!(state.outbox.list_messages(&OutboxStatus::Pending, 10).await.unwrap().len(), 1);
+
+    // staff removed from the directory are disabled, even if they sign in
+    // with their email
+    directory.remove(dn);
+
+    assert!(matches!(
+        password_sign_in(&ldap_db, "jdoe@corp.example.com", "Directory-Pass-1").await,
+        Err(AuthError::PasswordError(_))
+    ));
+    assert!(!user_db.find_user_by_uuid(&jdoe.uuid).await.unwrap().can_signin);
+}
+
+#[tokio::test]
+async fn test_saml_sign_in() {
+    use std::io::Read;
+
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use chrono::{DateTime, Utc};
+    use flate2::read::DeflateDecoder;
+    use url::Url;
+
+    use crate::{
+        config::{AuthConfig, SamlAttributes, SamlConfig, TokenConfig, UrlConfig},
+        jwt::decode_jwt,
+        keys::KeyPair,
+        password_policy::PasswordPolicy,
+        saml::{saml_acs, SamlServiceProvider},
+        signin::tokens,
+        AuthError,
+    };
+
+    let response = include_str!("../fixtures/saml/response.xml");
+    let encode = |xml: &str| STANDARD.encode(xml);
+    let at = |time: &str| time.parse::<DateTime<Utc>>().unwrap();
+
+    // the fixture was issued at 12:00 and is valid until 12:05
+    let now = at("2025-01-01T12:01:00Z");
+
+    let config = SamlConfig {
+        entity_id: "https://sp.example.com/saml/metadata".to_string(),
+        idp_entity_id: "https://idp.example.com/metadata".to_string(),
+        idp_sso_url: "https://idp.example.com/sso".to_string(),
+        idp_certificate: include_str!("../fixtures/saml/idp.crt").to_string(),
+        attributes: SamlAttributes {
+            username: Some("urn:oid:0.9.2342.19200300.100.1.1".to_string()),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    let auth_config = AuthConfig {
+        urls: UrlConfig {
+            public_url: Some("https://sp.example.com".to_string()),
+            ..Default::default()
+        },
+        saml: Some(config.clone()),
+        ..Default::default()
+    };
+
+    let sp = SamlServiceProvider::from_config(&auth_config).unwrap();
+    assert_eq!(sp.acs_url, "https://sp.example.com/saml/acs");
+
+    assert!(sp.metadata().contains(r#"entityID="https://sp.example.com/saml/metadata""#));
+
+    // AuthnRequests are deflated into the redirect url
+    let url = Url::parse(&sp.redirect_url("_request-1", Some("/home"), now).unwrap()).unwrap();
+    let param = |name: &str| {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.to_string())
+            .unwrap()
+    };
+
+    let mut request = String::new();
+    DeflateDecoder::new(STANDARD.decode(param("SAMLRequest")).unwrap().as_slice())
+        .read_to_string(&mut request)
+        .unwrap();
+
+    assert!(request.contains(r#"ID="_request-1""#));
+    assert!(request.contains("https://sp.example.com/saml/metadata</saml:Issuer>"));
+    assert_eq!(param("RelayState"), "/home");
+
+    let assertion = sp.validate_response(&encode(response), now).unwrap();
+    assert_eq!(assertion.id, "_assertion-1");
+    assert_eq!(assertion.name_id, "ada@example.com");
+    assert_eq!(assertion.in_response_to.as_deref(), Some("_request-1"));
+    assert_eq!(assertion.session_index.as_deref(), Some("_session-1"));
+
+    let saml_user = config.attributes.user(&assertion).unwrap();
+    assert_eq!(saml_user.username, "ada");
+    assert_eq!(saml_user.first_name, "Ada");
+    assert_eq!(saml_user.last_name, "Lovelace");
+
+    let rejected = |xml: &str, now: DateTime<Utc>| {
+        matches!(sp.validate_response(&encode(xml), now), Err(AuthError::SamlError(_)))
+    };
+
+    // signed content can not be changed
+    assert!(rejected(&response.replace(">Ada<", ">Eve<"), now));
+    assert!(rejected(&response.replace("ada@example.com</", "eve@example.com</"), now));
+
+    let start = response.find("<ds:Signature").unwrap();
+    let end = response.find("</ds:Signature>").unwrap() + "</ds:Signature>".len();
+    let unsigned = format!("{}{}", &response[..start], &response[end..]);
+    assert!(rejected(&unsigned, now));
+
+    // nor can a forged assertion be slipped in beside the signed one
+    let start = unsigned.find("<saml:Assertion").unwrap();
+    let end = unsigned.find("</saml:Assertion>").unwrap() + "</saml:Assertion>".len();
+    let forged = unsigned[start..end]
+        .replace("_assertion-1", "_assertion-2")
+        .replace(">Ada<", ">Eve<");
+
+    let wrapped = response.replace("</samlp:Response>", &format!("{}</samlp:Response>", forged));
+    assert!(rejected(&wrapped, now));
+
+    // validity times allow for some clock skew
+    assert!(sp.validate_response(&encode(response), at("2025-01-01T12:06:00Z")).is_ok());
+    assert!(rejected(response, at("2025-01-01T12:10:00Z")));
+    assert!(rejected(response, at("2025-01-01T11:50:00Z")));
+
+    // assertions for another service provider are refused
+    let other = SamlServiceProvider::new(
+        SamlConfig {
+            entity_id: "https://other.example.com/saml/metadata".to_string(),
+            ..config.clone()
+        },
+        "https://sp.example.com/saml/acs",
+    )
+    .unwrap();
+
+    assert!(other.validate_response(&encode(response), now).is_err());
+
+    let other = SamlServiceProvider::new(config.clone(), "https://other.example.com/acs").unwrap();
+    assert!(other.validate_response(&encode(response), now).is_err());
+
+    let user_db = test_user_db(&PasswordPolicy::default()).await;
+
+    user_db.create_saml_tables().await.unwrap();
+
+    // responses must answer one of our requests
+    assert!(saml_acs(&user_db, &sp, &encode(response), now).await.is_err());
+
+    user_db.save_saml_request("_request-1").await.unwrap();
+
+    let ada = saml_acs(&user_db, &sp, &encode(response), now).await.unwrap();
+    assert_eq!(ada.username, "ada");
+    assert_eq!(ada.email, "ada@example.com");
+    assert!(ada.email_verified);
+
+    // and each assertion can only be used once
+    user_db.save_saml_request("_request-1").await.unwrap();
+
+    assert!(matches!(
+        saml_acs(&user_db, &sp, &encode(response), now).await,
+        Err(AuthError::SamlError(_))
+    ));
+
+    let key_pair = KeyPair::generate();
+    let resp =
+        tokens(&ada.uuid, &TokenConfig::default(), &key_pair.jwt_encoding_key().unwrap()).unwrap();
+
+    let claims = decode_jwt(resp.access_token, &key_pair.jwt_decoding_key()).unwrap();
+    assert_eq!(claims.uuid, ada.uuid);
+}
+
+#[tokio::test]
+async fn test_scim_provisioning() {
+    use crate::{
+        jwt::{JwtClaims, TokenType},
+        password_policy::PasswordPolicy,
+        scim::{
+            check_scim_token, list_response, ScimFilter, ScimGroup, ScimListQuery, ScimMember,
+            ScimPatch, ScimUser,
+        },
+        AuthError, Credentials,
+    };
+    use serde_json::json;
+
+    let claims = |token_type: TokenType, scope: &str| JwtClaims {
+        uuid: "idp".to_string(),
+        token_type: token_type.to_string(),
+        otp: String::new(),
+        iss: String::new(),
+        client_id: "idp".to_string(),
+        scope: scope.to_string(),
+        exp: 0,
+    };
+
+    let clientless = JwtClaims {
+        client_id: String::new(),
+        ..claims(TokenType::Service, "scim")
+    };
+
+    assert!(check_scim_token(&claims(TokenType::Service, "scim")).is_ok());
+    assert!(matches!(
+        check_scim_token(&claims(TokenType::Service, "openid")),
+        Err(AuthError::ScimError(403, _, _))
+    ));
+    assert!(matches!(
+        check_scim_token(&claims(TokenType::Access, "scim")),
+        Err(AuthError::ScimError(401, _, _))
+    ));
+    assert!(matches!(check_scim_token(&clientless), Err(AuthError::ScimError(401, _, _))));
+
+    let user_db = test_user_db(&PasswordPolicy::default()).await;
+
+    user_db.create_scim_tables().await.unwrap();
+
+    let resource = |body| serde_json::from_value::<ScimUser>(body).unwrap();
+
+    let ada = user_db
+        .create_scim_user(
+            "idp",
+            &resource(json!({
+                "schemas": ["urn:ietf:params:scim:schemas:core:2.0:User"],
+                "externalId": "00u1",
+                "userName": "ada",
+                "name": { "givenName": "Ada", "familyName": "Lovelace" },
+                "emails": [{ "value": "ada@example.com", "type": "work", "primary": true }],
+            })),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(ada.external_id.as_deref(), Some("00u1"));
+    assert_eq!(ada.display_name, "Ada Lovelace");
+    assert!(ada.active);
+    assert!(user_db.find_user_by_uuid(&ada.id).await.unwrap().email_verified);
+
+    user_db
+        .create_scim_user(
+            "idp",
+            &resource(json!({
+                "userName": "grace",
+                "emails": [{ "value": "grace@example.com" }],
+                "active": "False",
+            })),
+        )
+        .await
+        .unwrap();
+
+    assert!(matches!(
+        user_db.create_scim_user("idp", &resource(json!({ "userName": "ada" }))).await,
+        Err(AuthError::UserAlreadyExistsError(_))
+    ));
+
+    // accounts SCIM did not create are not visible to it
+    let antony = user_db
+        .create_user(&Credentials {
+            username: "antony".to_string(),
+            password: "Violet-Kettle-42".to_string(),
+            email: Some("antony@example.com".to_string()),
+            first_name: None,
+            last_name: None,
+            callback_url: None,
+            url: None,
+            locale: None,
+        })
+        .await
+        .unwrap();
+
+    let page = |filter: &str, start_index, count| ScimListQuery {
+        filter: Some(filter.to_string()).filter(|filter| !filter.is_empty()),
+        start_index,
+        count,
+    };
+
+    // unfiltered lists are paged in SQL
+    let list = user_db.list_scim_users("idp", &page("", None, None)).await.unwrap();
+    assert_eq!(list.total_results, 2);
+    assert_eq!(list.items_per_page, 2);
+
+    let list = user_db.list_scim_users("idp", &page("", Some(2), Some(1))).await.unwrap();
+    assert_eq!(list.total_results, 2);
+    assert_eq!(list.start_index, 2);
+    assert_eq!(list.items_per_page, 1);
+    assert_eq!(list.resources[0].user_name, "grace");
+
+    let list = user_db.list_scim_users("idp", &page(r#"userName eq "grace""#, None, None));
+    assert_eq!(list.await.unwrap().total_results, 1);
+
+    let users = user_db.scim_users("idp", 0, i64::MAX).await.unwrap();
+    let list = list_response(users.clone(), &page("", Some(2), Some(1))).unwrap();
+    assert_eq!(list.items_per_page, 1);
+    assert_eq!(list.resources[0].user_name, "grace");
+
+    for (filter, expected) in [
+        (r#"userName eq "ADA""#, vec!["ada"]),
+        (r#"emails[type eq "work" and value ew "@example.com"]"#, vec!["ada"]),
+        (r#"name.familyName sw "love" or active eq false"#, vec!["ada", "grace"]),
+        (r#"not (externalId pr)"#, vec!["grace"]),
+        (r#"urn:ietf:params:scim:schemas:core:2.0:User:userName ne "ada""#, vec!["grace"]),
+    ] {
+        let list = list_response(users.clone(), &page(filter, None, None)).unwrap();
+        let names: Vec<&str> =
+            list.resources.iter().map(|user| user.user_name.as_str()).collect();
+        assert_eq!(names, expected, "{}", filter);
+    }
+
+    for filter in [r#"userName eq"#, r#"userName is "ada""#, r#"(userName pr"#] {
+        assert!(filter.parse::<ScimFilter>().is_err(), "{}", filter);
+    }
+
+    assert!(matches!(
+        user_db.scim_user("idp", &antony.uuid).await,
+        Err(AuthError::UserDoesNotExistError(_))
+    ));
+    assert!(user_db.delete_scim_user("idp", &antony.uuid).await.is_err());
+    assert!(user_db.find_user_by_uuid(&antony.uuid).await.is_ok());
+
+    // nor are users another client provisioned
+    assert!(user_db.scim_users("other", 0, i64::MAX).await.unwrap().is_empty());
+    assert!(user_db.scim_user("other", &ada.id).await.is_err());
+    assert!(user_db.replace_scim_user("other", &ada.id, &ada).await.is_err());
+    assert!(user_db.delete_scim_user("other", &ada.id).await.is_err());
+
+    // a rejected password leaves the rest of a replace unapplied
+    let rejected = ScimUser {
+        user_name: "ada.lovelace".to_string(),
+        password: Some("short".to_string()),
+        ..ada.clone()
+    };
+
+    assert!(matches!(
+        user_db.replace_scim_user("idp", &ada.id, &rejected).await,
+        Err(AuthError::PasswordPolicyError(_))
+    ));
+    assert_eq!(user_db.find_user_by_uuid(&ada.id).await.unwrap().username, "ada");
+
+    // PATCH as sent by Azure AD disables the account
+    let patch = |body| serde_json::from_value::<ScimPatch>(body).unwrap();
+
+    let ada = user_db
+        .patch_scim_user(
+            "idp",
+            &ada.id,
+            &patch(json!({
+                "schemas": ["urn:ietf:params:scim:api:messages:2.0:PatchOp"],
+                "Operations": [
+                    { "op": "Replace", "path": "active", "value": "False" },
+                    { "op": "replace", "path": "name.givenName", "value": "Augusta" },
+                    {
+                        "op": "replace",
+                        "path": "emails[type eq \"work\"].value",
+                        "value": "augusta@example.com"
+                    },
+                ],
+            })),
+        )
+        .await
+        .unwrap();
+
+    // a changed address is no longer verified
+    let user = user_db.find_user_by_uuid(&ada.id).await.unwrap();
+    assert!(!user.can_signin);
+    assert_eq!(user.first_name, "Augusta");
+    assert_eq!(user.last_name, "Lovelace");
+    assert_eq!(user.email, "augusta@example.com");
+    assert!(!user.email_verified);
+
+    let grace = user_db.find_user_by_username("grace").await.unwrap();
+
+    let engineers = |members: Vec<ScimMember>| ScimGroup {
+        schemas: vec![],
+        id: String::new(),
+        external_id: None,
+        display_name: "Engineers".to_string(),
+        members,
+        meta: None,
+    };
+
+    let member = |value: &str| ScimMember {
+        value: value.to_string(),
+        display: None,
+    };
+
+    // groups may only hold the client's own users
+    assert!(user_db
+        .create_scim_group("other", &engineers(vec![member(&ada.id)]))
+        .await
+        .is_err());
+    assert!(user_db
+        .create_scim_group("idp", &engineers(vec![member(&antony.uuid)]))
+        .await
+        .is_err());
+
+    let group = user_db
+        .create_scim_group("idp", &engineers(vec![member(&ada.id)]))
+        .await
+        .unwrap();
+
+    assert_eq!(group.members.len(), 1);
+    assert_eq!(user_db.scim_user("idp", &ada.id).await.unwrap().groups[0].value, group.id);
+
+    // a page of users carries the groups of only its own users
+    for start_index in [1, 2] {
+        let list = user_db.list_scim_users("idp", &page("", Some(start_index), Some(1)));
+        let user = &list.await.unwrap().resources[0];
+        assert_eq!(user.groups.len(), usize::from(user.id == ada.id));
+    }
+    assert!(user_db.scim_groups("other").await.unwrap().is_empty());
+    assert!(user_db.scim_group("other", &group.id).await.is_err());
+    assert!(user_db.delete_scim_group("other", &group.id).await.is_err());
+
+    // group names are only unique within a client
+    assert!(matches!(
+        user_db.create_scim_group("idp", &engineers(vec![])).await,
+        Err(AuthError::ScimError(409, _, _))
+    ));
+
+    let other_group = user_db.create_scim_group("other", &engineers(vec![])).await.unwrap();
+    assert_ne!(other_group.id, group.id);
+    assert_eq!(user_db.scim_groups("other").await.unwrap().len(), 1);
+    assert_eq!(user_db.scim_group("idp", &group.id).await.unwrap().members.len(), 1);
+
+    let group = user_db
+        .patch_scim_group(
+            "idp",
+            &group.id,
+            &patch(json!({
+                "Operations": [
+                    { "op": "add", "path": "members", "value": [{ "value": grace.uuid }] },
+                    { "op": "remove", "path": format!("members[value eq \"{}\"]", ada.id) },
+                ],
+            })),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(group.members.len(), 1);
+    assert_eq!(group.members[0].value, grace.uuid);
+    assert_eq!(group.members[0].display.as_deref(), Some("grace"));
+
+    // members must be users
+    for value in ["nobody", antony.uuid.as_str()] {
+        assert!(user_db
+            .patch_scim_group(
+                "idp",
+                &group.id,
+                &patch(json!({
+                    "Operations": [
+                        { "op": "add", "path": "members", "value": [{ "value": value }] },
+                    ],
+                })),
+            )
+            .await
+            .is_err());
+    }
+
+    user_db.delete_scim_user("idp", &grace.uuid).await.unwrap();
+
+    assert!(user_db.scim_group("idp", &group.id).await.unwrap().members.is_empty());
+    assert!(matches!(
+        user_db.scim_user("idp", &grace.uuid).await,
+        Err(AuthError::UserDoesNotExistError(_))
+    ));
+    assert!(matches!(
+        user_db.find_user_by_uuid(&grace.uuid).await,
+        Err(AuthError::UserDoesNotExistError(_))
+    ));
+
+    user_db.delete_scim_group("idp", &group.id).await.unwrap();
+    assert!(user_db.scim_group("idp", &group.id).await.is_err());
+}
@@ -0,0 +1,120 @@
+use axum::{extract::State, http::StatusCode, routing::post, Json, Router};
+
+use crate::{
+    email::{
+        callback_link, EmailVerificationTemplate, EmailVerificationWebTemplate,
+        EmailVerifiedTemplate,
+    },
+    i18n::Messages,
+    jwt::{verify_email_jwt, AppState, JwtClaims, JwtToken, TokenType},
+    signin::{check_token_type, email_callback_url, EmailLinkReq},
+    AuthError, AuthResult, User,
+};
+
+///
+/// Email a token to confirm the user owns their email address, either as
+/// a link for web clients or as a code for API clients.
+///
+pub async fn send_verification_email(
+    state: &AppState,
+    user: &User,
+    callback_url: Option<&str>,
+    url: Option<&str>,
+) -> AuthResult<()> {
+    let t = Messages::for_user(user);
+    let token = verify_email_jwt(&user.uuid, &state.config.tokens, &state.jwt_private_key)?;
+    let time = t.duration(state.config.tokens.verify_email_ttl_mins);
+    let subject = t.get("verify.subject");
+
+    match email_callback_url(&state.config, callback_url) {
+        Some(callback_url) => {
+            let body = EmailVerificationWebTemplate {
+                name: user.display_name(),
+                link: callback_link(callback_url, &token, url)?,
+                time,
+                t,
+            };
+
+            state.outbox.enqueue_html_email(&user.email, subject, &body).await?;
+        }
+        None => {
+            let body = EmailVerificationTemplate {
+                name: user.display_name(),
+                link: token,
+                time,
+                t,
+            };
+
+            state.outbox.enqueue_html_email(&user.email, subject, &body).await?;
+        }
+    }
+
+    Ok(())
+}
+
+///
+/// Mark a user's email address as verified using the emailed token.
+///
+pub async fn verify_email(state: &AppState, claims: &JwtClaims) -> AuthResult<User> {
+    check_token_type(claims, &TokenType::VerifyEmail)?;
+
+    state.user_db.user_verified(&claims.uuid).await?;
+
+    let user = state.user_db.find_user_by_uuid(&claims.uuid).await?;
+
+    let t = Messages::for_user(&user);
+    let subject = t.get("verified.subject");
+
+    let body = EmailVerifiedTemplate {
+        name: user.display_name(),
+        t,
+    };
+
+    state.outbox.enqueue_html_email(&user.email, subject, &body).await?;
+
+    Ok(user)
+}
+
+///
+/// Resend the verification email if the account exists and is not yet
+/// verified. Unknown and verified accounts succeed too so the response
+/// does not reveal account state.
+///
+pub async fn request_verification_email(
+    state: &AppState,
+    req: &EmailLinkReq,
+) -> AuthResult<()> {
+    let user = match state.user_db.find_user_by_id(&req.username).await {
+        Ok(user) => user,
+        Err(AuthError::UserDoesNotExistError(_)) => return Ok(()),
+        Err(err) => return Err(err),
+    };
+
+    if user.email_verified {
+        return Ok(());
+    }
+
+    send_verification_email(state, &user, req.callback_url.as_deref(), req.url.as_deref()).await
+}
+
+async fn verify_email_handler(
+    State(state): State<AppState>,
+    Json(req): Json<EmailLinkReq>,
+) -> AuthResult<StatusCode> {
+    request_verification_email(&state, &req).await?;
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+async fn verify_handler(
+    State(state): State<AppState>,
+    JwtToken(claims): JwtToken,
+) -> AuthResult<Json<User>> {
+    Ok(Json(verify_email(&state, &claims).await?))
+}
+
+pub fn verify_router() -> Router<AppState> {
+    Router::new()
+        .route("/verify/email", post(verify_email_handler))
+        .route("/verify", post(verify_handler))
+}